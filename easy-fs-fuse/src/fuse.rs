@@ -0,0 +1,975 @@
+//! Mount an easy-fs image on the host, speaking the FUSE protocol over `/dev/fuse`.
+
+use easy_fs::{EasyFileSystem, Inode, Stat, MAX_FILE_SIZE};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+const FUSE_ROOT_ID: u64 = 1;
+const MAX_WRITE: usize = 128 * 1024;
+/// Room for a request with `MAX_WRITE` bytes of data.
+const BUFFER_SIZE: usize = MAX_WRITE + 4096;
+
+/// Longest name easy-fs can store.
+const NAME_LENGTH_LIMIT: usize = 27;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const DT_DIR: u32 = 4;
+const DT_REG: u32 = 8;
+
+// opcodes
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_MKNOD: u32 = 8;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_LINK: u32 = 13;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FSYNC: u32 = 20;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_FSYNCDIR: u32 = 30;
+const FUSE_ACCESS: u32 = 34;
+const FUSE_CREATE: u32 = 35;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+const FUSE_RENAME2: u32 = 45;
+
+// bits of `SetattrIn::valid`
+const FATTR_SIZE: u32 = 1 << 3;
+const FATTR_ATIME: u32 = 1 << 4;
+const FATTR_MTIME: u32 = 1 << 5;
+const FATTR_ATIME_NOW: u32 = 1 << 7;
+const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct InHeader {
+    len: u32,
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct OutHeader {
+    len: u32,
+    error: i32,
+    unique: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct InitIn {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct InitOut {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+    max_background: u16,
+    congestion_threshold: u16,
+    max_write: u32,
+    time_gran: u32,
+    max_pages: u16,
+    map_alignment: u16,
+    flags2: u32,
+    unused: [u32; 7],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Attr {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    atimensec: u32,
+    mtimensec: u32,
+    ctimensec: u32,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EntryOut {
+    nodeid: u64,
+    generation: u64,
+    entry_valid: u64,
+    attr_valid: u64,
+    entry_valid_nsec: u32,
+    attr_valid_nsec: u32,
+    attr: Attr,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AttrOut {
+    attr_valid: u64,
+    attr_valid_nsec: u32,
+    dummy: u32,
+    attr: Attr,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SetattrIn {
+    valid: u32,
+    padding: u32,
+    fh: u64,
+    size: u64,
+    lock_owner: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    atimensec: u32,
+    mtimensec: u32,
+    ctimensec: u32,
+    mode: u32,
+    unused4: u32,
+    uid: u32,
+    gid: u32,
+    unused5: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MknodIn {
+    mode: u32,
+    rdev: u32,
+    umask: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MkdirIn {
+    mode: u32,
+    umask: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CreateIn {
+    flags: u32,
+    mode: u32,
+    umask: u32,
+    open_flags: u32,
+}
+
+#[repr(C)]
+struct OpenOut {
+    fh: u64,
+    open_flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ForgetIn {
+    nlookup: u64,
+}
+
+/// Followed by `count` of `ForgetOne`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BatchForgetIn {
+    count: u32,
+    dummy: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ForgetOne {
+    nodeid: u64,
+    nlookup: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RenameIn {
+    newdir: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Rename2In {
+    newdir: u64,
+    flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LinkIn {
+    oldnodeid: u64,
+}
+
+/// Also the layout of `fuse_write_in`, followed by the data.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ReadIn {
+    fh: u64,
+    offset: u64,
+    size: u32,
+    read_flags: u32,
+    lock_owner: u64,
+    flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct WriteOut {
+    size: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Dirent {
+    ino: u64,
+    off: u64,
+    namelen: u32,
+    type_: u32,
+}
+
+#[repr(C)]
+struct StatfsOut {
+    blocks: u64,
+    bfree: u64,
+    bavail: u64,
+    files: u64,
+    ffree: u64,
+    bsize: u32,
+    namelen: u32,
+    frsize: u32,
+    padding: u32,
+    spare: [u32; 6],
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
+/// Split a `T` off the front of `buf`.
+fn parse<T: Copy>(buf: &[u8]) -> Option<(T, &[u8])> {
+    if buf.len() < std::mem::size_of::<T>() {
+        return None;
+    }
+    let value = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const T) };
+    Some((value, &buf[std::mem::size_of::<T>()..]))
+}
+
+/// Split a NUL-terminated name off the front of `buf`.
+fn parse_name(buf: &[u8]) -> Option<(&str, &[u8])> {
+    let len = buf.iter().position(|&byte| byte == 0)?;
+    let name = std::str::from_utf8(&buf[..len]).ok()?;
+    Some((name, &buf[len + 1..]))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn attr(stat: &Stat) -> Attr {
+    Attr {
+        ino: stat.ino as u64 + FUSE_ROOT_ID,
+        size: stat.size as u64,
+        blocks: stat.blocks as u64,
+        atime: stat.atime,
+        mtime: stat.mtime,
+        ctime: stat.ctime,
+        atimensec: 0,
+        mtimensec: 0,
+        ctimensec: 0,
+        mode: stat.mode | if stat.is_dir { S_IFDIR } else { S_IFREG },
+        nlink: stat.nlink,
+        uid: stat.uid,
+        gid: stat.gid,
+        rdev: 0,
+        blksize: 512,
+        flags: 0,
+    }
+}
+
+/// Reply payload, or an errno.
+type Reply = Result<Vec<u8>, i32>;
+
+fn reply<T>(value: &T) -> Reply {
+    Ok(as_bytes(value).to_vec())
+}
+
+struct Session {
+    efs: Arc<spin::Mutex<EasyFileSystem>>,
+    /// Inodes known to the kernel by node ID, with the number of lookups it has not forgotten.
+    /// They keep files removed while open until they are closed.
+    nodes: RefCell<HashMap<u64, (Arc<Inode>, u64)>>,
+}
+
+impl Session {
+    fn new(efs: Arc<spin::Mutex<EasyFileSystem>>) -> Self {
+        Self {
+            efs,
+            nodes: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn inode(&self, nodeid: u64) -> Arc<Inode> {
+        match self.nodes.borrow().get(&nodeid) {
+            Some((inode, _)) => Arc::clone(inode),
+            None => Arc::new(EasyFileSystem::get_inode(
+                &self.efs,
+                (nodeid - FUSE_ROOT_ID) as u32,
+            )),
+        }
+    }
+
+    /// Reply to a lookup of `inode`, which the kernel then knows until it forgets it.
+    fn entry(&self, inode: Arc<Inode>) -> EntryOut {
+        let stat = inode.stat();
+        let nodeid = stat.ino as u64 + FUSE_ROOT_ID;
+        let mut nodes = self.nodes.borrow_mut();
+        nodes.entry(nodeid).or_insert((inode, 0)).1 += 1;
+        EntryOut {
+            nodeid,
+            generation: 0,
+            entry_valid: 1,
+            attr_valid: 1,
+            entry_valid_nsec: 0,
+            attr_valid_nsec: 0,
+            attr: attr(&stat),
+        }
+    }
+
+    fn forget(&self, nodeid: u64, nlookup: u64) {
+        let mut nodes = self.nodes.borrow_mut();
+        if let Some((_, count)) = nodes.get_mut(&nodeid) {
+            *count = count.saturating_sub(nlookup);
+            if *count == 0 {
+                nodes.remove(&nodeid);
+            }
+        }
+    }
+
+    /// Handle `FUSE_FORGET` or `FUSE_BATCH_FORGET`, which have no reply.
+    fn forget_request(&self, header: &InHeader, body: &[u8]) {
+        if header.opcode == FUSE_FORGET {
+            if let Some((forget, _)) = parse::<ForgetIn>(body) {
+                self.forget(header.nodeid, forget.nlookup);
+            }
+            return;
+        }
+        let (batch, mut rest) = match parse::<BatchForgetIn>(body) {
+            Some(x) => x,
+            None => return,
+        };
+        for _ in 0..batch.count {
+            match parse::<ForgetOne>(rest) {
+                Some((one, next)) => {
+                    self.forget(one.nodeid, one.nlookup);
+                    rest = next;
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Serve requests from `device` until the file system is unmounted.
+    fn run(&self, device: &mut File) -> std::io::Result<()> {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let len = match device.read(&mut buf) {
+                Ok(len) => len,
+                // the request was interrupted
+                Err(err) if err.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                // unmounted
+                Err(err) if err.raw_os_error() == Some(libc::ENODEV) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let request = &buf[..len];
+            if let Some(message) = self.handle(request) {
+                match device.write(&message) {
+                    // the request was interrupted meanwhile
+                    Err(err) if err.raw_os_error() == Some(libc::ENOENT) => {}
+                    result => {
+                        result?;
+                    }
+                }
+            }
+            if matches!(parse::<InHeader>(request), Some((header, _)) if header.opcode == FUSE_DESTROY)
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handle one request as read from `/dev/fuse`, returning the reply to write back, if any.
+    fn handle(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let (header, body) = parse::<InHeader>(request)?;
+        let body = &body[..(header.len as usize)
+            .min(request.len())
+            .saturating_sub(std::mem::size_of::<InHeader>())];
+        let result = match header.opcode {
+            // no reply
+            FUSE_FORGET | FUSE_BATCH_FORGET => {
+                self.forget_request(&header, body);
+                return None;
+            }
+            FUSE_INTERRUPT => return None,
+            FUSE_DESTROY => Ok(Vec::new()),
+            _ => self.dispatch(&header, body),
+        };
+        let (error, payload) = match result {
+            Ok(payload) => (0, payload),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let out = OutHeader {
+            len: (std::mem::size_of::<OutHeader>() + payload.len()) as u32,
+            error,
+            unique: header.unique,
+        };
+        let mut message = as_bytes(&out).to_vec();
+        message.extend_from_slice(&payload);
+        Some(message)
+    }
+
+    fn dispatch(&self, header: &InHeader, body: &[u8]) -> Reply {
+        match header.opcode {
+            FUSE_INIT => {
+                let (init, _) = parse::<InitIn>(body).ok_or(libc::EINVAL)?;
+                if init.major != FUSE_KERNEL_VERSION {
+                    return Err(libc::EPROTO);
+                }
+                reply(&InitOut {
+                    major: FUSE_KERNEL_VERSION,
+                    minor: FUSE_KERNEL_MINOR_VERSION.min(init.minor),
+                    max_readahead: init.max_readahead,
+                    flags: 0,
+                    max_background: 16,
+                    congestion_threshold: 12,
+                    max_write: MAX_WRITE as u32,
+                    time_gran: 1_000_000_000,
+                    max_pages: 0,
+                    map_alignment: 0,
+                    flags2: 0,
+                    unused: [0; 7],
+                })
+            }
+            FUSE_LOOKUP => {
+                let (name, _) = parse_name(body).ok_or(libc::EINVAL)?;
+                let inode = self.inode(header.nodeid).find(name).ok_or(libc::ENOENT)?;
+                reply(&self.entry(inode))
+            }
+            FUSE_GETATTR => reply(&self.attr_out(&self.inode(header.nodeid))),
+            FUSE_SETATTR => {
+                let (setattr, _) = parse::<SetattrIn>(body).ok_or(libc::EINVAL)?;
+                let inode = self.inode(header.nodeid);
+                if setattr.valid & FATTR_SIZE != 0 {
+                    if inode.is_dir() {
+                        return Err(libc::EISDIR);
+                    }
+                    if setattr.size > MAX_FILE_SIZE as u64 || !inode.truncate(setattr.size as u32) {
+                        return Err(libc::EFBIG);
+                    }
+                }
+                if setattr.valid & (FATTR_ATIME | FATTR_MTIME) != 0 {
+                    let stat = inode.stat();
+                    let time = |set: u32, now: u32, time: u64, old: u64| match (
+                        setattr.valid & set != 0,
+                        setattr.valid & now != 0,
+                    ) {
+                        (true, true) => unix_now(),
+                        (true, false) => time,
+                        _ => old,
+                    };
+                    inode.set_times(
+                        time(FATTR_ATIME, FATTR_ATIME_NOW, setattr.atime, stat.atime),
+                        time(FATTR_MTIME, FATTR_MTIME_NOW, setattr.mtime, stat.mtime),
+                    );
+                }
+                // mode and owner are fixed in easy-fs
+                reply(&self.attr_out(&inode))
+            }
+            FUSE_MKNOD => {
+                let (mknod, rest) = parse::<MknodIn>(body).ok_or(libc::EINVAL)?;
+                let (name, _) = parse_name(rest).ok_or(libc::EINVAL)?;
+                if mknod.mode & libc::S_IFMT != S_IFREG {
+                    return Err(libc::EPERM);
+                }
+                let inode = self.create(header.nodeid, name, false)?;
+                reply(&self.entry(inode))
+            }
+            FUSE_MKDIR => {
+                let (_, rest) = parse::<MkdirIn>(body).ok_or(libc::EINVAL)?;
+                let (name, _) = parse_name(rest).ok_or(libc::EINVAL)?;
+                let inode = self.create(header.nodeid, name, true)?;
+                reply(&self.entry(inode))
+            }
+            FUSE_CREATE => {
+                let (_, rest) = parse::<CreateIn>(body).ok_or(libc::EINVAL)?;
+                let (name, _) = parse_name(rest).ok_or(libc::EINVAL)?;
+                let inode = self.create(header.nodeid, name, false)?;
+                let mut payload = as_bytes(&self.entry(inode)).to_vec();
+                payload.extend_from_slice(as_bytes(&OpenOut {
+                    fh: 0,
+                    open_flags: 0,
+                    padding: 0,
+                }));
+                Ok(payload)
+            }
+            FUSE_UNLINK => {
+                let (name, _) = parse_name(body).ok_or(libc::EINVAL)?;
+                let dir = self.inode(header.nodeid);
+                let inode = dir.find(name).ok_or(libc::ENOENT)?;
+                if inode.is_dir() {
+                    return Err(libc::EISDIR);
+                }
+                if !dir.unlink(name) {
+                    return Err(libc::EPERM);
+                }
+                Ok(Vec::new())
+            }
+            FUSE_RMDIR => {
+                let (name, _) = parse_name(body).ok_or(libc::EINVAL)?;
+                let dir = self.inode(header.nodeid);
+                let inode = dir.find(name).ok_or(libc::ENOENT)?;
+                if !inode.is_dir() {
+                    return Err(libc::ENOTDIR);
+                }
+                if !dir.rmdir(name) {
+                    return Err(if name == "." || name == ".." {
+                        libc::EINVAL
+                    } else {
+                        libc::ENOTEMPTY
+                    });
+                }
+                Ok(Vec::new())
+            }
+            FUSE_RENAME | FUSE_RENAME2 => {
+                let (newdir, rest) = if header.opcode == FUSE_RENAME {
+                    let (rename, rest) = parse::<RenameIn>(body).ok_or(libc::EINVAL)?;
+                    (rename.newdir, rest)
+                } else {
+                    let (rename, rest) = parse::<Rename2In>(body).ok_or(libc::EINVAL)?;
+                    if rename.flags != 0 {
+                        return Err(libc::EINVAL);
+                    }
+                    (rename.newdir, rest)
+                };
+                let (old_name, rest) = parse_name(rest).ok_or(libc::EINVAL)?;
+                let (new_name, _) = parse_name(rest).ok_or(libc::EINVAL)?;
+                let dir = self.inode(header.nodeid);
+                let new_dir = self.inode(newdir);
+                let inode = dir.find(old_name).ok_or(libc::ENOENT)?;
+                if !dir.rename(old_name, &new_dir, new_name) {
+                    return Err(match new_dir.find(new_name) {
+                        Some(target) if target.is_dir() && !inode.is_dir() => libc::EISDIR,
+                        Some(target) if !target.is_dir() && inode.is_dir() => libc::ENOTDIR,
+                        Some(target) if target.is_dir() => libc::ENOTEMPTY,
+                        _ if new_name.len() > NAME_LENGTH_LIMIT => libc::ENAMETOOLONG,
+                        _ => libc::EINVAL,
+                    });
+                }
+                Ok(Vec::new())
+            }
+            FUSE_LINK => {
+                let (link, rest) = parse::<LinkIn>(body).ok_or(libc::EINVAL)?;
+                let (name, _) = parse_name(rest).ok_or(libc::EINVAL)?;
+                let dir = self.inode(header.nodeid);
+                let inode = self.inode(link.oldnodeid);
+                if inode.is_dir() {
+                    return Err(libc::EPERM);
+                }
+                if !dir.link(name, &inode) {
+                    return Err(self.create_error(&dir, name));
+                }
+                reply(&self.entry(inode))
+            }
+            FUSE_OPEN | FUSE_OPENDIR => reply(&OpenOut {
+                fh: 0,
+                open_flags: 0,
+                padding: 0,
+            }),
+            FUSE_READ => {
+                let (read, _) = parse::<ReadIn>(body).ok_or(libc::EINVAL)?;
+                let mut data = vec![0u8; read.size as usize];
+                let len = self
+                    .inode(header.nodeid)
+                    .read_at(read.offset as usize, &mut data);
+                data.truncate(len);
+                Ok(data)
+            }
+            FUSE_WRITE => {
+                let (write, data) = parse::<ReadIn>(body).ok_or(libc::EINVAL)?;
+                let data = data.get(..write.size as usize).ok_or(libc::EINVAL)?;
+                // a write crossing the maximum size is cut short there
+                if write.offset >= MAX_FILE_SIZE as u64 && !data.is_empty() {
+                    return Err(libc::EFBIG);
+                }
+                let size = self
+                    .inode(header.nodeid)
+                    .write_at(write.offset as usize, data);
+                reply(&WriteOut {
+                    size: size as u32,
+                    padding: 0,
+                })
+            }
+            FUSE_READDIR => {
+                let (read, _) = parse::<ReadIn>(body).ok_or(libc::EINVAL)?;
+                let dir = self.inode(header.nodeid);
+                let mut data = Vec::new();
+                for (i, name) in dir.ls().iter().enumerate().skip(read.offset as usize) {
+                    let inode = match dir.find(name) {
+                        Some(inode) => inode,
+                        None => continue,
+                    };
+                    let dirent = Dirent {
+                        ino: inode.stat().ino as u64 + FUSE_ROOT_ID,
+                        off: i as u64 + 1,
+                        namelen: name.len() as u32,
+                        type_: if inode.is_dir() { DT_DIR } else { DT_REG },
+                    };
+                    // entries are padded to 8 bytes
+                    let len = (std::mem::size_of::<Dirent>() + name.len() + 7) & !7;
+                    if data.len() + len > read.size as usize {
+                        break;
+                    }
+                    data.extend_from_slice(as_bytes(&dirent));
+                    data.extend_from_slice(name.as_bytes());
+                    data.resize(
+                        data.len() + len - std::mem::size_of::<Dirent>() - name.len(),
+                        0,
+                    );
+                }
+                Ok(data)
+            }
+            FUSE_STATFS => {
+                let efs = self.efs.lock();
+                let (inodes, blocks) = efs.usage();
+                let total_inodes = efs.inode_bitmap.maximum();
+                let total_blocks = efs.super_block().data_area_blocks as usize;
+                reply(&StatfsOut {
+                    blocks: total_blocks as u64,
+                    bfree: (total_blocks - blocks) as u64,
+                    bavail: (total_blocks - blocks) as u64,
+                    files: total_inodes as u64,
+                    ffree: (total_inodes - inodes) as u64,
+                    bsize: 512,
+                    namelen: NAME_LENGTH_LIMIT as u32,
+                    frsize: 512,
+                    padding: 0,
+                    spare: [0; 6],
+                })
+            }
+            FUSE_FSYNC | FUSE_FSYNCDIR => {
+                self.efs.lock().sync();
+                Ok(Vec::new())
+            }
+            FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH | FUSE_ACCESS => Ok(Vec::new()),
+            _ => Err(libc::ENOSYS),
+        }
+    }
+
+    fn attr_out(&self, inode: &Inode) -> AttrOut {
+        AttrOut {
+            attr_valid: 1,
+            attr_valid_nsec: 0,
+            dummy: 0,
+            attr: attr(&inode.stat()),
+        }
+    }
+
+    fn create(&self, parent: u64, name: &str, is_dir: bool) -> Result<Arc<Inode>, i32> {
+        let dir = self.inode(parent);
+        let inode = if is_dir {
+            dir.create_dir(name)
+        } else {
+            dir.create(name)
+        };
+        inode.ok_or_else(|| self.create_error(&dir, name))
+    }
+
+    /// Why `name` could not be added to `dir`.
+    fn create_error(&self, dir: &Inode, name: &str) -> i32 {
+        if dir.find(name).is_some() {
+            libc::EEXIST
+        } else if name.len() > NAME_LENGTH_LIMIT {
+            libc::ENAMETOOLONG
+        } else {
+            libc::EINVAL
+        }
+    }
+}
+
+/// Mount `efs` at `mount_point` and serve it until it is unmounted, e.g. by `umount`.
+///
+/// This calls mount(2) itself rather than going through `fusermount`, so it needs root.
+pub fn mount(efs: Arc<spin::Mutex<EasyFileSystem>>, mount_point: &str) -> std::io::Result<()> {
+    let mut device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let options = format!(
+        "fd={},rootmode={:o},user_id={},group_id={}",
+        device.as_raw_fd(),
+        S_IFDIR,
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() },
+    );
+    let source = CString::new("easy-fs").unwrap();
+    let target = CString::new(mount_point)?;
+    let fstype = CString::new("fuse.easy-fs").unwrap();
+    let options = CString::new(options).unwrap();
+    let ret = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr() as *const libc::c_void,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let result = Session::new(Arc::clone(&efs)).run(&mut device);
+    // the cache is written back only on fsync until now
+    efs.lock().sync();
+    result
+}
+
+/// Send the request `opcode` on `nodeid` with `body` to `session` and check the reply header.
+#[cfg(test)]
+fn request(session: &Session, opcode: u32, nodeid: u64, body: &[u8]) -> Reply {
+    let header = InHeader {
+        len: (std::mem::size_of::<InHeader>() + body.len()) as u32,
+        opcode,
+        unique: 7,
+        nodeid,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+        padding: 0,
+    };
+    let mut message = as_bytes(&header).to_vec();
+    message.extend_from_slice(body);
+    let message = session.handle(&message).expect("no reply");
+    let (out, payload) = parse::<OutHeader>(&message).unwrap();
+    assert_eq!(out.len as usize, message.len());
+    assert_eq!(out.unique, 7);
+    match out.error {
+        0 => Ok(payload.to_vec()),
+        error => Err(-error),
+    }
+}
+
+/// `value` followed by the NUL-terminated `names`.
+#[cfg(test)]
+fn request_body<T>(value: &T, names: &[&str]) -> Vec<u8> {
+    let mut body = as_bytes(value).to_vec();
+    for name in names {
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+    }
+    body
+}
+
+#[cfg(test)]
+fn read_in(offset: u64, size: u32) -> ReadIn {
+    ReadIn {
+        fh: 0,
+        offset,
+        size,
+        read_flags: 0,
+        lock_owner: 0,
+        flags: 0,
+        padding: 0,
+    }
+}
+
+#[cfg(test)]
+fn truncate_in(size: u64) -> SetattrIn {
+    SetattrIn {
+        valid: FATTR_SIZE,
+        padding: 0,
+        fh: 0,
+        size,
+        lock_owner: 0,
+        atime: 0,
+        mtime: 0,
+        ctime: 0,
+        atimensec: 0,
+        mtimensec: 0,
+        ctimensec: 0,
+        mode: 0,
+        unused4: 0,
+        uid: 0,
+        gid: 0,
+        unused5: 0,
+    }
+}
+
+#[test]
+fn fuse_test() -> std::io::Result<()> {
+    let block_file = crate::test_image("target/fuse.img")?;
+    EasyFileSystem::create(block_file.clone(), 8192, 1, 0);
+    let session = Session::new(EasyFileSystem::open(block_file));
+    let lookup = |dir: u64, name: &str| {
+        request(&session, FUSE_LOOKUP, dir, &request_body(&(), &[name]))
+            .map(|payload| parse::<EntryOut>(&payload).unwrap().0)
+    };
+    let read = |nodeid: u64, offset: u64| {
+        request(&session, FUSE_READ, nodeid, as_bytes(&read_in(offset, 100))).unwrap()
+    };
+
+    let init = InitIn {
+        major: FUSE_KERNEL_VERSION,
+        minor: 40,
+        max_readahead: 4096,
+        flags: 0,
+    };
+    let payload = request(&session, FUSE_INIT, 0, as_bytes(&init)).unwrap();
+    let (init_out, _) = parse::<InitOut>(&payload).unwrap();
+    assert_eq!(init_out.minor, FUSE_KERNEL_MINOR_VERSION);
+
+    // create, then look up the same node
+    let create = CreateIn {
+        flags: 0,
+        mode: S_IFREG | 0o644,
+        umask: 0,
+        open_flags: 0,
+    };
+    let body = request_body(&create, &["file"]);
+    let payload = request(&session, FUSE_CREATE, FUSE_ROOT_ID, &body).unwrap();
+    let (entry, rest) = parse::<EntryOut>(&payload).unwrap();
+    assert_eq!(rest.len(), std::mem::size_of::<OpenOut>());
+    assert_eq!(entry.attr.mode, S_IFREG | 0o644);
+    let file = entry.nodeid;
+    assert_eq!(
+        request(&session, FUSE_CREATE, FUSE_ROOT_ID, &body).err(),
+        Some(libc::EEXIST)
+    );
+    assert_eq!(lookup(FUSE_ROOT_ID, "file").unwrap().nodeid, file);
+    assert_eq!(lookup(FUSE_ROOT_ID, "missing").err(), Some(libc::ENOENT));
+
+    // write and read back
+    let mut body = as_bytes(&read_in(0, 11)).to_vec();
+    body.extend_from_slice(b"hello world");
+    let payload = request(&session, FUSE_WRITE, file, &body).unwrap();
+    assert_eq!(parse::<WriteOut>(&payload).unwrap().0.size, 11);
+    assert_eq!(read(file, 6), b"world");
+
+    // truncate
+    let payload = request(&session, FUSE_SETATTR, file, as_bytes(&truncate_in(5))).unwrap();
+    assert_eq!(parse::<AttrOut>(&payload).unwrap().0.attr.size, 5);
+    assert_eq!(read(file, 0), b"hello");
+
+    // past the maximum file size
+    let body = as_bytes(&truncate_in(MAX_FILE_SIZE as u64 + 1)).to_vec();
+    assert_eq!(
+        request(&session, FUSE_SETATTR, file, &body).err(),
+        Some(libc::EFBIG)
+    );
+    let mut body = as_bytes(&read_in(MAX_FILE_SIZE as u64, 1)).to_vec();
+    body.push(b'x');
+    assert_eq!(
+        request(&session, FUSE_WRITE, file, &body).err(),
+        Some(libc::EFBIG)
+    );
+    assert_eq!(read(file, 0), b"hello");
+
+    // rename into a new directory
+    let mkdir = MkdirIn {
+        mode: 0o755,
+        umask: 0,
+    };
+    let body = request_body(&mkdir, &["dir"]);
+    let payload = request(&session, FUSE_MKDIR, FUSE_ROOT_ID, &body).unwrap();
+    let dir = parse::<EntryOut>(&payload).unwrap().0.nodeid;
+    let body = request_body(&RenameIn { newdir: dir }, &["file", "moved"]);
+    request(&session, FUSE_RENAME, FUSE_ROOT_ID, &body).unwrap();
+    assert_eq!(lookup(FUSE_ROOT_ID, "file").err(), Some(libc::ENOENT));
+    assert_eq!(lookup(dir, "moved").unwrap().nodeid, file);
+
+    // readdir
+    let payload = request(
+        &session,
+        FUSE_READDIR,
+        FUSE_ROOT_ID,
+        as_bytes(&read_in(0, 4096)),
+    )
+    .unwrap();
+    let mut names = Vec::new();
+    let mut rest = &payload[..];
+    while let Some((dirent, name)) = parse::<Dirent>(rest) {
+        let name = &name[..dirent.namelen as usize];
+        names.push(String::from_utf8(name.to_vec()).unwrap());
+        let len = (std::mem::size_of::<Dirent>() + name.len() + 7) & !7;
+        if name == b"dir" {
+            assert_eq!((dirent.ino, dirent.type_), (dir, DT_DIR));
+        }
+        rest = &rest[len..];
+    }
+    assert!(names.contains(&"dir".to_string()));
+    assert!(!names.contains(&"file".to_string()));
+
+    // unlink, which keeps the file while the kernel knows it
+    assert_eq!(
+        request(
+            &session,
+            FUSE_UNLINK,
+            FUSE_ROOT_ID,
+            &request_body(&(), &["dir"])
+        )
+        .err(),
+        Some(libc::EISDIR)
+    );
+    request(&session, FUSE_UNLINK, dir, &request_body(&(), &["moved"])).unwrap();
+    assert_eq!(lookup(dir, "moved").err(), Some(libc::ENOENT));
+    assert_eq!(read(file, 0), b"hello");
+    Ok(())
+}
@@ -1,9 +1,14 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
-use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+mod fuse;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use easy_fs::{fsck, BlockDevice, EasyFileSystem, Inode, MAX_FILE_SIZE, MIN_JOURNAL_BLOCKS};
+use std::collections::HashMap;
+use std::fs::{read_dir, read_to_string, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const BLOCK_SZ: usize = 512;
 
@@ -23,65 +28,481 @@ impl BlockDevice for BlockFile {
             .expect("Error when seeking!");
         assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
     }
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        file.read_exact(buf).expect("Not complete blocks!");
+    }
+
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        file.write_all(buf).expect("Not complete blocks!");
+    }
+
+    fn flush(&self) {
+        self.0.lock().unwrap().sync_data().expect("Error when flushing!");
+    }
 }
 
-fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+/// An image opened read-only. The blocks written, such as those of a committed transaction
+/// replayed on open and access times, are kept in memory over it.
+struct ReadOnlyImage {
+    image: BlockFile,
+    written: Mutex<HashMap<usize, Vec<u8>>>,
+}
+
+impl BlockDevice for ReadOnlyImage {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        match self.written.lock().unwrap().get(&block_id) {
+            Some(data) => buf.copy_from_slice(data),
+            None => self.image.read_block(block_id, buf),
+        }
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.written.lock().unwrap().insert(block_id, buf.to_vec());
+    }
 }
 
-fn easy_fs_pack() -> std::io::Result<()> {
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn main() {
+    let image = || {
+        Arg::with_name("image")
+            .required(true)
+            .help("Path of the easy-fs image")
+    };
+    let path = |help| Arg::with_name("path").required(true).help(help);
     let matches = App::new("EasyFileSystem packer")
-        .arg(
-            Arg::with_name("source")
-                .short("s")
-                .long("source")
-                .takes_value(true)
-                .help("Executable source dir(with backslash)"),
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("pack")
+                .about("Create an image from host files")
+                .arg(
+                    Arg::with_name("source")
+                        .short("s")
+                        .long("source")
+                        .takes_value(true)
+                        .requires("target")
+                        .help("Executable source dir(with backslash)"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .short("t")
+                        .long("target")
+                        .takes_value(true)
+                        .help("Executable target dir(with backslash)"),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .short("m")
+                        .long("manifest")
+                        .takes_value(true)
+                        .conflicts_with("source")
+                        .help("File listing an image path and a host path per line"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .required_unless("target")
+                        .help("Image to create, fs.img in the target dir by default"),
+                )
+                .arg(
+                    Arg::with_name("blocks")
+                        .short("b")
+                        .long("blocks")
+                        .takes_value(true)
+                        .default_value("32768")
+                        .help("Size of the image in 512-byte blocks"),
+                )
+                .arg(
+                    Arg::with_name("inodes")
+                        .short("i")
+                        .long("inodes")
+                        .takes_value(true)
+                        .default_value("4096")
+                        .help("Number of inodes, rounded up to a multiple of 4096"),
+                )
+                .arg(
+                    Arg::with_name("journal")
+                        .short("j")
+                        .long("journal")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("Blocks of the metadata journal, none by default"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check an image for corruption")
+                .arg(image())
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Fix the problems found"),
+                ),
         )
-        .arg(
-            Arg::with_name("target")
-                .short("t")
-                .long("target")
-                .takes_value(true)
-                .help("Executable target dir(with backslash)"),
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about(
+                    "Mount an image on the host with FUSE until it is unmounted; \
+                     needs root, as it mounts without fusermount",
+                )
+                .arg(image())
+                .arg(Arg::with_name("dir").required(true).help("Mount point")),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Print the super block and how much of the image is used")
+                .arg(image()),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List a directory in an image")
+                .arg(image())
+                .arg(Arg::with_name("path").default_value("/")),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Dump a file in an image to stdout")
+                .arg(image())
+                .arg(path("File in the image")),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Extract a file from an image")
+                .arg(image())
+                .arg(path("File in the image"))
+                .arg(
+                    Arg::with_name("host")
+                        .required(true)
+                        .help("Host file to write"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Add a file to an image, replacing it if it exists")
+                .arg(image())
+                .arg(
+                    Arg::with_name("host")
+                        .required(true)
+                        .help("Host file to read"),
+                )
+                .arg(path("File in the image, parent directories are created")),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a file or an empty directory from an image")
+                .arg(image())
+                .arg(path("File or directory in the image")),
         )
         .get_matches();
-    let src_path = matches.value_of("source").unwrap();
-    let target_path = matches.value_of("target").unwrap();
-    println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+    let result = match matches.subcommand() {
+        ("pack", Some(matches)) => easy_fs_pack(matches),
+        ("check", Some(matches)) => {
+            let image = matches.value_of("image").unwrap();
+            match easy_fs_check(image, matches.is_present("repair")) {
+                Ok(problems) => std::process::exit(if problems { 1 } else { 0 }),
+                Err(err) => Err(err),
+            }
+        }
+        ("mount", Some(matches)) => easy_fs_mount(
+            matches.value_of("image").unwrap(),
+            matches.value_of("dir").unwrap(),
+        ),
+        ("info", Some(matches)) => easy_fs_info(matches.value_of("image").unwrap()),
+        (command, Some(matches)) => easy_fs_file(command, matches),
+        _ => unreachable!(),
+    };
+    if let Err(err) = result {
+        eprintln!("easy-fs-fuse: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn open_image(image: &str, writable: bool) -> std::io::Result<Arc<spin::Mutex<EasyFileSystem>>> {
+    let file = OpenOptions::new().read(true).write(writable).open(image)?;
+    EasyFileSystem::set_clock(|| unix_time(SystemTime::now()));
+    let image = BlockFile(Mutex::new(file));
+    let block_device: Arc<dyn BlockDevice> = if writable {
+        Arc::new(image)
+    } else {
+        Arc::new(ReadOnlyImage {
+            image,
+            written: Mutex::new(HashMap::new()),
+        })
+    };
+    Ok(EasyFileSystem::open(block_device))
+}
+
+fn not_found(path: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{}: not found", path))
+}
+
+/// Look up `path` from the root of the image.
+fn lookup(efs: &Arc<spin::Mutex<EasyFileSystem>>, path: &str) -> Option<Arc<Inode>> {
+    let mut inode = Arc::new(EasyFileSystem::root_inode(efs));
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        inode = inode.find(name)?;
+    }
+    Some(inode)
+}
+
+/// Return the directory that should hold `path` and the last name of `path`, creating the
+/// directories on the way.
+fn create_parents<'a>(
+    efs: &Arc<spin::Mutex<EasyFileSystem>>,
+    path: &'a str,
+) -> std::io::Result<(Arc<Inode>, &'a str)> {
+    let mut names: Vec<_> = path.split('/').filter(|name| !name.is_empty()).collect();
+    let last = names.pop().ok_or_else(|| not_found(path))?;
+    let mut dir = Arc::new(EasyFileSystem::root_inode(efs));
+    for name in names {
+        dir = match dir.find(name) {
+            Some(inode) => inode,
+            None => dir
+                .create_dir(name)
+                .ok_or_else(|| Error::other(format!("{}: cannot create {}", path, name)))?,
+        };
+        if !dir.is_dir() {
+            return Err(Error::other(format!("{}: not a directory", name)));
+        }
+    }
+    Ok((dir, last))
+}
+
+/// Write host file `host` to `path` in the image, keeping its timestamps.
+fn put_file(
+    efs: &Arc<spin::Mutex<EasyFileSystem>>,
+    host: &Path,
+    path: &str,
+) -> std::io::Result<()> {
+    let mut host_file = File::open(host)?;
+    let mut all_data: Vec<u8> = Vec::new();
+    host_file.read_to_end(&mut all_data)?;
+    let metadata = host_file.metadata()?;
+    if all_data.len() > MAX_FILE_SIZE {
+        return Err(Error::other(format!("{}: file too large", host.display())));
+    }
+    let (dir, name) = create_parents(efs, path)?;
+    let inode = match dir.find(name) {
+        Some(inode) if inode.is_dir() => {
+            return Err(Error::other(format!("{}: is a directory", path)));
+        }
+        Some(inode) => {
+            inode.clear();
+            inode
+        }
+        None => dir
+            .create(name)
+            .ok_or_else(|| Error::other(format!("{}: cannot create", path)))?,
+    };
+    inode.write_at(0, all_data.as_slice());
+    inode.set_times(
+        unix_time(metadata.accessed()?),
+        unix_time(metadata.modified()?),
+    );
+    Ok(())
+}
+
+/// The commands on a single file of an image.
+fn easy_fs_file(command: &str, matches: &ArgMatches) -> std::io::Result<()> {
+    let image = matches.value_of("image").unwrap();
+    let path = matches.value_of("path").unwrap();
+    let writable = matches!(command, "put" | "rm");
+    let efs = open_image(image, writable)?;
+    match command {
+        "ls" => {
+            let dir = lookup(&efs, path).ok_or_else(|| not_found(path))?;
+            if !dir.is_dir() {
+                println!("{:>10} {}", dir.stat().size, path);
+                return Ok(());
+            }
+            for name in dir.ls() {
+                let inode = dir.find(&name).unwrap();
+                let stat = inode.stat();
+                let slash = if stat.is_dir { "/" } else { "" };
+                println!("{:>10} {}{}", stat.size, name, slash);
+            }
+        }
+        "cat" | "get" => {
+            let inode = lookup(&efs, path).ok_or_else(|| not_found(path))?;
+            if inode.is_dir() {
+                return Err(Error::other(format!("{}: is a directory", path)));
+            }
+            let mut data = vec![0u8; inode.stat().size as usize];
+            inode.read_at(0, &mut data);
+            if command == "cat" {
+                std::io::stdout().write_all(&data)?;
+            } else {
+                File::create(matches.value_of("host").unwrap())?.write_all(&data)?;
+            }
+        }
+        "put" => put_file(&efs, Path::new(matches.value_of("host").unwrap()), path)?,
+        "rm" => {
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+            let dir = lookup(&efs, parent).ok_or_else(|| not_found(parent))?;
+            let inode = dir.find(name).ok_or_else(|| not_found(path))?;
+            let removed = if inode.is_dir() {
+                dir.rmdir(name)
+            } else {
+                dir.unlink(name)
+            };
+            if !removed {
+                return Err(Error::other(format!("{}: cannot remove", path)));
+            }
+        }
+        _ => unreachable!(),
+    }
+    // reading only changes access times, which a read-only image keeps
+    if writable {
+        efs.lock().sync();
+    }
+    Ok(())
+}
+
+fn easy_fs_info(image: &str) -> std::io::Result<()> {
+    let efs = open_image(image, false)?;
+    let efs = efs.lock();
+    let super_block = efs.super_block();
+    let (inodes, blocks) = efs.usage();
+    println!("{:#?}", super_block);
+    println!("inodes: {} of {} used", inodes, efs.inode_bitmap.maximum());
+    println!(
+        "data blocks: {} of {} used",
+        blocks, super_block.data_area_blocks
+    );
+    Ok(())
+}
+
+fn easy_fs_mount(image: &str, dir: &str) -> std::io::Result<()> {
+    fuse::mount(open_image(image, true)?, dir)
+}
+
+/// Print the problems of the image and return whether there are any.
+fn easy_fs_check(image: &str, repair: bool) -> std::io::Result<bool> {
+    let file = OpenOptions::new().read(true).write(repair).open(image)?;
+    let block_file = Arc::new(BlockFile(Mutex::new(file)));
+    let problems = match fsck(block_file, repair) {
+        Ok(problems) => problems,
+        Err(problem) => {
+            println!("{}: {}", image, problem);
+            return Ok(true);
+        }
+    };
+    for problem in problems.iter() {
+        println!(
+            "{}: {}{}",
+            image,
+            problem,
+            if repair { ", repaired" } else { "" }
+        );
+    }
+    if problems.is_empty() {
+        println!("{}: clean", image);
+    }
+    Ok(!problems.is_empty())
+}
+
+/// Return the image paths and host paths listed in `manifest`, one pair per line. Host paths
+/// are relative to the manifest, and lines starting with '#' are comments.
+fn read_manifest(manifest: &str) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let base = Path::new(manifest)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let mut files = Vec::new();
+    for (i, line) in read_to_string(manifest)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(path), Some(host), None) => files.push((path.to_string(), base.join(host))),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{}:{}: expected an image path and a host path",
+                        manifest,
+                        i + 1
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
+    let number = |name| {
+        matches.value_of(name).unwrap().parse::<u32>().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid number of {}", name),
+            )
+        })
+    };
+    let (total_blocks, inodes, journal_blocks) =
+        (number("blocks")?, number("inodes")?, number("journal")?);
+    if journal_blocks != 0 && journal_blocks < MIN_JOURNAL_BLOCKS {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("a journal needs at least {} blocks", MIN_JOURNAL_BLOCKS),
+        ));
+    }
+    let files = match (matches.value_of("manifest"), matches.value_of("source")) {
+        (Some(manifest), _) => read_manifest(manifest)?,
+        (None, Some(src_path)) => {
+            // executables named after their sources without extension
+            let target_path = matches.value_of("target").unwrap();
+            println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+            let mut files = Vec::new();
+            for dir_entry in read_dir(src_path)? {
+                let mut name_with_ext = dir_entry?.file_name().into_string().unwrap();
+                name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
+                let host = PathBuf::from(format!("{}{}", target_path, name_with_ext));
+                files.push((name_with_ext, host));
+            }
+            files
+        }
+        (None, None) => Vec::new(),
+    };
+    let image = match matches.value_of("output") {
+        Some(output) => output.to_string(),
+        None => format!("{}{}", matches.value_of("target").unwrap(), "fs.img"),
+    };
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(16 * 2048 * 512).unwrap();
+            .open(image)?;
+        f.set_len(total_blocks as u64 * BLOCK_SZ as u64)?;
         f
     })));
-    // 16MiB, at most 4095 files
-    let efs = EasyFileSystem::create(block_file, 16 * 2048, 1);
-    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
-    let apps: Vec<_> = read_dir(src_path)
-        .unwrap()
-        .into_iter()
-        .map(|dir_entry| {
-            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
-            name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
-            name_with_ext
-        })
-        .collect();
-    for app in apps {
-        // load app data from host file system
-        let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
-        let mut all_data: Vec<u8> = Vec::new();
-        host_file.read_to_end(&mut all_data).unwrap();
-        // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
-        // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+    EasyFileSystem::set_clock(|| unix_time(SystemTime::now()));
+    let inode_bitmap_blocks = inodes.div_ceil(BLOCK_SZ as u32 * 8);
+    let efs = EasyFileSystem::create(
+        block_file,
+        total_blocks,
+        inode_bitmap_blocks,
+        journal_blocks,
+    );
+    for (path, host) in files {
+        put_file(&efs, &host, &path)?;
     }
+    efs.lock().sync();
     // list apps
-    for app in root_inode.ls() {
+    for app in EasyFileSystem::root_inode(&efs).ls() {
         println!("{}", app);
     }
     Ok(())
@@ -98,7 +519,7 @@ fn efs_test() -> std::io::Result<()> {
         f.set_len(8192 * 512).unwrap();
         f
     })));
-    EasyFileSystem::create(block_file.clone(), 4096, 1);
+    EasyFileSystem::create(block_file.clone(), 4096, 1, 0);
     let efs = EasyFileSystem::open(block_file.clone());
     let root_inode = EasyFileSystem::root_inode(&efs);
     root_inode.create("filea");
@@ -149,3 +570,254 @@ fn efs_test() -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// Open the image `path` for a test, a separate one per test since they run in parallel.
+#[cfg(test)]
+fn test_image(path: &str) -> std::io::Result<Arc<BlockFile>> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    f.set_len(8192 * 512)?;
+    Ok(Arc::new(BlockFile(Mutex::new(f))))
+}
+
+#[test]
+fn truncate_test() -> std::io::Result<()> {
+    let block_file = test_image("target/truncate.img")?;
+    EasyFileSystem::create(block_file.clone(), 8192, 1, 0);
+    let efs = EasyFileSystem::open(block_file.clone());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("file").unwrap();
+    let used = efs.lock().usage().1;
+
+    // a block reached through the double indirect block, after a hole
+    file.write_at((17 + 128 + 200) * BLOCK_SZ + 100, b"tail");
+    // the block, its indirect1 block and the indirect2 block
+    assert_eq!(file.stat().blocks, 3);
+    assert_eq!(efs.lock().usage().1, used + 3);
+    let mut buffer = [1u8; BLOCK_SZ];
+    assert_eq!(file.read_at(20 * BLOCK_SZ, &mut buffer), BLOCK_SZ);
+    assert!(buffer.iter().all(|&b| b == 0));
+
+    // all direct blocks and some through the indirect1 block
+    file.write_at(0, &[7u8; 20 * BLOCK_SZ]);
+    assert_eq!(file.stat().blocks, 3 + 20 + 1);
+
+    // shrinking into the direct blocks frees the index blocks too
+    assert!(file.truncate(10 * BLOCK_SZ as u32 + 3));
+    assert_eq!(file.stat().size, 10 * BLOCK_SZ as u32 + 3);
+    assert_eq!(file.stat().blocks, 11);
+    assert_eq!(efs.lock().usage().1, used + 11);
+
+    // growing allocates nothing, and the cut tail reads as zeros
+    assert!(file.truncate(30 * BLOCK_SZ as u32));
+    assert_eq!(file.stat().blocks, 11);
+    assert_eq!(file.read_at(10 * BLOCK_SZ, &mut buffer), BLOCK_SZ);
+    assert!(buffer[..3].iter().all(|&b| b == 7));
+    assert!(buffer[3..].iter().all(|&b| b == 0));
+    assert_eq!(file.read_at(29 * BLOCK_SZ, &mut buffer), BLOCK_SZ);
+    assert!(buffer.iter().all(|&b| b == 0));
+    assert!(!file.truncate(MAX_FILE_SIZE as u32 + 1));
+
+    // 17 direct blocks, 128 through the indirect1 block, and 255 through two indirect1 blocks
+    // under the indirect2 block
+    file.write_at(0, &[9u8; 400 * BLOCK_SZ]);
+    assert_eq!(file.stat().blocks, 400 + 1 + 1 + 2);
+    // shrinking inside the indirect2 range keeps the indirect1 block
+    assert!(file.truncate(300 * BLOCK_SZ as u32));
+    assert_eq!(file.stat().blocks, 300 + 1 + 1 + 2);
+    assert!(file.truncate(200 * BLOCK_SZ as u32 - 1));
+    assert_eq!(file.stat().blocks, 200 + 1 + 1 + 1);
+    assert_eq!(efs.lock().usage().1, used + 203);
+    assert_eq!(file.read_at(199 * BLOCK_SZ, &mut buffer), BLOCK_SZ - 1);
+    assert!(buffer[..BLOCK_SZ - 1].iter().all(|&b| b == 9));
+    // and shrinking from there into the indirect1 range frees the indirect2 blocks
+    assert!(file.truncate(100 * BLOCK_SZ as u32));
+    assert_eq!(file.stat().blocks, 100 + 1);
+    assert_eq!(efs.lock().usage().1, used + 101);
+    assert_eq!(file.read_at(99 * BLOCK_SZ, &mut buffer), BLOCK_SZ);
+    assert!(buffer.iter().all(|&b| b == 9));
+    assert!(fsck(block_file.clone(), false).unwrap().is_empty());
+
+    assert!(file.truncate(0));
+    assert_eq!(file.stat().blocks, 0);
+    assert_eq!(efs.lock().usage().1, used);
+    assert!(fsck(block_file, false).unwrap().is_empty());
+    Ok(())
+}
+
+/// Block `i` of the bytes of an image.
+#[cfg(test)]
+fn image_block(image: &[u8], i: usize) -> &[u8] {
+    &image[i * BLOCK_SZ..(i + 1) * BLOCK_SZ]
+}
+
+/// Make the image `path` on `device` hold a committed transaction, which writes "new data" to
+/// "file" and creates "other", none of whose blocks are written home yet. Return the blocks of
+/// the transaction and the image as it is once the transaction is written home.
+#[cfg(test)]
+fn commit_journal(
+    path: &str,
+    device: &Arc<dyn BlockDevice>,
+) -> std::io::Result<(Vec<usize>, Vec<u8>)> {
+    EasyFileSystem::create(device.clone(), 8192, 1, MIN_JOURNAL_BLOCKS);
+    let efs = EasyFileSystem::open(device.clone());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("file").unwrap().write_at(0, b"old");
+    efs.lock().sync();
+    let before = std::fs::read(path)?;
+    root_inode.find("file").unwrap().write_at(0, b"new data");
+    root_inode.create("other");
+    efs.lock().sync();
+    let after = std::fs::read(path)?;
+    drop(root_inode);
+    drop(efs);
+    easy_fs::block_cache_clear(device);
+
+    let home = 1 + MIN_JOURNAL_BLOCKS as usize;
+    let changed: Vec<usize> = (home..8192)
+        .filter(|&i| image_block(&before, i) != image_block(&after, i))
+        .collect();
+    assert!(!changed.is_empty() && changed.len() < MIN_JOURNAL_BLOCKS as usize);
+    let mut image = before;
+    let mut header = vec![0u8; BLOCK_SZ];
+    header[..4].copy_from_slice(&0x4a4e4c31u32.to_le_bytes());
+    header[4..8].copy_from_slice(&(changed.len() as u32).to_le_bytes());
+    for (i, &block_id) in changed.iter().enumerate() {
+        header[8 + i * 4..12 + i * 4].copy_from_slice(&(block_id as u32).to_le_bytes());
+        image[(2 + i) * BLOCK_SZ..(3 + i) * BLOCK_SZ]
+            .copy_from_slice(image_block(&after, block_id));
+    }
+    image[BLOCK_SZ..2 * BLOCK_SZ].copy_from_slice(&header);
+    device.write_blocks(0, &image);
+    Ok((changed, after))
+}
+
+#[test]
+fn journal_replay_test() -> std::io::Result<()> {
+    let path = "target/journal.img";
+    let device: Arc<dyn BlockDevice> = test_image(path)?;
+    let (changed, after) = commit_journal(path, &device)?;
+
+    // a check leaves the transaction alone, and opening finishes it
+    assert!(fsck(device.clone(), false).unwrap().is_empty());
+    let mut buffer = [0u8; BLOCK_SZ];
+    device.read_block(1, &mut buffer);
+    assert_eq!(buffer[4..8], (changed.len() as u32).to_le_bytes());
+    easy_fs::block_cache_clear(&device);
+    let efs = EasyFileSystem::open(device.clone());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut buffer = [0u8; 16];
+    let len = root_inode.find("file").unwrap().read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"new data");
+    assert!(root_inode.find("other").is_some());
+    efs.lock().sync();
+    let replayed = std::fs::read(path)?;
+    for &i in &changed {
+        assert!(image_block(&replayed, i) == image_block(&after, i));
+    }
+    // the journal is clean again
+    assert_eq!(image_block(&replayed, 1)[4..8], [0; 4]);
+    assert!(fsck(device, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn fsck_repair_test() -> std::io::Result<()> {
+    use easy_fs::FsckProblem;
+    let block_file = test_image("target/fsck.img")?;
+    let device: Arc<dyn BlockDevice> = block_file.clone();
+    EasyFileSystem::create(device.clone(), 8192, 1, 0);
+    let efs = EasyFileSystem::open(device.clone());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let a = root_inode.create("a").unwrap();
+    a.write_at(0, b"data");
+    let a = a.stat().ino;
+    let [b, c, d] = ["b", "c", "d"].map(|name| root_inode.create(name).unwrap().stat().ino);
+    let usage = efs.lock().usage();
+
+    // the block holding a disk inode, as words: its size, the direct blocks, and the link count
+    // at 20
+    let disk_inode = |ino: u32| {
+        let (block_id, offset) = efs.lock().get_disk_inode_pos(ino);
+        (
+            easy_fs::get_block_cache(block_id as usize, device.clone()),
+            offset,
+        )
+    };
+    let (cache, offset) = disk_inode(a);
+    let block = cache.lock().read(offset, |words: &[u32; 32]| words[1]);
+    let (cache, offset) = disk_inode(b);
+    cache.lock().modify(offset, |words: &mut [u32; 32]| {
+        words[0] = BLOCK_SZ as u32;
+        words[1] = block;
+    });
+    let (cache, offset) = disk_inode(c);
+    cache
+        .lock()
+        .modify(offset, |words: &mut [u32; 32]| words[20] = 5);
+    // a type byte after the times, none of the values of the enum it is read as
+    let (cache, offset) = disk_inode(d);
+    cache
+        .lock()
+        .modify(offset + 120, |type_: &mut u8| *type_ = 7);
+    let orphan = efs.lock().alloc_inode();
+    let leaked = efs.lock().alloc_data();
+    efs.lock().sync();
+    drop(root_inode);
+    drop(efs);
+    easy_fs::block_cache_clear(&device);
+
+    let problems = vec![
+        FsckProblem::BadInodeType { inode: d, type_: 7 },
+        FsckProblem::DuplicateBlock { inode: b, block },
+        FsckProblem::WrongLinkCount {
+            inode: c,
+            nlink: 5,
+            links: 1,
+        },
+        FsckProblem::OrphanedInode { inode: orphan },
+        FsckProblem::LeakedBlock { block: leaked },
+    ];
+    assert_eq!(fsck(device.clone(), false).unwrap(), problems);
+    assert_eq!(fsck(device.clone(), true).unwrap(), problems);
+    assert!(fsck(device.clone(), false).unwrap().is_empty());
+
+    // the inode of the unknown type is freed
+    let efs = EasyFileSystem::open(device);
+    assert_eq!(efs.lock().usage(), (usage.0 - 1, usage.1));
+    assert!(EasyFileSystem::root_inode(&efs).find("d").is_none());
+    let mut buffer = [1u8; BLOCK_SZ];
+    let len = EasyFileSystem::get_inode(&efs, a).read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"data");
+    // the second reference is left a hole
+    let b = EasyFileSystem::get_inode(&efs, b);
+    assert_eq!(b.stat().blocks, 0);
+    assert_eq!(b.read_at(0, &mut buffer), BLOCK_SZ);
+    assert!(buffer.iter().all(|&byte| byte == 0));
+    assert_eq!(EasyFileSystem::get_inode(&efs, c).stat().nlink, 1);
+    Ok(())
+}
+
+#[test]
+fn read_only_journal_test() -> std::io::Result<()> {
+    let path = "target/journal_ro.img";
+    let device: Arc<dyn BlockDevice> = test_image(path)?;
+    commit_journal(path, &device)?;
+    drop(device);
+    let image = std::fs::read(path)?;
+
+    // the committed transaction is seen, but not written to the image
+    let efs = open_image(path, false)?;
+    let file = lookup(&efs, "/file").unwrap();
+    let mut buffer = [0u8; 16];
+    let len = file.read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"new data");
+    assert!(lookup(&efs, "/other").is_some());
+    efs.lock().sync();
+    assert!(std::fs::read(path)? == image);
+    Ok(())
+}
@@ -1,6 +1,10 @@
 use super::{BlockDevice, BLOCK_SZ};
-use alloc::collections::VecDeque;
+use crate::efs::OP_MAX_DIRTY_BLOCKS;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::*;
 use spin::Mutex;
 
@@ -14,10 +18,17 @@ pub struct BlockCache {
 impl BlockCache {
     /// Load a new BlockCache from disk.
     pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
-        let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
+        let mut block_cache = Self::empty(block_id, block_device);
+        block_cache
+            .block_device
+            .read_block(block_id, &mut block_cache.cache);
+        block_cache
+    }
+
+    /// A BlockCache of zeros, to be read from disk without holding the manager lock.
+    fn empty(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
         Self {
-            cache,
+            cache: [0u8; BLOCK_SZ],
             block_id,
             block_device,
             modified: false,
@@ -44,7 +55,10 @@ impl BlockCache {
     {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
-        self.modified = true;
+        if !self.modified {
+            self.modified = true;
+            count_dirty(&self.block_device, 1, true);
+        }
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
@@ -60,6 +74,7 @@ impl BlockCache {
     pub fn sync(&mut self) {
         if self.modified {
             self.modified = false;
+            count_dirty(&self.block_device, 1, false);
             self.block_device.write_block(self.block_id, &self.cache);
         }
     }
@@ -71,69 +86,395 @@ impl Drop for BlockCache {
     }
 }
 
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Number of blocks cached unless `set_block_cache_capacity` is called.
+const BLOCK_CACHE_CAPACITY: usize = 1024;
+/// Fewest blocks cached, so that the blocks of an operation fit in the half of the cache that
+/// `EasyFileSystem::sync_if_needed` keeps clean.
+pub const MIN_BLOCK_CACHE_CAPACITY: usize = 2 * OP_MAX_DIRTY_BLOCKS;
+/// End of the LRU list and of the hash chains.
+const NIL: usize = usize::MAX;
+
+/// Called while every block cached is in use, so that the tasks using them can run. It only
+/// spins unless one is set.
+static WAIT: Mutex<fn()> = Mutex::new(core::hint::spin_loop);
+/// Number of modified blocks in the cache.
+static DIRTY_BLOCKS: AtomicUsize = AtomicUsize::new(0);
+/// Lookups that found the block cached, and those that had to read it.
+static HITS: AtomicUsize = AtomicUsize::new(0);
+static MISSES: AtomicUsize = AtomicUsize::new(0);
 
+/// State of the block cache, as returned by `block_cache_stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockCacheStats {
+    pub capacity: usize,
+    pub cached: usize,
+    pub dirty: usize,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+struct Node {
+    /// Device and block number, which identify the block.
+    device: usize,
+    block_id: usize,
+    cache: Arc<Mutex<BlockCache>>,
+    /// Neighbours in the LRU list, the most recently used one first.
+    prev: usize,
+    next: usize,
+    /// Next node in the same hash bucket.
+    hash_next: usize,
+}
+
+/// Result of looking a block up in the cache.
+enum Lookup {
+    Hit(Arc<Mutex<BlockCache>>),
+    /// A new cache, which has to be read from the device before it is unlocked.
+    Miss(Arc<Mutex<BlockCache>>),
+    /// Every block cached that is not in use is modified, and this one has to be written back
+    /// before it is replaced.
+    WriteBack(Arc<Mutex<BlockCache>>),
+    /// Every block cached is in use.
+    Full,
+}
+
+/// Identifies `block_device` among those whose blocks are cached.
+fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
+/// LRU cache of blocks of any number of devices, with nodes hashed by device and block ID and
+/// linked in order of use.
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    nodes: Vec<Node>,
+    /// First node of each hash chain. The number of buckets is a power of two.
+    buckets: Vec<usize>,
+    head: usize,
+    tail: usize,
+    capacity: usize,
 }
 
 impl BlockCacheManager {
     pub fn new() -> Self {
-        Self {
-            queue: VecDeque::new(),
+        let mut manager = Self {
+            nodes: Vec::new(),
+            buckets: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            capacity: 0,
+        };
+        manager.set_capacity(BLOCK_CACHE_CAPACITY);
+        manager
+    }
+
+    /// Set the number of blocks cached, which cannot be less than the blocks already cached.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0 && capacity >= self.nodes.len());
+        self.capacity = capacity;
+        self.buckets = vec![NIL; capacity.next_power_of_two()];
+        for idx in 0..self.nodes.len() {
+            let bucket = self.bucket(self.nodes[idx].device, self.nodes[idx].block_id);
+            self.nodes[idx].hash_next = self.buckets[bucket];
+            self.buckets[bucket] = idx;
         }
     }
 
-    pub fn get_block_cache(
-        &mut self,
-        block_id: usize,
-        block_device: Arc<dyn BlockDevice>,
-    ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
-            Arc::clone(&pair.1)
+    fn bucket(&self, device: usize, block_id: usize) -> usize {
+        (block_id ^ device >> 4) & (self.buckets.len() - 1)
+    }
+
+    fn find(&self, device: usize, block_id: usize) -> Option<usize> {
+        let mut idx = self.buckets[self.bucket(device, block_id)];
+        while idx != NIL
+            && (self.nodes[idx].block_id != block_id || self.nodes[idx].device != device)
+        {
+            idx = self.nodes[idx].hash_next;
+        }
+        if idx == NIL {
+            None
         } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self
-                    .queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
-                {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
+            Some(idx)
+        }
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let (prev, next) = (self.nodes[idx].prev, self.nodes[idx].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.nodes[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.nodes[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, idx: usize) {
+        self.nodes[idx].prev = NIL;
+        self.nodes[idx].next = self.head;
+        match self.head {
+            NIL => self.tail = idx,
+            head => self.nodes[head].prev = idx,
+        }
+        self.head = idx;
+    }
+
+    fn unhash(&mut self, idx: usize) {
+        let bucket = self.bucket(self.nodes[idx].device, self.nodes[idx].block_id);
+        let hash_next = self.nodes[idx].hash_next;
+        if self.buckets[bucket] == idx {
+            self.buckets[bucket] = hash_next;
+            return;
+        }
+        let mut prev = self.buckets[bucket];
+        while self.nodes[prev].hash_next != idx {
+            prev = self.nodes[prev].hash_next;
+        }
+        self.nodes[prev].hash_next = hash_next;
+    }
+
+    /// Find a node to replace, going from the least recently used one. Clean blocks go first,
+    /// so that a transaction is not written back before it is committed. Blocks in use cannot
+    /// be evicted.
+    fn victim(&self) -> Option<usize> {
+        let mut dirty = None;
+        let mut idx = self.tail;
+        while idx != NIL {
+            let node = &self.nodes[idx];
+            if Arc::strong_count(&node.cache) == 1 {
+                if !node.cache.lock().modified {
+                    return Some(idx);
                 }
+                dirty = dirty.or(Some(idx));
             }
-            // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(BlockCache::new(
-                block_id,
-                Arc::clone(&block_device),
-            )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
+            idx = node.prev;
+        }
+        dirty
+    }
+
+    /// Put `cache` in the node `idx`, a new one if it is the number of nodes, as the most
+    /// recently used block.
+    fn insert(
+        &mut self,
+        idx: usize,
+        device: usize,
+        block_id: usize,
+        cache: Arc<Mutex<BlockCache>>,
+    ) {
+        let node = Node {
+            device,
+            block_id,
+            cache,
+            prev: NIL,
+            next: NIL,
+            hash_next: NIL,
+        };
+        if idx == self.nodes.len() {
+            self.nodes.push(node);
+        } else {
+            self.nodes[idx] = node;
         }
+        let bucket = self.bucket(device, block_id);
+        self.nodes[idx].hash_next = self.buckets[bucket];
+        self.buckets[bucket] = idx;
+        self.push_front(idx);
+    }
+
+    /// Look `block_id` of `block_device` up. A block not cached replaces a clean one, so that
+    /// no device I/O happens while the manager is locked.
+    fn try_get_block_cache(
+        &mut self,
+        block_id: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Lookup {
+        let device = device_id(block_device);
+        if let Some(idx) = self.find(device, block_id) {
+            self.unlink(idx);
+            self.push_front(idx);
+            HITS.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Hit(Arc::clone(&self.nodes[idx].cache));
+        }
+        let idx = if self.nodes.len() < self.capacity {
+            self.nodes.len()
+        } else {
+            let idx = match self.victim() {
+                Some(idx) => idx,
+                None => return Lookup::Full,
+            };
+            if self.nodes[idx].cache.lock().modified {
+                return Lookup::WriteBack(Arc::clone(&self.nodes[idx].cache));
+            }
+            self.unlink(idx);
+            self.unhash(idx);
+            idx
+        };
+        MISSES.fetch_add(1, Ordering::Relaxed);
+        let cache = Arc::new(Mutex::new(BlockCache::empty(
+            block_id,
+            Arc::clone(block_device),
+        )));
+        self.insert(idx, device, block_id, Arc::clone(&cache));
+        Lookup::Miss(cache)
     }
 }
 
 lazy_static! {
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> =
         Mutex::new(BlockCacheManager::new());
+    /// Number of modified blocks in the cache for each device with any.
+    static ref DEVICE_DIRTY_BLOCKS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
 }
 
+/// Count `count` blocks of `block_device` as modified, or as written back if `dirty` is false.
+fn count_dirty(block_device: &Arc<dyn BlockDevice>, count: usize, dirty: bool) {
+    let mut device_dirty = DEVICE_DIRTY_BLOCKS.lock();
+    let device = device_id(block_device);
+    let blocks = device_dirty.entry(device).or_insert(0);
+    if dirty {
+        *blocks += count;
+        DIRTY_BLOCKS.fetch_add(count, Ordering::Relaxed);
+    } else {
+        *blocks -= count;
+        DIRTY_BLOCKS.fetch_sub(count, Ordering::Relaxed);
+        if *blocks == 0 {
+            device_dirty.remove(&device);
+        }
+    }
+}
+
+/// Return the cache of `block_id` of `block_device`. If every block cached is in use, wait for one to be
+/// released.
 pub fn get_block_cache(
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
 ) -> Arc<Mutex<BlockCache>> {
+    loop {
+        let mut manager = BLOCK_CACHE_MANAGER.lock();
+        match manager.try_get_block_cache(block_id, &block_device) {
+            Lookup::Hit(cache) => return cache,
+            Lookup::Miss(cache) => {
+                // the block stays locked until it is read, should it be looked up meanwhile
+                let mut block_cache = cache.lock();
+                drop(manager);
+                block_device.read_block(block_id, &mut block_cache.cache);
+                drop(block_cache);
+                return cache;
+            }
+            Lookup::WriteBack(victim) => {
+                drop(manager);
+                victim.lock().sync();
+            }
+            Lookup::Full => {
+                drop(manager);
+                (WAIT.lock())();
+            }
+        }
+    }
+}
+
+/// Set the number of blocks cached, which cannot be less than the blocks already cached. It is
+/// raised to `MIN_BLOCK_CACHE_CAPACITY` if below.
+pub fn set_block_cache_capacity(capacity: usize) {
     BLOCK_CACHE_MANAGER
         .lock()
-        .get_block_cache(block_id, block_device)
+        .set_capacity(capacity.max(MIN_BLOCK_CACHE_CAPACITY));
 }
 
-pub fn block_cache_sync_all() {
+/// Set the function called while waiting for a cached block to be released, e.g. one yielding
+/// to the other tasks.
+pub fn set_block_cache_wait(wait: fn()) {
+    *WAIT.lock() = wait;
+}
+
+/// Write back and drop the cached blocks of `block_device`, e.g. once its file system is
+/// unmounted.
+pub fn block_cache_clear(block_device: &Arc<dyn BlockDevice>) {
+    let device = device_id(block_device);
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    let mut kept = BlockCacheManager::new();
+    kept.set_capacity(manager.capacity);
+    // from the least recently used block, which ends up last again
+    let mut idx = manager.tail;
+    while idx != NIL {
+        let node = &manager.nodes[idx];
+        if node.device != device {
+            let len = kept.nodes.len();
+            kept.insert(len, node.device, node.block_id, Arc::clone(&node.cache));
+        }
+        idx = node.prev;
+    }
+    // the blocks dropped are written back once the manager is unlocked
+    let dropped = core::mem::replace(&mut *manager, kept);
+    drop(manager);
+    drop(dropped);
+}
+
+/// Return the number of blocks cached at most.
+pub fn block_cache_capacity() -> usize {
+    BLOCK_CACHE_MANAGER.lock().capacity
+}
+
+/// Return the number of modified blocks in the cache.
+pub fn block_cache_dirty_blocks() -> usize {
+    DIRTY_BLOCKS.load(Ordering::Relaxed)
+}
+
+/// Return the number of modified blocks of `block_device` in the cache.
+pub fn block_cache_device_dirty_blocks(block_device: &Arc<dyn BlockDevice>) -> usize {
+    let device_dirty = DEVICE_DIRTY_BLOCKS.lock();
+    *device_dirty.get(&device_id(block_device)).unwrap_or(&0)
+}
+
+/// Return the state of the block cache.
+pub fn block_cache_stats() -> BlockCacheStats {
+    let manager = BLOCK_CACHE_MANAGER.lock();
+    BlockCacheStats {
+        capacity: manager.capacity,
+        cached: manager.nodes.len(),
+        dirty: DIRTY_BLOCKS.load(Ordering::Relaxed),
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+    }
+}
+
+/// Return the caches of the blocks of `block_device`, so that they are written back without
+/// holding the manager lock.
+fn device_caches(block_device: &Arc<dyn BlockDevice>) -> Vec<Arc<Mutex<BlockCache>>> {
+    let device = device_id(block_device);
     let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+    manager
+        .nodes
+        .iter()
+        .filter(|node| node.device == device)
+        .map(|node| Arc::clone(&node.cache))
+        .collect()
+}
+
+/// Write back the modified blocks of `block_device`.
+pub fn block_cache_sync_all(block_device: &Arc<dyn BlockDevice>) {
+    for cache in device_caches(block_device) {
         cache.lock().sync();
     }
 }
+
+/// Hand all modified blocks of `block_device` to `write` at once, e.g. to commit them through a
+/// journal, and mark them clean.
+pub fn block_cache_sync_all_with(
+    block_device: &Arc<dyn BlockDevice>,
+    write: impl FnOnce(&[(usize, &[u8; BLOCK_SZ])]),
+) {
+    let caches = device_caches(block_device);
+    let mut dirty: Vec<_> = caches
+        .iter()
+        .map(|cache| cache.lock())
+        .filter(|cache| cache.modified)
+        .collect();
+    let blocks: Vec<_> = dirty
+        .iter()
+        .map(|cache| (cache.block_id, &cache.cache))
+        .collect();
+    write(&blocks);
+    for cache in dirty.iter_mut() {
+        cache.modified = false;
+    }
+    count_dirty(block_device, dirty.len(), false);
+}
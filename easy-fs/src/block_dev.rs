@@ -1,6 +1,86 @@
+use super::BLOCK_SZ;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::any::Any;
 
+/// Operation of a `BlockRequest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOp {
+    Read,
+    Write,
+    /// Make the writes completed so far durable.
+    Flush,
+}
+
+/// Request queued on a device by `BlockDevice::submit`.
+pub struct BlockRequest {
+    pub op: BlockOp,
+    pub block_id: usize,
+    /// Data to write or space to read into, a whole number of blocks. Empty for a flush.
+    pub buf: Vec<u8>,
+    /// Called with the buffer and whether the request succeeded once it completes, possibly
+    /// from an interrupt handler.
+    pub callback: Box<dyn FnOnce(Vec<u8>, bool) + Send>,
+}
+
 pub trait BlockDevice: Send + Sync + Any {
     fn read_block(&self, block_id: usize, buf: &mut [u8]);
     fn write_block(&self, block_id: usize, buf: &[u8]);
+
+    /// Number of blocks of the device, 0 if unknown.
+    fn num_blocks(&self) -> usize {
+        0
+    }
+
+    /// Read the `buf.len() / BLOCK_SZ` blocks starting at `block_id`.
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.read_block(block_id + i, block);
+        }
+    }
+
+    /// Write the `buf.len() / BLOCK_SZ` blocks starting at `block_id`.
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+        for (i, block) in buf.chunks(BLOCK_SZ).enumerate() {
+            self.write_block(block_id + i, block);
+        }
+    }
+
+    /// Wait until the blocks written are on stable storage. Devices without a volatile write
+    /// cache have nothing to do.
+    fn flush(&self) {}
+
+    /// Queue a request. Devices without a queue complete it before returning.
+    fn submit(&self, request: BlockRequest) {
+        let BlockRequest {
+            op,
+            block_id,
+            mut buf,
+            callback,
+        } = request;
+        match op {
+            BlockOp::Read => self.read_blocks(block_id, &mut buf),
+            BlockOp::Write => self.write_blocks(block_id, &buf),
+            BlockOp::Flush => self.flush(),
+        }
+        callback(buf, true);
+    }
+}
+
+/// Write `blocks` in order of block ID, with one request for each run of consecutive blocks.
+pub fn write_blocks_sorted(block_device: &dyn BlockDevice, blocks: &[(usize, &[u8; BLOCK_SZ])]) {
+    let mut blocks: Vec<_> = blocks.to_vec();
+    blocks.sort_unstable_by_key(|&(block_id, _)| block_id);
+    let mut run = Vec::new();
+    for (i, (block_id, data)) in blocks.iter().enumerate() {
+        run.extend_from_slice(&data[..]);
+        match blocks.get(i + 1) {
+            Some((next, _)) if *next == block_id + 1 => {}
+            _ => {
+                let start = block_id + 1 - run.len() / BLOCK_SZ;
+                block_device.write_blocks(start, &run);
+                run.clear();
+            }
+        }
+    }
 }
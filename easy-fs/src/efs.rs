@@ -1,46 +1,68 @@
 use super::{
-    block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DiskInode, DiskInodeType, Inode,
-    SuperBlock,
+    block_cache_capacity, block_cache_device_dirty_blocks, block_cache_dirty_blocks,
+    block_cache_sync_all, block_cache_sync_all_with, get_block_cache, write_blocks_sorted, Bitmap,
+    BlockDevice, DiskInode, DiskInodeType, Inode, Journal, OpenInodes, SuperBlock,
 };
 use crate::BLOCK_SZ;
 use alloc::sync::Arc;
 use spin::Mutex;
 
+/// Source of timestamps, which stay at 0 unless one is set.
+static CLOCK: Mutex<fn() -> u64> = Mutex::new(|| 0);
+
+/// Upper bound on the blocks modified by one operation, e.g. a chunk of `Inode::write_at` with
+/// its index and bitmap blocks.
+pub(crate) const OP_MAX_DIRTY_BLOCKS: usize = 48;
+/// Smallest journal, holding the header and the blocks of an operation.
+pub const MIN_JOURNAL_BLOCKS: u32 = OP_MAX_DIRTY_BLOCKS as u32 + 1;
+
 pub struct EasyFileSystem {
     pub block_device: Arc<dyn BlockDevice>,
     pub inode_bitmap: Bitmap,
     pub data_bitmap: Bitmap,
+    pub journal: Option<Journal>,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    open: Arc<Mutex<OpenInodes>>,
 }
 
 type DataBlock = [u8; BLOCK_SZ];
 
 impl EasyFileSystem {
+    /// Format the device. With `journal_blocks` of 0, there is no journal and a crash in the
+    /// middle of an operation can leave the metadata inconsistent. Otherwise it must be at least
+    /// `MIN_JOURNAL_BLOCKS`.
     pub fn create(
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
+        journal_blocks: u32,
     ) -> Arc<Mutex<Self>> {
+        assert!(
+            journal_blocks == 0 || journal_blocks >= MIN_JOURNAL_BLOCKS,
+            "A journal needs room for the blocks of an operation"
+        );
         // calculate block size of areas & create bitmaps
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
+        let inode_bitmap = Bitmap::new(1 + journal_blocks as usize, inode_bitmap_blocks as usize);
         let inode_num = inode_bitmap.maximum();
         let inode_area_blocks =
             ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
+        let data_total_blocks = total_blocks - 1 - journal_blocks - inode_total_blocks;
         let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
         let data_bitmap = Bitmap::new(
-            (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
+            (1 + journal_blocks + inode_total_blocks) as usize,
             data_bitmap_blocks as usize,
         );
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
             inode_bitmap,
             data_bitmap,
-            inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            journal: (journal_blocks > 0).then(|| Journal::new(1, journal_blocks)),
+            inode_area_start_block: 1 + journal_blocks + inode_bitmap_blocks,
+            data_area_start_block: 1 + journal_blocks + inode_total_blocks + data_bitmap_blocks,
+            open: Arc::new(Mutex::new(OpenInodes::default())),
         };
         // clear all blocks
         for i in 0..total_blocks {
@@ -62,9 +84,11 @@ impl EasyFileSystem {
                     inode_area_blocks,
                     data_bitmap_blocks,
                     data_area_blocks,
+                    journal_blocks,
                 );
             },
         );
+
         // write back immediately
         // create a inode for root node "/"
         assert_eq!(efs.alloc_inode(), 0);
@@ -72,40 +96,174 @@ impl EasyFileSystem {
         get_block_cache(root_inode_block_id as usize, Arc::clone(&block_device))
             .lock()
             .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-                disk_inode.initialize(DiskInodeType::Directory);
+                disk_inode.initialize(DiskInodeType::Directory, Self::now());
             });
-        block_cache_sync_all();
-        Arc::new(Mutex::new(efs))
+        let efs = Arc::new(Mutex::new(efs));
+        // the parent of "/" is itself
+        let root_inode = Self::root_inode(&efs);
+        root_inode.add_dirent(".", 0, &mut efs.lock());
+        root_inode.add_dirent("..", 0, &mut efs.lock());
+        block_cache_sync_all(&block_device);
+        block_device.flush();
+        // after the cleared blocks are written back
+        if let Some(journal) = &efs.lock().journal {
+            journal.format(&block_device);
+        }
+        efs
+    }
+
+    /// Whether `block_device` holds an easy-fs file system, checked without caching its blocks.
+    pub fn probe(block_device: &dyn BlockDevice) -> bool {
+        let mut buf = [0u8; BLOCK_SZ];
+        block_device.read_block(0, &mut buf);
+        let super_block = unsafe { (buf.as_ptr() as *const SuperBlock).read_unaligned() };
+        super_block.is_valid()
     }
 
     pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+        let efs = Self::load(block_device);
+        // finish the last transaction if it was committed before a crash
+        if let Some(journal) = &efs.journal {
+            journal.replay(&efs.block_device);
+        }
+        Arc::new(Mutex::new(efs))
+    }
+
+    /// Read the super block of `block_device`, leaving a committed transaction in the journal.
+    pub(crate) fn load(block_device: Arc<dyn BlockDevice>) -> Self {
         // read SuperBlock
         get_block_cache(0, Arc::clone(&block_device))
             .lock()
             .read(0, |super_block: &SuperBlock| {
                 assert!(super_block.is_valid(), "Error loading EFS!");
+                let journal_blocks = super_block.journal_blocks;
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                let efs = Self {
+                Self {
                     block_device,
-                    inode_bitmap: Bitmap::new(1, super_block.inode_bitmap_blocks as usize),
+                    inode_bitmap: Bitmap::new(
+                        (1 + journal_blocks) as usize,
+                        super_block.inode_bitmap_blocks as usize,
+                    ),
                     data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
+                        (1 + journal_blocks + inode_total_blocks) as usize,
                         super_block.data_bitmap_blocks as usize,
                     ),
-                    inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-                Arc::new(Mutex::new(efs))
+                    journal: (journal_blocks > 0).then(|| Journal::new(1, journal_blocks)),
+                    inode_area_start_block: 1 + journal_blocks + super_block.inode_bitmap_blocks,
+                    data_area_start_block: 1
+                        + journal_blocks
+                        + inode_total_blocks
+                        + super_block.data_bitmap_blocks,
+                    open: Arc::new(Mutex::new(OpenInodes::default())),
+                }
             })
     }
 
+    /// Write all modified blocks back, as one transaction through the journal if there is one.
+    pub fn sync(&self) {
+        self.free_orphans();
+        self.commit();
+    }
+
+    fn commit(&self) {
+        match &self.journal {
+            Some(journal) => block_cache_sync_all_with(&self.block_device, |blocks| {
+                journal.commit(blocks, &self.block_device)
+            }),
+            None => {
+                block_cache_sync_all_with(&self.block_device, |blocks| {
+                    write_blocks_sorted(self.block_device.as_ref(), blocks)
+                });
+                self.block_device.flush();
+            }
+        }
+    }
+
+    /// Called after each operation. Modified blocks stay in the cache until `sync`, unless the
+    /// journal could not hold the blocks of another operation, which commits them now. Half of
+    /// the cache is kept clean, so that uncommitted blocks are not evicted.
+    pub fn sync_if_needed(&self) {
+        self.free_orphans();
+        self.commit_if_needed();
+    }
+
+    fn commit_if_needed(&self) {
+        if let Some(journal) = &self.journal {
+            // the blocks of this file system go in its journal, while the cache is shared with
+            // the other devices
+            let dirty = block_cache_device_dirty_blocks(&self.block_device);
+            if dirty + OP_MAX_DIRTY_BLOCKS > journal.capacity()
+                || block_cache_dirty_blocks() + OP_MAX_DIRTY_BLOCKS > block_cache_capacity() / 2
+            {
+                self.commit();
+            }
+        }
+    }
+
+    /// Free the inodes removed while open and closed since, as one operation each.
+    fn free_orphans(&self) {
+        loop {
+            let inode_id = match self.open.lock().closed.pop() {
+                Some(inode_id) => inode_id,
+                None => return,
+            };
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    for data_block in disk_inode.clear_size(&self.block_device) {
+                        self.dealloc_data(data_block);
+                    }
+                });
+            self.dealloc_inode(inode_id);
+            self.commit_if_needed();
+        }
+    }
+
+    /// Return a copy of the super block.
+    pub fn super_block(&self) -> SuperBlock {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| *super_block)
+    }
+
+    /// Return the number of inodes and data blocks in use.
+    pub fn usage(&self) -> (usize, usize) {
+        (
+            self.inode_bitmap.count(&self.block_device),
+            self.data_bitmap.count(&self.block_device),
+        )
+    }
+
+    /// Set the function returning the current time in seconds since the Unix epoch.
+    pub fn set_clock(clock: fn() -> u64) {
+        *CLOCK.lock() = clock;
+    }
+
+    pub fn now() -> u64 {
+        (CLOCK.lock())()
+    }
+
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
+        Self::get_inode(efs, 0)
+    }
+
+    /// Return the inode numbered `inode_id`, e.g. from `Stat::ino`.
+    pub fn get_inode(efs: &Arc<Mutex<Self>>, inode_id: u32) -> Inode {
         let block_device = Arc::clone(&efs.lock().block_device);
         // acquire efs lock temporarily
-        let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
+        let (block_id, block_offset) = efs.lock().get_disk_inode_pos(inode_id);
+        let open = Arc::clone(&efs.lock().open);
         // release efs lock
-        Inode::new(block_id, block_offset, Arc::clone(efs), block_device)
+        Inode::new(
+            inode_id,
+            block_id,
+            block_offset,
+            Arc::clone(efs),
+            block_device,
+            open,
+        )
     }
 
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
@@ -126,12 +284,15 @@ impl EasyFileSystem {
         self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
     }
 
-    /// Return a block ID not ID in the data area.
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    pub fn dealloc_inode(&self, inode_id: u32) {
+        self.inode_bitmap
+            .dealloc(&self.block_device, inode_id as usize)
     }
 
-    pub fn dealloc_data(&mut self, block_id: u32) {
+    /// Return a block ID not ID in the data area. The block is zeroed.
+    pub fn alloc_data(&mut self) -> u32 {
+        let block_id =
+            self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block;
         get_block_cache(block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(0, |data_block: &mut DataBlock| {
@@ -139,6 +300,10 @@ impl EasyFileSystem {
                     *p = 0;
                 })
             });
+        block_id
+    }
+
+    pub fn dealloc_data(&self, block_id: u32) {
         self.data_bitmap.dealloc(
             &self.block_device,
             (block_id - self.data_area_start_block) as usize,
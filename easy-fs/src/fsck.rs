@@ -0,0 +1,444 @@
+use super::{
+    get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType, EasyFileSystem, SuperBlock,
+    BLOCK_SZ, DIRENT_SZ, MAX_FILE_SIZE, NAME_LENGTH_LIMIT,
+};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter, Result};
+use spin::{Mutex, MutexGuard};
+
+type IndirectBlock = [u32; BLOCK_SZ / 4];
+type DataBlock = [u8; BLOCK_SZ];
+
+/// Inconsistency found by `fsck`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// The super block does not belong to easy-fs.
+    BadMagic,
+    /// The areas in the super block do not add up.
+    BadAreaSizes,
+    /// A block pointer of an inode is outside the data area. It is cleared on repair.
+    BadBlockPointer { inode: u32, block: u32 },
+    /// A block is referenced a second time. The second reference is cleared on repair.
+    DuplicateBlock { inode: u32, block: u32 },
+    /// The size of an inode is above `MAX_FILE_SIZE`. It is cut down to it on repair.
+    SizeTooLarge { inode: u32, size: u32 },
+    /// The size of a directory is not a multiple of the dirent size. It is rounded down on
+    /// repair.
+    UnalignedDirSize { inode: u32, size: u32 },
+    /// The type of an inode is unknown. It is cleared on repair, and the dirents referring to it
+    /// are removed, or the root is made a directory.
+    BadInodeType { inode: u32, type_: u8 },
+    /// A dirent has a malformed name or inode number. It is removed on repair.
+    BadDirent { dir: u32, index: u32 },
+    /// An inode in use is free in the bitmap. It is marked on repair.
+    UnmarkedInode { inode: u32 },
+    /// An inode is allocated but not reachable from the root. It is freed on repair, along with
+    /// its blocks.
+    OrphanedInode { inode: u32 },
+    /// The link count of an inode differs from the number of dirents referring to it. It is
+    /// corrected on repair.
+    WrongLinkCount { inode: u32, nlink: u32, links: u32 },
+    /// A block in use is free in the bitmap. It is marked on repair.
+    UnmarkedBlock { block: u32 },
+    /// A block is allocated but not used by any inode. It is freed on repair.
+    LeakedBlock { block: u32 },
+}
+
+impl Display for FsckProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match *self {
+            Self::BadMagic => write!(f, "not an easy-fs image"),
+            Self::BadAreaSizes => write!(f, "area sizes in the super block do not add up"),
+            Self::BadBlockPointer { inode, block } => {
+                write!(f, "inode {} points to block {} outside the data area", inode, block)
+            }
+            Self::DuplicateBlock { inode, block } => {
+                write!(f, "inode {} points to block {} used elsewhere", inode, block)
+            }
+            Self::SizeTooLarge { inode, size } => {
+                write!(f, "inode {} has size {} above the maximum", inode, size)
+            }
+            Self::UnalignedDirSize { inode, size } => {
+                write!(f, "directory {} has size {} not made of dirents", inode, size)
+            }
+            Self::BadInodeType { inode, type_ } => {
+                write!(f, "inode {} has unknown type {}", inode, type_)
+            }
+            Self::BadDirent { dir, index } => write!(f, "dirent {} of inode {} is bad", index, dir),
+            Self::UnmarkedInode { inode } => write!(f, "inode {} is in use but free", inode),
+            Self::OrphanedInode { inode } => write!(f, "inode {} is unreachable", inode),
+            Self::WrongLinkCount {
+                inode,
+                nlink,
+                links,
+            } => write!(f, "inode {} has link count {} but {} links", inode, nlink, links),
+            Self::UnmarkedBlock { block } => write!(f, "block {} is in use but free", block),
+            Self::LeakedBlock { block } => write!(f, "block {} is allocated but unused", block),
+        }
+    }
+}
+
+/// Check an easy-fs image, and fix what is found if `repair` is set. A bad super block is an
+/// error, since nothing else can be checked then.
+pub fn fsck(
+    block_device: Arc<dyn BlockDevice>,
+    repair: bool,
+) -> core::result::Result<Vec<FsckProblem>, FsckProblem> {
+    let data_area_blocks = get_block_cache(0, Arc::clone(&block_device))
+        .lock()
+        .read(0, |super_block: &SuperBlock| {
+            if !super_block.is_valid() {
+                return Err(FsckProblem::BadMagic);
+            }
+            let areas = 1u64
+                + super_block.journal_blocks as u64
+                + super_block.inode_bitmap_blocks as u64
+                + super_block.inode_area_blocks as u64
+                + super_block.data_bitmap_blocks as u64
+                + super_block.data_area_blocks as u64;
+            let inodes = super_block.inode_bitmap_blocks as u64 * BLOCK_SZ as u64 * 8;
+            let inodes_per_block = (BLOCK_SZ / core::mem::size_of::<DiskInode>()) as u64;
+            if areas != super_block.total_blocks as u64
+                || super_block.journal_blocks == 1
+                || super_block.inode_area_blocks as u64 * inodes_per_block < inodes
+                || (super_block.data_bitmap_blocks as u64 * BLOCK_SZ as u64 * 8)
+                    < super_block.data_area_blocks as u64
+            {
+                return Err(FsckProblem::BadAreaSizes);
+            }
+            Ok(super_block.data_area_blocks)
+        })?;
+    // a committed transaction is only finished on repair, so that a check writes nothing
+    let efs = if repair {
+        EasyFileSystem::open(block_device)
+    } else {
+        Arc::new(Mutex::new(EasyFileSystem::load(block_device)))
+    };
+    let mut efs = efs.lock();
+    let mut checker = Checker {
+        inode_visited: vec![false; efs.inode_bitmap.maximum()],
+        bad_type: vec![false; efs.inode_bitmap.maximum()],
+        links: vec![0; efs.inode_bitmap.maximum()],
+        block_used: vec![false; data_area_blocks as usize],
+        problems: Vec::new(),
+        repair,
+        efs: &mut efs,
+    };
+    checker.check_tree();
+    checker.check_inodes();
+    checker.check_blocks();
+    let problems = checker.problems;
+    if repair {
+        efs.sync();
+    }
+    Ok(problems)
+}
+
+struct Checker<'a, 'b> {
+    efs: &'a mut MutexGuard<'b, EasyFileSystem>,
+    repair: bool,
+    inode_visited: Vec<bool>,
+    /// Inodes of an unknown type, which are not read as `DiskInode`s.
+    bad_type: Vec<bool>,
+    /// Number of dirents referring to each inode.
+    links: Vec<u32>,
+    block_used: Vec<bool>,
+    problems: Vec<FsckProblem>,
+}
+
+impl Checker<'_, '_> {
+    fn read_disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.efs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.efs.block_device))
+            .lock()
+            .read(block_offset, f)
+    }
+
+    fn modify_disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.efs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.efs.block_device))
+            .lock()
+            .modify(block_offset, f)
+    }
+
+    /// Return the type byte of an inode, which is checked before the inode is read.
+    fn read_type(&self, inode_id: u32) -> u8 {
+        let (block_id, block_offset) = self.efs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.efs.block_device))
+            .lock()
+            .read(block_offset + DiskInode::TYPE_OFFSET, |type_: &u8| *type_)
+    }
+
+    /// Report an inode whose type is unknown, and clear it on repair.
+    fn check_type(&mut self, inode_id: u32) {
+        let type_ = self.read_type(inode_id);
+        if DiskInode::valid_type(type_) {
+            return;
+        }
+        self.bad_type[inode_id as usize] = true;
+        self.problems.push(FsckProblem::BadInodeType {
+            inode: inode_id,
+            type_,
+        });
+        if self.repair {
+            let (block_id, block_offset) = self.efs.get_disk_inode_pos(inode_id);
+            get_block_cache(block_id as usize, Arc::clone(&self.efs.block_device))
+                .lock()
+                .modify(block_offset, |bytes: &mut [u8; 128]| bytes.fill(0));
+            // its blocks are not recorded, so they are freed as leaked
+            self.efs.dealloc_inode(inode_id);
+        }
+    }
+
+    /// Visit every inode reachable from the root, counting the links to it.
+    fn check_tree(&mut self) {
+        let root_type = self.read_type(0);
+        if !DiskInode::valid_type(root_type) {
+            self.problems.push(FsckProblem::BadInodeType {
+                inode: 0,
+                type_: root_type,
+            });
+            if !self.repair {
+                return;
+            }
+            let (block_id, block_offset) = self.efs.get_disk_inode_pos(0);
+            get_block_cache(block_id as usize, Arc::clone(&self.efs.block_device))
+                .lock()
+                .modify(block_offset + DiskInode::TYPE_OFFSET, |type_: &mut u8| {
+                    *type_ = DiskInodeType::Directory as u8
+                });
+        }
+        let mut queue = VecDeque::from([0u32]);
+        self.inode_visited[0] = true;
+        let block_device = Arc::clone(&self.efs.block_device);
+        while let Some(inode_id) = queue.pop_front() {
+            self.check_pointers(inode_id);
+            let size = self.check_size(inode_id);
+            if !self.read_disk_inode(inode_id, |disk_inode| disk_inode.is_dir()) {
+                continue;
+            }
+            for index in 0..size / DIRENT_SZ {
+                let pos = index * DIRENT_SZ;
+                // only through pointers inside the data area, cleared or not
+                let block_id = self.read_disk_inode(inode_id, |disk_inode| {
+                    let inner_id = (pos / BLOCK_SZ) as u32;
+                    let valid = |block_id| self.in_data_area(block_id);
+                    disk_inode.get_block_id_checked(inner_id, valid, &block_device)
+                });
+                if block_id == 0 {
+                    // a hole, whose dirents are removed ones
+                    continue;
+                }
+                let block_cache = get_block_cache(block_id as usize, Arc::clone(&block_device));
+                let offset = pos % BLOCK_SZ;
+                let mut dirent = DirEntry::empty();
+                block_cache.lock().read(0, |data_block: &DataBlock| {
+                    dirent
+                        .as_bytes_mut()
+                        .copy_from_slice(&data_block[offset..offset + DIRENT_SZ])
+                });
+                let name = &dirent.as_bytes()[..NAME_LENGTH_LIMIT + 1];
+                if name[0] == 0 {
+                    // removed
+                    continue;
+                }
+                let child = dirent.inode_number();
+                let valid = match name.iter().position(|&byte| byte == 0) {
+                    Some(len) => core::str::from_utf8(&name[..len]).is_ok(),
+                    None => false,
+                };
+                if !valid || child as usize >= self.links.len() {
+                    self.problems.push(FsckProblem::BadDirent {
+                        dir: inode_id,
+                        index: index as u32,
+                    });
+                    if self.repair {
+                        block_cache.lock().modify(0, |data_block: &mut DataBlock| {
+                            data_block[offset..offset + DIRENT_SZ].fill(0)
+                        });
+                    }
+                    continue;
+                }
+                if !self.inode_visited[child as usize] && !self.bad_type[child as usize] {
+                    self.check_type(child);
+                }
+                if self.bad_type[child as usize] {
+                    if self.repair {
+                        block_cache.lock().modify(0, |data_block: &mut DataBlock| {
+                            data_block[offset..offset + DIRENT_SZ].fill(0)
+                        });
+                    }
+                    continue;
+                }
+                self.links[child as usize] += 1;
+                if !self.inode_visited[child as usize] {
+                    self.inode_visited[child as usize] = true;
+                    queue.push_back(child);
+                }
+            }
+        }
+    }
+
+    /// Check the size of an inode, cutting it down on repair, and return the size to check its
+    /// dirents in.
+    fn check_size(&mut self, inode_id: u32) -> usize {
+        let (size, is_dir) = self.read_disk_inode(inode_id, |disk_inode| {
+            (disk_inode.size, disk_inode.is_dir())
+        });
+        let mut fixed = (size as usize).min(MAX_FILE_SIZE);
+        if fixed < size as usize {
+            self.problems.push(FsckProblem::SizeTooLarge {
+                inode: inode_id,
+                size,
+            });
+        }
+        // bytes past the last whole dirent
+        let partial = if is_dir { fixed % DIRENT_SZ } else { 0 };
+        if partial != 0 {
+            self.problems.push(FsckProblem::UnalignedDirSize {
+                inode: inode_id,
+                size,
+            });
+            fixed -= partial;
+        }
+        if self.repair && fixed != size as usize {
+            self.modify_disk_inode(inode_id, |disk_inode| disk_inode.size = fixed as u32);
+        }
+        fixed
+    }
+
+    /// Whether `block_id` is in the data area.
+    fn in_data_area(&self, block_id: u32) -> bool {
+        let start = self.efs.get_data_block_id(0);
+        block_id >= start && ((block_id - start) as usize) < self.block_used.len()
+    }
+
+    /// Record the blocks of an inode, clearing bad pointers on repair.
+    fn check_pointers(&mut self, inode_id: u32) {
+        let mut pointers = self.read_disk_inode(inode_id, |disk_inode| {
+            (disk_inode.direct, disk_inode.indirect1, disk_inode.indirect2)
+        });
+        let (direct, indirect1, indirect2) = &mut pointers;
+        for block_id in direct.iter_mut() {
+            self.check_block(inode_id, block_id);
+        }
+        if self.check_block(inode_id, indirect1) {
+            self.check_index(inode_id, *indirect1, false);
+        }
+        if self.check_block(inode_id, indirect2) {
+            self.check_index(inode_id, *indirect2, true);
+        }
+        if self.repair {
+            self.modify_disk_inode(inode_id, |disk_inode| {
+                (disk_inode.direct, disk_inode.indirect1, disk_inode.indirect2) = pointers;
+            });
+        }
+    }
+
+    fn check_index(&mut self, inode_id: u32, block_id: u32, indirect2: bool) {
+        let block_cache = get_block_cache(block_id as usize, Arc::clone(&self.efs.block_device));
+        let mut entries = *block_cache.lock().get_ref::<IndirectBlock>(0);
+        for entry in entries.iter_mut() {
+            if self.check_block(inode_id, entry) && indirect2 {
+                self.check_index(inode_id, *entry, false);
+            }
+        }
+        if self.repair {
+            block_cache
+                .lock()
+                .modify(0, |indirect_block: &mut IndirectBlock| *indirect_block = entries);
+        }
+    }
+
+    /// Record a block pointer and return whether it is valid and seen for the first time.
+    fn check_block(&mut self, inode_id: u32, block_id: &mut u32) -> bool {
+        if *block_id == 0 {
+            return false;
+        }
+        let index = block_id.wrapping_sub(self.efs.get_data_block_id(0)) as usize;
+        let problem = if !self.in_data_area(*block_id) {
+            FsckProblem::BadBlockPointer {
+                inode: inode_id,
+                block: *block_id,
+            }
+        } else if self.block_used[index] {
+            FsckProblem::DuplicateBlock {
+                inode: inode_id,
+                block: *block_id,
+            }
+        } else {
+            self.block_used[index] = true;
+            return true;
+        };
+        self.problems.push(problem);
+        if self.repair {
+            *block_id = 0;
+        }
+        false
+    }
+
+    /// Compare the inode bitmap and link counts with the tree.
+    fn check_inodes(&mut self) {
+        let block_device = Arc::clone(&self.efs.block_device);
+        for inode_id in 0..self.links.len() as u32 {
+            let marked = self.efs.inode_bitmap.get(&block_device, inode_id as usize);
+            let visited = self.inode_visited[inode_id as usize];
+            if self.bad_type[inode_id as usize] {
+                continue;
+            }
+            if marked && !visited {
+                self.problems.push(FsckProblem::OrphanedInode { inode: inode_id });
+                if self.repair {
+                    // its blocks are not recorded, so they are freed as leaked
+                    self.efs.dealloc_inode(inode_id);
+                }
+                continue;
+            }
+            if !visited {
+                continue;
+            }
+            if !marked {
+                self.problems.push(FsckProblem::UnmarkedInode { inode: inode_id });
+                if self.repair {
+                    self.efs
+                        .inode_bitmap
+                        .set(&block_device, inode_id as usize, true);
+                }
+            }
+            let links = self.links[inode_id as usize];
+            let nlink = self.read_disk_inode(inode_id, |disk_inode| disk_inode.nlink);
+            if nlink != links {
+                self.problems.push(FsckProblem::WrongLinkCount {
+                    inode: inode_id,
+                    nlink,
+                    links,
+                });
+                if self.repair {
+                    self.modify_disk_inode(inode_id, |disk_inode| disk_inode.nlink = links);
+                }
+            }
+        }
+    }
+
+    /// Compare the data bitmap with the blocks recorded.
+    fn check_blocks(&mut self) {
+        let block_device = Arc::clone(&self.efs.block_device);
+        let start = self.efs.get_data_block_id(0);
+        for (index, &used) in self.block_used.iter().enumerate() {
+            let block = start + index as u32;
+            let marked = self.efs.data_bitmap.get(&block_device, index);
+            let problem = match (marked, used) {
+                (true, false) => FsckProblem::LeakedBlock { block },
+                (false, true) => FsckProblem::UnmarkedBlock { block },
+                _ => continue,
+            };
+            self.problems.push(problem);
+            if self.repair {
+                self.efs.data_bitmap.set(&block_device, index, used);
+            }
+        }
+    }
+}
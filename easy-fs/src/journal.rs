@@ -0,0 +1,118 @@
+use super::{block_cache_sync_all, get_block_cache, write_blocks_sorted, BlockDevice, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const JOURNAL_MAGIC: u32 = 0x4a4e4c31;
+/// Number of block IDs fitting in the header block.
+const JOURNAL_CAPACITY: usize = BLOCK_SZ / 4 - 2;
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// First block of the journal. A transaction is committed once a header with a non-zero count
+/// is on disk, and the blocks after the header are copies of the blocks in `block_ids`.
+#[repr(C)]
+struct JournalHeader {
+    magic: u32,
+    count: u32,
+    block_ids: [u32; JOURNAL_CAPACITY],
+}
+
+impl JournalHeader {
+    fn new(block_ids: &[u32]) -> Self {
+        let mut header = Self {
+            magic: JOURNAL_MAGIC,
+            count: block_ids.len() as u32,
+            block_ids: [0; JOURNAL_CAPACITY],
+        };
+        header.block_ids[..block_ids.len()].copy_from_slice(block_ids);
+        header
+    }
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, BLOCK_SZ) }
+    }
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, BLOCK_SZ) }
+    }
+}
+
+const _: () = assert!(core::mem::size_of::<JournalHeader>() == BLOCK_SZ);
+
+/// Write-ahead journal for metadata. Blocks go to the journal first and then to their home
+/// location, so a crash leaves either the old or the new version of every block of a
+/// transaction once the journal is replayed.
+pub struct Journal {
+    start_block: u32,
+    blocks: u32,
+}
+
+impl Journal {
+    pub fn new(start_block: u32, blocks: u32) -> Self {
+        assert!(blocks >= 2, "A journal needs a header and a block");
+        Self {
+            start_block,
+            blocks,
+        }
+    }
+
+    /// Maximum number of blocks in a transaction.
+    pub fn capacity(&self) -> usize {
+        JOURNAL_CAPACITY.min(self.blocks as usize - 1)
+    }
+
+    /// Write an empty header, which marks the journal as clean.
+    pub fn format(&self, block_device: &Arc<dyn BlockDevice>) {
+        block_device.write_block(self.start_block as usize, JournalHeader::new(&[]).as_bytes());
+    }
+
+    /// Write `blocks` as one transaction, or as several if the journal cannot hold them all. That
+    /// only happens with a journal smaller than `MIN_JOURNAL_BLOCKS`, as made by other tools, or
+    /// when more operations are committed at once than fit, and an operation may then be split
+    /// across transactions, but no block is written home outside of one. Each step is flushed
+    /// before the next one, so that the device cannot reorder them.
+    pub fn commit(&self, blocks: &[(usize, &DataBlock)], block_device: &Arc<dyn BlockDevice>) {
+        for blocks in blocks.chunks(self.capacity()) {
+            let copies: Vec<u8> = blocks
+                .iter()
+                .flat_map(|(_, data)| data.iter().copied())
+                .collect();
+            block_device.write_blocks(self.start_block as usize + 1, &copies);
+            block_device.flush();
+            // the commit point
+            let block_ids: Vec<u32> = blocks
+                .iter()
+                .map(|&(block_id, _)| block_id as u32)
+                .collect();
+            let header = JournalHeader::new(&block_ids);
+            block_device.write_block(self.start_block as usize, header.as_bytes());
+            block_device.flush();
+            write_blocks_sorted(block_device.as_ref(), blocks);
+            block_device.flush();
+            // so that the transaction is not replayed over later ones
+            self.format(block_device);
+            block_device.flush();
+        }
+    }
+
+    /// Copy a committed transaction to its home location and return the number of blocks.
+    pub fn replay(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        let mut header = JournalHeader::new(&[]);
+        block_device.read_block(self.start_block as usize, header.as_bytes_mut());
+        if header.magic != JOURNAL_MAGIC || header.count == 0 {
+            return 0;
+        }
+        let count = (header.count as usize).min(self.capacity());
+        for i in 0..count {
+            let mut data = [0u8; BLOCK_SZ];
+            block_device.read_block(self.start_block as usize + 1 + i, &mut data);
+            // through the cache, which may hold the old version
+            get_block_cache(header.block_ids[i] as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| *data_block = data);
+        }
+        block_cache_sync_all(block_device);
+        block_device.flush();
+        self.format(block_device);
+        block_device.flush();
+        count
+    }
+}
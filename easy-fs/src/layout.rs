@@ -2,18 +2,21 @@ use super::{get_block_cache, BlockDevice, BLOCK_SZ};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
+use core::ops::Range;
 
-const EFS_MAGIC: u32 = 0x3b800001;
-const INODE_DIRECT_COUNT: usize = 28;
-const NAME_LENGTH_LIMIT: usize = 27;
+const EFS_MAGIC: u32 = 0x3b800003;
+const INODE_DIRECT_COUNT: usize = 17;
+pub const NAME_LENGTH_LIMIT: usize = 27;
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
-#[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// Largest size of a file, whose blocks are all reachable from its direct and indirect blocks.
+pub const MAX_FILE_SIZE: usize = INDIRECT2_BOUND * BLOCK_SZ;
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct SuperBlock {
     magic: u32,
     pub total_blocks: u32,
@@ -21,6 +24,8 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// Blocks of the journal right after the super block, 0 if there is none.
+    pub journal_blocks: u32,
 }
 
 impl Debug for SuperBlock {
@@ -31,6 +36,7 @@ impl Debug for SuperBlock {
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("journal_blocks", &self.journal_blocks)
             .finish()
     }
 }
@@ -43,6 +49,7 @@ impl SuperBlock {
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
         data_area_blocks: u32,
+        journal_blocks: u32,
     ) {
         *self = Self {
             magic: EFS_MAGIC,
@@ -51,6 +58,7 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            journal_blocks,
         }
     }
     pub fn is_valid(&self) -> bool {
@@ -59,6 +67,7 @@ impl SuperBlock {
 }
 
 #[derive(PartialEq)]
+#[repr(u8)]
 pub enum DiskInodeType {
     File,
     Directory,
@@ -73,16 +82,49 @@ pub struct DiskInode {
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
+    /// Number of dirents referring to this inode.
+    pub nlink: u32,
+    /// Permission bits.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Times of the last access, modification and status change, in seconds since the epoch.
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
     type_: DiskInodeType,
 }
 
+// Four inodes per block.
+const _: () = assert!(core::mem::size_of::<DiskInode>() == 128);
+// After the block pointers, nlink, mode, uid, gid and the times.
+const _: () = assert!(DiskInode::TYPE_OFFSET == 4 * (INODE_DIRECT_COUNT + 7) + 3 * 8);
+
 impl DiskInode {
+    /// Offset of the type, whose byte has to be checked before an inode of a corrupt image is
+    /// read, since only the values of `DiskInodeType` are valid.
+    pub const TYPE_OFFSET: usize = 120;
+    /// Whether `type_` is the byte of a `DiskInodeType`.
+    pub fn valid_type(type_: u8) -> bool {
+        type_ <= DiskInodeType::Directory as u8
+    }
     /// indirect1 and indirect2 block are allocated only when they are needed.
-    pub fn initialize(&mut self, type_: DiskInodeType) {
+    pub fn initialize(&mut self, type_: DiskInodeType, now: u64) {
         self.size = 0;
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        self.nlink = 0;
+        self.mode = if type_ == DiskInodeType::Directory {
+            0o755
+        } else {
+            0o644
+        };
+        self.uid = 0;
+        self.gid = 0;
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
         self.type_ = type_;
     }
     pub fn is_dir(&self) -> bool {
@@ -99,198 +141,222 @@ impl DiskInode {
     fn _data_blocks(size: u32) -> u32 {
         (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
     }
-    /// Return number of blocks needed include indirect1/2.
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
-        // indirect1
-        if data_blocks > INODE_DIRECT_COUNT {
-            total += 1;
-        }
-        // indirect2
-        if data_blocks > INDIRECT1_BOUND {
-            total += 1;
-            // sub indirect1
-            total +=
-                (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
-        }
-        total as u32
-    }
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
-    }
+    /// Return the block holding data block `inner_id`, or 0 if it is a hole.
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
         let inner_id = inner_id as usize;
         if inner_id < INODE_DIRECT_COUNT {
             self.direct[inner_id]
         } else if inner_id < INDIRECT1_BOUND {
-            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-                .lock()
-                .read(0, |indirect_block: &IndirectBlock| {
-                    indirect_block[inner_id - INODE_DIRECT_COUNT]
-                })
+            Self::get_entry(self.indirect1, inner_id - INODE_DIRECT_COUNT, block_device)
         } else {
             let last = inner_id - INDIRECT1_BOUND;
-            let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
-                .lock()
-                .read(0, |indirect2: &IndirectBlock| {
-                    indirect2[last / INODE_INDIRECT1_COUNT]
-                });
-            get_block_cache(indirect1 as usize, Arc::clone(block_device))
-                .lock()
-                .read(0, |indirect1: &IndirectBlock| {
-                    indirect1[last % INODE_INDIRECT1_COUNT]
-                })
+            let indirect1 =
+                Self::get_entry(self.indirect2, last / INODE_INDIRECT1_COUNT, block_device);
+            Self::get_entry(indirect1, last % INODE_INDIRECT1_COUNT, block_device)
         }
     }
-    pub fn increase_size(
-        &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,
+    /// Like `get_block_id`, but pointers for which `valid` is false are holes, so that a corrupt
+    /// inode is read without following pointers off the device.
+    pub fn get_block_id_checked(
+        &self,
+        inner_id: u32,
+        valid: impl Fn(u32) -> bool,
         block_device: &Arc<dyn BlockDevice>,
-    ) {
-        let mut current_blocks = self.data_blocks();
-        self.size = new_size;
-        let mut total_blocks = self.data_blocks();
-        let mut new_blocks = new_blocks.into_iter();
-        // fill direct
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
-        }
-        // alloc indirect1
-        if total_blocks > INODE_DIRECT_COUNT as u32 {
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                self.indirect1 = new_blocks.next().unwrap();
-            }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
+    ) -> u32 {
+        let checked = |block_id: u32| if valid(block_id) { block_id } else { 0 };
+        let inner_id = inner_id as usize;
+        let block_id = if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id]
+        } else if inner_id < INDIRECT1_BOUND {
+            let index = inner_id - INODE_DIRECT_COUNT;
+            Self::get_entry(checked(self.indirect1), index, block_device)
         } else {
-            return;
+            let last = inner_id - INDIRECT1_BOUND;
+            let index = last / INODE_INDIRECT1_COUNT;
+            let indirect1 = Self::get_entry(checked(self.indirect2), index, block_device);
+            Self::get_entry(checked(indirect1), last % INODE_INDIRECT1_COUNT, block_device)
+        };
+        checked(block_id)
+    }
+    /// Return entry `index` of the index block `block_id`, where block 0 is a hole of zeros.
+    fn get_entry(block_id: u32, index: usize, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        if block_id == 0 {
+            return 0;
         }
-        // fill indirect1
-        get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+        get_block_cache(block_id as usize, Arc::clone(block_device))
             .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                    indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                    current_blocks += 1;
-                }
-            });
-        // alloc indirect2
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                self.indirect2 = new_blocks.next().unwrap();
+            .read(0, |indirect_block: &IndirectBlock| indirect_block[index])
+    }
+    /// Like `get_block_id`, but fill a hole with a block from `alloc`, along with the index
+    /// blocks leading to it. Allocated blocks must be zeroed.
+    pub fn alloc_block_id(
+        &mut self,
+        inner_id: u32,
+        alloc: &mut dyn FnMut() -> u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> u32 {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            if self.direct[inner_id] == 0 {
+                self.direct[inner_id] = alloc();
+            }
+            self.direct[inner_id]
+        } else if inner_id < INDIRECT1_BOUND {
+            if self.indirect1 == 0 {
+                self.indirect1 = alloc();
             }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
+            Self::alloc_entry(self.indirect1, inner_id - INODE_DIRECT_COUNT, alloc, block_device)
         } else {
-            return;
+            if self.indirect2 == 0 {
+                self.indirect2 = alloc();
+            }
+            let last = inner_id - INDIRECT1_BOUND;
+            let indirect1 = Self::alloc_entry(
+                self.indirect2,
+                last / INODE_INDIRECT1_COUNT,
+                alloc,
+                block_device,
+            );
+            Self::alloc_entry(indirect1, last % INODE_INDIRECT1_COUNT, alloc, block_device)
         }
-        // fill indirect2 from (a0, b0) -> (a1, b1)
-        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
-        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
-        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
-        // alloc low-level indirect1
-        get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+    }
+    fn alloc_entry(
+        block_id: u32,
+        index: usize,
+        alloc: &mut dyn FnMut() -> u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> u32 {
+        get_block_cache(block_id as usize, Arc::clone(block_device))
             .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-                while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                    if b0 == 0 {
-                        indirect2[a0] = new_blocks.next().unwrap();
-                    }
-                    // fill current
-                    get_block_cache(indirect2[a0] as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            indirect1[b0] = new_blocks.next().unwrap();
-                        });
-                    // move to next
-                    b0 += 1;
-                    if b0 == INODE_INDIRECT1_COUNT {
-                        b0 = 0;
-                        a0 += 1;
-                    }
+            .modify(0, |indirect_block: &mut IndirectBlock| {
+                if indirect_block[index] == 0 {
+                    indirect_block[index] = alloc();
                 }
-            });
+                indirect_block[index]
+            })
     }
-
-    /// Clear size to zero and return blocks that should be deallocated.
+    /// Grow the size to `new_size` if it is larger. No block is allocated, so the new space is
+    /// a hole until it is written.
+    pub fn increase_size(&mut self, new_size: u32) {
+        self.size = self.size.max(new_size);
+    }
+    /// Shrink the size to `new_size` and return the blocks that should be deallocated: data
+    /// blocks past the end, and index blocks left without entries.
     ///
-    /// We will clear the block contents to zero later.
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+    /// Freed blocks are not cleared, since `EasyFileSystem::alloc_data` zeroes blocks.
+    pub fn decrease_size(
+        &mut self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        assert!(new_size <= self.size);
         let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
-        let mut current_blocks = 0usize;
-        // direct
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
-            current_blocks += 1;
-        }
-        // indirect1 block
-        if data_blocks > INODE_DIRECT_COUNT {
-            v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
-        } else {
-            return v;
+        let old_blocks = self.data_blocks() as usize;
+        let new_blocks = Self::_data_blocks(new_size) as usize;
+        // zero the rest of the last block, so that the file reads as zeros if it grows again
+        let tail = new_size as usize % BLOCK_SZ;
+        if tail != 0 {
+            let block_id = self.get_block_id(new_blocks as u32 - 1, block_device);
+            if block_id != 0 {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| data_block[tail..].fill(0));
+            }
         }
-        // indirect1
-        get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-            .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                    v.push(indirect1[current_blocks]);
-                    //indirect1[current_blocks] = 0;
-                    current_blocks += 1;
-                }
-            });
-        self.indirect1 = 0;
-        // indirect2 block
-        if data_blocks > INODE_INDIRECT1_COUNT {
-            v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
-        } else {
-            return v;
+        self.size = new_size;
+        // push the non-zero entries of `entries` and clear them
+        let mut free = |entries: &mut [u32]| {
+            for entry in entries.iter_mut().filter(|entry| **entry != 0) {
+                v.push(*entry);
+                *entry = 0;
+            }
+        };
+        // direct
+        free(&mut self.direct[new_blocks.min(DIRECT_BOUND)..old_blocks.min(DIRECT_BOUND)]);
+        // indirect1, untouched if the file still reaches past it
+        if old_blocks > DIRECT_BOUND && new_blocks < INDIRECT1_BOUND && self.indirect1 != 0 {
+            let start = new_blocks.max(DIRECT_BOUND) - DIRECT_BOUND;
+            let end = old_blocks.min(INDIRECT1_BOUND) - DIRECT_BOUND;
+            Self::free_entries(self.indirect1, start..end, start == 0, &mut free, block_device);
+            if start == 0 {
+                free(core::slice::from_mut(&mut self.indirect1));
+            }
         }
         // indirect2
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
-        get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
-            .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-                // full indirect1 blocks
-                for entry in indirect2.iter_mut().take(a1) {
-                    v.push(*entry);
-                    get_block_cache(*entry as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            for entry in indirect1.iter() {
-                                v.push(*entry);
-                            }
-                        });
-                }
-                // last indirect1 block
-                if b1 > 0 {
-                    v.push(indirect2[a1]);
-                    get_block_cache(indirect2[a1] as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            for entry in indirect1.iter().take(b1) {
-                                v.push(*entry);
-                            }
-                        });
-                    //indirect2[a1] = 0;
+        if old_blocks > INDIRECT1_BOUND && self.indirect2 != 0 {
+            let start = new_blocks.max(INDIRECT1_BOUND) - INDIRECT1_BOUND;
+            let end = old_blocks - INDIRECT1_BOUND;
+            assert!(end <= INODE_INDIRECT2_COUNT);
+            let first = start / INODE_INDIRECT1_COUNT;
+            let last = (end - 1) / INODE_INDIRECT1_COUNT + 1;
+            let mut free_indirect1 = |entries: &mut [u32]| {
+                for (a, entry) in (first..last).zip(entries.iter_mut()) {
+                    if *entry == 0 {
+                        continue;
+                    }
+                    // range of this low-level indirect1 block to free
+                    let base = a * INODE_INDIRECT1_COUNT;
+                    let b0 = start.max(base) - base;
+                    let b1 = end.min(base + INODE_INDIRECT1_COUNT) - base;
+                    Self::free_entries(*entry, b0..b1, b0 == 0, &mut free, block_device);
+                    if b0 == 0 {
+                        free(core::slice::from_mut(entry));
+                    }
                 }
-            });
-        self.indirect2 = 0;
+            };
+            let (indirect2, whole) = (self.indirect2, start == 0);
+            Self::free_entries(indirect2, first..last, whole, &mut free_indirect1, block_device);
+            if start == 0 {
+                free(core::slice::from_mut(&mut self.indirect2));
+            }
+        }
         v
     }
+    /// Pass `range` of the entries in index block `block_id` to `free`. A `whole` block is about
+    /// to be freed, so it is left untouched.
+    fn free_entries(
+        block_id: u32,
+        range: Range<usize>,
+        whole: bool,
+        free: &mut dyn FnMut(&mut [u32]),
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device));
+        let mut block_cache = block_cache.lock();
+        if whole {
+            let mut entries = *block_cache.get_ref::<IndirectBlock>(0);
+            free(&mut entries[range]);
+        } else {
+            block_cache.modify(0, |entries: &mut IndirectBlock| free(&mut entries[range]));
+        }
+    }
+    /// Clear size to zero and return blocks that should be deallocated.
+    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        self.decrease_size(0, block_device)
+    }
+    /// Return the number of data and index blocks allocated, holes excluded.
+    pub fn allocated_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        let count = |entries: &[u32]| entries.iter().filter(|&&entry| entry != 0).count() as u32;
+        let count_block = |block_id: u32| {
+            if block_id == 0 {
+                return 0;
+            }
+            1 + get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect_block: &IndirectBlock| count(indirect_block))
+        };
+        let mut total = count(&self.direct) + count_block(self.indirect1);
+        if self.indirect2 != 0 {
+            total += 1;
+            let indirect2 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| *indirect2);
+            for indirect1 in indirect2 {
+                total += count_block(indirect1);
+            }
+        }
+        total
+    }
     pub fn read_at(
         &self,
         offset: usize,
@@ -298,7 +364,8 @@ impl DiskInode {
         block_device: &Arc<dyn BlockDevice>,
     ) -> usize {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.size as usize);
+        // a corrupt size must not lead past the last indirect block
+        let end = (offset + buf.len()).min(self.size as usize).min(MAX_FILE_SIZE);
         if start >= end {
             return 0;
         }
@@ -311,15 +378,19 @@ impl DiskInode {
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            let block_id = self.get_block_id(start_block as u32, block_device);
+            if block_id == 0 {
+                // a hole
+                dst.fill(0);
+            } else {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        let src =
+                            &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                        dst.copy_from_slice(src);
+                    });
+            }
             read_size += block_read_size;
             // move to next block
             if end_current_block == end {
@@ -330,11 +401,13 @@ impl DiskInode {
         }
         read_size
     }
-    /// File size must be adjusted before.
+    /// File size must be adjusted before, and not beyond `MAX_FILE_SIZE`. Holes written to are
+    /// filled with blocks from `alloc`.
     pub fn write_at(
         &mut self,
         offset: usize,
         buf: &[u8],
+        alloc: &mut dyn FnMut() -> u32,
         block_device: &Arc<dyn BlockDevice>,
     ) -> usize {
         let mut start = offset;
@@ -349,7 +422,7 @@ impl DiskInode {
             // write and update write size
             let block_write_size = end_current_block - start;
             get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
+                self.alloc_block_id(start_block as u32, alloc, block_device) as usize,
                 Arc::clone(block_device),
             )
             .lock()
@@ -6,13 +6,25 @@ mod bitmap;
 mod block_cache;
 mod block_dev;
 mod efs;
+mod fsck;
+mod journal;
 mod layout;
 mod vfs;
 
 pub const BLOCK_SZ: usize = 512;
 use bitmap::Bitmap;
-use block_cache::{block_cache_sync_all, get_block_cache};
-pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
+use block_cache::{block_cache_device_dirty_blocks, block_cache_sync_all_with};
+pub use block_cache::{
+    block_cache_capacity, block_cache_clear, block_cache_dirty_blocks, block_cache_stats,
+    block_cache_sync_all, get_block_cache, set_block_cache_capacity, set_block_cache_wait,
+    BlockCache, BlockCacheStats, MIN_BLOCK_CACHE_CAPACITY,
+};
+use block_dev::write_blocks_sorted;
+pub use block_dev::{BlockDevice, BlockOp, BlockRequest};
+pub use efs::{EasyFileSystem, MIN_JOURNAL_BLOCKS};
+pub use fsck::{fsck, FsckProblem};
+use journal::Journal;
 use layout::*;
-pub use vfs::Inode;
+pub use layout::{SuperBlock, MAX_FILE_SIZE};
+use vfs::OpenInodes;
+pub use vfs::{Inode, Stat};
@@ -1,32 +1,74 @@
 use super::{
-    block_cache_sync_all, get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType,
-    EasyFileSystem, DIRENT_SZ,
+    get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType, EasyFileSystem, BLOCK_SZ,
+    DIRENT_SZ, MAX_FILE_SIZE, NAME_LENGTH_LIMIT,
 };
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use spin::{Mutex, MutexGuard};
 
+/// Data blocks written per operation by `Inode::write_at`, within `OP_MAX_DIRTY_BLOCKS`.
+const WRITE_CHUNK_BLOCKS: usize = 32;
+
+/// Metadata of an inode.
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub ino: u32,
+    pub is_dir: bool,
+    /// Permission bits.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    /// Number of data and index blocks allocated, not counting holes.
+    pub blocks: u32,
+    /// Times of the last access, modification and status change, in seconds since the epoch.
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+/// The `Inode`s in memory of a file system, so that an inode whose last link is removed while
+/// it is open is freed only once it is closed.
+#[derive(Default)]
+pub(crate) struct OpenInodes {
+    /// Number of `Inode`s of each inode.
+    refs: BTreeMap<u32, usize>,
+    /// Inodes without links that are still open.
+    orphans: BTreeSet<u32>,
+    /// Inodes without links closed since, freed on the next operation.
+    pub(crate) closed: Vec<u32>,
+}
+
 pub struct Inode {
+    inode_id: u32,
     block_id: usize,
     block_offset: usize,
     fs: Arc<Mutex<EasyFileSystem>>,
     block_device: Arc<dyn BlockDevice>,
+    open: Arc<Mutex<OpenInodes>>,
 }
 
 impl Inode {
     /// We should not acquire efs lock here.
-    pub fn new(
+    pub(crate) fn new(
+        inode_id: u32,
         block_id: u32,
         block_offset: usize,
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
+        open: Arc<Mutex<OpenInodes>>,
     ) -> Self {
+        *open.lock().refs.entry(inode_id).or_insert(0) += 1;
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
             fs,
             block_device,
+            open,
         }
     }
 
@@ -42,7 +84,8 @@ impl Inode {
             .modify(self.block_offset, f)
     }
 
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
+    /// Return the index and inode number of the dirent named `name`.
+    fn find_dirent(&self, name: &str, disk_inode: &DiskInode) -> Option<(usize, u32)> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
         let file_count = (disk_inode.size as usize) / DIRENT_SZ;
@@ -52,135 +95,425 @@ impl Inode {
                 disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
                 DIRENT_SZ,
             );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
+            // empty names are removed entries
+            if !name.is_empty() && dirent.name() == name {
+                return Some((i, dirent.inode_number() as u32));
             }
         }
         None
     }
 
+    fn get_inode(&self, inode_id: u32, fs: &EasyFileSystem) -> Arc<Inode> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Arc::new(Self::new(
+            inode_id,
+            block_id,
+            block_offset,
+            self.fs.clone(),
+            self.block_device.clone(),
+            self.open.clone(),
+        ))
+    }
+
+    /// Return the index and inode of dirent `name`, or None if this is not a directory.
+    /// The caller holds the efs lock.
+    fn lookup_dirent(&self, name: &str, fs: &EasyFileSystem) -> Option<(usize, Arc<Inode>)> {
+        let (index, inode_id) = self.read_disk_inode(|disk_inode| {
+            if disk_inode.is_dir() {
+                self.find_dirent(name, disk_inode)
+            } else {
+                None
+            }
+        })?;
+        Some((index, self.get_inode(inode_id, fs)))
+    }
+
+    /// Look up `name` in this directory, return None if this is not a directory.
     pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
         let fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
+        self.lookup_dirent(name, &fs).map(|(_, inode)| inode)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+
+    /// Whether `name` can be added to this directory. The caller holds the efs lock.
+    fn can_add(&self, name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= NAME_LENGTH_LIMIT
+            && !name.contains('/')
+            && self.read_disk_inode(|disk_inode| {
+                // is it a directory still linked, and has the file not been created?
+                disk_inode.is_dir()
+                    && disk_inode.nlink > 0
+                    && self.find_dirent(name, disk_inode).is_none()
             })
-        })
     }
 
-    fn increase_size(
+    /// Return the index of the first removed dirent, or the number of dirents if there is none.
+    fn free_dirent(&self, disk_inode: &DiskInode) -> usize {
+        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+        let mut dirent = DirEntry::empty();
+        (0..file_count)
+            .find(|&i| {
+                disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                dirent.name().is_empty()
+            })
+            .unwrap_or(file_count)
+    }
+
+    fn set_dirent(&self, index: usize, dirent: &DirEntry) {
+        let now = EasyFileSystem::now();
+        self.modify_disk_inode(|disk_inode| {
+            // the dirent is inside the directory, which has no holes
+            let alloc = &mut || unreachable!();
+            disk_inode.write_at(index * DIRENT_SZ, dirent.as_bytes(), alloc, &self.block_device);
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+        });
+    }
+
+    /// Add a dirent to this directory, reusing a removed one if possible, and count the link in
+    /// the target inode. The caller holds the efs lock.
+    pub(crate) fn add_dirent(
         &self,
-        new_size: u32,
-        disk_inode: &mut DiskInode,
+        name: &str,
+        inode_id: u32,
         fs: &mut MutexGuard<EasyFileSystem>,
     ) {
-        if new_size < disk_inode.size {
-            return;
+        let now = EasyFileSystem::now();
+        self.modify_disk_inode(|disk_inode| {
+            let index = self.free_dirent(disk_inode);
+            // increase size
+            disk_inode.increase_size(((index + 1) * DIRENT_SZ) as u32);
+            // write dirent
+            let dirent = DirEntry::new(name, inode_id);
+            let alloc = &mut || fs.alloc_data();
+            disk_inode.write_at(index * DIRENT_SZ, dirent.as_bytes(), alloc, &self.block_device);
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+        });
+        self.get_inode(inode_id, fs).link_inode();
+    }
+
+    /// Count a new link to this inode. The caller holds the efs lock.
+    fn link_inode(&self) {
+        let now = EasyFileSystem::now();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.nlink += 1;
+            disk_inode.ctime = now;
+        });
+    }
+
+    /// Drop a link to this inode. Without links, the inode and its data blocks are freed once its
+    /// last `Inode` is dropped. The caller holds the efs lock.
+    fn unlink_inode(&self) {
+        let now = EasyFileSystem::now();
+        let nlink = self.modify_disk_inode(|disk_inode| {
+            disk_inode.nlink -= 1;
+            disk_inode.ctime = now;
+            disk_inode.nlink
+        });
+        if nlink == 0 {
+            self.open.lock().orphans.insert(self.inode_id);
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+    }
+
+    /// Remove dirent `index` of this directory, which refers to `inode`. A directory also drops
+    /// the links of its "." and ".." entries. The caller holds the efs lock.
+    fn remove_entry(&self, index: usize, inode: &Inode) {
+        let is_dir = inode.read_disk_inode(|disk_inode| disk_inode.is_dir());
+        self.set_dirent(index, &DirEntry::empty());
+        inode.unlink_inode();
+        if is_dir {
+            inode.unlink_inode();
+            self.unlink_inode();
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
     }
 
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+    /// Whether this is a directory holding only "." and "..". The caller holds the efs lock.
+    fn is_empty_dir(&self) -> bool {
+        self.read_disk_inode(|disk_inode| {
+            disk_inode.is_dir() && self.entries(disk_inode).len() == 2
+        })
+    }
+
+    fn create_inode(&self, name: &str, type_: DiskInodeType) -> Option<Arc<Inode>> {
         let mut fs = self.fs.lock();
-        let op = |root_inode: &DiskInode| {
-            // assert it is a directory
-            assert!(root_inode.is_dir());
-            // has the file been created?
-            self.find_inode_id(name, root_inode)
-        };
-        if self.read_disk_inode(op).is_some() {
+        if !self.can_add(name) {
             return None;
         }
         // create a new file
         // alloc a inode with an indirect block
         let new_inode_id = fs.alloc_inode();
+        let is_dir = type_ == DiskInodeType::Directory;
         // initialize inode
         let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
         get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(DiskInodeType::File);
+                new_inode.initialize(type_, EasyFileSystem::now());
             });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
-        });
-
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        block_cache_sync_all();
+        let new_inode = self.get_inode(new_inode_id, &fs);
+        if is_dir {
+            new_inode.add_dirent(".", new_inode_id, &mut fs);
+            new_inode.add_dirent("..", self.inode_id, &mut fs);
+        }
+        self.add_dirent(name, new_inode_id, &mut fs);
+        fs.sync_if_needed();
         // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
+        Some(new_inode)
         // release efs lock automatically by compiler
     }
 
-    pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
+    /// Create a file in this directory, return None if `name` exists.
+    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::File)
+    }
+
+    /// Create a directory holding the "." and ".." entries, return None if `name` exists.
+    pub fn create_dir(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+
+    /// Remove the empty directory `name`, freeing its inode and data blocks once it is closed.
+    pub fn rmdir(&self, name: &str) -> bool {
+        if name == "." || name == ".." {
+            return false;
+        }
+        let fs = self.fs.lock();
+        match self.lookup_dirent(name, &fs) {
+            Some((index, dir)) if dir.is_empty_dir() => {
+                self.remove_entry(index, &dir);
+                drop(dir);
+                fs.sync_if_needed();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove the entry `name` of a file. The file is freed with its last link, or once it is
+    /// closed if it is still open.
+    pub fn unlink(&self, name: &str) -> bool {
+        let fs = self.fs.lock();
+        match self.lookup_dirent(name, &fs) {
+            Some((index, inode)) if !inode.read_disk_inode(|disk_inode| disk_inode.is_dir()) => {
+                self.remove_entry(index, &inode);
+                drop(inode);
+                fs.sync_if_needed();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Add the entry `name` to this directory for `inode`, which must not be a directory nor
+    /// have lost its last link.
+    pub fn link(&self, name: &str, inode: &Inode) -> bool {
+        let mut fs = self.fs.lock();
+        if inode.read_disk_inode(|disk_inode| disk_inode.is_dir() || disk_inode.nlink == 0)
+            || !self.can_add(name)
+        {
+            return false;
+        }
+        self.add_dirent(name, inode.inode_id, &mut fs);
+        fs.sync_if_needed();
+        true
+    }
+
+    /// Move the entry `old_name` of this directory to `new_name` in `new_dir`. An existing
+    /// `new_name` is replaced if it is a file and so is `old_name`, or if both are directories
+    /// and it is empty.
+    pub fn rename(&self, old_name: &str, new_dir: &Inode, new_name: &str) -> bool {
+        if [old_name, new_name].iter().any(|&name| name == "." || name == "..") {
+            return false;
+        }
+        let mut fs = self.fs.lock();
+        let (old_index, inode) = match self.lookup_dirent(old_name, &fs) {
+            Some(x) => x,
+            None => return false,
+        };
+        let is_dir = inode.read_disk_inode(|disk_inode| disk_inode.is_dir());
+        if is_dir {
+            // a directory cannot move into itself or one of its subdirectories
+            let mut dir = self.get_inode(new_dir.inode_id, &fs);
+            while dir.inode_id != inode.inode_id {
+                let parent = match dir.lookup_dirent("..", &fs) {
+                    Some((_, parent)) if parent.inode_id != dir.inode_id => parent,
+                    _ => break,
+                };
+                dir = parent;
+            }
+            if dir.inode_id == inode.inode_id {
+                return false;
+            }
+        }
+        match new_dir.lookup_dirent(new_name, &fs) {
+            Some((_, target)) if target.inode_id == inode.inode_id => return true,
+            Some((index, target)) => {
+                let target_is_dir = target.read_disk_inode(|disk_inode| disk_inode.is_dir());
+                if target_is_dir != is_dir || (is_dir && !target.is_empty_dir()) {
+                    return false;
+                }
+                new_dir.remove_entry(index, &target);
+            }
+            None if !new_dir.can_add(new_name) => return false,
+            None => {}
+        }
+        new_dir.add_dirent(new_name, inode.inode_id, &mut fs);
+        self.set_dirent(old_index, &DirEntry::empty());
+        inode.unlink_inode();
+        if is_dir && new_dir.inode_id != self.inode_id {
+            // the link of ".." moves from this directory to the new one
+            let (index, _) = inode.lookup_dirent("..", &fs).unwrap();
+            inode.set_dirent(index, &DirEntry::new("..", new_dir.inode_id));
+            new_dir.link_inode();
+            self.unlink_inode();
+        }
+        fs.sync_if_needed();
+        true
+    }
+
+    /// Names of the dirents in use, including "." and "..".
+    fn entries(&self, disk_inode: &DiskInode) -> Vec<String> {
+        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+        let mut v: Vec<String> = Vec::new();
+        for i in 0..file_count {
+            let mut dirent = DirEntry::empty();
+            assert_eq!(
+                disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
+                DIRENT_SZ,
+            );
+            if !dirent.name().is_empty() {
                 v.push(String::from(dirent.name()));
             }
-            v
-        })
+        }
+        v
     }
 
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    pub fn ls(&self) -> Vec<String> {
         let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| self.entries(disk_inode))
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let fs = self.fs.lock();
+        // only dirty the inode block once per second
+        let now = EasyFileSystem::now();
+        if self.read_disk_inode(|disk_inode| disk_inode.atime != now) {
+            self.modify_disk_inode(|disk_inode| disk_inode.atime = now);
+            fs.sync_if_needed();
+        }
         self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
     }
 
+    /// Write `buf` at `offset`, return the number of bytes written, which is short if the file
+    /// would grow past `MAX_FILE_SIZE`.
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let buf = &buf[..buf.len().min(MAX_FILE_SIZE.saturating_sub(offset))];
         let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
-        });
-        block_cache_sync_all();
+        let now = EasyFileSystem::now();
+        let mut size = 0;
+        // one operation per chunk, so that the blocks modified fit in the journal
+        for chunk in buf.chunks(WRITE_CHUNK_BLOCKS * BLOCK_SZ) {
+            let offset = offset + size;
+            size += self.modify_disk_inode(|disk_inode| {
+                disk_inode.increase_size((offset + chunk.len()) as u32);
+                disk_inode.mtime = now;
+                disk_inode.ctime = now;
+                disk_inode.write_at(offset, chunk, &mut || fs.alloc_data(), &self.block_device)
+            });
+            fs.sync_if_needed();
+        }
         size
     }
 
     pub fn clear(&self) {
-        let mut fs = self.fs.lock();
+        let fs = self.fs.lock();
+        let now = EasyFileSystem::now();
         self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
             let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
             for data_block in data_blocks_dealloc.into_iter() {
                 fs.dealloc_data(data_block);
             }
         });
-        block_cache_sync_all();
+        fs.sync_if_needed();
+    }
+
+    /// Set the size to `new_size`. Data beyond it is lost, and new space reads as zeros. Return
+    /// false if the size is above `MAX_FILE_SIZE`.
+    pub fn truncate(&self, new_size: u32) -> bool {
+        if new_size as usize > MAX_FILE_SIZE {
+            return false;
+        }
+        let fs = self.fs.lock();
+        let now = EasyFileSystem::now();
+        self.modify_disk_inode(|disk_inode| {
+            if new_size < disk_inode.size {
+                for data_block in disk_inode.decrease_size(new_size, &self.block_device) {
+                    fs.dealloc_data(data_block);
+                }
+            } else {
+                disk_inode.increase_size(new_size);
+            }
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+        });
+        fs.sync_if_needed();
+        true
+    }
+
+    pub fn stat(&self) -> Stat {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| Stat {
+            ino: self.inode_id,
+            is_dir: disk_inode.is_dir(),
+            mode: disk_inode.mode,
+            nlink: disk_inode.nlink,
+            uid: disk_inode.uid,
+            gid: disk_inode.gid,
+            size: disk_inode.size,
+            blocks: disk_inode.allocated_blocks(&self.block_device),
+            atime: disk_inode.atime,
+            mtime: disk_inode.mtime,
+            ctime: disk_inode.ctime,
+        })
+    }
+
+    /// Write all modified blocks of the file system back, as done by `sync` and `fsync`.
+    pub fn sync(&self) {
+        self.fs.lock().sync();
+    }
+
+    /// Set the access and modification times, e.g. to those of a file copied in.
+    pub fn set_times(&self, atime: u64, mtime: u64) {
+        let fs = self.fs.lock();
+        let now = EasyFileSystem::now();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.atime = atime;
+            disk_inode.mtime = mtime;
+            disk_inode.ctime = now;
+        });
+        fs.sync_if_needed();
+    }
+}
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        let mut open = self.open.lock();
+        let refs = open.refs.get_mut(&self.inode_id).unwrap();
+        *refs -= 1;
+        if *refs == 0 {
+            open.refs.remove(&self.inode_id);
+            // The efs lock may be held, so free it later.
+            if open.orphans.remove(&self.inode_id) {
+                open.closed.push(self.inode_id);
+            }
+        }
     }
 }
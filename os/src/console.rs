@@ -33,7 +33,7 @@ pub fn init() {
   // Mark data terminal ready, signal request to send
   // and enable auxilliary output #2 (used as interrupt line for CPU)
   out8(SERIAL_MODEM_CTRL, 0x0B);
-  // Enable interrupts
+  // Enable receive interrupts (IRQ 4), which feed the TTY
   out8(SERIAL_INT_EN, 0x01);
 }
 
@@ -41,8 +41,9 @@ fn line_sts() -> LineSts {
   LineSts::from_bits_truncate(in8(SERIAL_LINE_STS))
 }
 
-/// Sends a byte on the serial port.
+/// Sends a byte on the serial port, and mirrors it on the framebuffer console.
 pub fn send(data: u8) {
+  crate::drivers::FB_CONSOLE.get().putc(data);
   match data {
     8 | 0x7F => {
       while !line_sts().contains(LineSts::OUTPUT_EMPTY) {}
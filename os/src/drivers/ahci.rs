@@ -1,52 +1,446 @@
-use crate::{*, mm::PhysFrame};
-use isomorphic_drivers::{provider, block::ahci::{AHCI, BLOCK_SIZE}};
+//! AHCI driver for the first SATA disk. A request is split into commands of at most a page, each
+//! in its own command slot, so that with native command queuing (NCQ) the disk works on several
+//! at once. Commands complete on the MSI interrupt of the controller.
 
-pub struct AHCIDriver(Cell<AHCI<Provider>>);
+use crate::{*, mm::{PhysFrame, PAGE_SIZE}};
+use super::Controller;
+use alloc::sync::Arc;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use easy_fs::{BlockOp, BlockRequest, BLOCK_SZ};
+
+// Generic host control registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0c;
+
+const CAP_NCS_SHIFT: u32 = 8;
+const CAP_SNCQ: u32 = 1 << 30;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+// Port registers
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_SACT: usize = 0x34;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+const SSTS_DET_MASK: u32 = 0xf;
+const SSTS_DET_PRESENT: u32 = 3;
+const SIG_ATA: u32 = 0x101;
+
+// Port interrupts: D2H register FIS, PIO setup FIS, set device bits FIS (NCQ completion) and
+// task file error
+const IS_DHRS: u32 = 1;
+const IS_PSS: u32 = 1 << 1;
+const IS_SDBS: u32 = 1 << 3;
+const IS_TFES: u32 = 1 << 30;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const FIS_COMMAND: u8 = 0x80;
+const FIS_LEN_DWORDS: u16 = 5;
+const DEVICE_LBA: u8 = 1 << 6;
+const HEADER_WRITE: u16 = 1 << 6;
+const PRD_INTERRUPT: u32 = 1 << 31;
+
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_IDENTIFY: u8 = 0xec;
+
+const MAX_SLOTS: usize = 32;
+/// Command list (1 KiB) and received FIS area (256 B), both in the first page.
+const FIS_OFFSET: usize = 0x400;
+/// Command table with one PRD, padded to the 128-byte alignment.
+const TABLE_SIZE: usize = 0x100;
+const TABLES_PER_PAGE: usize = PAGE_SIZE / TABLE_SIZE;
+
+#[repr(C)]
+struct CommandHeader {
+  /// FIS length in dwords and flags.
+  flags: u16,
+  /// Number of PRD entries.
+  prdtl: u16,
+  /// Bytes transferred.
+  prdbc: u32,
+  ctba: u64,
+  reserved: [u32; 4],
+}
+
+#[repr(C)]
+struct CommandTable {
+  cfis: [u8; 64],
+  acmd: [u8; 16],
+  reserved: [u8; 48],
+  /// The only PRD entry: data base address, reserved, and byte count - 1 with flags.
+  prd: [u32; 4],
+}
+
+/// Tasks waiting for requests to complete. They are all woken on each interrupt.
+static WAITERS: Cell<Vec<task::TaskPtr>> = Cell::new(Vec::new());
+
+/// Part of a request being done in a command slot.
+struct Chunk {
+  id: usize,
+  offset: usize,
+  len: usize,
+  queued: bool,
+}
+
+struct Slot {
+  /// DMA buffer of the slot, since request buffers are not physically contiguous.
+  buf: PhysFrame,
+  chunk: Option<Chunk>,
+}
+
+struct Pending {
+  request: BlockRequest,
+  /// Offset of the first byte without a command, None once all commands are started.
+  next: Option<usize>,
+  in_flight: usize,
+  ok: bool,
+}
+
+struct Ahci {
+  base: usize,
+  port: usize,
+  ncq: bool,
+  /// Capacity of the disk in sectors.
+  sectors: usize,
+  /// Whether commands complete on interrupts, rather than being polled.
+  msi: bool,
+  /// Command list and received FIS.
+  list: PhysFrame,
+  tables: Vec<PhysFrame>,
+  slots: Vec<Slot>,
+  /// Requests by sequence number, so that they start in the order submitted.
+  requests: BTreeMap<usize, Pending>,
+  next_id: usize,
+}
+
+pub struct AHCIDriver(Cell<Ahci>);
 
 impl AHCIDriver {
-  pub fn new(header: usize, size: usize) -> Option<Self> {
-    AHCI::new(header, size).map(|x| Self(Cell::new(x)))
+  /// Set up the first SATA disk found on the controller with registers at `base`.
+  pub fn new(base: usize) -> Option<Self> {
+    let mut ahci = Ahci {
+      base,
+      port: 0,
+      ncq: false,
+      sectors: 0,
+      msi: false,
+      list: PhysFrame::alloc_zero()?,
+      tables: Vec::new(),
+      slots: Vec::new(),
+      requests: BTreeMap::new(),
+      next_id: 0,
+    };
+    ahci.write(HBA_GHC, ahci.read(HBA_GHC) | GHC_AE);
+    let implemented = ahci.read(HBA_PI);
+    ahci.port = (0..32).find(|&port| {
+      ahci.port = port;
+      implemented & (1 << port) != 0
+        && ahci.port_read(PX_SSTS) & SSTS_DET_MASK == SSTS_DET_PRESENT
+        && ahci.port_read(PX_SIG) == SIG_ATA
+    })?;
+    for _ in 0..(MAX_SLOTS + TABLES_PER_PAGE - 1) / TABLES_PER_PAGE {
+      ahci.tables.push(PhysFrame::alloc_zero()?);
+    }
+    ahci.slots.push(Slot { buf: PhysFrame::alloc_zero()?, chunk: None });
+    ahci.start_port();
+
+    let identify = ahci.identify();
+    let cap = ahci.read(HBA_CAP);
+    let command_slots = ((cap >> CAP_NCS_SHIFT) & 0x1f) as usize + 1;
+    let queue_depth = (identify[75] & 0x1f) as usize + 1;
+    ahci.ncq = cap & CAP_SNCQ != 0 && identify[76] & (1 << 8) != 0;
+    let slots = if ahci.ncq { command_slots.min(queue_depth) } else { 1 };
+    while ahci.slots.len() < slots {
+      ahci.slots.push(Slot { buf: PhysFrame::alloc_zero()?, chunk: None });
+    }
+    ahci.sectors = (0..4).fold(0, |n, i| n | (identify[100 + i] as usize) << (16 * i));
+    info!("AHCI: port {}, {} sectors, {} slots, NCQ {}", ahci.port, ahci.sectors, slots, ahci.ncq);
+
+    Some(Self(Cell::new(ahci)))
+  }
+
+  /// Submit a request and wait for it. Other tasks run meanwhile once the scheduler is started,
+  /// before that the controller is polled.
+  fn wait(&self, op: BlockOp, block_id: usize, buf: Vec<u8>) -> Vec<u8> {
+    let done = Arc::new(Cell::new(None));
+    let result = Arc::clone(&done);
+    self.submit(BlockRequest {
+      op,
+      block_id,
+      buf,
+      callback: Box::new(move |buf, ok| *result.get() = Some((buf, ok))),
+    });
+    loop {
+      if let Some((buf, ok)) = done.get().take() {
+        assert!(ok, "AHCI: {:?} at block {} failed", op, block_id);
+        return buf;
+      }
+      if !task::started() {
+        self.interrupt();
+      } else if self.0.msi {
+        WAITERS.get().push(task::current());
+        task::sched_block();
+      } else {
+        // without interrupts, poll between the time slices of other tasks
+        self.interrupt();
+        task::sched_yield();
+      }
+    }
+  }
+}
+
+impl Controller for AHCIDriver {
+  fn enable_interrupts(&self) {
+    let ahci = self.0.get();
+    ahci.port_write(PX_IS, !0);
+    ahci.write(HBA_IS, 1 << ahci.port);
+    ahci.port_write(PX_IE, IS_DHRS | IS_PSS | IS_SDBS | IS_TFES);
+    ahci.write(HBA_GHC, ahci.read(HBA_GHC) | GHC_IE);
+    ahci.msi = true;
+  }
+
+  fn interrupt(&self) {
+    let finished = self.0.get().complete();
+    for (request, ok) in finished {
+      (request.callback)(request.buf, ok);
+    }
+    for t in WAITERS.get().drain(..) {
+      task::sched_unblock(t);
+    }
   }
 }
 
 impl BlockDevice for AHCIDriver {
   fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-    self.0.get().read_block(block_id, buf);
+    self.read_blocks(block_id, &mut buf[..BLOCK_SZ]);
   }
 
   fn write_block(&self, block_id: usize, buf: &[u8]) {
-    assert!(buf.len() >= BLOCK_SIZE);
-    self.0.get().write_block(block_id, buf);
+    self.write_blocks(block_id, &buf[..BLOCK_SZ]);
+  }
+
+  fn num_blocks(&self) -> usize { self.0.sectors }
+
+  fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+    buf.copy_from_slice(&self.wait(BlockOp::Read, block_id, vec![0; buf.len()]));
+  }
+
+  fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+    self.wait(BlockOp::Write, block_id, buf.to_vec());
+  }
+
+  fn flush(&self) {
+    self.wait(BlockOp::Flush, 0, Vec::new());
+  }
+
+  fn submit(&self, request: BlockRequest) {
+    assert_eq!(request.buf.len() % BLOCK_SZ, 0);
+    if request.op != BlockOp::Flush && request.buf.is_empty() {
+      (request.callback)(request.buf, true);
+      return;
+    }
+    let ahci = self.0.get();
+    ahci.requests.insert(ahci.next_id, Pending { request, next: Some(0), in_flight: 0, ok: true });
+    ahci.next_id += 1;
+    ahci.issue();
   }
 }
 
-struct Provider;
+impl Ahci {
+  fn read(&self, reg: usize) -> u32 {
+    unsafe { read_volatile((self.base + reg) as *const u32) }
+  }
+
+  fn write(&self, reg: usize, val: u32) {
+    unsafe { write_volatile((self.base + reg) as *mut u32, val) }
+  }
 
-impl provider::Provider for Provider {
-  const PAGE_SIZE: usize = mm::PAGE_SIZE;
+  fn port_read(&self, reg: usize) -> u32 {
+    self.read(PORT_BASE + self.port * PORT_SIZE + reg)
+  }
 
-  fn alloc_dma(size: usize) -> (usize, usize) {
-    println!("alloc_dma: {:x}", size);
-    let pages = size / mm::PAGE_SIZE;
-    let mut base = 0;
-    for i in 0..pages {
-      let frame = PhysFrame::alloc().unwrap();
-      let frame_pa = frame.start_pa().0;
-      core::mem::forget(frame);
-      if i == 0 { base = frame_pa; }
-      assert_eq!(frame_pa, base + i * mm::PAGE_SIZE);
+  fn port_write(&self, reg: usize, val: u32) {
+    self.write(PORT_BASE + self.port * PORT_SIZE + reg, val)
+  }
+
+  fn header(&self, slot: usize) -> &mut CommandHeader {
+    let list = self.list.as_slice().as_mut_ptr() as *mut CommandHeader;
+    unsafe { &mut *list.add(slot) }
+  }
+
+  /// Return the command table of `slot` and its physical address.
+  fn table(&self, slot: usize) -> (&mut CommandTable, usize) {
+    let frame = &self.tables[slot / TABLES_PER_PAGE];
+    let offset = slot % TABLES_PER_PAGE * TABLE_SIZE;
+    let table = unsafe { &mut *(frame.as_slice()[offset..].as_mut_ptr() as *mut CommandTable) };
+    (table, frame.start_pa().0 + offset)
+  }
+
+  /// Stop the command engine, point the port at our memory and start it again. Commands in
+  /// flight are aborted.
+  fn start_port(&mut self) {
+    self.port_write(PX_CMD, self.port_read(PX_CMD) & !CMD_ST);
+    while self.port_read(PX_CMD) & CMD_CR != 0 { core::hint::spin_loop(); }
+    self.port_write(PX_CMD, self.port_read(PX_CMD) & !CMD_FRE);
+    while self.port_read(PX_CMD) & CMD_FR != 0 { core::hint::spin_loop(); }
+    let pa = self.list.start_pa().0;
+    self.port_write(PX_CLB, pa as u32);
+    self.port_write(PX_CLBU, (pa >> 32) as u32);
+    self.port_write(PX_FB, (pa + FIS_OFFSET) as u32);
+    self.port_write(PX_FBU, ((pa + FIS_OFFSET) >> 32) as u32);
+    self.port_write(PX_SERR, !0);
+    self.port_write(PX_IS, !0);
+    self.port_write(PX_CMD, self.port_read(PX_CMD) | CMD_FRE);
+    while self.port_read(PX_TFD) & (TFD_BSY | TFD_DRQ) != 0 { core::hint::spin_loop(); }
+    self.port_write(PX_CMD, self.port_read(PX_CMD) | CMD_ST);
+  }
+
+  /// Read the IDENTIFY DEVICE data by polling, before interrupts are enabled.
+  fn identify(&mut self) -> [u16; 256] {
+    self.start_command(0, ATA_IDENTIFY, 0, 1);
+    while self.port_read(PX_CI) & 1 != 0 { core::hint::spin_loop(); }
+    let mut identify = [0u16; 256];
+    for (i, word) in identify.iter_mut().enumerate() {
+      let buf = self.slots[0].buf.as_slice();
+      *word = u16::from_le_bytes([buf[2 * i], buf[2 * i + 1]]);
+    }
+    identify
+  }
+
+  /// Fill in `slot` and issue it. Queued commands carry the slot as their NCQ tag.
+  fn start_command(&self, slot: usize, command: u8, lba: usize, count: usize) {
+    let write = matches!(command, ATA_WRITE_DMA_EXT | ATA_WRITE_FPDMA_QUEUED);
+    let queued = matches!(command, ATA_READ_FPDMA_QUEUED | ATA_WRITE_FPDMA_QUEUED);
+    let (table, table_pa) = self.table(slot);
+    let mut fis = [0u8; 20];
+    fis[0] = FIS_TYPE_REG_H2D;
+    fis[1] = FIS_COMMAND;
+    fis[2] = command;
+    for i in 0..3 {
+      fis[4 + i] = (lba >> (8 * i)) as u8;
+      fis[8 + i] = (lba >> (8 * (i + 3))) as u8;
+    }
+    fis[7] = DEVICE_LBA;
+    if queued {
+      fis[3] = count as u8;
+      fis[11] = (count >> 8) as u8;
+      fis[12] = (slot << 3) as u8;
+    } else {
+      fis[12] = count as u8;
+      fis[13] = (count >> 8) as u8;
+    }
+    table.cfis[..fis.len()].copy_from_slice(&fis);
+    let buf_pa = self.slots[slot].buf.start_pa().0;
+    table.prd = [buf_pa as u32, (buf_pa >> 32) as u32, 0,
+      (count * BLOCK_SZ).saturating_sub(1) as u32 | PRD_INTERRUPT];
+    let header = self.header(slot);
+    header.flags = FIS_LEN_DWORDS | if write { HEADER_WRITE } else { 0 };
+    header.prdtl = if count > 0 { 1 } else { 0 };
+    header.prdbc = 0;
+    header.ctba = table_pa as u64;
+    // the command must be in memory before the controller is told about it
+    fence(Ordering::SeqCst);
+    if queued {
+      self.port_write(PX_SACT, 1 << slot);
     }
-    println!("virtio_dma_alloc: {:x} {}", base, pages);
-    (mm::phys_to_virt(base), base)
+    self.port_write(PX_CI, 1 << slot);
   }
 
-  fn dealloc_dma(va: usize, size: usize) {
-    println!("dealloc_dma: {:x} {:x}", va, size);
-    let pages = size / mm::PAGE_SIZE;
-    let mut pa = mm::virt_to_phys(va);
-    for _ in 0..pages {
-      PhysFrame::dealloc(pa);
-      pa += mm::PAGE_SIZE;
+  /// Start commands for the requests in order while there are free slots. A command that is
+  /// not queued needs the port to itself.
+  fn issue(&mut self) {
+    loop {
+      let id = match self.requests.iter().find(|(_, pending)| pending.next.is_some()) {
+        Some((&id, _)) => id,
+        None => return,
+      };
+      let op = self.requests[&id].request.op;
+      let queued = self.ncq && op != BlockOp::Flush;
+      let busy = self.slots.iter().filter(|slot| slot.chunk.is_some()).count();
+      let exclusive =
+        self.slots.iter().any(|slot| matches!(slot.chunk, Some(Chunk { queued: false, .. })));
+      if busy == self.slots.len() || exclusive || (busy > 0 && !queued) {
+        return;
+      }
+      let slot = self.slots.iter().position(|slot| slot.chunk.is_none()).unwrap();
+      let pending = self.requests.get_mut(&id).unwrap();
+      let offset = pending.next.unwrap();
+      let len = (pending.request.buf.len() - offset).min(PAGE_SIZE);
+      let end = offset + len;
+      pending.next = if end < pending.request.buf.len() { Some(end) } else { None };
+      pending.in_flight += 1;
+      let lba = pending.request.block_id + offset / BLOCK_SZ;
+      let command = match (op, queued) {
+        (BlockOp::Read, true) => ATA_READ_FPDMA_QUEUED,
+        (BlockOp::Read, false) => ATA_READ_DMA_EXT,
+        (BlockOp::Write, true) => ATA_WRITE_FPDMA_QUEUED,
+        (BlockOp::Write, false) => ATA_WRITE_DMA_EXT,
+        (BlockOp::Flush, _) => ATA_FLUSH_CACHE_EXT,
+      };
+      if op == BlockOp::Write {
+        self.slots[slot].buf.as_slice()[..len].copy_from_slice(&pending.request.buf[offset..end]);
+      }
+      self.slots[slot].chunk = Some(Chunk { id, offset, len, queued });
+      self.start_command(slot, command, lba, len / BLOCK_SZ);
+    }
+  }
+
+  /// Finish the commands the disk is done with and start more, return the requests completed.
+  /// An error aborts every command in flight, which fail with their requests.
+  fn complete(&mut self) -> Vec<(BlockRequest, bool)> {
+    let status = self.port_read(PX_IS);
+    self.port_write(PX_IS, status);
+    self.write(HBA_IS, 1 << self.port);
+    let error = status & IS_TFES != 0;
+    if error {
+      warn!("AHCI: task file error {:#x}", self.port_read(PX_TFD));
+      self.start_port();
+    }
+    let active = if error { 0 } else { self.port_read(PX_SACT) | self.port_read(PX_CI) };
+    let mut finished = Vec::new();
+    for slot in 0..self.slots.len() {
+      if self.slots[slot].chunk.is_none() || active & (1 << slot) != 0 {
+        continue;
+      }
+      let chunk = self.slots[slot].chunk.take().unwrap();
+      let pending = self.requests.get_mut(&chunk.id).unwrap();
+      if pending.request.op == BlockOp::Read && !error {
+        pending.request.buf[chunk.offset..chunk.offset + chunk.len]
+          .copy_from_slice(&self.slots[slot].buf.as_slice()[..chunk.len]);
+      }
+      pending.ok &= !error;
+      pending.in_flight -= 1;
+      if pending.in_flight == 0 && pending.next.is_none() {
+        let pending = self.requests.remove(&chunk.id).unwrap();
+        finished.push((pending.request, pending.ok));
+      }
     }
+    self.issue();
+    finished
   }
 }
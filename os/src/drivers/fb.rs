@@ -0,0 +1,265 @@
+use crate::*;
+use super::font::{FONT, FONT_WIDTH, FONT_HEIGHT};
+use rboot::{GraphicInfo, PixelFormat};
+
+/// The 16 VGA colors as 0xRRGGBB, indexed by ANSI color number (+ 8 for bright colors).
+const PALETTE: [u32; 16] = [
+  0x000000, 0xAA0000, 0x00AA00, 0xAA5500, 0x0000AA, 0xAA00AA, 0x00AAAA, 0xAAAAAA,
+  0x555555, 0xFF5555, 0x55FF55, 0xFFFF55, 0x5555FF, 0xFF55FF, 0x55FFFF, 0xFFFFFF,
+];
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+
+const UNDERLINE_ROW: usize = FONT_HEIGHT - 1;
+const STRIKE_ROW: usize = FONT_HEIGHT / 2;
+
+const ESC: u8 = 0x1B;
+const MAX_PARAMS: usize = 8;
+
+#[derive(Clone, Copy)]
+enum State {
+  Normal,
+  /// After ESC.
+  Escape,
+  /// Inside a control sequence "ESC [ params final".
+  Csi,
+}
+
+/// A text console drawn on the UEFI framebuffer. It understands the ANSI escape sequences for
+/// colors and attributes (SGR), cursor positioning and erasing.
+pub struct FbConsole {
+  /// Virtual address of the framebuffer, 0 if there is none.
+  fb: usize,
+  /// Pixels per framebuffer line.
+  stride: usize,
+  /// The framebuffer stores red in the lowest byte.
+  rgb: bool,
+  cols: usize,
+  rows: usize,
+  x: usize,
+  y: usize,
+  fg: u8,
+  bg: u8,
+  bold: bool,
+  underline: bool,
+  reverse: bool,
+  strike: bool,
+  state: State,
+  params: [u16; MAX_PARAMS],
+  nparams: usize,
+}
+
+pub static FB_CONSOLE: Cell<FbConsole> = zero();
+
+impl FbConsole {
+  pub fn init(&mut self, info: &GraphicInfo) {
+    let rgb = match info.mode.pixel_format() {
+      PixelFormat::Rgb => true,
+      PixelFormat::Bgr | PixelFormat::Bitmask => false,
+      PixelFormat::BltOnly => return,
+    };
+    let (width, height) = info.mode.resolution();
+    *self = FbConsole {
+      fb: mm::phys_to_virt(info.fb_addr as _),
+      stride: info.mode.stride(),
+      rgb,
+      cols: width / FONT_WIDTH,
+      rows: height / FONT_HEIGHT,
+      fg: DEFAULT_FG,
+      bg: DEFAULT_BG,
+      ..zero()
+    };
+    self.clear(0, self.rows);
+    self.toggle_cursor();
+  }
+
+  /// Output one byte, mirroring what is sent to the serial port.
+  pub fn putc(&mut self, c: u8) {
+    if self.fb == 0 { return; }
+    self.toggle_cursor();
+    match self.state {
+      State::Normal => self.put_normal(c),
+      State::Escape => self.state = if c == b'[' {
+        self.params = [0; MAX_PARAMS];
+        self.nparams = 0;
+        State::Csi
+      } else {
+        State::Normal
+      },
+      State::Csi => match c {
+        b'0'..=b'9' => {
+          if self.nparams == 0 { self.nparams = 1; }
+          if let Some(p) = self.params.get_mut(self.nparams - 1) {
+            *p = p.saturating_mul(10).saturating_add((c - b'0') as u16);
+          }
+        }
+        b';' => self.nparams = (self.nparams.max(1) + 1).min(MAX_PARAMS),
+        0x40..=0x7E => {
+          self.control(c);
+          self.state = State::Normal;
+        }
+        _ => {}
+      },
+    }
+    self.toggle_cursor();
+  }
+
+  fn put_normal(&mut self, c: u8) {
+    match c {
+      ESC => self.state = State::Escape,
+      b'\n' => self.newline(),
+      b'\r' => self.x = 0,
+      b'\t' => self.x = ((self.x / 8 + 1) * 8).min(self.cols - 1),
+      0x08 => self.x = self.x.saturating_sub(1),
+      // The serial console turns DEL into "\b \b".
+      0x7F => if self.x > 0 {
+        self.x -= 1;
+        self.draw(self.x, self.y, b' ');
+      },
+      0x20..=0x7E => self.put_glyph(c),
+      // Draw one replacement glyph per UTF-8 sequence, skipping continuation bytes.
+      0xC0..=0xFF => self.put_glyph(b'?'),
+      _ => {}
+    }
+  }
+
+  fn put_glyph(&mut self, c: u8) {
+    if self.x == self.cols { self.newline(); }
+    self.draw(self.x, self.y, c);
+    self.x += 1;
+  }
+
+  fn newline(&mut self) {
+    self.x = 0;
+    if self.y + 1 < self.rows {
+      self.y += 1;
+    } else {
+      self.scroll();
+    }
+  }
+
+  /// Execute the control sequence ending with `c`.
+  fn control(&mut self, c: u8) {
+    let params = self.params;
+    let n = self.nparams;
+    // Most sequences default a missing or zero parameter to 1.
+    let arg = |i: usize| if i < n && params[i] != 0 { params[i] as usize } else { 1 };
+    match c {
+      b'm' => if n == 0 {
+        self.sgr(0);
+      } else {
+        for &p in &params[..n] { self.sgr(p); }
+      },
+      b'A' => self.y = self.y.saturating_sub(arg(0)),
+      b'B' => self.y = (self.y + arg(0)).min(self.rows - 1),
+      b'C' => self.x = (self.x + arg(0)).min(self.cols - 1),
+      b'D' => self.x = self.x.saturating_sub(arg(0)),
+      b'H' | b'f' => {
+        self.y = (arg(0) - 1).min(self.rows - 1);
+        self.x = (arg(1) - 1).min(self.cols - 1);
+      }
+      b'J' => match if n == 0 { 0 } else { params[0] } {
+        0 => {
+          self.erase(self.y, self.x, self.cols);
+          self.clear(self.y + 1, self.rows);
+        }
+        1 => {
+          self.clear(0, self.y);
+          self.erase(self.y, 0, self.x + 1);
+        }
+        _ => self.clear(0, self.rows),
+      },
+      b'K' => match if n == 0 { 0 } else { params[0] } {
+        0 => self.erase(self.y, self.x, self.cols),
+        1 => self.erase(self.y, 0, self.x + 1),
+        _ => self.erase(self.y, 0, self.cols),
+      },
+      _ => {}
+    }
+  }
+
+  /// Select graphic rendition.
+  fn sgr(&mut self, p: u16) {
+    match p {
+      0 => {
+        self.fg = DEFAULT_FG;
+        self.bg = DEFAULT_BG;
+        self.bold = false;
+        self.underline = false;
+        self.reverse = false;
+        self.strike = false;
+      }
+      1 => self.bold = true,
+      4 => self.underline = true,
+      7 => self.reverse = true,
+      9 => self.strike = true,
+      22 => self.bold = false,
+      24 => self.underline = false,
+      27 => self.reverse = false,
+      29 => self.strike = false,
+      30..=37 => self.fg = (p - 30) as u8,
+      39 => self.fg = DEFAULT_FG,
+      40..=47 => self.bg = (p - 40) as u8,
+      49 => self.bg = DEFAULT_BG,
+      90..=97 => self.fg = (p - 90) as u8 + 8,
+      100..=107 => self.bg = (p - 100) as u8 + 8,
+      _ => {}
+    }
+  }
+
+  fn pixel(&self, x: usize, y: usize) -> *mut u32 {
+    (self.fb as *mut u32).wrapping_add(y * self.stride + x)
+  }
+
+  fn color(&self, index: u8) -> u32 {
+    let c = PALETTE[index as usize];
+    if self.rgb { (c & 0xFF00) | (c >> 16) | ((c & 0xFF) << 16) } else { c }
+  }
+
+  /// Draw `c` in the current attributes at text cell (`col`, `row`).
+  fn draw(&self, col: usize, row: usize, c: u8) {
+    let mut fg = if self.bold && self.fg < 8 { self.fg + 8 } else { self.fg };
+    let mut bg = self.bg;
+    if self.reverse { core::mem::swap(&mut fg, &mut bg); }
+    let (fg, bg) = (self.color(fg), self.color(bg));
+    let glyph = &FONT[(c - b' ') as usize];
+    for (r, &bits) in glyph.iter().enumerate() {
+      let line = (r == UNDERLINE_ROW && self.underline) || (r == STRIKE_ROW && self.strike);
+      let bits = if line { 0xFF } else { bits };
+      for i in 0..FONT_WIDTH {
+        let color = if bits & (0x80 >> i) != 0 { fg } else { bg };
+        unsafe { self.pixel(col * FONT_WIDTH + i, row * FONT_HEIGHT + r).write_volatile(color); }
+      }
+    }
+  }
+
+  /// Clear text rows [`start`, `end`) with the background color.
+  fn clear(&self, start: usize, end: usize) {
+    for row in start..end { self.erase(row, 0, self.cols); }
+  }
+
+  /// Clear columns [`start`, `end`) of text row `row` with the background color.
+  fn erase(&self, row: usize, start: usize, end: usize) {
+    let bg = self.color(self.bg);
+    for y in row * FONT_HEIGHT..(row + 1) * FONT_HEIGHT {
+      for x in start * FONT_WIDTH..end.min(self.cols) * FONT_WIDTH {
+        unsafe { self.pixel(x, y).write_volatile(bg); }
+      }
+    }
+  }
+
+  fn scroll(&self) {
+    let line = FONT_HEIGHT * self.stride;
+    unsafe { core::ptr::copy(self.pixel(0, FONT_HEIGHT), self.pixel(0, 0), (self.rows - 1) * line); }
+    self.clear(self.rows - 1, self.rows);
+  }
+
+  /// Show or hide the underline cursor, by inverting the bottom row of the current cell.
+  fn toggle_cursor(&self) {
+    let x = self.x.min(self.cols - 1);
+    for i in 0..FONT_WIDTH {
+      let p = self.pixel(x * FONT_WIDTH + i, self.y * FONT_HEIGHT + UNDERLINE_ROW);
+      unsafe { p.write_volatile(p.read_volatile() ^ 0xFFFFFF); }
+    }
+  }
+}
@@ -0,0 +1,176 @@
+use crate::{*, x86_64::*};
+
+/// The i8042 PS/2 controller.
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer comes from the second (mouse) port.
+const STATUS_AUX: u8 = 1 << 5;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CONFIG_IRQ1: u8 = 1;
+/// The controller translates set 2 scancodes from the keyboard to set 1.
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+const EXTENDED: u8 = 0xE0;
+/// Prefix of set 2 break codes. Set 1 break codes have the top bit set instead.
+const SET2_BREAK: u8 = 0xF0;
+
+// Set 1 make codes of the keys handled specially.
+const CTRL: u8 = 0x1D;
+const LSHIFT: u8 = 0x2A;
+const RSHIFT: u8 = 0x36;
+const ALT: u8 = 0x38;
+const CAPS_LOCK: u8 = 0x3A;
+const NUM_LOCK: u8 = 0x45;
+const KEYPAD_START: u8 = 0x47;
+const KEYPAD_END: u8 = 0x53;
+const KEYPAD_SLASH: u8 = 0x35;
+
+/// Characters of set 1 make codes 0x00..0x3A, without and with shift.
+const KEYMAP: &[u8; 0x3A] =
+  b"\0\x1B1234567890-=\x7F\tqwertyuiop[]\r\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFT: &[u8; 0x3A] =
+  b"\0\x1B!@#$%^&*()_+\x7F\tQWERTYUIOP{}\r\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+/// Characters of the keypad, set 1 make codes 0x47..=0x53, with num lock on.
+const KEYPAD: &[u8; 13] = b"789-456+1230.";
+/// Escape sequences of the navigation keys, shared by the keypad with num lock off.
+const NAVIGATION: [&[u8]; 13] = [
+  b"\x1B[H", b"\x1B[A", b"\x1B[5~", b"", b"\x1B[D", b"", b"\x1B[C", b"", b"\x1B[F", b"\x1B[B",
+  b"\x1B[6~", b"\x1B[2~", b"\x1B[3~",
+];
+
+/// Set 1 make codes of set 2 make codes. The same table works for codes prefixed by 0xE0.
+const SET2_TO_SET1: [u8; 0x84] = {
+  let pairs: [(u8, u8); 85] = [
+    (0x76, 0x01), (0x16, 0x02), (0x1E, 0x03), (0x26, 0x04), (0x25, 0x05), (0x2E, 0x06),
+    (0x36, 0x07), (0x3D, 0x08), (0x3E, 0x09), (0x46, 0x0A), (0x45, 0x0B), (0x4E, 0x0C),
+    (0x55, 0x0D), (0x66, 0x0E), (0x0D, 0x0F), (0x15, 0x10), (0x1D, 0x11), (0x24, 0x12),
+    (0x2D, 0x13), (0x2C, 0x14), (0x35, 0x15), (0x3C, 0x16), (0x43, 0x17), (0x44, 0x18),
+    (0x4D, 0x19), (0x54, 0x1A), (0x5B, 0x1B), (0x5A, 0x1C), (0x14, 0x1D), (0x1C, 0x1E),
+    (0x1B, 0x1F), (0x23, 0x20), (0x2B, 0x21), (0x34, 0x22), (0x33, 0x23), (0x3B, 0x24),
+    (0x42, 0x25), (0x4B, 0x26), (0x4C, 0x27), (0x52, 0x28), (0x0E, 0x29), (0x12, 0x2A),
+    (0x5D, 0x2B), (0x1A, 0x2C), (0x22, 0x2D), (0x21, 0x2E), (0x2A, 0x2F), (0x32, 0x30),
+    (0x31, 0x31), (0x3A, 0x32), (0x41, 0x33), (0x49, 0x34), (0x4A, 0x35), (0x59, 0x36),
+    (0x7C, 0x37), (0x11, 0x38), (0x29, 0x39), (0x58, 0x3A), (0x05, 0x3B), (0x06, 0x3C),
+    (0x04, 0x3D), (0x0C, 0x3E), (0x03, 0x3F), (0x0B, 0x40), (0x83, 0x41), (0x0A, 0x42),
+    (0x01, 0x43), (0x09, 0x44), (0x77, 0x45), (0x7E, 0x46), (0x6C, 0x47), (0x75, 0x48),
+    (0x7D, 0x49), (0x7B, 0x4A), (0x6B, 0x4B), (0x73, 0x4C), (0x74, 0x4D), (0x79, 0x4E),
+    (0x69, 0x4F), (0x72, 0x50), (0x7A, 0x51), (0x70, 0x52), (0x71, 0x53), (0x78, 0x57),
+    (0x07, 0x58),
+  ];
+  let mut table = [0; 0x84];
+  let mut i = 0;
+  while i < pairs.len() {
+    table[pairs[i].0 as usize] = pairs[i].1;
+    i += 1;
+  }
+  table
+};
+
+/// Decoding state and modifiers of the PS/2 keyboard.
+pub struct Keyboard {
+  /// The keyboard sends set 2 scancodes untranslated.
+  set2: bool,
+  extended: bool,
+  /// A set 2 break prefix was received.
+  release: bool,
+  lshift: bool,
+  rshift: bool,
+  ctrl: bool,
+  alt: bool,
+  caps_lock: bool,
+  num_lock: bool,
+}
+
+pub static KEYBOARD: Cell<Keyboard> = zero();
+
+/// Wait until the status register matches, giving up if there is no controller.
+fn wait(mask: u8, set: bool) -> bool {
+  (0..100000).any(|_| (in8(STATUS) & mask != 0) == set)
+}
+
+pub fn init() {
+  while in8(STATUS) & STATUS_OUTPUT_FULL != 0 { in8(DATA); }
+  if !wait(STATUS_INPUT_FULL, false) { return; }
+  out8(COMMAND, CMD_READ_CONFIG);
+  if !wait(STATUS_OUTPUT_FULL, true) { return; }
+  let config = in8(DATA) | CONFIG_IRQ1;
+  wait(STATUS_INPUT_FULL, false);
+  out8(COMMAND, CMD_WRITE_CONFIG);
+  wait(STATUS_INPUT_FULL, false);
+  out8(DATA, config);
+  KEYBOARD.get().set2 = config & CONFIG_TRANSLATE == 0;
+}
+
+impl Keyboard {
+  /// Handle IRQ 1: decode the pending scancodes and feed the characters to the TTY.
+  pub fn interrupt(&mut self) {
+    loop {
+      let status = in8(STATUS);
+      if status & STATUS_OUTPUT_FULL == 0 { break; }
+      let code = in8(DATA);
+      if status & STATUS_AUX == 0 { self.scancode(code); }
+    }
+  }
+
+  fn scancode(&mut self, code: u8) {
+    let (code, release) = match code {
+      EXTENDED => {
+        self.extended = true;
+        return;
+      }
+      SET2_BREAK if self.set2 => {
+        self.release = true;
+        return;
+      }
+      _ if self.set2 => {
+        let release = core::mem::replace(&mut self.release, false);
+        (SET2_TO_SET1.get(code as usize).copied().unwrap_or(0), release)
+      }
+      _ => (code & 0x7F, code & 0x80 != 0),
+    };
+    let extended = core::mem::replace(&mut self.extended, false);
+    self.key(code, extended, release);
+  }
+
+  fn key(&mut self, code: u8, extended: bool, release: bool) {
+    match code {
+      // Some keys send extended shifts along, which are not real key presses.
+      LSHIFT if !extended => self.lshift = !release,
+      RSHIFT if !extended => self.rshift = !release,
+      CTRL => self.ctrl = !release,
+      ALT => self.alt = !release,
+      _ if release => {}
+      CAPS_LOCK => self.caps_lock = !self.caps_lock,
+      NUM_LOCK => self.num_lock = !self.num_lock,
+      KEYPAD_START..=KEYPAD_END => {
+        let i = (code - KEYPAD_START) as usize;
+        if extended || (!self.num_lock && !b"-+".contains(&KEYPAD[i])) {
+          for &c in NAVIGATION[i] { fs::TTY.get().receive(c); }
+        } else {
+          self.send(KEYPAD[i]);
+        }
+      }
+      KEYPAD_SLASH if extended => self.send(b'/'),
+      _ => if let Some(&c) = KEYMAP.get(code as usize) {
+        if c == 0 { return; }
+        let shift = self.lshift || self.rshift;
+        let mut c = if shift { KEYMAP_SHIFT[code as usize] } else { c };
+        if self.caps_lock && c.is_ascii_alphabetic() { c ^= 0x20; }
+        if self.ctrl && (c.is_ascii_alphabetic() || b"@[\\]^_".contains(&c)) { c &= 0x1F; }
+        self.send(c);
+      },
+    }
+  }
+
+  /// Send one character to the TTY, prefixed by ESC if alt is held.
+  fn send(&self, c: u8) {
+    if self.alt { fs::TTY.get().receive(0x1B); }
+    fs::TTY.get().receive(c);
+  }
+}
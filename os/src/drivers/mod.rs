@@ -1,14 +1,109 @@
 use crate::*;
-use alloc::sync::Arc;
+use alloc::{format, sync::Arc};
 
 mod ahci;
+mod fb;
+mod font;
+mod keyboard;
+mod partition;
 mod pci;
+mod ramdisk;
+pub mod rtc;
+mod virtio_blk;
 
-/// Used only for initialization hacks.
-pub const DUMMY_BLOCK_DEVICE: *const dyn BlockDevice = unsafe { transmute(&0 as *const _ as *const ahci::AHCIDriver as *const dyn BlockDevice) };
+pub use fb::FB_CONSOLE;
+pub use keyboard::KEYBOARD;
+pub use ramdisk::RamDisk;
 
-pub static BLOCK_DEVICE: Cell<Arc<dyn BlockDevice>> = unsafe { transmute(DUMMY_BLOCK_DEVICE) };
+/// Block devices by name.
+static BLOCK_DEVICES: Cell<Vec<(&'static str, Arc<dyn BlockDevice>)>> = Cell::new(Vec::new());
+
+/// A disk controller, which completes requests on its interrupts or by being polled.
+trait Controller: BlockDevice {
+  /// Complete requests on interrupts, once the MSI of the controller is set up.
+  fn enable_interrupts(&self);
+  /// Handle an interrupt of the controller, or poll it.
+  fn interrupt(&self);
+}
+
+/// Controllers by interrupt vector, with the names of their drivers.
+static IRQ_CONTROLLERS: Cell<Vec<(usize, &'static str, Arc<dyn Controller>)>> =
+  Cell::new(Vec::new());
 
 pub fn init() {
-  unsafe { (BLOCK_DEVICE.get() as *mut Arc<dyn BlockDevice>).write(Arc::new(pci::init().unwrap())); }
+  rtc::init();
+  keyboard::init();
+  let disks = pci::init();
+  if disks.is_empty() {
+    info!("no disk");
+  }
+  for (disk, vector) in disks {
+    match disk {
+      pci::Disk::Ahci(ahci) => add_controller("sd", "ahci", ahci, vector),
+      pci::Disk::VirtioBlk(blk) => add_controller("vd", "virtio-blk", blk, vector),
+    }
+  }
+}
+
+/// Register the disk of `controller` under the next name starting with `prefix`, as "sda" then
+/// "sdb" for SATA disks, and its interrupt `vector` if it has one.
+fn add_controller<T: Controller + 'static>(
+  prefix: &str, driver: &'static str, controller: T, vector: Option<usize>,
+) {
+  let controller = Arc::new(controller);
+  if let Some(vector) = vector {
+    controller.enable_interrupts();
+    IRQ_CONTROLLERS.get().push((vector, driver, controller.clone()));
+  }
+  let disks = BLOCK_DEVICES.iter().filter(|(n, _)| n.len() == prefix.len() + 1);
+  let n = disks.filter(|(n, _)| n.starts_with(prefix)).count();
+  add_disk(Box::leak(format!("{}{}", prefix, (b'a' + n as u8) as char).into()), controller);
+}
+
+/// Register the disk `name` and its partitions as block devices.
+pub fn add_disk(name: &'static str, device: Arc<dyn BlockDevice>) {
+  add_block_device(name, device.clone());
+  partition::scan(name, device);
+}
+
+pub fn add_block_device(name: &'static str, device: Arc<dyn BlockDevice>) {
+  BLOCK_DEVICES.get().push((name, device));
+}
+
+/// The name of the block device `source` refers to: its name, which may start with "/dev/", or
+/// "PARTUUID=" or "PARTLABEL=" followed by the unique GUID or the name of a partition. SATA
+/// disks are "sda", "sdb", ..., virtio ones "vda", "vdb", ..., and partitions "sda1", "sda2", ...
+pub fn block_device_name(source: &str) -> Option<&'static str> {
+  if let Some((key, value)) = source.split_once('=') {
+    return partition::find(key, value);
+  }
+  let name = source.trim_start_matches("/dev/");
+  BLOCK_DEVICES.iter().find(|(n, _)| *n == name).map(|(n, _)| *n)
+}
+
+/// The block device `source` refers to, as in `block_device_name`.
+pub fn block_device(source: &str) -> Option<Arc<dyn BlockDevice>> {
+  let name = block_device_name(source)?;
+  BLOCK_DEVICES.iter().find(|(n, _)| *n == name).map(|(_, device)| device.clone())
+}
+
+/// Block devices with their names, in the order they were found.
+pub fn block_devices() -> Vec<(&'static str, Arc<dyn BlockDevice>)> {
+  BLOCK_DEVICES.to_vec()
+}
+
+/// Name of the driver of the device using the interrupt `vector`.
+pub fn interrupt_name(vector: usize) -> Option<&'static str> {
+  IRQ_CONTROLLERS.iter().find(|c| c.0 == vector).map(|c| c.1)
+}
+
+/// Handle the interrupt `vector` if it belongs to a device, return whether it did.
+pub fn interrupt(vector: usize) -> bool {
+  let controller = match IRQ_CONTROLLERS.iter().find(|c| c.0 == vector) {
+    Some(c) => c.2.clone(),
+    None => return false,
+  };
+  pci::msi_eoi();
+  controller.interrupt();
+  true
 }
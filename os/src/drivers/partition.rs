@@ -0,0 +1,219 @@
+use crate::*;
+use super::add_block_device;
+use alloc::{format, sync::Arc};
+use easy_fs::{BlockRequest, BLOCK_SZ};
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// MBR partition type of the single partition covering a GPT disk.
+const GPT_PROTECTIVE: u8 = 0xEE;
+/// MBR partition types of extended partitions, which hold a chain of logical partitions.
+const EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Logical partitions followed at most, so that a chain looping back ends.
+const MAX_LOGICAL: usize = 128;
+
+fn u16_at(b: &[u8], i: usize) -> u16 { u16::from_le_bytes([b[i], b[i + 1]]) }
+fn u32_at(b: &[u8], i: usize) -> u32 { u32::from_le_bytes(b[i..i + 4].try_into().unwrap()) }
+fn u64_at(b: &[u8], i: usize) -> u64 { u64::from_le_bytes(b[i..i + 8].try_into().unwrap()) }
+
+/// A partition of a block device, whose blocks are those of the device from `start` on.
+pub struct Partition {
+  device: Arc<dyn BlockDevice>,
+  start: usize,
+  blocks: usize,
+}
+
+impl Partition {
+  /// Whether the `count` blocks from `block_id` are in the partition, so that a corrupt file
+  /// system cannot reach the partitions next to it.
+  fn contains(&self, block_id: usize, count: usize) -> bool {
+    let inside = block_id.checked_add(count).map_or(false, |end| end <= self.blocks);
+    if !inside { warn!("partition: blocks {}+{} out of {}", block_id, count, self.blocks); }
+    inside
+  }
+}
+
+impl BlockDevice for Partition {
+  fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    self.read_blocks(block_id, buf);
+  }
+  fn write_block(&self, block_id: usize, buf: &[u8]) {
+    self.write_blocks(block_id, buf);
+  }
+  fn num_blocks(&self) -> usize { self.blocks }
+  fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+    if self.contains(block_id, buf.len() / BLOCK_SZ) {
+      self.device.read_blocks(self.start + block_id, buf);
+    } else {
+      buf.fill(0);
+    }
+  }
+  fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+    if self.contains(block_id, buf.len() / BLOCK_SZ) {
+      self.device.write_blocks(self.start + block_id, buf);
+    }
+  }
+  fn flush(&self) {
+    self.device.flush();
+  }
+  fn submit(&self, request: BlockRequest) {
+    if self.contains(request.block_id, request.buf.len() / BLOCK_SZ) {
+      self.device.submit(BlockRequest { block_id: self.start + request.block_id, ..request });
+    } else {
+      (request.callback)(request.buf, false);
+    }
+  }
+}
+
+/// A partition found in a partition table.
+struct Entry {
+  number: usize,
+  start: usize,
+  blocks: usize,
+  /// Unique GUID of a GPT partition, or the disk signature and partition number of an MBR one,
+  /// as Linux shows them in PARTUUID.
+  uuid: String,
+  /// Name of a GPT partition, empty for an MBR one.
+  label: String,
+}
+
+/// Partitions registered as block devices by name, with their PARTUUID and PARTLABEL.
+static PARTITIONS: Cell<Vec<(&'static str, String, String)>> = Cell::new(Vec::new());
+
+/// Mixed-endian text form of a GUID, as in "c12a7328-f81f-11d2-ba4b-00a0c93ec93b".
+fn guid(g: &[u8]) -> String {
+  format!(
+    "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{}",
+    u32_at(g, 0), u16_at(g, 4), u16_at(g, 6), g[8], g[9],
+    g[10..16].iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+  )
+}
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for &b in data {
+    crc ^= b as u32;
+    for _ in 0..8 {
+      crc = crc >> 1 ^ 0xEDB8_8320 & (crc & 1).wrapping_neg();
+    }
+  }
+  !crc
+}
+
+/// Partitions of the GPT whose header is at `lba`, None if it is not valid.
+fn gpt(device: &dyn BlockDevice, lba: usize) -> Option<Vec<Entry>> {
+  let mut header = [0u8; BLOCK_SZ];
+  device.read_block(lba, &mut header);
+  let size = u32_at(&header, 12) as usize;
+  if &header[..8] != GPT_SIGNATURE || !(92..=BLOCK_SZ).contains(&size) { return None; }
+  let mut copy = header;
+  copy[16..20].fill(0);
+  if crc32(&copy[..size]) != u32_at(&header, 16) { return None; }
+  let (table, count) = (u64_at(&header, 72) as usize, u32_at(&header, 80) as usize);
+  let entry_size = u32_at(&header, 84) as usize;
+  if entry_size < 128 || entry_size % 8 != 0 || count > 1024 { return None; }
+  let mut entries = vec![0u8; (count * entry_size + BLOCK_SZ - 1) / BLOCK_SZ * BLOCK_SZ];
+  device.read_blocks(table, &mut entries);
+  if crc32(&entries[..count * entry_size]) != u32_at(&header, 88) { return None; }
+  let entries = entries.chunks(entry_size).take(count).enumerate();
+  // Entries with a type GUID of zeros are unused.
+  let entries = entries.filter(|(_, e)| e[..16].iter().any(|&b| b != 0)).map(|(i, e)| {
+    let (first, last) = (u64_at(e, 32) as usize, u64_at(e, 40) as usize);
+    let name = (56..128).step_by(2).map(|j| u16_at(e, j)).take_while(|&u| u != 0);
+    Entry {
+      number: i + 1,
+      start: first,
+      blocks: (last + 1).saturating_sub(first),
+      uuid: guid(&e[16..32]),
+      label: char::decode_utf16(name).map(|c| c.unwrap_or('\u{FFFD}')).collect(),
+    }
+  });
+  Some(entries.collect())
+}
+
+/// Partitions of the MBR `mbr`: the primary ones numbered from 1 to 4, then the logical ones of
+/// an extended partition from 5.
+fn mbr(device: &dyn BlockDevice, mbr: &[u8]) -> Vec<Entry> {
+  let signature = u32_at(mbr, 440);
+  let entry = |number, start, blocks| Entry {
+    number,
+    start,
+    blocks,
+    uuid: format!("{:08x}-{:02x}", signature, number),
+    label: String::new(),
+  };
+  let mut entries = Vec::new();
+  for i in 0..4 {
+    let e = &mbr[446 + i * 16..462 + i * 16];
+    let (kind, start, blocks) = (e[4], u32_at(e, 8) as usize, u32_at(e, 12) as usize);
+    if kind == 0 || blocks == 0 { continue; }
+    if !EXTENDED.contains(&kind) {
+      entries.push(entry(i + 1, start, blocks));
+      continue;
+    }
+    // Each extended boot record holds a logical partition, relative to itself, and the next
+    // record, relative to the extended partition.
+    let mut ebr = [0u8; BLOCK_SZ];
+    let mut pos = start;
+    for number in 5..5 + MAX_LOGICAL {
+      device.read_block(pos, &mut ebr);
+      if ebr[510..] != [0x55, 0xAA] { break; }
+      let (first, next) = (&ebr[446..462], &ebr[462..478]);
+      if first[4] != 0 && u32_at(first, 12) != 0 {
+        entries.push(entry(number, pos + u32_at(first, 8) as usize, u32_at(first, 12) as usize));
+      }
+      if next[4] == 0 || u32_at(next, 8) == 0 { break; }
+      pos = start + u32_at(next, 8) as usize;
+    }
+  }
+  entries
+}
+
+/// Register the partitions of the disk `name` as block devices, such as "sda1" for the first
+/// one of "sda", or "ram0p1" if the name ends with a digit.
+pub fn scan(name: &'static str, device: Arc<dyn BlockDevice>) {
+  let mut sector = [0u8; BLOCK_SZ];
+  device.read_block(0, &mut sector);
+  if sector[510..] != [0x55, 0xAA] { return; }
+  let protective = (0..4).any(|i| sector[446 + i * 16 + 4] == GPT_PROTECTIVE);
+  let entries = if protective {
+    // The backup GPT is in the last blocks, should the primary one be damaged.
+    let last = device.num_blocks().saturating_sub(1);
+    match gpt(device.as_ref(), 1).or_else(|| gpt(device.as_ref(), last)) {
+      Some(entries) => entries,
+      None => {
+        warn!("{}: invalid GPT", name);
+        return;
+      }
+    }
+  } else {
+    mbr(device.as_ref(), &sector)
+  };
+  let sep = if name.ends_with(|c: char| c.is_ascii_digit()) { "p" } else { "" };
+  for e in entries {
+    // Devices of unknown size report no blocks.
+    let size = device.num_blocks();
+    let end = e.start.checked_add(e.blocks);
+    if e.start == 0 || end.map_or(true, |end| size != 0 && end > size) {
+      warn!("{}: partition {} out of the disk", name, e.number);
+      continue;
+    }
+    let part_name: &'static str = Box::leak(format!("{}{}{}", name, sep, e.number).into());
+    info!(
+      "{}: {} blocks from {}, PARTUUID={} PARTLABEL={}",
+      part_name, e.blocks, e.start, e.uuid, e.label
+    );
+    let partition = Partition { device: device.clone(), start: e.start, blocks: e.blocks };
+    add_block_device(part_name, Arc::new(partition));
+    PARTITIONS.get().push((part_name, e.uuid, e.label));
+  }
+}
+
+/// The name of the partition `value` refers to, by `key` being "PARTUUID" or "PARTLABEL".
+pub fn find(key: &str, value: &str) -> Option<&'static str> {
+  let found = PARTITIONS.iter().find(|(_, uuid, label)| match key {
+    "PARTUUID" => uuid.eq_ignore_ascii_case(value),
+    "PARTLABEL" => label == value,
+    _ => false,
+  });
+  found.map(|p| p.0)
+}
@@ -1,5 +1,5 @@
 use crate::*;
-use super::ahci::AHCIDriver;
+use super::{ahci::AHCIDriver, virtio_blk::{Transport, VirtIOBlk}};
 use pci::*;
 
 const PCI_COMMAND: u16 = 0x04;
@@ -13,7 +13,25 @@ const PCI_MSI_UPPER_ADDR: u16 = 0x08;
 const PCI_MSI_DATA_32: u16 = 0x08;
 const PCI_MSI_DATA_64: u16 = 0x0C;
 
+const PCI_MSIX_TABLE: u16 = 0x04;
+/// MSI-X enable and function mask, in the dword of the capability header.
+const PCI_MSIX_ENABLE: u32 = 1 << 31;
+const PCI_MSIX_MASKALL: u32 = 1 << 30;
+
 const PCI_CAP_ID_MSI: u8 = 0x05;
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+/// Device IDs of virtio-blk: transitional, which also has the legacy interface, and modern.
+const VIRTIO_BLK_IDS: [u16; 2] = [0x1001, 0x1042];
+// Types of the virtio capabilities, which locate the register blocks in the BARs
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// End-of-interrupt register of the local APIC, which receives the MSIs.
+const LAPIC_EOI: usize = 0xfee0_00b0;
 
 struct PortOpsImpl;
 
@@ -26,11 +44,18 @@ impl PortOps for PortOpsImpl {
   unsafe fn write32(&self, port: u16, val: u32) { x86_64::out32(port, val); }
 }
 
+/// A disk controller found on the PCI bus.
+pub enum Disk {
+  Ahci(AHCIDriver),
+  VirtioBlk(VirtIOBlk),
+}
+
 /// Enable the pci device and its interrupt
 /// Return assigned MSI interrupt number when applicable
-unsafe fn enable(loc: Location) {
+unsafe fn enable(dev: &PCIDevice) -> Option<u32> {
   let ops = &PortOpsImpl;
   let am = CSpaceAccessMethod::IO;
+  let loc = dev.loc;
 
   // 23 and lower are used
   static mut MSI_IRQ: u32 = 23;
@@ -40,10 +65,14 @@ unsafe fn enable(loc: Location) {
   am.write32(ops, loc, PCI_COMMAND, (orig | 0x40f) as u32);
 
   // find MSI cap
-  let mut msi_found = false;
+  let mut msi_irq = None;
+  let mut msix_cap = None;
   let mut cap_ptr = am.read8(ops, loc, PCI_CAP_PTR) as u16;
   while cap_ptr > 0 {
     let cap_id = am.read8(ops, loc, cap_ptr);
+    if cap_id == PCI_CAP_ID_MSIX {
+      msix_cap = Some(cap_ptr);
+    }
     if cap_id == PCI_CAP_ID_MSI {
       let orig_ctrl = am.read32(ops, loc, cap_ptr + PCI_MSI_CTRL_CAP);
       // The manual Volume 3 Chapter 10.11 Message Signalled Interrupts
@@ -62,34 +91,109 @@ unsafe fn enable(loc: Location) {
 
       // enable MSI interrupt, assuming 64bit for now
       am.write32(ops, loc, cap_ptr + PCI_MSI_CTRL_CAP, orig_ctrl | 0x10000);
-      msi_found = true;
+      msi_irq = Some(irq);
     }
     cap_ptr = am.read8(ops, loc, cap_ptr + 1) as u16;
   }
 
-  if !msi_found {
+  // Devices without MSI, such as virtio ones, may have MSI-X. Only the first entry of its table
+  // is set up, all the interrupts of the device use it.
+  if let (None, Some(cap_ptr)) = (msi_irq, msix_cap) {
+    let table = am.read32(ops, loc, cap_ptr + PCI_MSIX_TABLE);
+    if let Some(Some(BAR::Memory(pa, ..))) = dev.bars.get((table & 7) as usize) {
+      let pa = *pa as usize + (table & !7) as usize;
+      mm::map_mmio(pa, 16);
+      let entry = mm::phys_to_virt(pa) as *mut u32;
+      MSI_IRQ += 1;
+      let irq = MSI_IRQ;
+      // address, upper address, data, and vector control with the entry unmasked
+      for (i, val) in [0xfee00000, 0, irq + 32, 0].iter().enumerate() {
+        core::ptr::write_volatile(entry.add(i), *val);
+      }
+      let ctrl = am.read32(ops, loc, cap_ptr);
+      am.write32(ops, loc, cap_ptr, (ctrl | PCI_MSIX_ENABLE) & !PCI_MSIX_MASKALL);
+      msi_irq = Some(irq);
+    }
+  }
+
+  if msi_irq.is_none() {
     // Use PCI legacy interrupt instead
     // IO Space | MEM Space | Bus Mastering | Special Cycles
     am.write32(ops, loc, PCI_COMMAND, (orig | 0xf) as u32);
   }
+  msi_irq
+}
+
+/// Acknowledge an MSI interrupt.
+pub fn msi_eoi() {
+  unsafe { core::ptr::write_volatile(mm::phys_to_virt(LAPIC_EOI) as *mut u32, 0); }
+}
+
+/// Find the registers of a virtio device from its capabilities, mapping them if need be.
+unsafe fn virtio_transport(dev: &PCIDevice) -> Option<Transport> {
+  let ops = &PortOpsImpl;
+  let am = CSpaceAccessMethod::IO;
+  let loc = dev.loc;
+  let (mut common, mut notify, mut device, mut other) = (None, None, None, None);
+  let mut notify_multiplier = 0;
+  let mut cap_ptr = am.read8(ops, loc, PCI_CAP_PTR) as u16;
+  while cap_ptr > 0 {
+    if am.read8(ops, loc, cap_ptr) == PCI_CAP_ID_VNDR {
+      let bar = am.read8(ops, loc, cap_ptr + 4) as usize;
+      let offset = am.read32(ops, loc, cap_ptr + 8) as usize;
+      let len = am.read32(ops, loc, cap_ptr + 12) as usize;
+      let regs = match am.read8(ops, loc, cap_ptr + 3) {
+        VIRTIO_PCI_CAP_COMMON_CFG => &mut common,
+        VIRTIO_PCI_CAP_NOTIFY_CFG => {
+          notify_multiplier = am.read32(ops, loc, cap_ptr + 16);
+          &mut notify
+        }
+        VIRTIO_PCI_CAP_DEVICE_CFG => &mut device,
+        _ => &mut other,
+      };
+      // The first capability of a type is the preferred one.
+      if let (None, Some(Some(BAR::Memory(pa, ..)))) = (*regs, dev.bars.get(bar)) {
+        let pa = *pa as usize + offset;
+        mm::map_mmio(pa, len);
+        *regs = Some(mm::phys_to_virt(pa));
+      }
+    }
+    cap_ptr = am.read8(ops, loc, cap_ptr + 1) as u16;
+  }
+  Some(Transport { common: common?, notify: notify?, notify_multiplier, device: device? })
 }
 
-pub fn init() -> Option<AHCIDriver> {
+/// Find the AHCI and virtio-blk controllers, return their drivers in the order of the bus and
+/// the interrupt vectors of their MSI or MSI-X if they have one. Legacy interrupts are not
+/// supported, the drivers poll then.
+pub fn init() -> Vec<(Disk, Option<usize>)> {
+  let mut disks = Vec::new();
   for dev in unsafe { scan_bus(&PortOpsImpl, CSpaceAccessMethod::IO) } {
-    println!("pci: {:02x}:{:02x}.{} {:#x} {:#x} ({} {}) irq: {}:{:?}",
+    debug!("pci: {:02x}:{:02x}.{} {:#x} {:#x} ({} {}) irq: {}:{:?}",
       dev.loc.bus, dev.loc.device, dev.loc.function, dev.id.vendor_id, dev.id.device_id,
       dev.id.class, dev.id.subclass, dev.pic_interrupt_line, dev.interrupt_pin);
-    if dev.id.class == 0x01 && dev.id.subclass == 0x06 {
+    let disk = if dev.id.class == 0x01 && dev.id.subclass == 0x06 {
       // Mass storage class, SATA subclass
       if let Some(BAR::Memory(pa, len, _, _)) = dev.bars[5] {
-        println!("Found AHCI dev {:?} BAR5 {:x?}", dev, pa);
-        unsafe { enable(dev.loc) };
+        info!("Found AHCI dev {:?} BAR5 {:x?}", dev, pa);
+        let irq = unsafe { enable(&dev) };
         assert!(len as usize <= mm::PAGE_SIZE);
-        if let Some(x) = AHCIDriver::new(mm::phys_to_virt(pa as _), len as _) {
-          return Some(x);
-        }
+        AHCIDriver::new(mm::phys_to_virt(pa as _)).map(|x| (Disk::Ahci(x), irq))
+      } else {
+        None
       }
+    } else if dev.id.vendor_id == VIRTIO_VENDOR_ID && VIRTIO_BLK_IDS.contains(&dev.id.device_id) {
+      info!("Found virtio-blk dev {:?}", dev);
+      let irq = unsafe { enable(&dev) };
+      let transport = unsafe { virtio_transport(&dev) };
+      transport.and_then(VirtIOBlk::new).map(|x| (Disk::VirtioBlk(x), irq))
+    } else {
+      None
+    };
+    if let Some((disk, irq)) = disk {
+      // we offset all our irq numbers by 32
+      disks.push((disk, irq.map(|irq| irq as usize + 32)));
     }
   }
-  None
+  disks
 }
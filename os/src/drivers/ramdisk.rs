@@ -0,0 +1,29 @@
+use crate::*;
+use easy_fs::BLOCK_SZ;
+
+/// A block device in memory, such as an initramfs loaded by the boot loader.
+pub struct RamDisk(Cell<&'static mut [u8]>);
+
+impl RamDisk {
+  pub fn new(data: &'static mut [u8]) -> Self {
+    Self(Cell::new(data))
+  }
+}
+
+impl BlockDevice for RamDisk {
+  fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    self.read_blocks(block_id, buf);
+  }
+  fn write_block(&self, block_id: usize, buf: &[u8]) {
+    self.write_blocks(block_id, buf);
+  }
+  fn num_blocks(&self) -> usize { self.0.len() / BLOCK_SZ }
+  fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+    let start = block_id * BLOCK_SZ;
+    buf.copy_from_slice(&self.0[start..start + buf.len()]);
+  }
+  fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+    let start = block_id * BLOCK_SZ;
+    self.0.get()[start..start + buf.len()].copy_from_slice(buf);
+  }
+}
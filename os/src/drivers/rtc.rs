@@ -0,0 +1,75 @@
+use crate::{*, x86_64::*};
+
+/// The CMOS real-time clock.
+const CMOS_ADDR: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// Registers of the date and time: second, minute, hour, day, month and year.
+const TIME_REGS: [u8; 6] = [0x00, 0x02, 0x04, 0x07, 0x08, 0x09];
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24H: u8 = 2;
+const STATUS_B_BINARY: u8 = 4;
+const HOUR_PM: u8 = 0x80;
+
+/// Seconds since the Unix epoch when `pic::TICKS` was 0.
+static BOOT_TIME: Cell<u64> = zero();
+
+fn read(reg: u8) -> u8 {
+  out8(CMOS_ADDR, reg);
+  in8(CMOS_DATA)
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian calendar.
+pub fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year / 400;
+  let yoe = year - era * 400;
+  let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  era * 146097 + doe - 719468
+}
+
+/// Year, month and day of the date `days` after 1970-01-01, the inverse of `days_from_civil`.
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+  let z = days + 719468;
+  let era = z / 146097;
+  let doe = z - era * 146097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  (yoe + era * 400 + (month <= 2) as u64, month, day)
+}
+
+pub fn init() {
+  // Read until two reads agree, so that the clock did not update midway.
+  let mut time = [0u8; 6];
+  loop {
+    while read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    let t = TIME_REGS.map(read);
+    if t == time { break; }
+    time = t;
+  }
+  let status = read(STATUS_B);
+  let pm = time[2] & HOUR_PM != 0;
+  time[2] &= !HOUR_PM;
+  if status & STATUS_B_BINARY == 0 {
+    for x in time.iter_mut() { *x = (*x >> 4) * 10 + (*x & 0xF); }
+  }
+  if status & STATUS_B_24H == 0 {
+    // 12 AM is midnight and 12 PM is noon.
+    time[2] = time[2] % 12 + if pm { 12 } else { 0 };
+  }
+  let [sec, min, hour, day, month, year] = time.map(|x| x as u64);
+  let days = days_from_civil(2000 + year, month, day);
+  *BOOT_TIME.get() = days * 86400 + hour * 3600 + min * 60 + sec - (*pic::TICKS / 1000) as u64;
+  info!("RTC: {} seconds since the epoch", now());
+}
+
+/// The current time in seconds since the Unix epoch.
+pub fn now() -> u64 {
+  *BOOT_TIME + (*pic::TICKS / 1000) as u64
+}
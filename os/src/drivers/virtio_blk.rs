@@ -0,0 +1,450 @@
+//! virtio-blk driver over the modern PCI transport (virtio 1.0). A request is split into chunks
+//! of at most a page, each sent on the only virtqueue as a chain of three descriptors: the
+//! request header, the DMA buffer of a slot and the status byte. The device works on all the
+//! chains at once and completes them on its MSI-X interrupt.
+
+use crate::{*, mm::{PhysFrame, PAGE_SIZE}};
+use super::Controller;
+use alloc::sync::Arc;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use easy_fs::{BlockOp, BlockRequest, BLOCK_SZ};
+
+// Common configuration registers
+const COMMON_DFSELECT: usize = 0x00;
+const COMMON_DF: usize = 0x04;
+const COMMON_GFSELECT: usize = 0x08;
+const COMMON_GF: usize = 0x0c;
+const COMMON_MSIX: usize = 0x10;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_Q_SELECT: usize = 0x16;
+const COMMON_Q_SIZE: usize = 0x18;
+const COMMON_Q_MSIX: usize = 0x1a;
+const COMMON_Q_ENABLE: usize = 0x1c;
+const COMMON_Q_NOFF: usize = 0x1e;
+const COMMON_Q_DESC: usize = 0x20;
+const COMMON_Q_AVAIL: usize = 0x28;
+const COMMON_Q_USED: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+const F_BLK_FLUSH: u64 = 1 << 9;
+const F_VERSION_1: u64 = 1 << 32;
+
+/// MSI-X vector of the configuration changes, which are not handled.
+const NO_VECTOR: u16 = 0xffff;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_T_FLUSH: u32 = 4;
+const BLK_S_OK: u8 = 0;
+
+/// Largest queue used, so that the descriptors and the available ring fit in a page, and the used
+/// ring in another.
+const MAX_QUEUE_SIZE: usize = 128;
+const DESC_SIZE: usize = 16;
+const AVAIL_OFFSET: usize = MAX_QUEUE_SIZE * DESC_SIZE;
+/// Descriptors of a chunk: header, data and status.
+const CHAIN_LEN: usize = 3;
+const MAX_SLOTS: usize = 32;
+/// Request header (type, reserved and sector) and status byte of a slot.
+const HEADER_SIZE: usize = 32;
+const STATUS_OFFSET: usize = 16;
+
+/// Addresses of the register blocks of a virtio device, from its PCI capabilities.
+pub struct Transport {
+  pub common: usize,
+  /// Notification register of the queue with notification offset 0.
+  pub notify: usize,
+  /// Bytes between the notification registers of consecutive notification offsets.
+  pub notify_multiplier: u32,
+  pub device: usize,
+}
+
+#[repr(C)]
+struct Descriptor {
+  addr: u64,
+  len: u32,
+  flags: u16,
+  next: u16,
+}
+
+/// Tasks waiting for requests to complete. They are all woken on each interrupt.
+static WAITERS: Cell<Vec<task::TaskPtr>> = Cell::new(Vec::new());
+
+/// Part of a request being done in a slot.
+struct Chunk {
+  id: usize,
+  offset: usize,
+  len: usize,
+  /// A flush covers the writes completed before it, so it runs alone.
+  flush: bool,
+}
+
+struct Slot {
+  /// DMA buffer of the slot, since request buffers are not physically contiguous.
+  buf: PhysFrame,
+  chunk: Option<Chunk>,
+}
+
+struct Pending {
+  request: BlockRequest,
+  /// Offset of the first byte without a chunk, None once all chunks are started.
+  next: Option<usize>,
+  in_flight: usize,
+  ok: bool,
+}
+
+struct VirtIO {
+  transport: Transport,
+  /// Notification register of the queue.
+  notify: usize,
+  /// Whether the device has a volatile write cache to flush.
+  flush: bool,
+  /// Capacity of the disk in sectors.
+  sectors: usize,
+  /// Whether chunks complete on interrupts, rather than being polled.
+  msi: bool,
+  queue_size: usize,
+  /// Descriptor table and available ring.
+  ring: PhysFrame,
+  used: PhysFrame,
+  /// Headers and status bytes of the slots.
+  headers: PhysFrame,
+  /// Next index of the available ring, and of the used ring to look at.
+  avail_idx: u16,
+  used_idx: u16,
+  slots: Vec<Slot>,
+  /// Requests by sequence number, so that they start in the order submitted.
+  requests: BTreeMap<usize, Pending>,
+  next_id: usize,
+}
+
+pub struct VirtIOBlk(Cell<VirtIO>);
+
+impl VirtIOBlk {
+  /// Set up the virtio-blk device with the registers of `transport`, None if it does not
+  /// support virtio 1.0.
+  pub fn new(transport: Transport) -> Option<Self> {
+    let mut blk = VirtIO {
+      transport,
+      notify: 0,
+      flush: false,
+      sectors: 0,
+      msi: false,
+      queue_size: 0,
+      ring: PhysFrame::alloc_zero()?,
+      used: PhysFrame::alloc_zero()?,
+      headers: PhysFrame::alloc_zero()?,
+      avail_idx: 0,
+      used_idx: 0,
+      slots: Vec::new(),
+      requests: BTreeMap::new(),
+      next_id: 0,
+    };
+    blk.write8(COMMON_STATUS, 0);
+    while blk.read8(COMMON_STATUS) != 0 { core::hint::spin_loop(); }
+    blk.write8(COMMON_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    blk.write32(COMMON_DFSELECT, 0);
+    let mut features = blk.read32(COMMON_DF) as u64;
+    blk.write32(COMMON_DFSELECT, 1);
+    features |= (blk.read32(COMMON_DF) as u64) << 32;
+    let features = features & (F_VERSION_1 | F_BLK_FLUSH);
+    blk.write32(COMMON_GFSELECT, 0);
+    blk.write32(COMMON_GF, features as u32);
+    blk.write32(COMMON_GFSELECT, 1);
+    blk.write32(COMMON_GF, (features >> 32) as u32);
+    let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+    blk.write8(COMMON_STATUS, status);
+    if features & F_VERSION_1 == 0 || blk.read8(COMMON_STATUS) & STATUS_FEATURES_OK == 0 {
+      warn!("virtio-blk: features {:#x} not accepted", features);
+      blk.write8(COMMON_STATUS, status | STATUS_FAILED);
+      return None;
+    }
+    blk.flush = features & F_BLK_FLUSH != 0;
+
+    blk.write16(COMMON_Q_SELECT, 0);
+    blk.queue_size = (blk.read16(COMMON_Q_SIZE) as usize).min(MAX_QUEUE_SIZE);
+    if blk.queue_size < CHAIN_LEN {
+      blk.write8(COMMON_STATUS, status | STATUS_FAILED);
+      return None;
+    }
+    let ring = blk.ring.start_pa().0;
+    blk.write16(COMMON_Q_SIZE, blk.queue_size as u16);
+    blk.write64(COMMON_Q_DESC, ring);
+    blk.write64(COMMON_Q_AVAIL, ring + AVAIL_OFFSET);
+    blk.write64(COMMON_Q_USED, blk.used.start_pa().0);
+    // Chunks complete on the first MSI-X vector, the one `pci::enable` sets up.
+    blk.write16(COMMON_MSIX, NO_VECTOR);
+    blk.write16(COMMON_Q_MSIX, 0);
+    let offset = blk.read16(COMMON_Q_NOFF) as usize;
+    blk.notify = blk.transport.notify + offset * blk.transport.notify_multiplier as usize;
+    blk.set_avail_flags(AVAIL_F_NO_INTERRUPT);
+    blk.write16(COMMON_Q_ENABLE, 1);
+    for _ in 0..(blk.queue_size / CHAIN_LEN).min(MAX_SLOTS) {
+      blk.slots.push(Slot { buf: PhysFrame::alloc_zero()?, chunk: None });
+    }
+    blk.write8(COMMON_STATUS, status | STATUS_DRIVER_OK);
+
+    // The capacity in sectors is the first field of the device configuration.
+    let device = blk.transport.device;
+    let read = |offset| unsafe { read_volatile((device + offset) as *const u32) as usize };
+    blk.sectors = read(0) | read(4) << 32;
+    info!("virtio-blk: {} sectors, {} slots, flush {}", blk.sectors, blk.slots.len(), blk.flush);
+
+    Some(Self(Cell::new(blk)))
+  }
+
+  /// Submit a request and wait for it. Other tasks run meanwhile once the scheduler is started,
+  /// before that the device is polled.
+  fn wait(&self, op: BlockOp, block_id: usize, buf: Vec<u8>) -> Vec<u8> {
+    let done = Arc::new(Cell::new(None));
+    let result = Arc::clone(&done);
+    self.submit(BlockRequest {
+      op,
+      block_id,
+      buf,
+      callback: Box::new(move |buf, ok| *result.get() = Some((buf, ok))),
+    });
+    loop {
+      if let Some((buf, ok)) = done.get().take() {
+        assert!(ok, "virtio-blk: {:?} at block {} failed", op, block_id);
+        return buf;
+      }
+      if !task::started() {
+        self.interrupt();
+      } else if self.0.msi {
+        WAITERS.get().push(task::current());
+        task::sched_block();
+      } else {
+        // without interrupts, poll between the time slices of other tasks
+        self.interrupt();
+        task::sched_yield();
+      }
+    }
+  }
+}
+
+impl Controller for VirtIOBlk {
+  fn enable_interrupts(&self) {
+    let blk = self.0.get();
+    blk.set_avail_flags(0);
+    blk.msi = true;
+  }
+
+  fn interrupt(&self) {
+    let finished = self.0.get().complete();
+    for (request, ok) in finished {
+      (request.callback)(request.buf, ok);
+    }
+    for t in WAITERS.get().drain(..) {
+      task::sched_unblock(t);
+    }
+  }
+}
+
+impl BlockDevice for VirtIOBlk {
+  fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    self.read_blocks(block_id, &mut buf[..BLOCK_SZ]);
+  }
+
+  fn write_block(&self, block_id: usize, buf: &[u8]) {
+    self.write_blocks(block_id, &buf[..BLOCK_SZ]);
+  }
+
+  fn num_blocks(&self) -> usize { self.0.sectors }
+
+  fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+    buf.copy_from_slice(&self.wait(BlockOp::Read, block_id, vec![0; buf.len()]));
+  }
+
+  fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+    self.wait(BlockOp::Write, block_id, buf.to_vec());
+  }
+
+  fn flush(&self) {
+    self.wait(BlockOp::Flush, 0, Vec::new());
+  }
+
+  fn submit(&self, request: BlockRequest) {
+    assert_eq!(request.buf.len() % BLOCK_SZ, 0);
+    // Without a write cache, a write is on the disk once it completes.
+    let flush = request.op == BlockOp::Flush;
+    if (flush && !self.0.flush) || (!flush && request.buf.is_empty()) {
+      (request.callback)(request.buf, true);
+      return;
+    }
+    let blk = self.0.get();
+    blk.requests.insert(blk.next_id, Pending { request, next: Some(0), in_flight: 0, ok: true });
+    blk.next_id += 1;
+    blk.issue();
+  }
+}
+
+impl VirtIO {
+  fn read8(&self, reg: usize) -> u8 {
+    unsafe { read_volatile((self.transport.common + reg) as *const u8) }
+  }
+
+  fn read16(&self, reg: usize) -> u16 {
+    unsafe { read_volatile((self.transport.common + reg) as *const u16) }
+  }
+
+  fn read32(&self, reg: usize) -> u32 {
+    unsafe { read_volatile((self.transport.common + reg) as *const u32) }
+  }
+
+  fn write8(&self, reg: usize, val: u8) {
+    unsafe { write_volatile((self.transport.common + reg) as *mut u8, val) }
+  }
+
+  fn write16(&self, reg: usize, val: u16) {
+    unsafe { write_volatile((self.transport.common + reg) as *mut u16, val) }
+  }
+
+  fn write32(&self, reg: usize, val: u32) {
+    unsafe { write_volatile((self.transport.common + reg) as *mut u32, val) }
+  }
+
+  /// Write a 64-bit register as two halves, which the device accepts in either order.
+  fn write64(&self, reg: usize, val: usize) {
+    self.write32(reg, val as u32);
+    self.write32(reg + 4, (val >> 32) as u32);
+  }
+
+  fn descriptor(&self, index: usize) -> &mut Descriptor {
+    let table = self.ring.as_slice().as_mut_ptr() as *mut Descriptor;
+    unsafe { &mut *table.add(index) }
+  }
+
+  /// Pointer to the `index`th 16-bit field of the available ring: flags, index, then the ring.
+  fn avail(&self, index: usize) -> *mut u16 {
+    unsafe { (self.ring.as_slice().as_mut_ptr().add(AVAIL_OFFSET) as *mut u16).add(index) }
+  }
+
+  fn set_avail_flags(&self, flags: u16) {
+    unsafe { write_volatile(self.avail(0), flags) }
+  }
+
+  /// Pointer to the `index`th 32-bit field of the used ring: flags and index, then the ID and
+  /// length of each element.
+  fn used(&self, index: usize) -> *mut u32 {
+    unsafe { (self.used.as_slice().as_mut_ptr() as *mut u32).add(index) }
+  }
+
+  /// Fill in the header and the descriptors of `slot`, and make the device start on them. A
+  /// flush has no data descriptor.
+  fn start_chunk(&mut self, slot: usize, op: BlockOp, sector: usize, len: usize) {
+    let kind = match op {
+      BlockOp::Read => BLK_T_IN,
+      BlockOp::Write => BLK_T_OUT,
+      BlockOp::Flush => BLK_T_FLUSH,
+    };
+    let header = &mut self.headers.as_slice()[slot * HEADER_SIZE..(slot + 1) * HEADER_SIZE];
+    header[..4].copy_from_slice(&kind.to_le_bytes());
+    header[4..8].fill(0);
+    header[8..16].copy_from_slice(&(sector as u64).to_le_bytes());
+    header[STATUS_OFFSET] = !0;
+    let header_pa = self.headers.start_pa().0 + slot * HEADER_SIZE;
+    let head = slot * CHAIN_LEN;
+    let mut next = head + 2;
+    if len > 0 {
+      next = head + 1;
+      let flags = DESC_F_NEXT | if op == BlockOp::Read { DESC_F_WRITE } else { 0 };
+      let addr = self.slots[slot].buf.start_pa().0 as u64;
+      *self.descriptor(head + 1) =
+        Descriptor { addr, len: len as u32, flags, next: head as u16 + 2 };
+    }
+    *self.descriptor(head) =
+      Descriptor { addr: header_pa as u64, len: 16, flags: DESC_F_NEXT, next: next as u16 };
+    *self.descriptor(head + 2) = Descriptor {
+      addr: (header_pa + STATUS_OFFSET) as u64,
+      len: 1,
+      flags: DESC_F_WRITE,
+      next: 0,
+    };
+    let pos = self.avail_idx as usize % self.queue_size;
+    self.avail_idx = self.avail_idx.wrapping_add(1);
+    // the chain must be in memory before the device sees it in the ring, and the ring before
+    // the device is notified
+    unsafe {
+      write_volatile(self.avail(2 + pos), head as u16);
+      fence(Ordering::SeqCst);
+      write_volatile(self.avail(1), self.avail_idx);
+      fence(Ordering::SeqCst);
+      write_volatile(self.notify as *mut u16, 0);
+    }
+  }
+
+  /// Start chunks of the requests in order while there are free slots. A flush needs the queue
+  /// to itself.
+  fn issue(&mut self) {
+    loop {
+      let id = match self.requests.iter().find(|(_, pending)| pending.next.is_some()) {
+        Some((&id, _)) => id,
+        None => return,
+      };
+      let op = self.requests[&id].request.op;
+      let busy = self.slots.iter().filter(|slot| slot.chunk.is_some()).count();
+      let flushing =
+        self.slots.iter().any(|slot| matches!(slot.chunk, Some(Chunk { flush: true, .. })));
+      if busy == self.slots.len() || flushing || (busy > 0 && op == BlockOp::Flush) {
+        return;
+      }
+      let slot = self.slots.iter().position(|slot| slot.chunk.is_none()).unwrap();
+      let pending = self.requests.get_mut(&id).unwrap();
+      let offset = pending.next.unwrap();
+      let len = (pending.request.buf.len() - offset).min(PAGE_SIZE);
+      let end = offset + len;
+      pending.next = if end < pending.request.buf.len() { Some(end) } else { None };
+      pending.in_flight += 1;
+      let sector = pending.request.block_id + offset / BLOCK_SZ;
+      if op == BlockOp::Write {
+        self.slots[slot].buf.as_slice()[..len].copy_from_slice(&pending.request.buf[offset..end]);
+      }
+      self.slots[slot].chunk = Some(Chunk { id, offset, len, flush: op == BlockOp::Flush });
+      self.start_chunk(slot, op, sector, len);
+    }
+  }
+
+  /// Finish the chunks in the used ring and start more, return the requests completed. A
+  /// request fails if one of its chunks does.
+  fn complete(&mut self) -> Vec<(BlockRequest, bool)> {
+    let mut finished = Vec::new();
+    loop {
+      let used_idx = unsafe { read_volatile(self.used(0)) >> 16 } as u16;
+      if used_idx == self.used_idx { break; }
+      // the element must not be read before the index
+      fence(Ordering::SeqCst);
+      let pos = self.used_idx as usize % self.queue_size;
+      let head = unsafe { read_volatile(self.used(1 + 2 * pos)) } as usize;
+      self.used_idx = self.used_idx.wrapping_add(1);
+      let slot = head / CHAIN_LEN;
+      let chunk = self.slots[slot].chunk.take().unwrap();
+      let ok = self.headers.as_slice()[slot * HEADER_SIZE + STATUS_OFFSET] == BLK_S_OK;
+      let pending = self.requests.get_mut(&chunk.id).unwrap();
+      if pending.request.op == BlockOp::Read && ok {
+        pending.request.buf[chunk.offset..chunk.offset + chunk.len]
+          .copy_from_slice(&self.slots[slot].buf.as_slice()[..chunk.len]);
+      }
+      if !ok { warn!("virtio-blk: {:?} failed", pending.request.op); }
+      pending.ok &= ok;
+      pending.in_flight -= 1;
+      if pending.in_flight == 0 && pending.next.is_none() {
+        let pending = self.requests.remove(&chunk.id).unwrap();
+        finished.push((pending.request, pending.ok));
+      }
+    }
+    self.issue();
+    finished
+  }
+}
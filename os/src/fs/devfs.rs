@@ -0,0 +1,189 @@
+use crate::{*, syscall::*};
+use super::*;
+
+use alloc::sync::Arc;
+use core::any::Any;
+use easy_fs::BLOCK_SZ;
+
+/// Get the size of a block device in bytes, as a u64.
+const BLKGETSIZE64: u32 = 0x80081272;
+/// Get the block size of a block device, as an i32.
+const BLKSSZGET: u32 = 0x1268;
+
+#[derive(Clone)]
+enum Node {
+  Root,
+  /// Discards writes and reads nothing.
+  Null,
+  /// Discards writes and reads zeros.
+  Zero,
+  /// Reads pseudo-random bytes.
+  Random,
+  /// The terminal.
+  Console,
+  /// Raw access to a block device.
+  Block(&'static str, Arc<dyn BlockDevice>),
+}
+
+/// Character devices by name, with their major and minor numbers on Linux.
+const CHAR_DEVICES: &[(&str, fn() -> Node, u64)] = &[
+  ("null", || Node::Null, 1 << 8 | 3),
+  ("zero", || Node::Zero, 1 << 8 | 5),
+  ("random", || Node::Random, 1 << 8 | 8),
+  ("tty", || Node::Console, 5 << 8),
+  ("console", || Node::Console, 5 << 8 | 1),
+];
+
+/// A device file, or the directory of them.
+struct DevInode {
+  node: Node,
+  ino: usize,
+  rdev: u64,
+}
+
+struct DevFs;
+
+pub fn new() -> Arc<dyn FileSystem> {
+  Arc::new(DevFs)
+}
+
+impl FileSystem for DevFs {
+  fn name(&self) -> &'static str { "devfs" }
+  fn root(&self) -> Arc<dyn Inode> { Arc::new(DevInode { node: Node::Root, ino: 1, rdev: 0 }) }
+}
+
+/// State of the xorshift64* generator, seeded from RDRAND or the time-stamp counter.
+static RANDOM: Cell<u64> = zero();
+
+fn random() -> u64 {
+  let x = RANDOM.get();
+  if *x == 0 {
+    *x = x86_64::rdrand().unwrap_or_else(x86_64::rdtsc) | 1;
+  }
+  *x ^= *x >> 12;
+  *x ^= *x << 25;
+  *x ^= *x >> 27;
+  x.wrapping_mul(0x2545F4914F6CDD1D)
+}
+
+/// Whether a file system is mounted on the block device `name`.
+fn is_mounted(name: &str) -> bool {
+  mounts().iter().any(|m| m.source.trim_start_matches("/dev/") == name)
+}
+
+/// Read the bytes at `offset` of `device` up to its end, or write them if `write`, through whole
+/// blocks. `f` copies the part of each block at its offset in the buffer.
+fn block_io(
+  device: &dyn BlockDevice, offset: usize, len: usize, write: bool,
+  mut f: impl FnMut(usize, &mut [u8]),
+) -> usize {
+  let size = device.num_blocks() * BLOCK_SZ;
+  let end = size.min(offset.saturating_add(len));
+  let mut block = [0u8; BLOCK_SZ];
+  let mut pos = offset;
+  while pos < end {
+    let (id, start) = (pos / BLOCK_SZ, pos % BLOCK_SZ);
+    let n = (BLOCK_SZ - start).min(end - pos);
+    // A block written as a whole need not be read.
+    if !write || n < BLOCK_SZ { device.read_block(id, &mut block); }
+    f(pos - offset, &mut block[start..start + n]);
+    if write { device.write_block(id, &block); }
+    pos += n;
+  }
+  end.saturating_sub(offset)
+}
+
+impl Inode for DevInode {
+  fn stat(&self) -> Stat {
+    let (mode, size) = match &self.node {
+      Node::Root => (S_IFDIR | 0o755, 0),
+      Node::Block(_, device) => (S_IFBLK | 0o660, device.num_blocks() * BLOCK_SZ),
+      Node::Console => (S_IFCHR | 0o620, 0),
+      _ => (S_IFCHR | 0o666, 0),
+    };
+    Stat {
+      ino: self.ino as _,
+      nlink: 1,
+      mode,
+      rdev: self.rdev,
+      size: size as _,
+      blksize: BLOCK_SZ as _,
+      ..Stat::default()
+    }
+  }
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    match &self.node {
+      Node::Root | Node::Null => 0,
+      Node::Zero => {
+        buf.fill(0);
+        buf.len()
+      }
+      Node::Random => {
+        for chunk in buf.chunks_mut(8) {
+          chunk.copy_from_slice(&random().to_le_bytes()[..chunk.len()]);
+        }
+        buf.len()
+      }
+      Node::Console => TTY.get().read(buf),
+      Node::Block(name, device) => {
+        // Write back what the file system has cached, to read the current contents.
+        if is_mounted(name) { sync(); }
+        block_io(device.as_ref(), offset, buf.len(), false, |pos, data| {
+          buf[pos..pos + data.len()].copy_from_slice(data)
+        })
+      }
+    }
+  }
+  fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    match &self.node {
+      Node::Root => 0,
+      Node::Null | Node::Zero | Node::Random => buf.len(),
+      Node::Console => {
+        if let Ok(str) = core::str::from_utf8(buf) {
+          print!("{}", str);
+          buf.len()
+        } else {
+          0
+        }
+      }
+      // The file system would overwrite the blocks from its cache.
+      Node::Block(name, _) if is_mounted(name) => 0,
+      Node::Block(_, device) => block_io(device.as_ref(), offset, buf.len(), true, |pos, data| {
+        data.copy_from_slice(&buf[pos..pos + data.len()])
+      }),
+    }
+  }
+  fn ioctl(&self, cmd: u32, arg: usize) -> isize {
+    match (&self.node, cmd) {
+      (Node::Console, _) => TTY.get().ioctl(cmd, arg),
+      (Node::Block(_, device), BLKGETSIZE64) => {
+        try_!((arg as *mut usize).write_user(device.num_blocks() * BLOCK_SZ), -1);
+        0
+      }
+      (Node::Block(..), BLKSSZGET) => {
+        try_!((arg as *mut u32).write_user(BLOCK_SZ as _), -1);
+        0
+      }
+      _ => -1,
+    }
+  }
+  fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    if !matches!(self.node, Node::Root) { return None; }
+    let inode = if let Some(i) = CHAR_DEVICES.iter().position(|d| d.0 == name) {
+      DevInode { node: CHAR_DEVICES[i].1(), ino: 2 + i, rdev: CHAR_DEVICES[i].2 }
+    } else {
+      let devices = drivers::block_devices();
+      let i = devices.iter().position(|d| d.0 == name)?;
+      let (name, device) = devices[i].clone();
+      let ino = 2 + CHAR_DEVICES.len() + i;
+      DevInode { node: Node::Block(name, device), ino, rdev: 8 << 8 | (i as u64 * 16) }
+    };
+    Some(Arc::new(inode))
+  }
+  fn readdir(&self) -> Vec<String> {
+    if !matches!(self.node, Node::Root) { return Vec::new(); }
+    let names = CHAR_DEVICES.iter().map(|d| d.0);
+    names.chain(drivers::block_devices().iter().map(|d| d.0)).map(String::from).collect()
+  }
+  fn as_any(&self) -> &dyn Any { self }
+}
@@ -0,0 +1,169 @@
+use crate::{*, drivers::*, task::{TaskPtr, TaskStatus}};
+use super::*;
+
+use alloc::sync::Arc;
+use core::any::Any;
+use easy_fs::{
+  block_cache_clear, set_block_cache_capacity, set_block_cache_wait, EasyFileSystem,
+  Inode as EfsInode, BLOCK_SZ, MAX_FILE_SIZE, MIN_BLOCK_CACHE_CAPACITY,
+};
+
+/// PID of the task inside a disk file system. It leaves data structures halfway updated while it
+/// waits for the disk, easy-fs its spin locks held, so the other tasks must wait outside until it
+/// leaves.
+static FS_OWNER: Cell<Option<usize>> = Cell::new(None);
+static FS_WAITERS: Cell<Vec<TaskPtr>> = Cell::new(Vec::new());
+
+/// Held by the task inside the file system, which leaves it when dropped.
+pub(super) struct FsGuard;
+
+pub(super) fn enter_fs() -> FsGuard {
+  while FS_OWNER.is_some() {
+    FS_WAITERS.get().push(task::current());
+    task::sched_block();
+  }
+  *FS_OWNER.get() = Some(if task::started() { task::current().proc.pid } else { 0 });
+  FsGuard
+}
+
+impl Drop for FsGuard {
+  fn drop(&mut self) {
+    *FS_OWNER.get() = None;
+    for t in FS_WAITERS.get().drain(..) {
+      task::sched_unblock(t);
+    }
+  }
+}
+
+/// Wait until no task of process `pid` is inside the file system, which cannot be left halfway.
+pub fn wait_fs_leave(pid: usize) {
+  while *FS_OWNER == Some(pid) {
+    task::sched_yield();
+  }
+}
+
+/// Forget tasks waiting for the file system that have exited.
+pub fn clear_zombie() {
+  FS_WAITERS.get().retain(|t| t.status == TaskStatus::Blocking);
+}
+
+
+pub fn init() {
+  EasyFileSystem::set_clock(rtc::now);
+  // Blocks are released by the tasks using them, which must run meanwhile.
+  set_block_cache_wait(|| if task::started() { task::sched_yield() });
+  match cmdline_arg("bcache").map(|b| b.parse::<usize>()) {
+    Some(Ok(blocks)) if blocks >= MIN_BLOCK_CACHE_CAPACITY => set_block_cache_capacity(blocks),
+    Some(_) => warn!("bcache: expected a number of blocks from {}", MIN_BLOCK_CACHE_CAPACITY),
+    None => {}
+  }
+}
+
+/// easy-fs on a block device, with its root directory.
+struct EasyFs(Arc<EfsInode>, Arc<dyn BlockDevice>);
+
+/// Open the easy-fs file system on the block device `source`.
+pub fn open(source: &str) -> Option<Arc<dyn FileSystem>> {
+  let device = block_device(source)?;
+  let _fs = enter_fs();
+  if !EasyFileSystem::probe(device.as_ref()) { return None; }
+  let efs = EasyFileSystem::open(device.clone());
+  Some(Arc::new(EasyFs(Arc::new(EasyFileSystem::root_inode(&efs)), device)))
+}
+
+impl Drop for EasyFs {
+  fn drop(&mut self) {
+    let _fs = enter_fs();
+    block_cache_clear(&self.1);
+  }
+}
+
+impl FileSystem for EasyFs {
+  fn name(&self) -> &'static str { "easyfs" }
+  fn root(&self) -> Arc<dyn Inode> { self.0.clone() }
+  fn sync(&self) {
+    let _fs = enter_fs();
+    self.0.sync();
+  }
+}
+
+impl Inode for EfsInode {
+  fn stat(&self) -> Stat {
+    let s = {
+      let _fs = enter_fs();
+      EfsInode::stat(self)
+    };
+    Stat {
+      ino: s.ino as _,
+      nlink: s.nlink as _,
+      mode: if s.is_dir { S_IFDIR } else { S_IFREG } | s.mode,
+      uid: s.uid,
+      gid: s.gid,
+      size: s.size as _,
+      blksize: BLOCK_SZ as _,
+      blocks: (s.blocks as usize * BLOCK_SZ / 512) as _,
+      atime: s.atime as _,
+      mtime: s.mtime as _,
+      ctime: s.ctime as _,
+      ..Stat::default()
+    }
+  }
+  fn is_dir(&self) -> bool {
+    let _fs = enter_fs();
+    EfsInode::is_dir(self)
+  }
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    let _fs = enter_fs();
+    EfsInode::read_at(self, offset, buf)
+  }
+  fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    let _fs = enter_fs();
+    // The file cannot grow past its last indirect block.
+    let len = buf.len().min(MAX_FILE_SIZE.saturating_sub(offset));
+    EfsInode::write_at(self, offset, &buf[..len])
+  }
+  fn truncate(&self, len: usize) -> bool {
+    let _fs = enter_fs();
+    if EfsInode::is_dir(self) || len > MAX_FILE_SIZE { return false; }
+    EfsInode::truncate(self, len as _)
+  }
+  fn sync(&self) {
+    let _fs = enter_fs();
+    EfsInode::sync(self);
+  }
+  fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let _fs = enter_fs();
+    self.find(name).map(|inode| inode as _)
+  }
+  fn readdir(&self) -> Vec<String> {
+    let _fs = enter_fs();
+    self.ls().into_iter().filter(|name| name != "." && name != "..").collect()
+  }
+  fn create(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let _fs = enter_fs();
+    EfsInode::create(self, name).map(|inode| inode as _)
+  }
+  fn mkdir(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let _fs = enter_fs();
+    self.create_dir(name).map(|inode| inode as _)
+  }
+  fn unlink(&self, name: &str) -> bool {
+    let _fs = enter_fs();
+    EfsInode::unlink(self, name)
+  }
+  fn rmdir(&self, name: &str) -> bool {
+    let _fs = enter_fs();
+    EfsInode::rmdir(self, name)
+  }
+  fn link(&self, name: &str, inode: &dyn Inode) -> bool {
+    let _fs = enter_fs();
+    let inode = try_!(inode.as_any().downcast_ref::<EfsInode>(), false);
+    EfsInode::link(self, name, inode)
+  }
+  fn rename(&self, old_name: &str, new_dir: &dyn Inode, new_name: &str) -> bool {
+    let _fs = enter_fs();
+    let new_dir = try_!(new_dir.as_any().downcast_ref::<EfsInode>(), false);
+    EfsInode::rename(self, old_name, new_dir, new_name)
+  }
+  fn as_any(&self) -> &dyn Any { self }
+}
@@ -0,0 +1,1072 @@
+use crate::{*, drivers::{block_device, rtc}};
+use super::{*, easyfs::{enter_fs, FsGuard}};
+
+use alloc::sync::{Arc, Weak};
+use core::any::Any;
+use easy_fs::{
+  block_cache_capacity, block_cache_clear, block_cache_dirty_blocks, block_cache_sync_all,
+  get_block_cache, BLOCK_SZ,
+};
+
+/// Position of the super block on the device, and its size.
+const SUPER_BLOCK_POS: usize = 1024;
+const SUPER_BLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+const GROUP_DESC_SIZE: usize = 32;
+const ROOT_INO: u32 = 2;
+/// Inode size and first inode not reserved of revision 0 file systems.
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+/// State of the super block when the file system was cleanly unmounted.
+const VALID_FS: u16 = 1;
+
+/// Directory entries hold the type of their file. Other incompatible features are unsupported.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Features written as ext2 does, the file system is read-only with any other one.
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+/// Flag of a directory indexed by a hash tree, which becomes stale when entries are added.
+const INDEX_FL: u32 = 0x1000;
+
+/// Direct block pointers of an inode, followed by a singly, doubly and triply indirect one.
+const DIRECT_BLOCKS: usize = 12;
+/// Bytes of the block pointers, which hold the target of a shorter symbolic link.
+const FAST_SYMLINK_SIZE: usize = 60;
+const LINK_MAX: u16 = 32000;
+
+const S_IFIFO: u32 = 0o010000;
+const S_IFSOCK: u32 = 0o140000;
+
+/// An inode as stored in the inode table, whose entries may be larger.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct DiskInode {
+  mode: u16,
+  uid: u16,
+  size: u32,
+  atime: u32,
+  ctime: u32,
+  mtime: u32,
+  dtime: u32,
+  gid: u16,
+  links: u16,
+  /// 512-byte sectors allocated, indirect blocks included.
+  sectors: u32,
+  flags: u32,
+  osd1: u32,
+  block: [u32; 15],
+  generation: u32,
+  file_acl: u32,
+  /// High 32 bits of the size of regular files.
+  size_high: u32,
+  faddr: u32,
+  frag: u16,
+  pad: u16,
+  uid_high: u16,
+  gid_high: u16,
+  reserved: u32,
+}
+
+impl DiskInode {
+  fn kind(&self) -> u32 { self.mode as u32 & S_IFMT }
+  fn size(&self) -> usize {
+    let high = if self.kind() == S_IFREG { (self.size_high as usize) << 32 } else { 0 };
+    high | self.size as usize
+  }
+  fn set_size(&mut self, size: usize) {
+    self.size = size as u32;
+    if self.kind() == S_IFREG { self.size_high = (size >> 32) as u32; }
+  }
+  /// Whether the block pointers point to blocks, rather than hold the target of a symbolic
+  /// link or the number of a device. An extended attribute block may be counted in `sectors`.
+  fn has_blocks(&self, sectors_per_block: u32) -> bool {
+    let acl = if self.file_acl != 0 { sectors_per_block } else { 0 };
+    self.sectors > acl
+  }
+  fn touch(&mut self) {
+    self.mtime = rtc::now() as u32;
+    self.ctime = self.mtime;
+  }
+}
+
+/// Type of a file in the directory entries of file systems with `INCOMPAT_FILETYPE`.
+fn file_type(mode: u32) -> u8 {
+  match mode & S_IFMT {
+    S_IFREG => 1,
+    S_IFDIR => 2,
+    S_IFCHR => 3,
+    S_IFBLK => 4,
+    S_IFIFO => 5,
+    S_IFSOCK => 6,
+    S_IFLNK => 7,
+    _ => 0,
+  }
+}
+
+/// Bytes of a directory entry named `name_len` bytes, which are 4-byte aligned.
+fn rec_size(name_len: usize) -> usize { (8 + name_len + 3) / 4 * 4 }
+
+fn is_valid_name(name: &str) -> bool {
+  !name.is_empty() && name.len() <= 255 && name != "." && name != ".." && !name.contains('\0')
+}
+
+/// A directory entry. The entries of a block cover all of it: the space after an entry belongs
+/// to it, and a free space at the start of a block to an entry of inode 0.
+struct Record {
+  /// Position on the device.
+  pos: usize,
+  /// Position of the previous entry in the same block, None for the first one.
+  prev: Option<usize>,
+  ino: u32,
+  rec_len: usize,
+  name: Vec<u8>,
+}
+
+impl Record {
+  fn is_dot(&self) -> bool { self.name == b"." || self.name == b".." }
+}
+
+/// A block group, as given by its descriptor.
+struct Group {
+  block_bitmap: u32,
+  inode_bitmap: u32,
+  inode_table: u32,
+  free_blocks: u16,
+  free_inodes: u16,
+  used_dirs: u16,
+}
+
+/// A mounted ext2 file system. All of its blocks go through the block cache.
+struct Ext2 {
+  device: Arc<dyn BlockDevice>,
+  block_size: usize,
+  blocks_count: u32,
+  first_data_block: u32,
+  blocks_per_group: u32,
+  inodes_per_group: u32,
+  /// Number of inodes, the highest inode number.
+  inodes_count: u32,
+  inode_size: usize,
+  first_ino: u32,
+  rev_level: u32,
+  /// Whether directory entries hold the type of their file.
+  filetype: bool,
+  /// Whether the file system has features that would be lost by writing to it.
+  read_only: bool,
+  ro_compat: Cell<u32>,
+  groups: Cell<Vec<Group>>,
+  /// Open files and directories by inode number, so that they share one inode.
+  inodes: Cell<BTreeMap<u32, Weak<Ext2Inode>>>,
+  /// Inodes whose last link was removed while they were open and that are closed since, freed
+  /// on the next operation.
+  orphans: Cell<Vec<u32>>,
+}
+
+impl Ext2 {
+  /// Enter the file system, freeing the inodes of removed files closed meanwhile.
+  fn enter(&self) -> FsGuard {
+    let guard = enter_fs();
+    for ino in core::mem::take(self.orphans.get()) {
+      self.free_inode(ino);
+    }
+    self.write_back_if_needed();
+    guard
+  }
+
+  /// Write the modified blocks of this file system back before they fill half of the cache,
+  /// which is kept for the uncommitted blocks of easy-fs.
+  fn write_back_if_needed(&self) {
+    if block_cache_dirty_blocks() > block_cache_capacity() / 2 {
+      block_cache_sync_all(&self.device);
+    }
+  }
+
+  fn block_pos(&self, block: u32) -> usize { block as usize * self.block_size }
+
+  fn sectors_per_block(&self) -> u32 { (self.block_size / 512) as u32 }
+
+  /// Read the bytes at `pos` of the device through the block cache.
+  fn read_bytes(&self, pos: usize, buf: &mut [u8]) {
+    let mut done = 0;
+    while done < buf.len() {
+      let (id, offset) = ((pos + done) / BLOCK_SZ, (pos + done) % BLOCK_SZ);
+      let n = (BLOCK_SZ - offset).min(buf.len() - done);
+      get_block_cache(id, self.device.clone()).lock().read(0, |block: &[u8; BLOCK_SZ]| {
+        buf[done..done + n].copy_from_slice(&block[offset..offset + n])
+      });
+      done += n;
+    }
+  }
+
+  /// Write the bytes at `pos` of the device through the block cache.
+  fn write_bytes(&self, pos: usize, buf: &[u8]) {
+    let mut done = 0;
+    while done < buf.len() {
+      let (id, offset) = ((pos + done) / BLOCK_SZ, (pos + done) % BLOCK_SZ);
+      let n = (BLOCK_SZ - offset).min(buf.len() - done);
+      get_block_cache(id, self.device.clone()).lock().modify(0, |block: &mut [u8; BLOCK_SZ]| {
+        block[offset..offset + n].copy_from_slice(&buf[done..done + n])
+      });
+      done += n;
+    }
+  }
+
+  fn read_u32(&self, pos: usize) -> u32 {
+    let mut buf = [0; 4];
+    self.read_bytes(pos, &mut buf);
+    u32::from_le_bytes(buf)
+  }
+
+  /// Write the counts of the super block and whether the file system is cleanly unmounted.
+  fn write_super(&self, clean: bool) {
+    let mut sb = [0u8; SUPER_BLOCK_SIZE];
+    self.read_bytes(SUPER_BLOCK_POS, &mut sb);
+    let free_blocks: u32 = self.groups.iter().map(|g| g.free_blocks as u32).sum();
+    let free_inodes: u32 = self.groups.iter().map(|g| g.free_inodes as u32).sum();
+    set_u32(&mut sb, 12, free_blocks);
+    set_u32(&mut sb, 16, free_inodes);
+    set_u32(&mut sb, 48, rtc::now() as u32);
+    let state = u16_at(&sb, 58);
+    set_u16(&mut sb, 58, if clean { state | VALID_FS } else { state & !VALID_FS });
+    if self.rev_level > 0 { set_u32(&mut sb, 100, *self.ro_compat); }
+    self.write_bytes(SUPER_BLOCK_POS, &sb);
+  }
+
+  /// Write the counts of the descriptor of group `g`.
+  fn write_group(&self, g: usize) {
+    let pos = self.block_pos(self.first_data_block + 1) + g * GROUP_DESC_SIZE;
+    let mut desc = [0u8; GROUP_DESC_SIZE];
+    self.read_bytes(pos, &mut desc);
+    let group = &self.groups[g];
+    set_u16(&mut desc, 12, group.free_blocks);
+    set_u16(&mut desc, 14, group.free_inodes);
+    set_u16(&mut desc, 16, group.used_dirs);
+    self.write_bytes(pos, &desc);
+  }
+
+  /// Largest size of a file, as limited by its block pointers.
+  fn max_size(&self) -> usize {
+    // Revision 0 has no feature to mark files of 2 GiB or more.
+    if self.rev_level == 0 { return i32::MAX as usize; }
+    let p = self.block_size / 4;
+    (DIRECT_BLOCKS + p + p * p + p * p * p) * self.block_size
+  }
+
+  /// Set the size of `di`, marking the file system as having large files if it is one.
+  fn set_size(&self, di: &mut DiskInode, size: usize) {
+    di.set_size(size);
+    if size > i32::MAX as usize && *self.ro_compat & RO_COMPAT_LARGE_FILE == 0 {
+      *self.ro_compat.get() |= RO_COMPAT_LARGE_FILE;
+      self.write_super(false);
+    }
+  }
+
+  /// Position of the inode `ino`, None if there is no such inode.
+  fn inode_pos(&self, ino: u32) -> Option<usize> {
+    if !(1..=self.inodes_count).contains(&ino) { return None; }
+    let (g, i) = ((ino - 1) / self.inodes_per_group, (ino - 1) % self.inodes_per_group);
+    Some(self.block_pos(self.groups[g as usize].inode_table) + i as usize * self.inode_size)
+  }
+
+  /// The inode `ino`, None if there is no such inode.
+  fn read_inode(&self, ino: u32) -> Option<DiskInode> {
+    let pos = self.inode_pos(ino)?;
+    // Inodes are aligned to their size, so none crosses a cached block.
+    let di = get_block_cache(pos / BLOCK_SZ, self.device.clone()).lock()
+      .read(pos % BLOCK_SZ, |di: &DiskInode| *di);
+    Some(di)
+  }
+
+  fn write_inode(&self, ino: u32, di: &DiskInode) {
+    let pos = try_!(self.inode_pos(ino), ());
+    get_block_cache(pos / BLOCK_SZ, self.device.clone()).lock()
+      .modify(pos % BLOCK_SZ, |old: &mut DiskInode| *old = *di);
+  }
+
+  /// First block of the group of inode `ino`, where its blocks are allocated from.
+  fn group_start(&self, ino: u32) -> u32 {
+    self.first_data_block + (ino - 1) / self.inodes_per_group * self.blocks_per_group
+  }
+
+  /// Allocate a block filled with zeros, the first free one from `goal` on.
+  fn alloc_block(&self, goal: u32) -> Option<u32> {
+    let groups = self.groups.get();
+    let bpg = self.blocks_per_group as usize;
+    let data_blocks = (self.blocks_count - self.first_data_block) as usize;
+    let goal = (goal.max(self.first_data_block) - self.first_data_block) as usize % data_blocks;
+    let mut bitmap = alloc::vec![0u8; self.block_size];
+    for i in 0..groups.len() {
+      let g = (goal / bpg + i) % groups.len();
+      if groups[g].free_blocks == 0 { continue; }
+      let count = bpg.min(data_blocks - g * bpg);
+      let start = if i == 0 { goal % bpg } else { 0 };
+      self.read_bytes(self.block_pos(groups[g].block_bitmap), &mut bitmap);
+      let is_free = |b: usize| bitmap[b / 8] & 1 << (b % 8) == 0;
+      let bit = (0..count).map(|k| (start + k) % count).find(|&b| is_free(b));
+      if let Some(bit) = bit {
+        bitmap[bit / 8] |= 1 << (bit % 8);
+        self.write_bytes(self.block_pos(groups[g].block_bitmap) + bit / 8, &bitmap[bit / 8..][..1]);
+        groups[g].free_blocks -= 1;
+        self.write_group(g);
+        let block = self.first_data_block + (g * bpg + bit) as u32;
+        self.write_bytes(self.block_pos(block), &alloc::vec![0; self.block_size]);
+        return Some(block);
+      }
+    }
+    None
+  }
+
+  fn free_block(&self, block: u32) {
+    let bpg = self.blocks_per_group;
+    let (g, bit) = ((block - self.first_data_block) / bpg, (block - self.first_data_block) % bpg);
+    let (g, bit) = (g as usize, bit as usize);
+    let pos = self.block_pos(self.groups[g].block_bitmap) + bit / 8;
+    let mut byte = [0u8];
+    self.read_bytes(pos, &mut byte);
+    if byte[0] & 1 << (bit % 8) == 0 { return; }
+    byte[0] &= !(1 << (bit % 8));
+    self.write_bytes(pos, &byte);
+    self.groups.get()[g].free_blocks += 1;
+    self.write_group(g);
+  }
+
+  /// Allocate an inode filled with zeros for a file in the directory `parent`. Files go to the
+  /// group of their directory if they can, directories to the group with the most free blocks.
+  fn alloc_inode(&self, parent: u32, is_dir: bool) -> Option<u32> {
+    let groups = self.groups.get();
+    let ipg = self.inodes_per_group as usize;
+    let first = (parent - 1) as usize / ipg;
+    let order: Vec<usize> = if is_dir {
+      let g = (0..groups.len()).filter(|&g| groups[g].free_inodes > 0)
+        .max_by_key(|&g| groups[g].free_blocks)?;
+      alloc::vec![g]
+    } else {
+      (0..groups.len()).map(|i| (first + i) % groups.len()).collect()
+    };
+    let mut bitmap = alloc::vec![0u8; self.block_size];
+    for g in order {
+      if groups[g].free_inodes == 0 { continue; }
+      self.read_bytes(self.block_pos(groups[g].inode_bitmap), &mut bitmap);
+      let bit = (0..ipg).find(|&b| {
+        let ino = (g * ipg + b + 1) as u32;
+        bitmap[b / 8] & 1 << (b % 8) == 0 && (self.first_ino..=self.inodes_count).contains(&ino)
+      });
+      if let Some(bit) = bit {
+        bitmap[bit / 8] |= 1 << (bit % 8);
+        self.write_bytes(self.block_pos(groups[g].inode_bitmap) + bit / 8, &bitmap[bit / 8..][..1]);
+        groups[g].free_inodes -= 1;
+        if is_dir { groups[g].used_dirs += 1; }
+        self.write_group(g);
+        let ino = (g * ipg + bit + 1) as u32;
+        self.write_bytes(self.inode_pos(ino)?, &alloc::vec![0; self.inode_size]);
+        return Some(ino);
+      }
+    }
+    None
+  }
+
+  /// Free the inode `ino` and its blocks.
+  fn free_inode(&self, ino: u32) {
+    let mut di = try_!(self.read_inode(ino), ());
+    if di.has_blocks(self.sectors_per_block()) { self.truncate_blocks(&mut di, 0); }
+    di.links = 0;
+    di.set_size(0);
+    di.dtime = rtc::now() as u32;
+    self.write_inode(ino, &di);
+    let (g, bit) = ((ino - 1) / self.inodes_per_group, (ino - 1) % self.inodes_per_group);
+    let (g, bit) = (g as usize, bit as usize);
+    let pos = self.block_pos(self.groups[g].inode_bitmap) + bit / 8;
+    let mut byte = [0u8];
+    self.read_bytes(pos, &mut byte);
+    byte[0] &= !(1 << (bit % 8));
+    self.write_bytes(pos, &byte);
+    let group = &mut self.groups.get()[g];
+    group.free_inodes += 1;
+    if di.kind() == S_IFDIR { group.used_dirs -= 1; }
+    self.write_group(g);
+  }
+
+  /// The block holding the block `index` of the file `di`, 0 for a hole. If `goal` is given,
+  /// holes are filled with blocks allocated from there. None if the block is beyond the largest
+  /// file or the volume is full.
+  fn map(&self, di: &mut DiskInode, index: usize, goal: Option<u32>) -> Option<u32> {
+    let p = self.block_size / 4;
+    // Slot of the block pointer, levels of indirect blocks and index below it.
+    let (slot, level, rel) = if index < DIRECT_BLOCKS {
+      (index, 0, 0)
+    } else {
+      let (mut rel, mut span, mut level) = (index - DIRECT_BLOCKS, p, 1);
+      while rel >= span {
+        rel -= span;
+        span *= p;
+        level += 1;
+        if level > 3 { return None; }
+      }
+      (DIRECT_BLOCKS + level - 1, level, rel)
+    };
+    let mut block = di.block[slot];
+    if block == 0 {
+      block = match goal {
+        Some(goal) => self.alloc_block(goal)?,
+        None => return Some(0),
+      };
+      di.block[slot] = block;
+      di.sectors += self.sectors_per_block();
+    }
+    for l in (0..level).rev() {
+      let pos = self.block_pos(block) + rel / p.pow(l as u32) % p * 4;
+      block = match (self.read_u32(pos), goal) {
+        (0, Some(goal)) => {
+          let new = self.alloc_block(goal)?;
+          self.write_bytes(pos, &new.to_le_bytes());
+          di.sectors += self.sectors_per_block();
+          new
+        }
+        (0, None) => return Some(0),
+        (next, _) => next,
+      };
+    }
+    Some(block)
+  }
+
+  fn block_of(&self, di: &DiskInode, index: usize) -> u32 {
+    self.map(&mut di.clone(), index, None).unwrap_or(0)
+  }
+
+  /// Free the blocks of the file `di` from the block `keep` on.
+  fn truncate_blocks(&self, di: &mut DiskInode, keep: usize) {
+    let spb = self.sectors_per_block();
+    for slot in keep.min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+      if di.block[slot] != 0 {
+        self.free_block(di.block[slot]);
+        di.block[slot] = 0;
+        di.sectors -= spb;
+      }
+    }
+    let p = self.block_size / 4;
+    let (mut start, mut span) = (DIRECT_BLOCKS, p);
+    for level in 1..=3 {
+      let slot = DIRECT_BLOCKS + level - 1;
+      if di.block[slot] != 0 && keep < start + span {
+        let from = keep.saturating_sub(start);
+        if self.truncate_tree(di.block[slot], level as u32, from, &mut di.sectors) {
+          di.block[slot] = 0;
+        }
+      }
+      start += span;
+      span *= p;
+    }
+  }
+
+  /// Free the blocks below `block`, an indirect block of `level` or a data block, holding the
+  /// blocks of the file from `from` on. Return whether `block` was freed too.
+  fn truncate_tree(&self, block: u32, level: u32, from: usize, sectors: &mut u32) -> bool {
+    if level > 0 {
+      let p = self.block_size / 4;
+      let span = p.pow(level - 1);
+      let mut ptrs = alloc::vec![0u8; self.block_size];
+      self.read_bytes(self.block_pos(block), &mut ptrs);
+      for i in from / span..p {
+        let ptr = u32_at(&ptrs, i * 4);
+        if ptr != 0 && self.truncate_tree(ptr, level - 1, from.saturating_sub(i * span), sectors) {
+          set_u32(&mut ptrs, i * 4, 0);
+        }
+      }
+      if from > 0 {
+        self.write_bytes(self.block_pos(block), &ptrs);
+        return false;
+      }
+    }
+    self.free_block(block);
+    *sectors -= self.sectors_per_block();
+    true
+  }
+
+  /// All entries of the directory `dir`, free ones included.
+  fn records(&self, dir: u32) -> Vec<Record> {
+    let di = try_!(self.read_inode(dir), Vec::new());
+    let bs = self.block_size;
+    let mut records = Vec::new();
+    let mut buf = alloc::vec![0u8; bs];
+    for index in 0..di.size() / bs {
+      let block = self.block_of(&di, index);
+      if block == 0 { continue; }
+      let pos = self.block_pos(block);
+      self.read_bytes(pos, &mut buf);
+      let (mut offset, mut prev) = (0, None);
+      while offset + 8 <= bs {
+        let rec_len = u16_at(&buf, offset + 4) as usize;
+        // Without `INCOMPAT_FILETYPE`, the name length has 16 bits, the high ones 0.
+        let name_len = buf[offset + 6] as usize;
+        let ino = u32_at(&buf, offset);
+        // A corrupt entry ends the block.
+        if rec_len < 8 || rec_len % 4 != 0 || offset + rec_len > bs || 8 + name_len > rec_len
+          || ino > self.inodes_count
+        {
+          break;
+        }
+        let name = buf[offset + 8..offset + 8 + name_len].to_vec();
+        records.push(Record { pos: pos + offset, prev, ino, rec_len, name });
+        prev = Some(pos + offset);
+        offset += rec_len;
+      }
+    }
+    records
+  }
+
+  /// The entry `name` of the directory `dir`, other than "." and "..".
+  fn find(&self, dir: u32, name: &str) -> Option<Record> {
+    self.records(dir).into_iter().find(|r| r.ino != 0 && !r.is_dot() && r.name == name.as_bytes())
+  }
+
+  /// The inode of the parent of the directory `dir`.
+  fn parent(&self, dir: u32) -> Option<u32> {
+    self.records(dir).into_iter().find(|r| r.name == b"..").map(|r| r.ino)
+  }
+
+  fn is_empty(&self, dir: u32) -> bool {
+    self.records(dir).iter().all(|r| r.ino == 0 || r.is_dot())
+  }
+
+  fn write_record(&self, pos: usize, ino: u32, rec_len: usize, name: &[u8], mode: u32) {
+    let mut raw = alloc::vec![0u8; rec_size(name.len())];
+    set_u32(&mut raw, 0, ino);
+    set_u16(&mut raw, 4, rec_len as u16);
+    raw[6] = name.len() as u8;
+    raw[7] = if self.filetype { file_type(mode) } else { 0 };
+    raw[8..8 + name.len()].copy_from_slice(name);
+    self.write_bytes(pos, &raw);
+  }
+
+  /// Add the entry `name` for inode `ino` of type `mode` to the directory `dir`, in the first
+  /// space large enough or in a new block.
+  fn add_entry(&self, dir: u32, name: &str, ino: u32, mode: u32) -> bool {
+    let need = rec_size(name.len());
+    let space = self.records(dir).into_iter().find_map(|r| {
+      let used = if r.ino == 0 { 0 } else { rec_size(r.name.len()) };
+      (r.rec_len >= used + need).then(|| (r, used))
+    });
+    let mut di = try_!(self.read_inode(dir), false);
+    let added = match space {
+      Some((r, used)) => {
+        if used > 0 { self.write_bytes(r.pos + 4, &(used as u16).to_le_bytes()); }
+        self.write_record(r.pos + used, ino, r.rec_len - used, name.as_bytes(), mode);
+        true
+      }
+      None => {
+        let index = di.size() / self.block_size;
+        match self.map(&mut di, index, Some(self.group_start(dir))) {
+          Some(block) => {
+            let pos = self.block_pos(block);
+            self.write_record(pos, ino, self.block_size, name.as_bytes(), mode);
+            di.set_size(di.size() + self.block_size);
+            true
+          }
+          None => false,
+        }
+      }
+    };
+    if added {
+      // Its hash tree would not have the new entry.
+      di.flags &= !INDEX_FL;
+      di.touch();
+    }
+    // Indirect blocks may have been allocated, even if the block was not.
+    self.write_inode(dir, &di);
+    added
+  }
+
+  /// Remove the entry `r` of the directory `dir`, whose space goes to the previous entry.
+  fn remove_entry(&self, dir: u32, r: &Record) {
+    match r.prev {
+      Some(prev) => {
+        let mut rec_len = [0u8; 2];
+        self.read_bytes(prev + 4, &mut rec_len);
+        let rec_len = u16::from_le_bytes(rec_len) as usize + r.rec_len;
+        self.write_bytes(prev + 4, &(rec_len as u16).to_le_bytes());
+      }
+      None => self.write_bytes(r.pos, &0u32.to_le_bytes()),
+    }
+    self.touch_inode(dir);
+  }
+
+  /// Make the entry `r` of the directory `dir` refer to inode `ino` of type `mode`.
+  fn replace_entry(&self, dir: u32, r: &Record, ino: u32, mode: u32) {
+    self.write_bytes(r.pos, &ino.to_le_bytes());
+    if self.filetype { self.write_bytes(r.pos + 7, &[file_type(mode)]); }
+    self.touch_inode(dir);
+  }
+
+  /// Update the times of inode `ino`, a directory whose entries changed.
+  fn touch_inode(&self, ino: u32) {
+    let mut di = try_!(self.read_inode(ino), ());
+    di.touch();
+    self.write_inode(ino, &di);
+  }
+
+  /// Add `delta` to the links of inode `ino`.
+  fn add_links(&self, ino: u32, delta: i16) {
+    let mut di = try_!(self.read_inode(ino), ());
+    di.links = (di.links as i16 + delta) as u16;
+    di.ctime = rtc::now() as u32;
+    self.write_inode(ino, &di);
+  }
+
+  /// Remove a link to inode `ino`, whose entry is gone, and all of them for a directory, which
+  /// has one from itself. It is freed without links, once it is closed.
+  fn unlink_inode(&self, ino: u32) {
+    let mut di = try_!(self.read_inode(ino), ());
+    di.links = if di.kind() == S_IFDIR { 0 } else { di.links.saturating_sub(1) };
+    di.ctime = rtc::now() as u32;
+    self.write_inode(ino, &di);
+    if di.links > 0 { return; }
+    match (*self.inodes).get(&ino).and_then(|i| i.upgrade()) {
+      Some(inode) => {
+        *inode.removed.get() = true;
+        self.inodes.get().remove(&ino);
+      }
+      None => self.free_inode(ino),
+    }
+  }
+
+  /// The inode `ino`, shared with those already open.
+  fn inode(self: &Arc<Self>, ino: u32) -> Arc<Ext2Inode> {
+    if let Some(inode) = (*self.inodes).get(&ino).and_then(|i| i.upgrade()) { return inode; }
+    let inode = Arc::new(Ext2Inode { fs: self.clone(), ino, removed: Cell::new(false) });
+    self.inodes.get().insert(ino, Arc::downgrade(&inode));
+    inode
+  }
+}
+
+/// A file or directory of an ext2 file system, whose data is in its inode on the device.
+pub struct Ext2Inode {
+  fs: Arc<Ext2>,
+  ino: u32,
+  /// Whether its last link was removed while it was open, so that it is freed once closed.
+  removed: Cell<bool>,
+}
+
+/// ext2 on a block device, with its root directory.
+struct Ext2Fs(Arc<Ext2Inode>);
+
+/// Open the ext2 file system on the block device `source`.
+pub fn open(source: &str) -> Option<Arc<dyn FileSystem>> {
+  let device = block_device(source)?;
+  let _fs = enter_fs();
+  // The device is read directly until it is known to hold ext2, so that nothing is cached
+  // otherwise.
+  let mut sb = [0u8; SUPER_BLOCK_SIZE];
+  for (i, half) in sb.chunks_mut(BLOCK_SZ).enumerate() {
+    device.read_block(SUPER_BLOCK_POS / BLOCK_SZ + i, half);
+  }
+  let log_block_size = u32_at(&sb, 24);
+  if u16_at(&sb, 56) != MAGIC || log_block_size > 2 { return None; }
+  let block_size = 1024 << log_block_size;
+  let (blocks_count, first_data_block) = (u32_at(&sb, 4), u32_at(&sb, 20));
+  let (blocks_per_group, inodes_per_group) = (u32_at(&sb, 32), u32_at(&sb, 40));
+  let inodes_count = u32_at(&sb, 0);
+  let rev_level = u32_at(&sb, 76);
+  let (inode_size, first_ino, incompat, ro_compat) = match rev_level {
+    0 => (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INO, 0, 0),
+    _ => (u16_at(&sb, 88) as usize, u32_at(&sb, 84), u32_at(&sb, 96), u32_at(&sb, 100)),
+  };
+  let max_per_group = block_size as u32 * 8;
+  let device_size = device.num_blocks() * BLOCK_SZ;
+  if incompat & !INCOMPAT_FILETYPE != 0 || blocks_count <= first_data_block
+    || !(1..=max_per_group).contains(&blocks_per_group)
+    || !(1..=max_per_group).contains(&inodes_per_group)
+    || !inode_size.is_power_of_two() || !(GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size)
+    || device_size != 0 && blocks_count as usize * block_size > device_size
+  {
+    return None;
+  }
+  let count = (blocks_count - first_data_block + blocks_per_group - 1) / blocks_per_group;
+  // Every inode has to be in a group.
+  if !(ROOT_INO as u64..=count as u64 * inodes_per_group as u64).contains(&(inodes_count as u64)) {
+    return None;
+  }
+  let table_blocks = (count as usize * GROUP_DESC_SIZE + BLOCK_SZ - 1) / BLOCK_SZ;
+  let mut table = alloc::vec![0u8; table_blocks * BLOCK_SZ];
+  let start = (first_data_block as usize + 1) * block_size / BLOCK_SZ;
+  device.read_blocks(start, &mut table);
+  let groups = table.chunks(GROUP_DESC_SIZE).take(count as usize).map(|desc| Group {
+    block_bitmap: u32_at(desc, 0),
+    inode_bitmap: u32_at(desc, 4),
+    inode_table: u32_at(desc, 8),
+    free_blocks: u16_at(desc, 12),
+    free_inodes: u16_at(desc, 14),
+    used_dirs: u16_at(desc, 16),
+  }).collect();
+  let supported = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+  let fs = Arc::new(Ext2 {
+    device,
+    block_size,
+    blocks_count,
+    first_data_block,
+    blocks_per_group,
+    inodes_per_group,
+    inodes_count,
+    inode_size,
+    first_ino,
+    rev_level,
+    filetype: incompat & INCOMPAT_FILETYPE != 0,
+    read_only: ro_compat & !supported != 0,
+    ro_compat: Cell::new(ro_compat),
+    groups: Cell::new(groups),
+    inodes: Cell::new(BTreeMap::new()),
+    orphans: Cell::new(Vec::new()),
+  });
+  let root = fs.inode(ROOT_INO);
+  if fs.read_inode(ROOT_INO)?.kind() != S_IFDIR { return None; }
+  if !fs.read_only {
+    // Count the mount, and mark the file system as in use until it is unmounted.
+    set_u32(&mut sb, 44, rtc::now() as u32);
+    let mounts = u16_at(&sb, 52).wrapping_add(1);
+    set_u16(&mut sb, 52, mounts);
+    fs.write_bytes(SUPER_BLOCK_POS, &sb);
+    fs.write_super(false);
+  }
+  Some(Arc::new(Ext2Fs(root)))
+}
+
+impl FileSystem for Ext2Fs {
+  fn name(&self) -> &'static str { "ext2" }
+  fn root(&self) -> Arc<dyn Inode> { self.0.clone() }
+  fn sync(&self) {
+    let fs = &self.0.fs;
+    let _fs = fs.enter();
+    if !fs.read_only { fs.write_super(false); }
+    block_cache_sync_all(&fs.device);
+    fs.device.flush();
+  }
+}
+
+impl Drop for Ext2Fs {
+  fn drop(&mut self) {
+    let fs = &self.0.fs;
+    let _fs = fs.enter();
+    if !fs.read_only { fs.write_super(true); }
+    block_cache_clear(&fs.device);
+    fs.device.flush();
+  }
+}
+
+impl Ext2Inode {
+  /// Its disk inode. Open inodes have numbers from directory entries, which are checked, but
+  /// one of no type would make every operation fail otherwise.
+  fn disk(&self) -> DiskInode { self.fs.read_inode(self.ino).unwrap_or_default() }
+
+  fn is_dir(&self) -> bool { self.disk().kind() == S_IFDIR }
+
+  /// Write `buf` at `offset` of the file `di`, allocating blocks as needed. Return the bytes
+  /// written, fewer if the volume is full.
+  fn write(&self, di: &mut DiskInode, offset: usize, buf: &[u8]) -> usize {
+    let fs = &self.fs;
+    let bs = fs.block_size;
+    let end = offset.saturating_add(buf.len()).min(fs.max_size());
+    // Blocks are allocated after those before them if there are.
+    let mut goal = match fs.block_of(di, (offset / bs).saturating_sub(1)) {
+      0 => fs.group_start(self.ino),
+      block => block + 1,
+    };
+    let mut pos = offset;
+    while pos < end {
+      let (index, start) = (pos / bs, pos % bs);
+      let n = (bs - start).min(end - pos);
+      // Out of space, write what fits.
+      let block = match fs.map(di, index, Some(goal)) {
+        Some(block) => block,
+        None => break,
+      };
+      fs.write_bytes(fs.block_pos(block) + start, &buf[pos - offset..pos - offset + n]);
+      fs.write_back_if_needed();
+      goal = block + 1;
+      pos += n;
+    }
+    if pos > di.size() { fs.set_size(di, pos); }
+    di.touch();
+    pos.saturating_sub(offset)
+  }
+
+  /// Create a file of type and permissions `mode` named `name` in this directory, whose
+  /// contents `init` sets up.
+  fn add(
+    &self, name: &str, mode: u32, init: impl FnOnce(&Ext2, u32, &mut DiskInode) -> Option<()>,
+  ) -> Option<Arc<Ext2Inode>> {
+    let fs = &self.fs;
+    let dir = self.disk();
+    if fs.read_only || dir.kind() != S_IFDIR || !is_valid_name(name)
+      || fs.find(self.ino, name).is_some()
+    {
+      return None;
+    }
+    let is_dir = mode & S_IFMT == S_IFDIR;
+    if is_dir && dir.links >= LINK_MAX { return None; }
+    let ino = fs.alloc_inode(self.ino, is_dir)?;
+    let mut di = DiskInode { mode: mode as u16, links: 1, ..DiskInode::default() };
+    di.touch();
+    di.atime = di.mtime;
+    let done = init(fs, ino, &mut di).is_some();
+    fs.write_inode(ino, &di);
+    if !done || !fs.add_entry(self.ino, name, ino, mode) {
+      fs.free_inode(ino);
+      return None;
+    }
+    // The ".." of a new directory links to this one.
+    if is_dir { fs.add_links(self.ino, 1); }
+    Some(fs.inode(ino))
+  }
+}
+
+impl Drop for Ext2Inode {
+  fn drop(&mut self) {
+    // Removed while open. The file system may be in use, so free the inode later.
+    if *self.removed {
+      self.fs.orphans.get().push(self.ino);
+      return;
+    }
+    let inodes = self.fs.inodes.get();
+    if inodes.get(&self.ino).map_or(false, |i| i.strong_count() == 0) { inodes.remove(&self.ino); }
+  }
+}
+
+impl Inode for Ext2Inode {
+  fn stat(&self) -> Stat {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    let di = self.disk();
+    // Device numbers are in the first block pointer, or the second one if they are large.
+    let rdev = match di.kind() {
+      S_IFCHR | S_IFBLK if di.block[0] != 0 => di.block[0],
+      S_IFCHR | S_IFBLK => di.block[1],
+      _ => 0,
+    };
+    Stat {
+      ino: self.ino as _,
+      nlink: di.links as _,
+      mode: di.mode as _,
+      uid: di.uid as u32 | (di.uid_high as u32) << 16,
+      gid: di.gid as u32 | (di.gid_high as u32) << 16,
+      rdev: rdev as _,
+      size: di.size() as _,
+      blksize: fs.block_size as _,
+      blocks: di.sectors as _,
+      atime: di.atime as _,
+      mtime: di.mtime as _,
+      ctime: di.ctime as _,
+      ..Stat::default()
+    }
+  }
+  fn is_dir(&self) -> bool {
+    let _fs = self.fs.enter();
+    Ext2Inode::is_dir(self)
+  }
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    let di = self.disk();
+    if di.kind() != S_IFREG { return 0; }
+    let end = di.size().min(offset.saturating_add(buf.len()));
+    let bs = fs.block_size;
+    let mut pos = offset;
+    while pos < end {
+      let (index, start) = (pos / bs, pos % bs);
+      let n = (bs - start).min(end - pos);
+      let data = &mut buf[pos - offset..pos - offset + n];
+      match fs.block_of(&di, index) {
+        0 => data.fill(0),
+        block => fs.read_bytes(fs.block_pos(block) + start, data),
+      }
+      pos += n;
+    }
+    end.saturating_sub(offset)
+  }
+  fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    let mut di = self.disk();
+    if fs.read_only || di.kind() != S_IFREG { return 0; }
+    let written = self.write(&mut di, offset, buf);
+    fs.write_inode(self.ino, &di);
+    written
+  }
+  fn truncate(&self, len: usize) -> bool {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    let mut di = self.disk();
+    if fs.read_only || di.kind() != S_IFREG || len > fs.max_size() { return false; }
+    let bs = fs.block_size;
+    if len < di.size() {
+      fs.truncate_blocks(&mut di, (len + bs - 1) / bs);
+      // Clear the end of the last block, which the file may grow over again.
+      let block = fs.block_of(&di, len / bs);
+      if len % bs != 0 && block != 0 {
+        fs.write_bytes(fs.block_pos(block) + len % bs, &alloc::vec![0; bs - len % bs]);
+      }
+    }
+    // Growing leaves a hole.
+    fs.set_size(&mut di, len);
+    di.touch();
+    fs.write_inode(self.ino, &di);
+    true
+  }
+  fn sync(&self) {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    block_cache_sync_all(&fs.device);
+    fs.device.flush();
+  }
+  fn readlink(&self) -> Option<String> {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    let di = self.disk();
+    if di.kind() != S_IFLNK { return None; }
+    let target: Vec<u8> = if di.has_blocks(fs.sectors_per_block()) {
+      let mut target = alloc::vec![0; di.size().min(fs.block_size)];
+      fs.read_bytes(fs.block_pos(fs.block_of(&di, 0)), &mut target);
+      target
+    } else {
+      let bytes = di.block.iter().flat_map(|b| b.to_le_bytes());
+      bytes.take(di.size().min(FAST_SYMLINK_SIZE)).collect()
+    };
+    Some(String::from_utf8_lossy(&target).into_owned())
+  }
+  fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    if !Ext2Inode::is_dir(self) { return None; }
+    Some(fs.inode(fs.find(self.ino, name)?.ino))
+  }
+  fn readdir(&self) -> Vec<String> {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    if !Ext2Inode::is_dir(self) { return Vec::new(); }
+    fs.records(self.ino).into_iter().filter(|r| r.ino != 0 && !r.is_dot())
+      .map(|r| String::from_utf8_lossy(&r.name).into_owned()).collect()
+  }
+  fn create(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let _fs = self.fs.enter();
+    Some(self.add(name, S_IFREG | 0o644, |_, _, _| Some(()))?)
+  }
+  fn mkdir(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let _fs = self.fs.enter();
+    let parent = self.ino;
+    let inode = self.add(name, S_IFDIR | 0o755, |fs, ino, di| {
+      let block = fs.map(di, 0, Some(fs.group_start(ino)))?;
+      let pos = fs.block_pos(block);
+      fs.write_record(pos, ino, 12, b".", S_IFDIR);
+      fs.write_record(pos + 12, parent, fs.block_size - 12, b"..", S_IFDIR);
+      di.set_size(fs.block_size);
+      di.links = 2;
+      Some(())
+    });
+    Some(inode?)
+  }
+  fn symlink(&self, name: &str, target: &str) -> bool {
+    let _fs = self.fs.enter();
+    if target.is_empty() || target.len() > self.fs.block_size { return false; }
+    let inode = self.add(name, S_IFLNK | 0o777, |fs, ino, di| {
+      if target.len() < FAST_SYMLINK_SIZE {
+        let mut bytes = [0u8; FAST_SYMLINK_SIZE];
+        bytes[..target.len()].copy_from_slice(target.as_bytes());
+        for (i, b) in bytes.chunks(4).enumerate() {
+          di.block[i] = u32_at(b, 0);
+        }
+      } else {
+        let block = fs.map(di, 0, Some(fs.group_start(ino)))?;
+        fs.write_bytes(fs.block_pos(block), target.as_bytes());
+      }
+      di.set_size(target.len());
+      Some(())
+    });
+    inode.is_some()
+  }
+  fn unlink(&self, name: &str) -> bool {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    if fs.read_only || !Ext2Inode::is_dir(self) { return false; }
+    let r = try_!(fs.find(self.ino, name), false);
+    if try_!(fs.read_inode(r.ino), false).kind() == S_IFDIR { return false; }
+    fs.remove_entry(self.ino, &r);
+    fs.unlink_inode(r.ino);
+    true
+  }
+  fn rmdir(&self, name: &str) -> bool {
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    if fs.read_only || !Ext2Inode::is_dir(self) { return false; }
+    let r = try_!(fs.find(self.ino, name), false);
+    if try_!(fs.read_inode(r.ino), false).kind() != S_IFDIR || !fs.is_empty(r.ino) {
+      return false;
+    }
+    fs.remove_entry(self.ino, &r);
+    fs.add_links(self.ino, -1);
+    fs.unlink_inode(r.ino);
+    true
+  }
+  fn link(&self, name: &str, inode: &dyn Inode) -> bool {
+    let inode = try_!(inode.as_any().downcast_ref::<Ext2Inode>(), false);
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    let di = inode.disk();
+    if fs.read_only || !Arc::ptr_eq(fs, &inode.fs) || !Ext2Inode::is_dir(self)
+      || di.kind() == S_IFDIR || di.links >= LINK_MAX || !is_valid_name(name)
+      || fs.find(self.ino, name).is_some()
+    {
+      return false;
+    }
+    if !fs.add_entry(self.ino, name, inode.ino, di.mode as u32) { return false; }
+    fs.add_links(inode.ino, 1);
+    true
+  }
+  fn rename(&self, old_name: &str, new_dir: &dyn Inode, new_name: &str) -> bool {
+    let new_dir = try_!(new_dir.as_any().downcast_ref::<Ext2Inode>(), false);
+    let fs = &self.fs;
+    let _fs = fs.enter();
+    if fs.read_only || !Arc::ptr_eq(fs, &new_dir.fs) || !is_valid_name(new_name)
+      || !Ext2Inode::is_dir(self) || !Ext2Inode::is_dir(new_dir)
+    {
+      return false;
+    }
+    let (src, dst) = (self.ino, new_dir.ino);
+    let r = try_!(fs.find(src, old_name), false);
+    let mode = try_!(fs.read_inode(r.ino), false).mode as u32;
+    let is_dir = mode & S_IFMT == S_IFDIR;
+    if is_dir {
+      // A directory cannot move into itself or below.
+      let mut d = dst;
+      while d != ROOT_INO {
+        if d == r.ino { return false; }
+        d = try_!(fs.parent(d).filter(|&p| p != d), false);
+      }
+    }
+    match fs.find(dst, new_name) {
+      // Both names are links to the same file.
+      Some(old) if old.ino == r.ino => return true,
+      Some(old) => {
+        // A directory only replaces an empty directory, and a file only a file.
+        let old_is_dir = try_!(fs.read_inode(old.ino), false).kind() == S_IFDIR;
+        if old_is_dir != is_dir || old_is_dir && !fs.is_empty(old.ino) { return false; }
+        fs.replace_entry(dst, &old, r.ino, mode);
+        if old_is_dir { fs.add_links(dst, -1); }
+        fs.unlink_inode(old.ino);
+      }
+      None => {
+        if !fs.add_entry(dst, new_name, r.ino, mode) { return false; }
+      }
+    }
+    // Adding the entry to the same directory may have changed the old one, which a corrupt
+    // block may have made unreadable since.
+    let r = try_!(fs.find(src, old_name), false);
+    fs.remove_entry(src, &r);
+    if is_dir && src != dst {
+      let dotdot = fs.records(r.ino).into_iter().find(|r| r.name == b"..");
+      if let Some(dotdot) = dotdot { fs.replace_entry(r.ino, &dotdot, dst, S_IFDIR); }
+      fs.add_links(src, -1);
+      fs.add_links(dst, 1);
+    }
+    true
+  }
+  fn as_any(&self) -> &dyn Any { self }
+}
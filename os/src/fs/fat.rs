@@ -0,0 +1,868 @@
+use crate::{*, drivers::{block_device, rtc}};
+use super::{*, easyfs::{enter_fs, FsGuard}};
+
+use alloc::sync::{Arc, Weak};
+use core::any::Any;
+use easy_fs::BLOCK_SZ;
+
+const ENTRY_SIZE: usize = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Attributes of an entry holding a part of a long name.
+const ATTR_LONG_NAME: u8 = 0x0F;
+/// Flag of the ordinal of the long name entry holding the end of the name.
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Units of a long name in each entry, at these offsets.
+const LONG_NAME_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// First byte of a free entry, and of one after which all entries are free.
+const DELETED: u8 = 0xE5;
+const END: u8 = 0x00;
+/// Flags of a short name whose base name and extension are shown in lower case.
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+/// FAT entries are 28 bits, the top 4 bits are reserved.
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const FREE_CLUSTER: u32 = 0;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+const FS_INFO_LEAD_SIG: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIG: u32 = 0x6141_7272;
+/// Free count of the FS information sector when it is not known.
+const UNKNOWN_FREE: u32 = 0xFFFF_FFFF;
+
+/// Date and time of FAT directory entries, which have 2-second resolution and start at 1980.
+fn to_fat_time(secs: u64) -> (u16, u16) {
+  let (year, month, day) = rtc::civil_from_days(secs / 86400);
+  if year < 1980 { return ((1 << 5) | 1, 0); }
+  let rem = secs % 86400;
+  let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+  let time = ((rem / 3600) << 11 | (rem / 60 % 60) << 5 | (rem % 60 / 2)) as u16;
+  (date, time)
+}
+
+fn from_fat_time(date: u16, time: u16) -> u64 {
+  let (date, time) = (date as u64, time as u64);
+  let (year, month, day) = (1980 + (date >> 9), date >> 5 & 0xF, date & 0x1F);
+  if month == 0 || day == 0 { return 0; }
+  let (hour, min, sec) = (time >> 11, time >> 5 & 0x3F, (time & 0x1F) * 2);
+  rtc::days_from_civil(year, month, day) * 86400 + hour * 3600 + min * 60 + sec
+}
+
+/// Characters allowed in short names besides letters and digits.
+fn is_short_char(c: u8) -> bool {
+  c.is_ascii_uppercase() || c.is_ascii_digit() || c >= 0x80 || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// The short name `name` stored as is, if it is an upper case 8.3 name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+  let (base, ext) = match name.find('.') {
+    Some(i) => (&name[..i], &name[i + 1..]),
+    None => (name, ""),
+  };
+  let valid = |s: &str, max| s.len() <= max && s.bytes().all(|c| c < 0x80 && is_short_char(c));
+  if base.is_empty() || !valid(base, 8) || !valid(ext, 3) { return None; }
+  let mut short = [b' '; 11];
+  short[..base.len()].copy_from_slice(base.as_bytes());
+  short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+  Some(short)
+}
+
+/// A short name for the long name `name` unlike those in `taken`, such as "LONGNA~1.TXT".
+fn alias(name: &str, taken: &[[u8; 11]]) -> [u8; 11] {
+  let name = name.trim_start_matches('.');
+  let (base, ext) = match name.rfind('.') {
+    Some(i) => (&name[..i], &name[i + 1..]),
+    None => (name, ""),
+  };
+  let convert = |s: &str| -> Vec<u8> {
+    s.chars().filter(|&c| c != ' ' && c != '.').map(|c| {
+      let c = if c.is_ascii() { c.to_ascii_uppercase() as u8 } else { b'_' };
+      if is_short_char(c) { c } else { b'_' }
+    }).collect()
+  };
+  let (mut base, ext) = (convert(base), convert(ext));
+  if base.is_empty() { base.push(b'_'); }
+  let mut short = [b' '; 11];
+  let ext = &ext[..ext.len().min(3)];
+  short[8..8 + ext.len()].copy_from_slice(ext);
+  for n in 1.. {
+    let tail = alloc::format!("~{}", n);
+    let len = base.len().min(8 - tail.len());
+    short[..8].fill(b' ');
+    short[..len].copy_from_slice(&base[..len]);
+    short[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+    if !taken.contains(&short) { break; }
+  }
+  short
+}
+
+fn checksum(short: &[u8]) -> u8 {
+  short[..11].iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Whether `name` can be the long name of an entry.
+fn is_valid_name(name: &str) -> bool {
+  !name.is_empty() && name.encode_utf16().count() <= 255 && !name.ends_with(['.', ' '])
+    && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+}
+
+/// A directory entry, with its name from its long name entries if it has them.
+struct DirEntry {
+  name: String,
+  raw: [u8; ENTRY_SIZE],
+  /// Positions on the device of the long name entries and of the short entry, which is last.
+  slots: Vec<usize>,
+}
+
+impl DirEntry {
+  fn pos(&self) -> usize { *self.slots.last().unwrap() }
+  fn attr(&self) -> u8 { self.raw[11] }
+  fn is_dir(&self) -> bool { self.attr() & ATTR_DIRECTORY != 0 }
+  fn cluster(&self) -> u32 { (u16_at(&self.raw, 20) as u32) << 16 | u16_at(&self.raw, 26) as u32 }
+}
+
+/// Name of a short entry, with the case given by its flags.
+fn short_name(raw: &[u8]) -> String {
+  let part = |bytes: &[u8], lower: bool| -> String {
+    let s = bytes.iter().map(|&c| c as char).collect::<String>();
+    let s = s.trim_end_matches(' ');
+    if lower { s.to_ascii_lowercase() } else { String::from(s) }
+  };
+  let mut base = raw[..8].to_vec();
+  if base[0] == 0x05 { base[0] = DELETED; }
+  let mut name = part(&base, raw[12] & LOWER_BASE != 0);
+  let ext = part(&raw[8..11], raw[12] & LOWER_EXT != 0);
+  if !ext.is_empty() {
+    name.push('.');
+    name.push_str(&ext);
+  }
+  name
+}
+
+/// A mounted FAT32 volume.
+struct Fat {
+  device: Arc<dyn BlockDevice>,
+  sectors_per_cluster: usize,
+  /// First sector of the first FAT, and the sectors of each FAT.
+  fat_start: usize,
+  fat_sectors: usize,
+  num_fats: usize,
+  /// First sector of cluster 2, the first data cluster.
+  data_start: usize,
+  /// Number of data clusters.
+  clusters: usize,
+  root_cluster: u32,
+  /// Sector of the FS information sector, 0 if there is none.
+  fs_info: usize,
+  /// Cluster to look for a free one from.
+  next_free: Cell<u32>,
+  /// Whether clusters were allocated or freed since the FS information sector was written.
+  info_dirty: Cell<bool>,
+  /// Sector of the FAT last accessed, relative to `fat_start`, since chains are mostly followed
+  /// in order.
+  fat_cache: Cell<(usize, [u8; BLOCK_SZ])>,
+  /// Open files and directories by the position of their short entry, so that they share one
+  /// inode.
+  inodes: Cell<BTreeMap<usize, Weak<FatInode>>>,
+  /// First clusters of files removed while open and closed since, freed on the next operation.
+  orphans: Cell<Vec<u32>>,
+}
+
+impl Fat {
+  /// Enter the file system, freeing the clusters of removed files closed meanwhile.
+  fn enter(&self) -> FsGuard {
+    let guard = enter_fs();
+    for cluster in core::mem::take(self.orphans.get()) {
+      self.free_chain(cluster);
+    }
+    guard
+  }
+
+  fn cluster_bytes(&self) -> usize { self.sectors_per_cluster * BLOCK_SZ }
+
+  /// Position on the device of the data of `cluster`.
+  fn cluster_pos(&self, cluster: u32) -> usize {
+    (self.data_start + (cluster as usize - 2) * self.sectors_per_cluster) * BLOCK_SZ
+  }
+
+  fn is_data(&self, cluster: u32) -> bool {
+    cluster >= 2 && (cluster as usize) < self.clusters + 2
+  }
+
+  /// Read the bytes at `pos` of the device.
+  fn read_bytes(&self, pos: usize, buf: &mut [u8]) {
+    let mut done = 0;
+    while done < buf.len() {
+      let (sector, offset) = ((pos + done) / BLOCK_SZ, (pos + done) % BLOCK_SZ);
+      let left = buf.len() - done;
+      if offset == 0 && left >= BLOCK_SZ {
+        let n = left / BLOCK_SZ * BLOCK_SZ;
+        self.device.read_blocks(sector, &mut buf[done..done + n]);
+        done += n;
+      } else {
+        let mut block = [0u8; BLOCK_SZ];
+        self.device.read_block(sector, &mut block);
+        let n = (BLOCK_SZ - offset).min(left);
+        buf[done..done + n].copy_from_slice(&block[offset..offset + n]);
+        done += n;
+      }
+    }
+  }
+
+  /// Write the bytes at `pos` of the device, reading the sectors only partly written first.
+  fn write_bytes(&self, pos: usize, buf: &[u8]) {
+    let mut done = 0;
+    while done < buf.len() {
+      let (sector, offset) = ((pos + done) / BLOCK_SZ, (pos + done) % BLOCK_SZ);
+      let left = buf.len() - done;
+      if offset == 0 && left >= BLOCK_SZ {
+        let n = left / BLOCK_SZ * BLOCK_SZ;
+        self.device.write_blocks(sector, &buf[done..done + n]);
+        done += n;
+      } else {
+        let mut block = [0u8; BLOCK_SZ];
+        self.device.read_block(sector, &mut block);
+        let n = (BLOCK_SZ - offset).min(left);
+        block[offset..offset + n].copy_from_slice(&buf[done..done + n]);
+        self.device.write_block(sector, &block);
+        done += n;
+      }
+    }
+  }
+
+  /// Load the FAT sector holding the entry of `cluster` into the cache, return its offset there.
+  fn load_fat(&self, cluster: u32) -> usize {
+    let (sector, offset) = (cluster as usize * 4 / BLOCK_SZ, cluster as usize * 4 % BLOCK_SZ);
+    let cache = self.fat_cache.get();
+    if cache.0 != sector {
+      self.device.read_block(self.fat_start + sector, &mut cache.1);
+      cache.0 = sector;
+    }
+    offset
+  }
+
+  /// The FAT entry of `cluster`: the next cluster of its chain, or a special value.
+  fn next(&self, cluster: u32) -> u32 {
+    let offset = self.load_fat(cluster);
+    u32_at(&self.fat_cache.1, offset) & FAT_MASK
+  }
+
+  /// Set the FAT entry of `cluster` in every copy of the FAT.
+  fn set_next(&self, cluster: u32, next: u32) {
+    let offset = self.load_fat(cluster);
+    let (sector, block) = self.fat_cache.get();
+    let old = u32_at(block, offset);
+    set_u32(block, offset, old & !FAT_MASK | next & FAT_MASK);
+    for i in 0..self.num_fats {
+      self.device.write_block(self.fat_start + i * self.fat_sectors + *sector, block);
+    }
+  }
+
+  /// Clusters of the chain starting at `cluster`, none if it is 0.
+  fn chain(&self, mut cluster: u32) -> Vec<u32> {
+    let mut chain = Vec::new();
+    // A corrupt FAT may link a chain into a loop.
+    while self.is_data(cluster) && chain.len() < self.clusters {
+      chain.push(cluster);
+      cluster = self.next(cluster);
+    }
+    chain
+  }
+
+  /// Allocate a cluster and append it to the chain ending with `last`.
+  fn alloc(&self, last: Option<u32>) -> Option<u32> {
+    let start = *self.next_free as usize - 2;
+    let cluster = (0..self.clusters).map(|i| ((start + i) % self.clusters + 2) as u32)
+      .find(|&c| self.next(c) == FREE_CLUSTER)?;
+    self.set_next(cluster, END_OF_CHAIN);
+    if let Some(last) = last { self.set_next(last, cluster); }
+    *self.next_free.get() = if cluster as usize + 1 < self.clusters + 2 { cluster + 1 } else { 2 };
+    *self.info_dirty.get() = true;
+    Some(cluster)
+  }
+
+  /// Allocate a cluster filled with zeros, as directories need.
+  fn alloc_zero(&self, last: Option<u32>) -> Option<u32> {
+    let cluster = self.alloc(last)?;
+    self.write_bytes(self.cluster_pos(cluster), &alloc::vec![0; self.cluster_bytes()]);
+    Some(cluster)
+  }
+
+  fn free_chain(&self, cluster: u32) {
+    for c in self.chain(cluster) {
+      self.set_next(c, FREE_CLUSTER);
+    }
+    *self.info_dirty.get() = true;
+  }
+
+  fn read_entry(&self, pos: usize) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0; ENTRY_SIZE];
+    self.read_bytes(pos, &mut raw);
+    raw
+  }
+
+  /// Positions and contents of all entry slots of the directory starting at `cluster`.
+  fn slots(&self, cluster: u32) -> Vec<(usize, [u8; ENTRY_SIZE])> {
+    let mut slots = Vec::new();
+    let mut buf = alloc::vec![0; self.cluster_bytes()];
+    for c in self.chain(cluster) {
+      let pos = self.cluster_pos(c);
+      self.read_bytes(pos, &mut buf);
+      for (i, raw) in buf.chunks(ENTRY_SIZE).enumerate() {
+        slots.push((pos + i * ENTRY_SIZE, raw.try_into().unwrap()));
+      }
+    }
+    slots
+  }
+
+  /// Entries of the directory starting at `cluster`, without "." and "..".
+  fn entries(&self, cluster: u32) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    // Long name units collected so far, the ordinal of the next entry and the checksum.
+    let mut long: Vec<u16> = Vec::new();
+    let (mut ord, mut sum) = (0, 0);
+    let mut slots = Vec::new();
+    for (pos, raw) in self.slots(cluster) {
+      match raw[0] {
+        END => break,
+        DELETED => {
+          (ord, long, slots) = (0, Vec::new(), Vec::new());
+          continue;
+        }
+        _ => {}
+      }
+      if raw[11] & 0x3F == ATTR_LONG_NAME {
+        let units = LONG_NAME_OFFSETS.iter().map(|&i| u16_at(&raw, i));
+        if raw[0] & LAST_LONG_ENTRY != 0 {
+          long.clear();
+          slots.clear();
+          ord = raw[0] & !LAST_LONG_ENTRY;
+          sum = raw[13];
+        } else if raw[0] != ord || raw[13] != sum {
+          (ord, long, slots) = (0, Vec::new(), Vec::new());
+          continue;
+        }
+        // Entries hold the parts of the name from the last one to the first.
+        let part: Vec<u16> = units.take_while(|&u| u != 0).collect();
+        long.splice(0..0, part);
+        slots.push(pos);
+        ord = ord.wrapping_sub(1);
+        continue;
+      }
+      let has_long = ord == 0 && !slots.is_empty() && checksum(&raw) == sum;
+      if raw[11] & ATTR_VOLUME_ID == 0 && raw[0] != b'.' {
+        let name = if has_long {
+          char::decode_utf16(long.iter().cloned()).map(|c| c.unwrap_or('\u{FFFD}')).collect()
+        } else {
+          short_name(&raw)
+        };
+        if !has_long { slots.clear(); }
+        slots.push(pos);
+        entries.push(DirEntry { name, raw, slots: core::mem::take(&mut slots) });
+      }
+      slots.clear();
+      ord = 0;
+    }
+    entries
+  }
+
+  /// The entry `name` of the directory starting at `cluster`, whose case does not matter.
+  fn find(&self, cluster: u32, name: &str) -> Option<DirEntry> {
+    self.entries(cluster).into_iter().find(|e| e.name.eq_ignore_ascii_case(name))
+  }
+
+  /// Add the entry `name` to the directory starting at `cluster`, with long name entries unless
+  /// it is a short name. `raw` gives all but the name. Return the position of the short entry.
+  fn add_entry(&self, cluster: u32, name: &str, mut raw: [u8; ENTRY_SIZE]) -> Option<usize> {
+    if !is_valid_name(name) || self.find(cluster, name).is_some() { return None; }
+    let mut slots = self.slots(cluster);
+    let mut entries = Vec::new();
+    match exact_short_name(name) {
+      Some(short) => raw[..11].copy_from_slice(&short),
+      None => {
+        let taken: Vec<[u8; 11]> = slots.iter().map(|s| s.1[..11].try_into().unwrap()).collect();
+        raw[..11].copy_from_slice(&alias(name, &taken));
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let count = (units.len() + 12) / 13;
+        for ord in (1..=count).rev() {
+          let mut long = [0u8; ENTRY_SIZE];
+          long[0] = ord as u8 | if ord == count { LAST_LONG_ENTRY } else { 0 };
+          long[11] = ATTR_LONG_NAME;
+          long[13] = checksum(&raw);
+          for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+            // The name ends with a 0 unless it fills the entry, then the rest is padding.
+            let unit = match (ord - 1) * 13 + i {
+              j if j < units.len() => units[j],
+              j if j == units.len() => 0,
+              _ => 0xFFFF,
+            };
+            set_u16(&mut long, offset, unit);
+          }
+          entries.push(long);
+        }
+      }
+    }
+    entries.push(raw);
+    // Find a run of free slots, growing the directory until there is one.
+    let is_free = |raw: &[u8]| raw[0] == END || raw[0] == DELETED;
+    let start = loop {
+      let run = slots.windows(entries.len()).position(|w| w.iter().all(|s| is_free(&s.1)));
+      if let Some(i) = run { break i; }
+      let last = *self.chain(cluster).last().unwrap();
+      let new = self.alloc_zero(Some(last))?;
+      let pos = self.cluster_pos(new);
+      slots.extend((0..self.cluster_bytes() / ENTRY_SIZE).map(|i| (pos + i * ENTRY_SIZE, [0; 32])));
+    };
+    for (slot, entry) in slots[start..].iter().zip(&entries) {
+      self.write_bytes(slot.0, entry);
+    }
+    Some(slots[start + entries.len() - 1].0)
+  }
+
+  /// Mark the entry and its long name entries free.
+  fn remove_entry(&self, entry: &DirEntry) {
+    for &pos in &entry.slots {
+      self.write_bytes(pos, &[DELETED]);
+    }
+  }
+
+  /// The inode of `entry`, shared with those already open.
+  fn inode(self: &Arc<Self>, entry: &DirEntry) -> Arc<FatInode> {
+    let pos = entry.pos();
+    if let Some(inode) = (*self.inodes).get(&pos).and_then(|i| i.upgrade()) { return inode; }
+    let raw = &entry.raw;
+    let inode = FatInode::new(self, Some(pos), entry.attr(), entry.cluster(), u32_at(raw, 28));
+    *inode.atime.get() = from_fat_time(u16_at(raw, 18), 0);
+    *inode.mtime.get() = from_fat_time(u16_at(raw, 24), u16_at(raw, 22));
+    self.inodes.get().insert(pos, Arc::downgrade(&inode));
+    inode
+  }
+
+  /// The first cluster of the parent of the directory starting at `cluster`, 0 for the root.
+  fn parent(&self, cluster: u32) -> u32 {
+    let raw = self.read_entry(self.cluster_pos(cluster) + ENTRY_SIZE);
+    (u16_at(&raw, 20) as u32) << 16 | u16_at(&raw, 26) as u32
+  }
+}
+
+/// A file or directory of a FAT32 volume.
+pub struct FatInode {
+  fat: Arc<Fat>,
+  /// Position of the short entry on the device, None for the root and for files removed.
+  entry: Cell<Option<usize>>,
+  attr: u8,
+  /// First cluster, 0 for an empty file.
+  cluster: Cell<u32>,
+  size: Cell<u32>,
+  atime: Cell<u64>,
+  mtime: Cell<u64>,
+}
+
+/// FAT32 on a block device, with its root directory.
+struct FatFs(Arc<FatInode>);
+
+/// Open the FAT32 file system on the block device `source`.
+pub fn open(source: &str) -> Option<Arc<dyn FileSystem>> {
+  let device = block_device(source)?;
+  let _fs = enter_fs();
+  let mut boot = [0u8; BLOCK_SZ];
+  device.read_block(0, &mut boot);
+  let sectors_per_cluster = boot[13] as usize;
+  let (reserved, num_fats) = (u16_at(&boot, 14) as usize, boot[16] as usize);
+  let fat_sectors = u32_at(&boot, 36) as usize;
+  // FAT32 has no fixed root directory and no 16-bit FAT size or sector count.
+  let fat32 = u16_at(&boot, 17) == 0 && u16_at(&boot, 22) == 0 && u16_at(&boot, 19) == 0;
+  if boot[510..] != [0x55, 0xAA] || !fat32 || u16_at(&boot, 11) as usize != BLOCK_SZ
+    || !sectors_per_cluster.is_power_of_two() || reserved == 0 || num_fats == 0
+  {
+    return None;
+  }
+  let data_start = reserved + num_fats * fat_sectors;
+  let total = u32_at(&boot, 32) as usize;
+  // The FAT may have entries for fewer clusters than the volume has.
+  let clusters = (total.checked_sub(data_start)? / sectors_per_cluster)
+    .min((fat_sectors * BLOCK_SZ / 4).checked_sub(2)?);
+  let fs_info = match u16_at(&boot, 48) as usize {
+    sector if sector != 0 && sector < reserved => sector,
+    _ => 0,
+  };
+  let mut fat = Fat {
+    device,
+    sectors_per_cluster,
+    fat_start: reserved,
+    fat_sectors,
+    num_fats,
+    data_start,
+    clusters,
+    root_cluster: u32_at(&boot, 44),
+    fs_info,
+    next_free: Cell::new(2),
+    info_dirty: Cell::new(false),
+    fat_cache: Cell::new((usize::MAX, [0; BLOCK_SZ])),
+    inodes: Cell::new(BTreeMap::new()),
+    orphans: Cell::new(Vec::new()),
+  };
+  if !fat.is_data(fat.root_cluster) { return None; }
+  if fs_info != 0 {
+    fat.device.read_block(fs_info, &mut boot);
+    let hint = u32_at(&boot, 492);
+    if u32_at(&boot, 0) == FS_INFO_LEAD_SIG && fat.is_data(hint) { *fat.next_free = hint; }
+  }
+  let fat = Arc::new(fat);
+  let root = FatInode::new(&fat, None, ATTR_DIRECTORY, fat.root_cluster, 0);
+  Some(Arc::new(FatFs(root)))
+}
+
+impl FileSystem for FatFs {
+  fn name(&self) -> &'static str { "vfat" }
+  fn root(&self) -> Arc<dyn Inode> { self.0.clone() }
+  fn sync(&self) {
+    let fat = &self.0.fat;
+    let _fs = fat.enter();
+    if *fat.info_dirty && fat.fs_info != 0 {
+      let mut info = [0u8; BLOCK_SZ];
+      fat.device.read_block(fat.fs_info, &mut info);
+      if u32_at(&info, 0) == FS_INFO_LEAD_SIG && u32_at(&info, 484) == FS_INFO_STRUCT_SIG {
+        // Counting the free clusters would read the whole FAT.
+        set_u32(&mut info, 488, UNKNOWN_FREE);
+        set_u32(&mut info, 492, *fat.next_free);
+        fat.device.write_block(fat.fs_info, &info);
+      }
+      *fat.info_dirty.get() = false;
+    }
+    fat.device.flush();
+  }
+}
+
+impl FatInode {
+  fn new(fat: &Arc<Fat>, entry: Option<usize>, attr: u8, cluster: u32, size: u32) -> Arc<Self> {
+    Arc::new(FatInode {
+      fat: fat.clone(),
+      entry: Cell::new(entry),
+      attr,
+      cluster: Cell::new(cluster),
+      size: Cell::new(if attr & ATTR_DIRECTORY != 0 { 0 } else { size }),
+      atime: zero(),
+      mtime: zero(),
+    })
+  }
+
+  fn is_dir(&self) -> bool { self.attr & ATTR_DIRECTORY != 0 }
+
+  /// Write the first cluster, size and times back to the directory entry.
+  fn update_entry(&self) {
+    let pos = try_!(*self.entry, ());
+    let mut raw = self.fat.read_entry(pos);
+    set_u16(&mut raw, 20, (*self.cluster >> 16) as u16);
+    set_u16(&mut raw, 26, *self.cluster as u16);
+    set_u32(&mut raw, 28, *self.size);
+    let (date, time) = to_fat_time(*self.mtime);
+    set_u16(&mut raw, 22, time);
+    set_u16(&mut raw, 24, date);
+    set_u16(&mut raw, 18, to_fat_time(*self.atime).0);
+    self.fat.write_bytes(pos, &raw);
+  }
+
+  fn touch(&self) {
+    *self.mtime.get() = rtc::now();
+    *self.atime.get() = *self.mtime;
+  }
+
+  /// Write `buf` at `offset`, allocating clusters as needed. Return the bytes written, fewer if
+  /// the volume is full.
+  fn write(&self, offset: usize, buf: &[u8]) -> usize {
+    let fat = &self.fat;
+    let size = *self.size as usize;
+    if offset > size {
+      // Clear the gap, whose clusters may hold old data.
+      let zeros = alloc::vec![0; fat.cluster_bytes()];
+      let mut pos = size;
+      while pos < offset {
+        let n = self.write(pos, &zeros[..zeros.len().min(offset - pos)]);
+        if n == 0 { return 0; }
+        pos += n;
+      }
+    }
+    let end = offset.saturating_add(buf.len()).min(u32::MAX as usize);
+    if end <= offset { return 0; }
+    let cluster_bytes = fat.cluster_bytes();
+    let mut chain = fat.chain(*self.cluster);
+    while chain.len() * cluster_bytes < end {
+      // Out of space, write what fits.
+      let cluster = match fat.alloc(chain.last().cloned()) {
+        Some(cluster) => cluster,
+        None => break,
+      };
+      if chain.is_empty() { *self.cluster.get() = cluster; }
+      chain.push(cluster);
+    }
+    let end = end.min(chain.len() * cluster_bytes);
+    if end <= offset { return 0; }
+    let mut pos = offset;
+    while pos < end {
+      let (i, start) = (pos / cluster_bytes, pos % cluster_bytes);
+      let n = (cluster_bytes - start).min(end - pos);
+      fat.write_bytes(fat.cluster_pos(chain[i]) + start, &buf[pos - offset..pos - offset + n]);
+      pos += n;
+    }
+    *self.size.get() = size.max(end) as u32;
+    self.touch();
+    self.update_entry();
+    end - offset
+  }
+
+  /// Create the entry `name` in this directory for a new file or directory.
+  fn add(&self, name: &str, attr: u8, cluster: u32) -> Option<Arc<FatInode>> {
+    let fat = &self.fat;
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[11] = attr;
+    set_u16(&mut raw, 20, (cluster >> 16) as u16);
+    set_u16(&mut raw, 26, cluster as u16);
+    let (date, time) = to_fat_time(rtc::now());
+    for (t, d) in [(14, 16), (22, 24)] {
+      set_u16(&mut raw, t, time);
+      set_u16(&mut raw, d, date);
+    }
+    set_u16(&mut raw, 18, date);
+    let pos = fat.add_entry(*self.cluster, name, raw)?;
+    self.touch();
+    self.update_entry();
+    let entry = DirEntry { name: String::from(name), raw, slots: alloc::vec![pos] };
+    Some(fat.inode(&entry))
+  }
+
+  /// Remove the entry of a file or directory, freeing its clusters unless it is open.
+  fn remove(&self, entry: &DirEntry) {
+    let fat = &self.fat;
+    fat.remove_entry(entry);
+    let open = fat.inodes.get().remove(&entry.pos()).and_then(|i| i.upgrade());
+    match open {
+      // Its clusters are freed once it is closed.
+      Some(inode) => *inode.entry.get() = None,
+      None => fat.free_chain(entry.cluster()),
+    }
+    self.touch();
+    self.update_entry();
+  }
+}
+
+impl Drop for FatInode {
+  fn drop(&mut self) {
+    match *self.entry {
+      Some(pos) => {
+        let inodes = self.fat.inodes.get();
+        if inodes.get(&pos).map_or(false, |i| i.strong_count() == 0) { inodes.remove(&pos); }
+      }
+      // Removed while open. The file system may be in use, so free the clusters later.
+      None if self.fat.is_data(*self.cluster) && *self.cluster != self.fat.root_cluster => {
+        self.fat.orphans.get().push(*self.cluster)
+      }
+      None => {}
+    }
+  }
+}
+
+impl Inode for FatInode {
+  fn stat(&self) -> Stat {
+    let fat = &self.fat;
+    let _fs = fat.enter();
+    let clusters = if self.is_dir() {
+      fat.chain(*self.cluster).len()
+    } else {
+      (*self.size as usize + fat.cluster_bytes() - 1) / fat.cluster_bytes()
+    };
+    let perm = if self.attr & ATTR_READ_ONLY != 0 { 0o555 } else { 0o755 };
+    Stat {
+      ino: self.entry.map_or(1, |pos| pos / ENTRY_SIZE) as _,
+      nlink: 1,
+      mode: if self.is_dir() { S_IFDIR | perm } else { S_IFREG | (perm & 0o666) },
+      size: *self.size as _,
+      blksize: fat.cluster_bytes() as _,
+      blocks: (clusters * fat.sectors_per_cluster * BLOCK_SZ / 512) as _,
+      atime: *self.atime as _,
+      mtime: *self.mtime as _,
+      ctime: *self.mtime as _,
+      ..Stat::default()
+    }
+  }
+  fn is_dir(&self) -> bool { FatInode::is_dir(self) }
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    let fat = &self.fat;
+    let _fs = fat.enter();
+    let end = (*self.size as usize).min(offset.saturating_add(buf.len()));
+    if end <= offset { return 0; }
+    let cluster_bytes = fat.cluster_bytes();
+    let chain = fat.chain(*self.cluster);
+    let end = end.min(chain.len() * cluster_bytes);
+    let mut pos = offset;
+    while pos < end {
+      let (i, start) = (pos / cluster_bytes, pos % cluster_bytes);
+      let n = (cluster_bytes - start).min(end - pos);
+      fat.read_bytes(fat.cluster_pos(chain[i]) + start, &mut buf[pos - offset..pos - offset + n]);
+      pos += n;
+    }
+    end.saturating_sub(offset)
+  }
+  fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    let _fs = self.fat.enter();
+    if self.is_dir() || self.attr & ATTR_READ_ONLY != 0 { return 0; }
+    self.write(offset, buf)
+  }
+  fn truncate(&self, len: usize) -> bool {
+    let fat = &self.fat;
+    let _fs = fat.enter();
+    let size = *self.size as usize;
+    if self.is_dir() || self.attr & ATTR_READ_ONLY != 0 || len > u32::MAX as usize { return false; }
+    if len > size {
+      return self.write(size, &alloc::vec![0; len - size]) == len - size;
+    }
+    let keep = (len + fat.cluster_bytes() - 1) / fat.cluster_bytes();
+    let chain = fat.chain(*self.cluster);
+    if keep == 0 {
+      fat.free_chain(*self.cluster);
+      *self.cluster.get() = 0;
+    } else if chain.len() > keep {
+      fat.set_next(chain[keep - 1], END_OF_CHAIN);
+      fat.free_chain(chain[keep]);
+    }
+    *self.size.get() = len as u32;
+    self.touch();
+    self.update_entry();
+    true
+  }
+  fn sync(&self) {
+    let _fs = self.fat.enter();
+    self.fat.device.flush();
+  }
+  fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let _fs = self.fat.enter();
+    if !self.is_dir() { return None; }
+    let entry = self.fat.find(*self.cluster, name)?;
+    Some(self.fat.inode(&entry))
+  }
+  fn readdir(&self) -> Vec<String> {
+    let _fs = self.fat.enter();
+    if !self.is_dir() { return Vec::new(); }
+    self.fat.entries(*self.cluster).into_iter().map(|e| e.name).collect()
+  }
+  fn create(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let _fs = self.fat.enter();
+    if !self.is_dir() { return None; }
+    Some(self.add(name, ATTR_ARCHIVE, 0)?)
+  }
+  fn mkdir(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let fat = &self.fat;
+    let _fs = fat.enter();
+    if !self.is_dir() || fat.find(*self.cluster, name).is_some() { return None; }
+    let cluster = fat.alloc_zero(None)?;
+    // "." and "..", whose cluster is 0 for the root.
+    let parent = if *self.cluster == fat.root_cluster { 0 } else { *self.cluster };
+    for (i, c) in [cluster, parent].iter().enumerate() {
+      let mut raw = [b' '; ENTRY_SIZE];
+      raw[..i + 1].fill(b'.');
+      raw[11..].fill(0);
+      raw[11] = ATTR_DIRECTORY;
+      set_u16(&mut raw, 20, (c >> 16) as u16);
+      set_u16(&mut raw, 26, *c as u16);
+      fat.write_bytes(fat.cluster_pos(cluster) + i * ENTRY_SIZE, &raw);
+    }
+    match self.add(name, ATTR_DIRECTORY, cluster) {
+      Some(inode) => Some(inode),
+      None => {
+        fat.free_chain(cluster);
+        None
+      }
+    }
+  }
+  fn unlink(&self, name: &str) -> bool {
+    let _fs = self.fat.enter();
+    match self.fat.find(*self.cluster, name) {
+      Some(entry) if !entry.is_dir() && self.is_dir() => {
+        self.remove(&entry);
+        true
+      }
+      _ => false,
+    }
+  }
+  fn rmdir(&self, name: &str) -> bool {
+    let fat = &self.fat;
+    let _fs = fat.enter();
+    match fat.find(*self.cluster, name) {
+      Some(entry) if entry.is_dir() && fat.entries(entry.cluster()).is_empty() => {
+        self.remove(&entry);
+        true
+      }
+      _ => false,
+    }
+  }
+  fn rename(&self, old_name: &str, new_dir: &dyn Inode, new_name: &str) -> bool {
+    let new_dir = try_!(new_dir.as_any().downcast_ref::<FatInode>(), false);
+    let fat = &self.fat;
+    let _fs = fat.enter();
+    if !self.is_dir() || !new_dir.is_dir() || !is_valid_name(new_name) { return false; }
+    let entry = try_!(fat.find(*self.cluster, old_name), false);
+    if entry.is_dir() {
+      // A directory cannot move into itself or below.
+      let mut c = *new_dir.cluster;
+      while c != 0 && c != fat.root_cluster {
+        if c == entry.cluster() { return false; }
+        c = fat.parent(c);
+      }
+    }
+    if let Some(old) = fat.find(*new_dir.cluster, new_name) {
+      if old.pos() == entry.pos() {
+        // Only the case of the name changes.
+        if old.name == new_name { return true; }
+      } else {
+        // A directory only replaces an empty directory, and a file only a file.
+        let empty = |e: &DirEntry| !e.is_dir() || fat.entries(e.cluster()).is_empty();
+        if old.is_dir() != entry.is_dir() || !empty(&old) { return false; }
+        new_dir.remove(&old);
+      }
+    }
+    fat.remove_entry(&entry);
+    let mut raw = entry.raw;
+    // The new short name is chosen again, and the case flags go with the old one.
+    raw[12] &= !(LOWER_BASE | LOWER_EXT);
+    let pos = match fat.add_entry(*new_dir.cluster, new_name, raw) {
+      Some(pos) => pos,
+      None => {
+        // Put the old entry back, whose slots are still free. Long name entries begin with
+        // their ordinals, from the last one down to 1.
+        let count = entry.slots.len() - 1;
+        for (i, &pos) in entry.slots.iter().enumerate() {
+          let first = match i {
+            _ if i == count => entry.raw[0],
+            0 => count as u8 | LAST_LONG_ENTRY,
+            _ => (count - i) as u8,
+          };
+          fat.write_bytes(pos, &[first]);
+        }
+        return false;
+      }
+    };
+    if entry.is_dir() && !core::ptr::eq(self, new_dir) {
+      let parent = if *new_dir.cluster == fat.root_cluster { 0 } else { *new_dir.cluster };
+      let dotdot = fat.cluster_pos(entry.cluster()) + ENTRY_SIZE;
+      let mut raw = fat.read_entry(dotdot);
+      set_u16(&mut raw, 20, (parent >> 16) as u16);
+      set_u16(&mut raw, 26, parent as u16);
+      fat.write_bytes(dotdot, &raw);
+    }
+    let inodes = fat.inodes.get();
+    if let Some(inode) = inodes.remove(&entry.pos()) {
+      if let Some(i) = inode.upgrade() { *i.entry.get() = Some(pos); }
+      inodes.insert(pos, inode);
+    }
+    self.touch();
+    self.update_entry();
+    new_dir.touch();
+    new_dir.update_entry();
+    true
+  }
+  fn as_any(&self) -> &dyn Any { self }
+}
@@ -0,0 +1,58 @@
+use crate::{*, drivers::*};
+use super::*;
+
+use alloc::sync::Arc;
+
+/// Magic of the "newc" cpio format, made by `cpio -H newc`.
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_LEN: usize = 110;
+
+/// Mount the initramfs `data` as the root file system. A cpio archive is unpacked to a tmpfs,
+/// and an easy-fs image is used in place as the RAM disk "ram0".
+pub fn mount_root(data: &'static mut [u8]) {
+  if data.starts_with(CPIO_MAGIC) {
+    vfs::mount_root("tmpfs", "none");
+    if unpack(data).is_none() {
+      warn!("initramfs: truncated cpio archive");
+    }
+  } else {
+    add_block_device("ram0", Arc::new(RamDisk::new(data)));
+    vfs::mount_root("easyfs", "ram0");
+  }
+}
+
+/// Create the directories, files and symbolic links of the cpio archive `data` in "/".
+fn unpack(data: &[u8]) -> Option<()> {
+  let align = |x: usize| (x + 3) & !3;
+  let mut pos = 0;
+  loop {
+    let header = data.get(pos..pos + CPIO_HEADER_LEN)?;
+    if !header.starts_with(CPIO_MAGIC) { return None; }
+    // Fields after the magic are 8 hexadecimal digits each.
+    let field = |i: usize| {
+      let digits = core::str::from_utf8(&header[6 + i * 8..14 + i * 8]).ok()?;
+      usize::from_str_radix(digits, 16).ok()
+    };
+    let (mode, size, name_len) = (field(1)? as u32, field(6)?, field(11)?);
+    let name_start = pos + CPIO_HEADER_LEN;
+    let name = data.get(name_start..name_start + name_len.checked_sub(1)?)?;
+    let name = core::str::from_utf8(name).ok()?;
+    let content_start = align(name_start + name_len);
+    let content = data.get(content_start..content_start + size)?;
+    pos = align(content_start + size);
+    if name == "TRAILER!!!" { return Some(()); }
+    let path = name.trim_start_matches("./");
+    if path.is_empty() || path == "." { continue; }
+    let ok = match mode & S_IFMT {
+      S_IFDIR => mkdir("/", path),
+      S_IFLNK => symlink("/", core::str::from_utf8(content).ok()?, path),
+      S_IFREG => vfs::open("/", path, OpenFlags::CREATE | OpenFlags::WRONLY)
+        .map_or(false, |d| d.inode.write_at(0, content) == content.len()),
+      // Device files are left out.
+      _ => true,
+    };
+    if !ok {
+      warn!("initramfs: cannot create {}", path);
+    }
+  }
+}
@@ -1,35 +1,50 @@
-use crate::{*, drivers::*};
-use super::File;
-
-use alloc::sync::Arc;
-use easy_fs::{EasyFileSystem, Inode};
+use crate::*;
+use super::*;
 
 pub struct OSInode {
   readable: bool,
   writable: bool,
+  /// Every write goes to the end of the file.
+  append: bool,
   offset: Cell<usize>,
-  inode: Cell<Arc<Inode>>,
+  dentry: Dentry,
 }
 
-pub static ROOT_INODE: Cell<Inode> = unsafe { transmute((1usize, 1usize, 1usize, DUMMY_BLOCK_DEVICE)) };
-
-pub fn init() {
-  let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-  unsafe { (ROOT_INODE.get() as *mut Inode).write(EasyFileSystem::root_inode(&efs)); }
+/// Mount the root file system, from `initramfs` if the boot loader loaded one and the command
+/// line does not choose another one.
+pub fn init(initramfs: &'static mut [u8]) {
+  easyfs::init();
+  match cmdline_arg("rootfstype") {
+    None if !initramfs.is_empty() => initramfs::mount_root(initramfs),
+    fstype => {
+      let fstype = fstype.unwrap_or("easyfs");
+      // Disk file systems are on the first disk found by default.
+      let disk = drivers::block_devices().first().map_or("sda", |d| d.0);
+      let source = if matches!(fstype, "easyfs" | "ext2") { disk } else { "none" };
+      mount_root(fstype, cmdline_arg("root").unwrap_or(source));
+    }
+  }
+  // Scratch files are kept in memory.
+  mkdir("/", "/tmp");
+  mount("/", "none", "/tmp", "tmpfs");
+  mkdir("/", "/proc");
+  mount("/", "none", "/proc", "proc");
+  mkdir("/", "/dev");
+  mount("/", "none", "/dev", "devfs");
   println!("/**** APPS ****");
-  for app in ROOT_INODE.ls() {
+  for app in lookup("/", "/").unwrap().inode.readdir() {
     println!("{}", app);
   }
   println!("**************/");
 }
 
 impl OSInode {
-  pub fn new(readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
-    Self { readable, writable, offset: Cell::new(0), inode: Cell::new(inode) }
+  pub fn new(readable: bool, writable: bool, append: bool, dentry: Dentry) -> Self {
+    Self { readable, writable, append, offset: Cell::new(0), dentry }
   }
 
   pub fn read_all(&self) -> Vec<u8> {
-    let (offset, inode) = (self.offset.get(), self.inode.get());
+    let (offset, inode) = (self.offset.get(), &self.dentry.inode);
     let mut buffer = [0u8; 512];
     let mut v: Vec<u8> = Vec::new();
     loop {
@@ -49,6 +64,7 @@ bitflags::bitflags! {
     const RDWR = 1 << 1;
     const CREATE = 1 << 9;
     const TRUNC = 1 << 10;
+    const APPEND = 1 << 11;
   }
 }
 
@@ -56,52 +72,84 @@ impl OpenFlags {
   /// Do not check validity for simplicity
   /// Return (readable, writable)
   pub fn read_write(&self) -> (bool, bool) {
-    if self.is_empty() {
-      (true, false)
-    } else if self.contains(Self::WRONLY) {
+    if self.contains(Self::WRONLY) {
       (false, true)
-    } else {
+    } else if self.contains(Self::RDWR) {
       (true, true)
+    } else {
+      (true, false)
     }
   }
 }
 
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Rc<OSInode>> {
+pub fn open_file(cwd: &str, path: &str, flags: OpenFlags) -> Option<Rc<OSInode>> {
   let (readable, writable) = flags.read_write();
-  if flags.contains(OpenFlags::CREATE) {
-    if let Some(inode) = ROOT_INODE.find(name) {
-      // clear size
-      inode.clear();
-      Some(Rc::new(OSInode::new(readable, writable, inode)))
-    } else {
-      // create file
-      ROOT_INODE.create(name)
-        .map(|inode| Rc::new(OSInode::new(readable, writable, inode)))
-    }
-  } else {
-    ROOT_INODE.find(name).map(|inode| {
-      if flags.contains(OpenFlags::TRUNC) {
-        inode.clear();
-      }
-      Rc::new(OSInode::new(readable, writable, inode))
-    })
-  }
+  let dentry = vfs::open(cwd, path, flags)?;
+  Some(Rc::new(OSInode::new(readable, writable, flags.contains(OpenFlags::APPEND), dentry)))
 }
 
 impl File for OSInode {
   fn readable(&self) -> bool { self.readable }
   fn writable(&self) -> bool { self.writable }
   fn read(&self, buf: &mut [u8]) -> usize {
-    let (offset, inode) = (self.offset.get(), self.inode.get());
+    let (offset, inode) = (self.offset.get(), &self.dentry.inode);
     let n = inode.read_at(*offset, buf);
     *offset += n;
     n
   }
   fn write(&self, buf: &[u8]) -> usize {
-    let (offset, inode) = (self.offset.get(), self.inode.get());
+    let (offset, inode) = (self.offset.get(), &self.dentry.inode);
+    if self.append { *offset = inode.stat().size as usize; }
+    // Less than `buf` is written if the file system is full.
     let n = inode.write_at(*offset, buf);
-    assert_eq!(n, buf.len());
     *offset += n;
     n
   }
+  fn seek(&self, offset: isize, whence: usize) -> isize {
+    let base = match whence {
+      SEEK_SET => 0,
+      SEEK_CUR => *self.offset as isize,
+      SEEK_END => self.dentry.inode.stat().size as isize,
+      _ => return -1,
+    };
+    let offset = try_!(base.checked_add(offset).filter(|&x| x >= 0), -1);
+    *self.offset.get() = offset as usize;
+    offset
+  }
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> isize {
+    self.dentry.inode.read_at(offset, buf) as _
+  }
+  fn write_at(&self, offset: usize, buf: &[u8]) -> isize {
+    self.dentry.inode.write_at(offset, buf) as _
+  }
+  fn truncate(&self, len: usize) -> isize {
+    if self.dentry.inode.truncate(len) { 0 } else { -1 }
+  }
+  fn fsync(&self) -> isize {
+    self.dentry.inode.sync();
+    0
+  }
+  fn stat(&self) -> Option<Stat> {
+    Some(Stat { dev: self.dentry.mount.id as _, ..self.dentry.inode.stat() })
+  }
+  /// The file offset of a directory counts its entries.
+  fn getdents(&self, buf: &mut [u8]) -> isize {
+    if !self.dentry.inode.is_dir() { return -1; }
+    let offset = self.offset.get();
+    let mut len = 0;
+    for name in self.dentry.inode.readdir().iter().skip(*offset) {
+      if len + name.len() + 1 > buf.len() {
+        // The buffer cannot hold even one name.
+        if len == 0 { return -1; }
+        break;
+      }
+      buf[len..len + name.len()].copy_from_slice(name.as_bytes());
+      buf[len + name.len()] = 0;
+      len += name.len() + 1;
+      *offset += 1;
+    }
+    len as _
+  }
+  fn ioctl(&self, cmd: u32, arg: usize) -> isize { self.dentry.inode.ioctl(cmd, arg) }
+  fn path(&self) -> Option<String> { Some(self.dentry.path.clone()) }
 }
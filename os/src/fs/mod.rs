@@ -1,14 +1,94 @@
+mod devfs;
+mod easyfs;
+mod ext2;
+mod fat;
+mod initramfs;
 mod inode;
 mod pipe;
-mod stdio;
+mod procfs;
+mod tmpfs;
+mod tty;
+mod vfs;
+
+use alloc::string::String;
 
 pub trait File {
   fn readable(&self) -> bool;
   fn writable(&self) -> bool;
   fn read(&self, buf: &mut [u8]) -> usize;
   fn write(&self, buf: &[u8]) -> usize;
+  /// Device-specific control, unsupported by default.
+  fn ioctl(&self, _cmd: u32, _arg: usize) -> isize { -1 }
+  /// File status, unsupported by default.
+  fn stat(&self) -> Option<Stat> { None }
+  /// Move the file offset according to `whence` (SEEK_*), return the new offset. Files that
+  /// cannot seek return -1 by default.
+  fn seek(&self, _offset: isize, _whence: usize) -> isize { -1 }
+  /// Read at `offset` without moving the file offset, unsupported by default.
+  fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> isize { -1 }
+  /// Write at `offset` without moving the file offset, unsupported by default.
+  fn write_at(&self, _offset: usize, _buf: &[u8]) -> isize { -1 }
+  /// Change the file size, unsupported by default.
+  fn truncate(&self, _len: usize) -> isize { -1 }
+  /// Write modified data back to the disk. Files not on a disk have nothing to write.
+  fn fsync(&self) -> isize { 0 }
+  /// Copy the names of the directory entries from the file offset on to `buf`, each with a '\0'
+  /// terminator, and move the offset past them. Return the bytes copied, 0 at the end. Files
+  /// that are not directories return -1 by default.
+  fn getdents(&self, _buf: &mut [u8]) -> isize { -1 }
+  /// Path the file was opened by, None if it has none.
+  fn path(&self) -> Option<String> { None }
+}
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFBLK: u32 = 0o060000;
+
+/// Little-endian integers of on-disk structures, at byte `i` of `b`.
+fn u16_at(b: &[u8], i: usize) -> u16 { u16::from_le_bytes([b[i], b[i + 1]]) }
+fn u32_at(b: &[u8], i: usize) -> u32 { u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]) }
+fn set_u16(b: &mut [u8], i: usize, x: u16) { b[i..i + 2].copy_from_slice(&x.to_le_bytes()); }
+fn set_u32(b: &mut [u8], i: usize, x: u32) { b[i..i + 4].copy_from_slice(&x.to_le_bytes()); }
+
+/// File status, with the same layout as Linux's `struct stat` on x86_64. Times are in seconds
+/// since the epoch, without the nanoseconds.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stat {
+  pub dev: u64,
+  pub ino: u64,
+  pub nlink: u64,
+  /// File type (S_IF*) and permission bits.
+  pub mode: u32,
+  pub uid: u32,
+  pub gid: u32,
+  pad0: u32,
+  pub rdev: u64,
+  pub size: i64,
+  pub blksize: i64,
+  /// Number of 512-byte blocks allocated.
+  pub blocks: i64,
+  pub atime: i64,
+  pub atime_nsec: i64,
+  pub mtime: i64,
+  pub mtime_nsec: i64,
+  pub ctime: i64,
+  pub ctime_nsec: i64,
+  unused: [i64; 3],
 }
 
+pub use easyfs::{clear_zombie, wait_fs_leave};
 pub use inode::{init, open_file, OSInode, OpenFlags};
 pub use pipe::{make_pipe, Pipe};
-pub use stdio::{Stdin, Stdout};
+pub use tty::{Tty, TTY};
+pub use vfs::{
+  link, lookup, lookup_link, lookup_parent, mkdir, mount, mount_root, mounts, rename, rmdir,
+  symlink, sync, umount, unlink, Dentry, FileSystem, Inode, Mount,
+};
@@ -0,0 +1,253 @@
+use crate::{*, mm::PTFlags, task::*, trap::{interrupt_name, INTERRUPTS}};
+use super::{*, easyfs::enter_fs};
+
+use alloc::{format, string::ToString, sync::Arc};
+use core::{any::Any, fmt::Write};
+
+#[derive(Clone, Copy)]
+enum Node {
+  Root,
+  /// "self", a link to the directory of the current process.
+  SelfLink,
+  /// Entry of `GLOBALS`.
+  Global(usize),
+  ProcDir(usize),
+  /// Entry of `PROC_FILES` of a process.
+  ProcFile(usize, usize),
+  FdDir(usize),
+  /// Link to the path of an open file.
+  Fd(usize, usize),
+}
+
+/// A file or directory of the proc file system, whose contents are generated on each read.
+struct ProcInode(Node);
+
+struct ProcFs;
+
+pub fn new() -> Arc<dyn FileSystem> {
+  Arc::new(ProcFs)
+}
+
+impl FileSystem for ProcFs {
+  fn name(&self) -> &'static str { "proc" }
+  fn root(&self) -> Arc<dyn Inode> { Arc::new(ProcInode(Node::Root)) }
+}
+
+/// Files of the root directory about the whole system.
+const GLOBALS: &[(&str, fn() -> String)] = &[
+  ("meminfo", meminfo),
+  ("uptime", uptime),
+  ("interrupts", interrupts),
+  ("bcache", bcache),
+  ("mounts", mounts_info),
+];
+
+/// Files of the directory of each process.
+const PROC_FILES: &[(&str, fn(&Proc) -> String)] = &[
+  ("status", status),
+  ("maps", maps),
+  ("threads", threads),
+  ("cmdline", cmdline),
+];
+
+fn meminfo() -> String {
+  let (frames, free) = mm::frame_stats();
+  let (heap, heap_used) = mm::heap_stats();
+  let kb = mm::PAGE_SIZE / 1024;
+  format!(
+    "MemTotal: {:>10} kB\nMemFree:  {:>10} kB\nHeapTotal:{:>10} kB\nHeapUsed: {:>10} kB\n",
+    frames * kb, free * kb, heap / 1024, heap_used / 1024,
+  )
+}
+
+/// Seconds since boot.
+fn uptime() -> String {
+  let ms = *pic::TICKS;
+  format!("{}.{:02}\n", ms / 1000, ms % 1000 / 10)
+}
+
+fn interrupts() -> String {
+  let mut s = String::new();
+  for (id, &count) in INTERRUPTS.iter().enumerate().filter(|(_, &count)| count != 0) {
+    writeln!(s, "{:>3}: {:>10} {}", id, count, interrupt_name(id)).unwrap();
+  }
+  s
+}
+
+fn bcache() -> String {
+  // The block cache is locked by the file systems, which may be waiting for the disk meanwhile.
+  let s = {
+    let _fs = enter_fs();
+    easy_fs::block_cache_stats()
+  };
+  format!(
+    "capacity: {}\ncached:   {}\ndirty:    {}\nhits:     {}\nmisses:   {}\n",
+    s.capacity, s.cached, s.dirty, s.hits, s.misses,
+  )
+}
+
+fn mounts_info() -> String {
+  let mut s = String::new();
+  for m in mounts() {
+    writeln!(s, "{} {} {}", m.source, m.path, m.fs.name()).unwrap();
+  }
+  s
+}
+
+/// One letter for the state of a process, as in the `ps` of Unix.
+fn state(p: &Proc) -> char {
+  if p.zombie {
+    'Z'
+  } else if p.stopped {
+    'T'
+  } else if p.tasks.iter().any(|t| t.status == TaskStatus::Runnable) {
+    'R'
+  } else {
+    'S'
+  }
+}
+
+fn status(p: &Proc) -> String {
+  let name = p.cmdline.first().map_or("kernel", |path| path.rsplit('/').next().unwrap());
+  let ppid = p.parent.as_ref().map_or(0, |parent| parent.pid);
+  let threads = p.tasks.iter().filter(|t| t.status != TaskStatus::Waited).count();
+  let mut s = format!(
+    "Name:    {}\nState:   {}\nPid:     {}\nPPid:    {}\nPgid:    {}\nSid:     {}\nThreads: {}\n",
+    name, state(p), p.pid, ppid, p.pgid, p.sid, threads,
+  );
+  if p.zombie {
+    writeln!(s, "ExitCode: {}", p.exit_code).unwrap();
+  }
+  if let Some(vm) = &p.vm {
+    let size: usize = vm.areas().map(|a| a.size).sum();
+    let rss: usize = vm.areas().map(|a| a.mapper.len() * mm::PAGE_SIZE).sum();
+    writeln!(s, "VmSize:  {} kB\nVmRSS:   {} kB", size / 1024, rss / 1024).unwrap();
+  }
+  writeln!(s, "Cwd:     {}", p.cwd).unwrap();
+  s
+}
+
+/// Areas of the address space, with whether they are writable and accessible from user mode.
+fn maps(p: &Proc) -> String {
+  let mut s = String::new();
+  for a in p.vm.iter().flat_map(|vm| vm.areas()) {
+    let w = if a.flags.contains(PTFlags::WRITABLE) { 'w' } else { '-' };
+    let u = if a.flags.contains(PTFlags::USER) { 'u' } else { '-' };
+    let (start, end) = (a.start.0, a.start.0 + a.size);
+    writeln!(s, "{:016x}-{:016x} r{}{} {:>8} kB", start, end, w, u, a.size / 1024).unwrap();
+  }
+  s
+}
+
+/// Threads not yet waited, with their state and exit code.
+fn threads(p: &Proc) -> String {
+  let mut s = String::new();
+  for t in p.tasks.iter().filter(|t| t.status != TaskStatus::Waited) {
+    writeln!(s, "{} {:?} {}", t.tid, t.status, t.exit_code).unwrap();
+  }
+  s
+}
+
+fn cmdline(p: &Proc) -> String {
+  let mut s = p.cmdline.join(" ");
+  s.push('\n');
+  s
+}
+
+/// Processes by PID, including zombies not yet waited.
+fn procs() -> Vec<&'static Proc> {
+  let mut procs: Vec<&'static Proc> = alloc::vec![root_proc()];
+  procs.extend(PID2PROC.values().map(|p| &**p));
+  // Zombies are only kept by their parents.
+  let zombies: Vec<_> =
+    procs.iter().flat_map(|p| p.children.iter()).filter(|c| c.zombie).map(|c| &**c).collect();
+  procs.extend(zombies);
+  procs.sort_by_key(|p| p.pid);
+  procs
+}
+
+fn find_proc(pid: usize) -> Option<&'static Proc> {
+  if let Some(p) = (*PID2PROC).get(&pid) { return Some(p); }
+  procs().into_iter().find(|p| p.pid == pid)
+}
+
+impl ProcInode {
+  /// Contents of a file, None if it is not one or its process is gone.
+  fn contents(&self) -> Option<String> {
+    match self.0 {
+      Node::Global(i) => Some(GLOBALS[i].1()),
+      Node::ProcFile(pid, i) => Some(PROC_FILES[i].1(find_proc(pid)?)),
+      _ => None,
+    }
+  }
+}
+
+impl Inode for ProcInode {
+  fn stat(&self) -> Stat {
+    // Unique inode numbers, with the process in the high bits.
+    let (mode, ino) = match self.0 {
+      Node::Root => (S_IFDIR | 0o555, 1),
+      Node::SelfLink => (S_IFLNK | 0o777, 2),
+      Node::Global(i) => (S_IFREG | 0o444, 3 + i),
+      Node::ProcDir(pid) => (S_IFDIR | 0o555, pid << 32),
+      Node::ProcFile(pid, i) => (S_IFREG | 0o444, pid << 32 | (i + 1)),
+      Node::FdDir(pid) => (S_IFDIR | 0o500, pid << 32 | 0xffff),
+      Node::Fd(pid, fd) => (S_IFLNK | 0o700, pid << 32 | 0x10000 | fd),
+    };
+    // Files are generated when read, so their size is unknown like on Linux.
+    Stat { ino: ino as _, nlink: 1, mode, blksize: 1024, ..Stat::default() }
+  }
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    let s = try_!(self.contents(), 0);
+    let data = s.as_bytes().get(offset..).unwrap_or(&[]);
+    let len = data.len().min(buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    len
+  }
+  fn write_at(&self, _offset: usize, _buf: &[u8]) -> usize { 0 }
+  fn readlink(&self) -> Option<String> {
+    match self.0 {
+      Node::SelfLink => Some(task::current().proc.pid.to_string()),
+      Node::Fd(pid, fd) => {
+        let file = find_proc(pid)?.files.get(fd)?.as_ref()?;
+        Some(file.path().unwrap_or_else(|| String::from("anon_inode")))
+      }
+      _ => None,
+    }
+  }
+  fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let node = match self.0 {
+      Node::Root if name == "self" => Node::SelfLink,
+      Node::Root => match GLOBALS.iter().position(|g| g.0 == name) {
+        Some(i) => Node::Global(i),
+        None => Node::ProcDir(find_proc(name.parse().ok()?)?.pid),
+      },
+      Node::ProcDir(pid) if name == "fd" => Node::FdDir(pid),
+      Node::ProcDir(pid) => Node::ProcFile(pid, PROC_FILES.iter().position(|f| f.0 == name)?),
+      Node::FdDir(pid) => {
+        let fd: usize = name.parse().ok()?;
+        find_proc(pid)?.files.get(fd)?.as_ref()?;
+        Node::Fd(pid, fd)
+      }
+      _ => return None,
+    };
+    Some(Arc::new(ProcInode(node)))
+  }
+  fn readdir(&self) -> Vec<String> {
+    match self.0 {
+      Node::Root => {
+        let names = GLOBALS.iter().map(|g| String::from(g.0)).chain([String::from("self")]);
+        names.chain(procs().iter().map(|p| p.pid.to_string())).collect()
+      }
+      Node::ProcDir(_) => {
+        PROC_FILES.iter().map(|f| String::from(f.0)).chain([String::from("fd")]).collect()
+      }
+      Node::FdDir(pid) => find_proc(pid).map_or(Vec::new(), |p| {
+        p.files.iter().enumerate().filter(|(_, f)| f.is_some()).map(|(fd, _)| fd.to_string())
+          .collect()
+      }),
+      _ => Vec::new(),
+    }
+  }
+  fn as_any(&self) -> &dyn Any { self }
+}
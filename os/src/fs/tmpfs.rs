@@ -0,0 +1,260 @@
+use crate::{*, drivers::rtc, mm::{PhysFrame, PAGE_SIZE}};
+use super::*;
+
+use alloc::{collections::BTreeMap, sync::{Arc, Weak}};
+use core::any::Any;
+
+static NEXT_INO: Cell<usize> = zero();
+/// Largest file, whose end is still an offset `lseek` can reach.
+const MAX_FILE_SIZE: usize = isize::MAX as usize;
+
+enum Data {
+  /// Pages of the file by index. Holes have none and read as zeros.
+  File { pages: BTreeMap<usize, PhysFrame>, size: usize },
+  Dir(BTreeMap<String, Arc<TmpInode>>),
+  Symlink(String),
+}
+
+/// A file, directory or symbolic link kept in memory, file data in physical frames.
+pub struct TmpInode {
+  ino: usize,
+  /// File type (S_IF*) and permission bits.
+  mode: u32,
+  /// Names of a file. Directories count their subdirectories instead.
+  nlink: Cell<usize>,
+  atime: Cell<u64>,
+  mtime: Cell<u64>,
+  ctime: Cell<u64>,
+  data: Cell<Data>,
+  /// Directory containing a directory, the root itself for the root.
+  parent: Cell<Weak<TmpInode>>,
+  this: Weak<TmpInode>,
+}
+
+/// A file system in memory, whose files are lost when it is unmounted.
+struct TmpFs(Arc<TmpInode>);
+
+pub fn new() -> Arc<dyn FileSystem> {
+  Arc::new(TmpFs(TmpInode::new(S_IFDIR | 0o755, Data::Dir(BTreeMap::new()), None)))
+}
+
+impl FileSystem for TmpFs {
+  fn name(&self) -> &'static str { "tmpfs" }
+  fn root(&self) -> Arc<dyn Inode> { self.0.clone() }
+}
+
+impl TmpInode {
+  fn new(mode: u32, data: Data, parent: Option<&TmpInode>) -> Arc<Self> {
+    let ino = *NEXT_INO;
+    *NEXT_INO.get() += 1;
+    let now = rtc::now();
+    Arc::new_cyclic(|this| TmpInode {
+      ino,
+      mode,
+      nlink: Cell::new(1),
+      atime: Cell::new(now),
+      mtime: Cell::new(now),
+      ctime: Cell::new(now),
+      data: Cell::new(data),
+      parent: Cell::new(parent.map_or(this.clone(), |p| p.this.clone())),
+      this: this.clone(),
+    })
+  }
+
+  fn entries(&self) -> Option<&mut BTreeMap<String, Arc<TmpInode>>> {
+    match self.data.get() {
+      Data::Dir(entries) => Some(entries),
+      _ => None,
+    }
+  }
+
+  fn touch(&self) {
+    let now = rtc::now();
+    *self.mtime.get() = now;
+    *self.ctime.get() = now;
+  }
+
+  /// Add the new entry `name` of type `mode` to this directory.
+  fn add(&self, name: &str, mode: u32, data: Data) -> Option<Arc<TmpInode>> {
+    if self.entries()?.contains_key(name) { return None; }
+    let parent = if mode & S_IFMT == S_IFDIR { Some(self) } else { None };
+    let inode = TmpInode::new(mode, data, parent);
+    self.entries()?.insert(String::from(name), inode.clone());
+    self.touch();
+    Some(inode)
+  }
+
+  /// Whether this directory is `dir` or one of its subdirectories.
+  fn is_in(&self, dir: &TmpInode) -> bool {
+    let mut inode = self.this.upgrade().unwrap();
+    loop {
+      if inode.ino == dir.ino { return true; }
+      let parent = inode.parent.upgrade().unwrap();
+      if parent.ino == inode.ino { return false; }
+      inode = parent;
+    }
+  }
+}
+
+impl Inode for TmpInode {
+  fn stat(&self) -> Stat {
+    let (size, blocks, nlink) = match &*self.data {
+      Data::File { pages, size } => (*size, pages.len() * PAGE_SIZE / 512, *self.nlink),
+      Data::Dir(entries) => (entries.len(), 0, 2 + entries.values().filter(|i| i.is_dir()).count()),
+      Data::Symlink(target) => (target.len(), 0, *self.nlink),
+    };
+    Stat {
+      ino: self.ino as _,
+      nlink: nlink as _,
+      mode: self.mode,
+      size: size as _,
+      blksize: PAGE_SIZE as _,
+      blocks: blocks as _,
+      atime: *self.atime as _,
+      mtime: *self.mtime as _,
+      ctime: *self.ctime as _,
+      ..Stat::default()
+    }
+  }
+  fn is_dir(&self) -> bool { matches!(*self.data, Data::Dir(_)) }
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    let (pages, size) = match &*self.data {
+      Data::File { pages, size } => (pages, *size),
+      _ => return 0,
+    };
+    let end = size.min(offset.saturating_add(buf.len()));
+    let mut pos = offset;
+    while pos < end {
+      let (i, start) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+      let len = (PAGE_SIZE - start).min(end - pos);
+      let dst = &mut buf[pos - offset..pos - offset + len];
+      match pages.get(&i) {
+        Some(page) => dst.copy_from_slice(&page.as_slice()[start..start + len]),
+        None => dst.fill(0),
+      }
+      pos += len;
+    }
+    *self.atime.get() = rtc::now();
+    end.saturating_sub(offset)
+  }
+  fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    let (pages, size) = match self.data.get() {
+      Data::File { pages, size } => (pages, size),
+      _ => return 0,
+    };
+    if offset >= MAX_FILE_SIZE { return 0; }
+    let end = offset + buf.len().min(MAX_FILE_SIZE - offset);
+    let mut pos = offset;
+    while pos < end {
+      let (i, start) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+      let len = (PAGE_SIZE - start).min(end - pos);
+      if !pages.contains_key(&i) {
+        // Out of memory, write what fits.
+        let page = if let Some(page) = PhysFrame::alloc_zero() { page } else { break };
+        pages.insert(i, page);
+      }
+      let page = &pages[&i];
+      page.as_slice()[start..start + len].copy_from_slice(&buf[pos - offset..pos - offset + len]);
+      pos += len;
+    }
+    *size = (*size).max(pos);
+    self.touch();
+    pos.saturating_sub(offset)
+  }
+  fn truncate(&self, len: usize) -> bool {
+    let (pages, size) = match self.data.get() {
+      Data::File { pages, size } => (pages, size),
+      _ => return false,
+    };
+    if len > MAX_FILE_SIZE { return false; }
+    if len < *size {
+      // Clear the rest of the last page, which shows up if the file grows again.
+      if let Some(page) = pages.get(&(len / PAGE_SIZE)) {
+        page.as_slice()[len % PAGE_SIZE..].fill(0);
+      }
+      // Free the pages past the end.
+      pages.split_off(&((len + PAGE_SIZE - 1) / PAGE_SIZE));
+    }
+    *size = len;
+    self.touch();
+    true
+  }
+  fn readlink(&self) -> Option<String> {
+    match &*self.data {
+      Data::Symlink(target) => Some(target.clone()),
+      _ => None,
+    }
+  }
+  fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    Some(self.entries()?.get(name)?.clone())
+  }
+  fn readdir(&self) -> Vec<String> {
+    self.entries().map_or(Vec::new(), |entries| entries.keys().cloned().collect())
+  }
+  fn create(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    let data = Data::File { pages: BTreeMap::new(), size: 0 };
+    Some(self.add(name, S_IFREG | 0o644, data)?)
+  }
+  fn mkdir(&self, name: &str) -> Option<Arc<dyn Inode>> {
+    Some(self.add(name, S_IFDIR | 0o755, Data::Dir(BTreeMap::new()))?)
+  }
+  fn symlink(&self, name: &str, target: &str) -> bool {
+    self.add(name, S_IFLNK | 0o777, Data::Symlink(String::from(target))).is_some()
+  }
+  fn unlink(&self, name: &str) -> bool {
+    let entries = try_!(self.entries(), false);
+    match entries.get(name) {
+      Some(inode) if !inode.is_dir() => {
+        *inode.nlink.get() -= 1;
+        *inode.ctime.get() = rtc::now();
+        entries.remove(name);
+        self.touch();
+        true
+      }
+      _ => false,
+    }
+  }
+  fn rmdir(&self, name: &str) -> bool {
+    let entries = try_!(self.entries(), false);
+    match entries.get(name).and_then(|inode| inode.entries()) {
+      Some(dir) if dir.is_empty() => {}
+      _ => return false,
+    }
+    entries.remove(name);
+    self.touch();
+    true
+  }
+  fn link(&self, name: &str, inode: &dyn Inode) -> bool {
+    let inode = try_!(inode.as_any().downcast_ref::<TmpInode>(), false);
+    if inode.is_dir() { return false; }
+    let entries = try_!(self.entries(), false);
+    if entries.contains_key(name) { return false; }
+    entries.insert(String::from(name), inode.this.upgrade().unwrap());
+    *inode.nlink.get() += 1;
+    *inode.ctime.get() = rtc::now();
+    self.touch();
+    true
+  }
+  fn rename(&self, old_name: &str, new_dir: &dyn Inode, new_name: &str) -> bool {
+    let new_dir = try_!(new_dir.as_any().downcast_ref::<TmpInode>(), false);
+    let inode = try_!(self.entries().and_then(|e| e.get(old_name)).cloned(), false);
+    let old = try_!(new_dir.entries(), false).get(new_name).cloned();
+    if inode.is_dir() && new_dir.is_in(&inode) { return false; }
+    match &old {
+      Some(old) if Arc::ptr_eq(old, &inode) => return true,
+      // A directory only replaces an empty directory, and a file only a file.
+      Some(old) if old.is_dir() != inode.is_dir() => return false,
+      Some(old) if old.entries().map_or(false, |e| !e.is_empty()) => return false,
+      Some(old) if !old.is_dir() => *old.nlink.get() -= 1,
+      _ => {}
+    }
+    self.entries().unwrap().remove(old_name);
+    new_dir.entries().unwrap().insert(String::from(new_name), inode.clone());
+    if inode.is_dir() { *inode.parent.get() = new_dir.this.clone(); }
+    *inode.ctime.get() = rtc::now();
+    self.touch();
+    new_dir.touch();
+    true
+  }
+  fn as_any(&self) -> &dyn Any { self }
+}
@@ -0,0 +1,265 @@
+use crate::{*, task::*, syscall::*};
+
+pub const TCGETS: u32 = 0x5401;
+pub const TCSETS: u32 = 0x5402;
+pub const TIOCGPGRP: u32 = 0x540F;
+pub const TIOCSPGRP: u32 = 0x5410;
+
+/// Input flag: translate carriage return to newline.
+pub const ICRNL: u32 = 0o400;
+/// Local flag: generate signals for the INTR and SUSP characters.
+pub const ISIG: u32 = 0o1;
+/// Local flag: canonical mode, i.e. input is line-edited and made available line by line.
+pub const ICANON: u32 = 0o2;
+/// Local flag: echo input characters.
+pub const ECHO: u32 = 0o10;
+
+pub const VINTR: usize = 0;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+const NCCS: usize = 19;
+
+const BS: u8 = 0x08;
+
+/// Terminal attributes, with the same layout as Linux's `struct termios`. Only the flags and
+/// control characters defined above are honored, so there is no read timeout (VTIME).
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Termios {
+  pub iflag: u32,
+  pub oflag: u32,
+  pub cflag: u32,
+  pub lflag: u32,
+  pub line: u8,
+  pub cc: [u8; NCCS],
+}
+
+impl Termios {
+  pub const DEFAULT: Self = {
+    let mut cc = [0; NCCS];
+    cc[VINTR] = 0x03; // ^C
+    cc[VERASE] = 0x7F;
+    cc[VKILL] = 0x15; // ^U
+    cc[VEOF] = 0x04; // ^D
+    cc[VMIN] = 1;
+    cc[VSUSP] = 0x1A; // ^Z
+    Self { iflag: ICRNL, oflag: 0, cflag: 0, lflag: ISIG | ICANON | ECHO, line: 0, cc }
+  };
+}
+
+const INPUT_SIZE: usize = 4096;
+
+/// A fixed size byte queue. It can be initialized in a static, unlike `VecDeque`.
+struct RingBuffer {
+  buf: [u8; INPUT_SIZE],
+  head: usize,
+  len: usize,
+}
+
+impl RingBuffer {
+  fn is_full(&self) -> bool { self.len == INPUT_SIZE }
+
+  fn push(&mut self, c: u8) {
+    self.buf[(self.head + self.len) % INPUT_SIZE] = c;
+    self.len += 1;
+  }
+
+  fn count(&self, f: impl Fn(u8) -> bool) -> usize {
+    (0..self.len).filter(|i| f(self.buf[(self.head + i) % INPUT_SIZE])).count()
+  }
+
+  fn pop(&mut self) -> Option<u8> {
+    if self.len == 0 { return None; }
+    let c = self.buf[self.head];
+    self.head = (self.head + 1) % INPUT_SIZE;
+    self.len -= 1;
+    Some(c)
+  }
+}
+
+/// The console terminal and its line discipline.
+pub struct Tty {
+  /// The session this terminal controls, 0 if none.
+  sid: usize,
+  /// The foreground process group, which reads input and receives keyboard signals.
+  fg_pgid: usize,
+  termios: Termios,
+  /// Input ready to be read. In canonical mode it only holds complete lines.
+  input: RingBuffer,
+  /// Number of line delimiters (newline or EOF) in `input`.
+  lines: usize,
+  /// The line being edited in canonical mode.
+  line: Vec<u8>,
+  /// Tasks blocked in `read`.
+  readers: Vec<TaskPtr>,
+}
+
+pub static TTY: Cell<Tty> = Cell::new(Tty {
+  sid: 0,
+  fg_pgid: 0,
+  termios: Termios::DEFAULT,
+  input: RingBuffer { buf: [0; INPUT_SIZE], head: 0, len: 0 },
+  lines: 0,
+  line: Vec::new(),
+  readers: Vec::new(),
+});
+
+impl Tty {
+  /// Drain the serial port into the line discipline. Called by the COM1 interrupt handler.
+  pub fn poll(&mut self) {
+    while let Some(c) = console::receive() {
+      self.receive(c);
+    }
+  }
+
+  /// Process one input byte according to the current termios.
+  pub fn receive(&mut self, mut c: u8) {
+    let t = self.termios;
+    if c == b'\r' && t.iflag & ICRNL != 0 { c = b'\n'; }
+    if t.lflag & ISIG != 0 && self.fg_pgid != 0 {
+      let signal = if c == t.cc[VINTR] {
+        SignalFlags::SIGINT
+      } else if c == t.cc[VSUSP] {
+        SignalFlags::SIGTSTP
+      } else {
+        SignalFlags::empty()
+      };
+      if !signal.is_empty() {
+        self.line.clear();
+        kill_group(self.fg_pgid, signal);
+        // Let blocked readers handle the signal.
+        self.wake_readers();
+        return;
+      }
+    }
+    if t.lflag & ICANON == 0 {
+      if self.input.is_full() { return; }
+      self.input.push(c);
+      self.echo(c);
+      self.wake_readers();
+      return;
+    }
+    if c == t.cc[VERASE] || c == BS {
+      if self.line.pop().is_some() { self.echo(0x7F); }
+    } else if c == t.cc[VKILL] {
+      while self.line.pop().is_some() { self.echo(0x7F); }
+    } else if c == b'\n' || c == t.cc[VEOF] {
+      // Keep room for the delimiter. EOF is stored as is and never copied to the reader.
+      if self.input.len + self.line.len() >= INPUT_SIZE { return; }
+      for &x in &self.line { self.input.push(x); }
+      self.input.push(c);
+      self.line.clear();
+      self.lines += 1;
+      if c == b'\n' { self.echo(c); }
+      self.wake_readers();
+    } else if self.input.len + self.line.len() + 1 < INPUT_SIZE {
+      self.line.push(c);
+      self.echo(c);
+    }
+  }
+
+  fn echo(&self, c: u8) {
+    if self.termios.lflag & ECHO != 0 { console::send(c); }
+  }
+
+  fn wake_readers(&mut self) {
+    for t in self.readers.drain(..) {
+      task::sched_unblock(t);
+    }
+  }
+
+  /// Forget readers belonging to exited tasks.
+  pub fn clear_zombie(&mut self) {
+    self.readers.retain(|t| t.status == TaskStatus::Blocking);
+  }
+
+  /// Read up to one line in canonical mode, where EOF reads as 0 bytes. In raw mode, wait for at
+  /// least VMIN bytes (capped by `buf.len()`); VMIN = 0 makes the read non-blocking.
+  pub fn read(&mut self, buf: &mut [u8]) -> usize {
+    loop {
+      // Background processes are stopped by SIGTTIN when reading the terminal.
+      let p = &mut task::current().proc;
+      if self.fg_pgid != 0 && p.sid == self.sid && p.pgid != self.fg_pgid {
+        if p.sig_ignore.contains(SignalFlags::SIGTTIN) { return 0; }
+        kill_group(p.pgid, SignalFlags::SIGTTIN);
+        current_check_signal();
+        continue;
+      }
+      let t = self.termios;
+      if t.lflag & ICANON != 0 {
+        if self.lines != 0 {
+          let mut n = 0;
+          while n < buf.len() {
+            let c = self.input.pop().unwrap();
+            if c == b'\n' || c == t.cc[VEOF] {
+              self.lines -= 1;
+              if c == b'\n' { buf[n] = c; n += 1; }
+              break;
+            }
+            buf[n] = c;
+            n += 1;
+          }
+          return n;
+        }
+      } else if self.input.len >= buf.len().min(t.cc[VMIN] as usize) {
+        let mut n = 0;
+        while n < buf.len() {
+          if let Some(c) = self.input.pop() { buf[n] = c; n += 1; } else { break; }
+        }
+        return n;
+      }
+      self.readers.push(task::current());
+      task::sched_block();
+      // Woken by input or by a keyboard signal, which may terminate or stop us here.
+      current_check_signal();
+    }
+  }
+
+  pub fn ioctl(&mut self, cmd: u32, arg: usize) -> isize {
+    let p = &mut task::current().proc;
+    match cmd {
+      TCGETS => {
+        let buf = try_!(validate_buf(p.root_pa(), arg as _, size_of::<Termios>(), true), -1);
+        buf.copy_from_slice(unsafe {
+          core::slice::from_raw_parts(&self.termios as *const _ as *const u8, size_of::<Termios>())
+        });
+        0
+      }
+      TCSETS => {
+        let buf = try_!(validate_buf(p.root_pa(), arg as _, size_of::<Termios>(), false), -1);
+        let termios = unsafe { (buf.as_ptr() as *const Termios).read_unaligned() };
+        if termios.lflag & ICANON == 0 {
+          // Leaving canonical mode: the partial line becomes readable input.
+          for &c in &self.line { self.input.push(c); }
+          self.line.clear();
+        }
+        self.termios = termios;
+        self.lines = if termios.lflag & ICANON != 0 {
+          self.input.count(|c| c == b'\n' || c == termios.cc[VEOF])
+        } else { 0 };
+        self.wake_readers();
+        0
+      }
+      TIOCGPGRP => {
+        try_!((arg as *mut u32).write_user(self.fg_pgid as _), -1);
+        0
+      }
+      TIOCSPGRP => {
+        let pgid = try_!((arg as *const u32).read_user(), -1) as usize;
+        // A session leader acquires the terminal if no session controls it yet.
+        if self.sid == 0 && p.sid == p.pid {
+          self.sid = p.sid;
+        }
+        if p.sid != self.sid || !PID2PROC.values().any(|q| q.pgid == pgid && q.sid == self.sid) {
+          return -1;
+        }
+        self.fg_pgid = pgid;
+        0
+      }
+      _ => -1,
+    }
+  }
+}
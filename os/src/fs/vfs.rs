@@ -0,0 +1,281 @@
+use crate::*;
+use super::*;
+
+use alloc::sync::Arc;
+use core::any::Any;
+
+/// A mounted file system, the super block of Unix kernels.
+pub trait FileSystem {
+  /// Type of the file system, as given to `mount`.
+  fn name(&self) -> &'static str;
+  fn root(&self) -> Arc<dyn Inode>;
+  /// Write all modified data back. File systems not on a disk have nothing to write.
+  fn sync(&self) {}
+}
+
+/// A file or directory of a file system. Operations of directories fail on files and the other
+/// way round, and those a file system does not support fail by default.
+pub trait Inode: Any {
+  fn stat(&self) -> Stat;
+  fn is_dir(&self) -> bool { self.stat().mode & S_IFMT == S_IFDIR }
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
+  fn write_at(&self, offset: usize, buf: &[u8]) -> usize;
+  /// Change the file size.
+  fn truncate(&self, _len: usize) -> bool { false }
+  /// Write the modified data of this file back.
+  fn sync(&self) {}
+  /// Device-specific control of a device file.
+  fn ioctl(&self, _cmd: u32, _arg: usize) -> isize { -1 }
+  /// Target of this symbolic link.
+  fn readlink(&self) -> Option<String> { None }
+  /// Look up the entry `name` of this directory.
+  fn lookup(&self, _name: &str) -> Option<Arc<dyn Inode>> { None }
+  /// Names of the entries of this directory.
+  fn readdir(&self) -> Vec<String> { Vec::new() }
+  /// Create an empty file `name` in this directory.
+  fn create(&self, _name: &str) -> Option<Arc<dyn Inode>> { None }
+  fn mkdir(&self, _name: &str) -> Option<Arc<dyn Inode>> { None }
+  /// Create a symbolic link `name` to `target` in this directory.
+  fn symlink(&self, _name: &str, _target: &str) -> bool { false }
+  /// Remove the file name `name`.
+  fn unlink(&self, _name: &str) -> bool { false }
+  /// Remove the empty directory `name`.
+  fn rmdir(&self, _name: &str) -> bool { false }
+  /// Make `name` another name of `inode`, which is in the same file system.
+  fn link(&self, _name: &str, _inode: &dyn Inode) -> bool { false }
+  /// Move the entry `old_name` to `new_dir`, which is in the same file system.
+  fn rename(&self, _old_name: &str, _new_dir: &dyn Inode, _new_name: &str) -> bool { false }
+  /// Used to get the concrete type of the other inode of `link` and `rename`.
+  fn as_any(&self) -> &dyn Any;
+}
+
+pub struct Mount {
+  /// Reported as `Stat::dev` of its files.
+  pub id: usize,
+  /// Canonical path of the mount point.
+  pub path: String,
+  /// Device or other source, "none" if there is none.
+  pub source: String,
+  pub fs: Arc<dyn FileSystem>,
+}
+
+/// Mounted file systems in the order they were mounted, the first one at "/". A later mount
+/// at the same path hides the earlier one.
+static MOUNTS: Cell<Vec<Arc<Mount>>> = Cell::new(Vec::new());
+static NEXT_MOUNT_ID: Cell<usize> = zero();
+
+/// Symbolic links followed by one lookup at most, so that loops fail.
+const MAX_SYMLINKS: usize = 40;
+
+/// Inode found at a path, and the mount it is in. Open files keep their mount busy.
+#[derive(Clone)]
+pub struct Dentry {
+  /// Canonical path.
+  pub path: String,
+  pub inode: Arc<dyn Inode>,
+  pub mount: Arc<Mount>,
+}
+
+impl Dentry {
+  fn child_path(&self, name: &str) -> String {
+    if self.path == "/" {
+      alloc::format!("/{}", name)
+    } else {
+      alloc::format!("{}/{}", self.path, name)
+    }
+  }
+
+  fn child(&self, name: &str, inode: Arc<dyn Inode>) -> Dentry {
+    Dentry { path: self.child_path(name), inode, mount: self.mount.clone() }
+  }
+}
+
+/// Create the file system `fstype` on `source`.
+fn open_fs(fstype: &str, source: &str) -> Option<Arc<dyn FileSystem>> {
+  match fstype {
+    "easyfs" => easyfs::open(source),
+    "ext2" => ext2::open(source),
+    "vfat" => fat::open(source),
+    "tmpfs" => Some(tmpfs::new()),
+    "proc" => Some(procfs::new()),
+    "devfs" => Some(devfs::new()),
+    _ => None,
+  }
+}
+
+fn add_mount(path: String, source: &str, fs: Arc<dyn FileSystem>) {
+  let id = *NEXT_MOUNT_ID;
+  *NEXT_MOUNT_ID.get() += 1;
+  MOUNTS.get().push(Arc::new(Mount { id, path, source: String::from(source), fs }));
+}
+
+/// The file system mounted last at the canonical `path`.
+fn mount_at(path: &str) -> Option<Arc<Mount>> {
+  MOUNTS.iter().rev().find(|m| m.path == path).cloned()
+}
+
+/// Mounted file systems in the order they were mounted.
+pub fn mounts() -> Vec<Arc<Mount>> {
+  MOUNTS.to_vec()
+}
+
+/// Mount the root file system.
+pub fn mount_root(fstype: &str, source: &str) {
+  let source = drivers::block_device_name(source).unwrap_or(source);
+  let fs = open_fs(fstype, source)
+    .unwrap_or_else(|| panic!("cannot mount {} on {} as root", fstype, source));
+  add_mount(String::from("/"), source, fs);
+}
+
+/// Mount the file system `fstype` on `source` at the directory `target`.
+pub fn mount(cwd: &str, source: &str, target: &str, fstype: &str) -> bool {
+  let target = try_!(lookup(cwd, target).filter(|d| d.inode.is_dir()), false);
+  // Name the device the same way however the source refers to it.
+  let source = drivers::block_device_name(source).unwrap_or(source);
+  // A device is mounted only once, since file systems cache its blocks.
+  if source != "none" && MOUNTS.iter().any(|m| m.source == source) { return false; }
+  let fs = try_!(open_fs(fstype, source), false);
+  add_mount(target.path, source, fs);
+  true
+}
+
+/// Unmount the file system at `target`, which fails while it has open files or mounts below it.
+pub fn umount(cwd: &str, target: &str) -> bool {
+  let path = try_!(lookup(cwd, target), false).path;
+  if path == "/" { return false; }
+  let i = try_!(MOUNTS.iter().rposition(|m| m.path == path), false);
+  let below = alloc::format!("{}/", path);
+  let busy = Arc::strong_count(&MOUNTS[i]) > 1 || MOUNTS.iter().any(|m| m.path.starts_with(&below));
+  if busy { return false; }
+  MOUNTS.get().remove(i).fs.sync();
+  true
+}
+
+fn push_names(names: &mut Vec<String>, path: &str) {
+  names.extend(path.split('/').rev().filter(|name| !name.is_empty()).map(String::from));
+}
+
+/// Look up `path`, which is relative to the directory `cwd` unless it starts with '/'. Symbolic
+/// links are followed, except the last component unless `follow`.
+fn walk(cwd: &str, path: &str, follow: bool) -> Option<Dentry> {
+  let root = mount_at("/").unwrap();
+  let root = Dentry { path: String::from("/"), inode: root.fs.root(), mount: root };
+  // The directories from the root to the current one, which ".." leaves.
+  let mut dirs = alloc::vec![root];
+  // Components left, the next one last.
+  let mut names = Vec::new();
+  push_names(&mut names, path);
+  if !path.starts_with('/') { push_names(&mut names, cwd); }
+  let mut links = 0;
+  while let Some(name) = names.pop() {
+    let dir = dirs.last().unwrap();
+    match name.as_str() {
+      "." => continue,
+      ".." => {
+        if dirs.len() > 1 { dirs.pop(); }
+        continue;
+      }
+      _ => {}
+    }
+    let inode = try_!(dir.inode.lookup(&name), None);
+    let d = dir.child(&name, inode);
+    if let Some(m) = mount_at(&d.path) {
+      dirs.push(Dentry { inode: m.fs.root(), mount: m, ..d });
+    } else if let Some(target) = d.inode.readlink().filter(|_| follow || !names.is_empty()) {
+      links += 1;
+      if links > MAX_SYMLINKS { return None; }
+      if target.starts_with('/') { dirs.truncate(1); }
+      push_names(&mut names, &target);
+    } else {
+      dirs.push(d);
+    }
+  }
+  dirs.pop()
+}
+
+/// Look up `path` following symbolic links. Its `Dentry::path` is free of them.
+pub fn lookup(cwd: &str, path: &str) -> Option<Dentry> {
+  walk(cwd, path, true)
+}
+
+/// Look up `path`, and not the target if it is a symbolic link.
+pub fn lookup_link(cwd: &str, path: &str) -> Option<Dentry> {
+  walk(cwd, path, false)
+}
+
+/// Split `path` into the directory containing it and its last component, which must not be a
+/// mount point.
+pub fn lookup_parent(cwd: &str, path: &str) -> Option<(Dentry, String)> {
+  let path = path.trim_end_matches('/');
+  let (dir, name) = match path.rfind('/') {
+    Some(i) => (&path[..i + 1], &path[i + 1..]),
+    None => ("", path),
+  };
+  if matches!(name, "" | "." | "..") { return None; }
+  let dir = lookup(cwd, dir)?;
+  if mount_at(&dir.child_path(name)).is_some() { return None; }
+  Some((dir, String::from(name)))
+}
+
+pub fn mkdir(cwd: &str, path: &str) -> bool {
+  lookup_parent(cwd, path).and_then(|(dir, name)| dir.inode.mkdir(&name)).is_some()
+}
+
+/// Create a symbolic link `path` to `target`.
+pub fn symlink(cwd: &str, target: &str, path: &str) -> bool {
+  lookup_parent(cwd, path).map_or(false, |(dir, name)| dir.inode.symlink(&name, target))
+}
+
+/// Remove an empty directory.
+pub fn rmdir(cwd: &str, path: &str) -> bool {
+  lookup_parent(cwd, path).map_or(false, |(dir, name)| dir.inode.rmdir(&name))
+}
+
+/// Remove a file name, the file is freed with its last name.
+pub fn unlink(cwd: &str, path: &str) -> bool {
+  lookup_parent(cwd, path).map_or(false, |(dir, name)| dir.inode.unlink(&name))
+}
+
+/// Make `new_path` another name of the file `old_path`, in the same file system.
+pub fn link(cwd: &str, old_path: &str, new_path: &str) -> bool {
+  match (lookup(cwd, old_path), lookup_parent(cwd, new_path)) {
+    (Some(d), Some((dir, name))) if Arc::ptr_eq(&d.mount, &dir.mount) =>
+      dir.inode.link(&name, d.inode.as_ref()),
+    _ => false,
+  }
+}
+
+/// Move `old_path` to `new_path`, in the same file system.
+pub fn rename(cwd: &str, old_path: &str, new_path: &str) -> bool {
+  match (lookup_parent(cwd, old_path), lookup_parent(cwd, new_path)) {
+    (Some((old_dir, old_name)), Some((new_dir, new_name)))
+      if Arc::ptr_eq(&old_dir.mount, &new_dir.mount) =>
+      old_dir.inode.rename(&old_name, new_dir.inode.as_ref(), &new_name),
+    _ => false,
+  }
+}
+
+/// Write all modified data of every file system back.
+pub fn sync() {
+  for m in MOUNTS.to_vec() {
+    m.fs.sync();
+  }
+}
+
+/// Open or create `path` according to `flags`.
+pub fn open(cwd: &str, path: &str, flags: OpenFlags) -> Option<Dentry> {
+  let writable = flags.read_write().1;
+  let clear = flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNC);
+  if let Some(d) = lookup(cwd, path) {
+    // Directories can only be opened for reading.
+    if d.inode.is_dir() && (writable || clear) { return None; }
+    if clear && !d.inode.truncate(0) { return None; }
+    Some(d)
+  } else if flags.contains(OpenFlags::CREATE) {
+    let (dir, name) = lookup_parent(cwd, path)?;
+    let inode = dir.inode.create(&name)?;
+    Some(dir.child(&name, inode))
+  } else {
+    None
+  }
+}
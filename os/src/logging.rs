@@ -0,0 +1,116 @@
+//! Kernel logger for the `log` crate. Every enabled record goes to the dmesg ring buffer, and
+//! records at least as severe as the console level are also printed.
+//!
+//! Filters come from the kernel command line:
+//! - `log=<directives>`: comma-separated `[module=]level`, e.g. `log=warn,os::drivers=trace`.
+//!   The longest matching module prefix wins. The default is `info`.
+//! - `loglevel=<level>`: the console level, `warn` by default.
+
+use crate::*;
+use core::fmt::{self, Write};
+use log::{LevelFilter, Log, Metadata, Record};
+
+const DMESG_SIZE: usize = 1 << 16;
+
+/// A byte ring that overwrites the oldest messages when full.
+pub struct Dmesg {
+  buf: [u8; DMESG_SIZE],
+  /// Total number of bytes ever written.
+  written: usize,
+  /// Value of `written` when the buffer was last cleared.
+  cleared: usize,
+}
+
+pub static DMESG: Cell<Dmesg> = zero();
+
+static CONSOLE_LEVEL: Cell<LevelFilter> = Cell::new(LevelFilter::Warn);
+
+impl Dmesg {
+  pub fn capacity(&self) -> usize { DMESG_SIZE }
+
+  /// Copy the most recent messages into `buf`, return the number of bytes copied.
+  pub fn read(&self, buf: &mut [u8]) -> usize {
+    let len = (self.written - self.cleared).min(DMESG_SIZE).min(buf.len());
+    for (i, b) in buf[..len].iter_mut().enumerate() {
+      *b = self.buf[(self.written - len + i) % DMESG_SIZE];
+    }
+    len
+  }
+
+  pub fn clear(&mut self) {
+    self.cleared = self.written;
+  }
+}
+
+impl Write for Dmesg {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    for &c in s.as_bytes() {
+      self.buf[self.written % DMESG_SIZE] = c;
+      self.written += 1;
+    }
+    Ok(())
+  }
+}
+
+struct Logger;
+
+/// Return the level enabled for `target` by the `log=` directives.
+fn target_level(target: &str) -> LevelFilter {
+  let mut best = (0, LevelFilter::Info);
+  for directive in cmdline_arg("log").unwrap_or("").split(',').filter(|d| !d.is_empty()) {
+    let (module, level) = directive.split_once('=').unwrap_or(("", directive));
+    let level = if let Ok(level) = level.parse() { level } else { continue };
+    let matches = module.is_empty() || target == module ||
+      (target.starts_with(module) && target[module.len()..].starts_with("::"));
+    if matches && module.len() >= best.0 {
+      best = (module.len(), level);
+    }
+  }
+  best.1
+}
+
+impl Log for Logger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    metadata.level() <= target_level(metadata.target())
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) { return; }
+    let ms = *pic::TICKS;
+    macro_rules! entry {
+      () => {
+        format_args!("[{:5}.{:03} {:5} {}] {}\n", ms / 1000, ms % 1000, record.level(),
+          record.target(), record.args())
+      };
+    }
+    DMESG.get().write_fmt(entry!()).unwrap();
+    if record.level() <= *CONSOLE_LEVEL {
+      console::print(entry!());
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+pub fn init() {
+  static LOGGER: Logger = Logger;
+  if let Some(level) = cmdline_arg("loglevel").and_then(|l| l.parse().ok()) {
+    *CONSOLE_LEVEL.get() = level;
+  }
+  log::set_logger(&LOGGER).unwrap();
+  // The most verbose level of any directive, finer filtering is done by `Logger::enabled`.
+  let max = cmdline_arg("log").unwrap_or("").split(',')
+    .filter_map(|d| d.rsplit('=').next().unwrap().parse().ok())
+    .fold(LevelFilter::Info, |a: LevelFilter, b| a.max(b));
+  log::set_max_level(max);
+}
+
+/// Set the console level, where 0 is off and 1 (error) to 5 (trace) follow `log::Level`.
+pub fn set_console_level(level: usize) -> bool {
+  if let Some(level) = LevelFilter::iter().nth(level) {
+    *CONSOLE_LEVEL.get() = level;
+    true
+  } else {
+    false
+  }
+}
@@ -7,6 +7,8 @@
 #![feature(const_btree_new)]
 
 extern crate alloc;
+#[macro_use]
+extern crate log;
 
 use core::{mem, cell::UnsafeCell, ops::{Deref, DerefMut}, panic::PanicInfo};
 
@@ -20,6 +22,7 @@ mod console;
 
 mod drivers;
 mod fs;
+mod logging;
 mod mm;
 mod sync;
 mod syscall;
@@ -32,6 +35,11 @@ mod x86_64;
 #[no_mangle]
 extern "C" fn _start(boot_info: &'static rboot::BootInfo) -> ! {
   console::init();
+  let len = boot_info.cmdline.len().min(CMDLINE_MAX);
+  CMDLINE.get().0[..len].copy_from_slice(&boot_info.cmdline.as_bytes()[..len]);
+  CMDLINE.get().1 = len;
+  logging::init();
+  drivers::FB_CONSOLE.get().init(&boot_info.graphic_info);
   trap::init();
   pic::init();
 
@@ -43,11 +51,16 @@ extern "C" fn _start(boot_info: &'static rboot::BootInfo) -> ! {
     }
   }
   size *= mm::PAGE_SIZE as u64;
-  println!("[kernel] physical frames start = {:x}, size = {:x}", start, size);
+  info!("physical frames start = {:x}, size = {:x}", start, size);
   mm::init(start as _, size as _);
 
   drivers::init();
-  fs::init();
+  // The boot loader leaves the initramfs out of the conventional memory used for frames.
+  let initramfs = unsafe {
+    let data = mm::phys_to_virt(boot_info.initramfs_addr as _) as *mut u8;
+    core::slice::from_raw_parts_mut(data, boot_info.initramfs_size as _)
+  };
+  fs::init(initramfs);
   task::init();
 }
 
@@ -87,6 +100,18 @@ impl<T> DerefMut for Cell<T> {
   fn deref_mut(&mut self) -> &mut Self::Target { self.get() }
 }
 
+const CMDLINE_MAX: usize = 256;
+
+/// A copy of the kernel command line, whose memory is no longer mapped after `mm::init`.
+static CMDLINE: Cell<([u8; CMDLINE_MAX], usize)> = zero();
+
+/// Return the value of the `key=value` option in the kernel command line.
+pub fn cmdline_arg(key: &str) -> Option<&'static str> {
+  let (buf, len) = &*CMDLINE;
+  core::str::from_utf8(&buf[..*len]).ok()?.split_whitespace()
+    .find_map(|opt| opt.strip_prefix(key)?.strip_prefix('='))
+}
+
 #[no_mangle]
 fn rust_oom() -> ! { panic!("rust_oom"); }
 
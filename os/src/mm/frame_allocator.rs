@@ -3,7 +3,7 @@ use super::*;
 use core::num::NonZeroUsize;
 
 static FRAME_ALLOCATOR: Cell<FreeListAllocator> = Cell::new(
-  FreeListAllocator { current: 0, end: 0, free_list: Vec::new() });
+  FreeListAllocator { start: 0, current: 0, end: 0, free_list: Vec::new() });
 
 trait FrameAllocator {
   fn alloc(&mut self) -> Option<usize>;
@@ -11,6 +11,7 @@ trait FrameAllocator {
 }
 
 pub struct FreeListAllocator {
+  start: usize,
   current: usize,
   end: usize,
   free_list: Vec<usize>,
@@ -45,10 +46,6 @@ impl PhysFrame {
     FRAME_ALLOCATOR.get().alloc().map(Self)
   }
 
-  pub fn dealloc(pa: usize) {
-    FRAME_ALLOCATOR.get().dealloc(pa)
-  }
-
   pub fn alloc_zero() -> Option<Self> {
     let mut f = Self::alloc()?;
     f.zero();
@@ -70,7 +67,14 @@ impl Drop for PhysFrame {
   }
 }
 
+/// Return the number of frames and of those free.
+pub fn frame_stats() -> (usize, usize) {
+  let a = &*FRAME_ALLOCATOR;
+  ((a.end - a.start) / PAGE_SIZE, (a.end - a.current) / PAGE_SIZE + a.free_list.len())
+}
+
 pub(crate) fn init(start: usize, size: usize) {
+  FRAME_ALLOCATOR.get().start = start;
   FRAME_ALLOCATOR.get().current = start;
   FRAME_ALLOCATOR.get().end = start + size;
 }
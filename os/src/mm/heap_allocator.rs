@@ -21,6 +21,12 @@ unsafe impl GlobalAlloc for LockedHeap {
 
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
+/// Return the bytes of the kernel heap and of those allocated.
+pub fn heap_stats() -> (usize, usize) {
+  let heap = HEAP_ALLOCATOR.0.get();
+  (heap.stats_total_bytes(), heap.stats_alloc_actual())
+}
+
 pub(crate) fn init() {
   unsafe { HEAP_ALLOCATOR.0.get().init(HEAP_SPACE.as_ptr() as _, KERNEL_HEAP_SIZE); }
 }
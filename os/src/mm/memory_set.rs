@@ -73,6 +73,11 @@ impl MemorySet {
     Self { pt: PageTable::new(), areas: BTreeMap::new() }
   }
 
+  /// Areas in order of address.
+  pub fn areas(&self) -> impl Iterator<Item = &MapArea> {
+    self.areas.values()
+  }
+
   pub fn insert(&mut self, area: MapArea) {
     if area.size > 0 {
       // TODO: check overlap
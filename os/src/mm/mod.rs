@@ -5,7 +5,7 @@ mod page_table;
 
 use core::fmt;
 
-pub use self::{frame_allocator::*, memory_set::*, page_table::*};
+pub use self::{frame_allocator::*, heap_allocator::heap_stats, memory_set::*, page_table::*};
 
 /// Total mapped memory in kernel. Region [ekernel, KERNEL_SIZE) is allocated as physical frames.
 /// It occupies less than one top-level entry in the kernel page table,
@@ -30,6 +30,8 @@ bitflags::bitflags! {
     const WRITE_THROUGH =   1 << 3;
     /// Disables caching for the pointed entry is cacheable.
     const NO_CACHE =        1 << 4;
+    /// Maps a 2 MiB page from a level 2 table, or a 1 GiB page from a level 3 table.
+    const HUGE_PAGE =       1 << 7;
     /// Indicates that the mapping is present in all address spaces, so it isn't flushed from
     /// the TLB on an address space switch.
     const GLOBAL =          1 << 8;
@@ -52,8 +54,6 @@ pub struct VirtAddr(pub usize);
 
 pub const fn phys_to_virt(pa: usize) -> usize { pa + PHYS_OFFSET }
 
-pub const fn virt_to_phys(va: usize) -> usize { va - PHYS_OFFSET }
-
 impl fmt::Debug for PhysAddr {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     write!(f, "PA:{:#x}", self.0)
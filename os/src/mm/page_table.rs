@@ -149,6 +149,32 @@ fn next_table_or_create<'a>(entry: &mut PageTableEntry, mut alloc: impl FnMut()
   }
 }
 
+/// Map the device registers at the physical range [pa, pa + size) to `phys_to_virt(pa)`, where
+/// the boot loader only mapped the memory, using uncached 2 MiB pages. Since the level 3 table
+/// of the physical memory is shared by all page tables, they all see the mapping.
+pub fn map_mmio(pa: usize, size: usize) {
+  const HUGE_PAGE_SIZE: usize = PAGE_SIZE * ENTRY_COUNT;
+  let p3 = next_table(&PHYS_PTE).unwrap();
+  let start = pa & !(HUGE_PAGE_SIZE - 1);
+  for page in (start..pa + size).step_by(HUGE_PAGE_SIZE) {
+    let va = VirtAddr(phys_to_virt(page));
+    let p3e = &mut p3[p3_index(va)];
+    if p3e.flags().contains(PTFlags::HUGE_PAGE) { continue; }
+    // The table is never freed, like the mapping.
+    let p2 = next_table_or_create(p3e, || {
+      let frame = PhysFrame::alloc_zero().unwrap();
+      let table = frame.start_pa();
+      core::mem::forget(frame);
+      table
+    }).unwrap();
+    let p2e = &mut p2[p2_index(va)];
+    if p2e.is_unused() {
+      let flags = PTFlags::PRESENT | PTFlags::WRITABLE | PTFlags::NO_CACHE | PTFlags::HUGE_PAGE;
+      *p2e = PageTableEntry::new_page(PhysAddr(page), flags);
+    }
+  }
+}
+
 pub(crate) fn init() {
   let cr3 = x86_64::get_cr3();
   let p4 = table_of(PhysAddr(cr3));
@@ -1,6 +1,8 @@
 use crate::{*, fs::*};
 use super::{*, uaccess::*};
 
+const AT_REMOVEDIR: u32 = 0x200;
+
 pub fn sys_dup(fd: usize) -> isize {
   let t = task::current();
   let file = if let Some(Some(x)) = &t.proc.files.get(fd) { x } else { return -1; };
@@ -11,7 +13,7 @@ pub fn sys_dup(fd: usize) -> isize {
 pub fn sys_open(path: *const u8, flags: u32) -> isize {
   let t = task::current();
   let path = try_!(read_cstr(path), EFAULT);
-  if let Some(inode) = open_file(&path, OpenFlags::from_bits(flags).unwrap()) {
+  if let Some(inode) = open_file(&t.proc.cwd, &path, OpenFlags::from_bits(flags).unwrap()) {
     t.proc.add_file(inode) as _
   } else {
     -1
@@ -52,3 +54,163 @@ pub fn sys_read(fd: usize, ptr: *mut u8, len: usize) -> isize {
   let buf = try_!(validate_buf(root_pa, ptr, len, true), EFAULT);
   file.read(buf) as _
 }
+
+pub fn sys_ioctl(fd: usize, cmd: u32, arg: usize) -> isize {
+  let t = task::current();
+  let file = if let Some(Some(x)) = &t.proc.files.get(fd) { x } else { return -1; };
+  file.ioctl(cmd, arg)
+}
+
+pub fn sys_getdents(fd: usize, ptr: *mut u8, len: usize) -> isize {
+  let t = task::current();
+  let root_pa = t.proc.root_pa();
+  let file = if let Some(Some(x)) = &t.proc.files.get(fd) { x } else { return -1; };
+  let buf = try_!(validate_buf(root_pa, ptr, len, true), EFAULT);
+  file.getdents(buf)
+}
+
+/// Remove a file, or an empty directory with AT_REMOVEDIR.
+pub fn sys_unlink(path: *const u8, flags: u32) -> isize {
+  let t = task::current();
+  let path = try_!(read_cstr(path), EFAULT);
+  let cwd = &t.proc.cwd;
+  let ok = if flags & AT_REMOVEDIR != 0 { rmdir(cwd, &path) } else { unlink(cwd, &path) };
+  if ok { 0 } else { -1 }
+}
+
+pub fn sys_link(old_path: *const u8, new_path: *const u8) -> isize {
+  let t = task::current();
+  let old_path = try_!(read_cstr(old_path), EFAULT);
+  let new_path = try_!(read_cstr(new_path), EFAULT);
+  if link(&t.proc.cwd, &old_path, &new_path) { 0 } else { -1 }
+}
+
+pub fn sys_rename(old_path: *const u8, new_path: *const u8) -> isize {
+  let t = task::current();
+  let old_path = try_!(read_cstr(old_path), EFAULT);
+  let new_path = try_!(read_cstr(new_path), EFAULT);
+  if rename(&t.proc.cwd, &old_path, &new_path) { 0 } else { -1 }
+}
+
+/// Create a symbolic link `path` to `target`.
+pub fn sys_symlink(target: *const u8, path: *const u8) -> isize {
+  let t = task::current();
+  let target = try_!(read_cstr(target), EFAULT);
+  let path = try_!(read_cstr(path), EFAULT);
+  if symlink(&t.proc.cwd, &target, &path) { 0 } else { -1 }
+}
+
+/// Copy the target of the symbolic link `path` to `buf` without a '\0' terminator, return the
+/// length copied.
+pub fn sys_readlink(path: *const u8, buf: *mut u8, len: usize) -> isize {
+  let p = &task::current().proc;
+  let path = try_!(read_cstr(path), EFAULT);
+  let target = try_!(lookup_link(&p.cwd, &path).and_then(|d| d.inode.readlink()), -1);
+  let len = len.min(target.len());
+  let buf = try_!(validate_buf(p.root_pa(), buf, len, true), EFAULT);
+  buf.copy_from_slice(&target.as_bytes()[..len]);
+  len as _
+}
+
+pub fn sys_mkdir(path: *const u8) -> isize {
+  let t = task::current();
+  let path = try_!(read_cstr(path), EFAULT);
+  if mkdir(&t.proc.cwd, &path) { 0 } else { -1 }
+}
+
+/// Mount the file system `fstype` on `source` at the directory `target`.
+pub fn sys_mount(source: *const u8, target: *const u8, fstype: *const u8) -> isize {
+  let t = task::current();
+  let source = try_!(read_cstr(source), EFAULT);
+  let target = try_!(read_cstr(target), EFAULT);
+  let fstype = try_!(read_cstr(fstype), EFAULT);
+  if mount(&t.proc.cwd, &source, &target, &fstype) { 0 } else { -1 }
+}
+
+pub fn sys_umount(target: *const u8) -> isize {
+  let t = task::current();
+  let target = try_!(read_cstr(target), EFAULT);
+  if umount(&t.proc.cwd, &target) { 0 } else { -1 }
+}
+
+pub fn sys_chdir(path: *const u8) -> isize {
+  let p = &mut task::current().proc;
+  let path = try_!(read_cstr(path), EFAULT);
+  let dir = try_!(lookup(&p.cwd, &path).filter(|d| d.inode.is_dir()), -1);
+  p.cwd = dir.path;
+  0
+}
+
+/// Copy the working directory with a '\0' terminator to `buf`, return the length copied.
+pub fn sys_getcwd(buf: *mut u8, len: usize) -> isize {
+  let p = &task::current().proc;
+  if p.cwd.len() + 1 > len { return -1; }
+  let buf = try_!(validate_buf(p.root_pa(), buf, p.cwd.len() + 1, true), EFAULT);
+  buf[..p.cwd.len()].copy_from_slice(p.cwd.as_bytes());
+  buf[p.cwd.len()] = 0;
+  buf.len() as _
+}
+
+fn write_stat(st: *mut Stat, stat: Stat) -> isize {
+  let p = &task::current().proc;
+  let buf = try_!(validate_buf(p.root_pa(), st as _, size_of::<Stat>(), true), EFAULT);
+  buf.copy_from_slice(unsafe {
+    core::slice::from_raw_parts(&stat as *const _ as *const u8, size_of::<Stat>())
+  });
+  0
+}
+
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
+  let t = task::current();
+  let file = if let Some(Some(x)) = &t.proc.files.get(fd) { x } else { return -1; };
+  write_stat(st, try_!(file.stat(), -1))
+}
+
+pub fn sys_stat(path: *const u8, st: *mut Stat) -> isize {
+  let t = task::current();
+  let path = try_!(read_cstr(path), EFAULT);
+  let file = try_!(open_file(&t.proc.cwd, &path, OpenFlags::RDONLY), -1);
+  write_stat(st, file.stat().unwrap())
+}
+
+pub fn sys_sync() -> isize {
+  sync();
+  0
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+  let t = task::current();
+  let file = if let Some(Some(x)) = &t.proc.files.get(fd) { x } else { return -1; };
+  file.fsync()
+}
+
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+  let t = task::current();
+  let file = if let Some(Some(x)) = &t.proc.files.get(fd) { x } else { return -1; };
+  file.seek(offset, whence)
+}
+
+pub fn sys_pread(fd: usize, ptr: *mut u8, len: usize, offset: usize) -> isize {
+  let t = task::current();
+  let root_pa = t.proc.root_pa();
+  let file = if let Some(Some(x)) = &t.proc.files.get(fd) { x } else { return -1; };
+  if !file.readable() { return -1; }
+  let buf = try_!(validate_buf(root_pa, ptr, len, true), EFAULT);
+  file.read_at(offset, buf)
+}
+
+pub fn sys_pwrite(fd: usize, ptr: *const u8, len: usize, offset: usize) -> isize {
+  let t = task::current();
+  let root_pa = t.proc.root_pa();
+  let file = if let Some(Some(x)) = &t.proc.files.get(fd) { x } else { return -1; };
+  if !file.writable() { return -1; }
+  let buf = try_!(validate_buf(root_pa, ptr, len, false), EFAULT);
+  file.write_at(offset, buf)
+}
+
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+  let t = task::current();
+  let file = if let Some(Some(x)) = &t.proc.files.get(fd) { x } else { return -1; };
+  if !file.writable() { return -1; }
+  file.truncate(len)
+}
@@ -0,0 +1,28 @@
+use crate::{*, logging::*};
+use super::{*, uaccess::*};
+
+const SYSLOG_ACTION_READ_ALL: usize = 3;
+const SYSLOG_ACTION_READ_CLEAR: usize = 4;
+const SYSLOG_ACTION_CLEAR: usize = 5;
+const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+
+/// Access the kernel log buffer as Linux's syslog(2). The console level is set from `len`, with
+/// 0 for off and 1 (error) to 5 (trace).
+pub fn sys_syslog(ty: usize, buf: *mut u8, len: usize) -> isize {
+  match ty {
+    SYSLOG_ACTION_READ_ALL | SYSLOG_ACTION_READ_CLEAR => {
+      let buf = try_!(validate_buf(task::current().proc.root_pa(), buf, len, true), EFAULT);
+      let n = DMESG.read(buf);
+      if ty == SYSLOG_ACTION_READ_CLEAR { DMESG.get().clear(); }
+      n as _
+    }
+    SYSLOG_ACTION_CLEAR => {
+      DMESG.get().clear();
+      0
+    }
+    SYSLOG_ACTION_CONSOLE_LEVEL => if set_console_level(len) { 0 } else { -1 },
+    SYSLOG_ACTION_SIZE_BUFFER => DMESG.capacity() as _,
+    _ => -1,
+  }
+}
@@ -1,13 +1,39 @@
+const SYSCALL_GETCWD: usize = 17;
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_IOCTL: usize = 29;
+const SYSCALL_MKDIR: usize = 34;
+const SYSCALL_UNLINK: usize = 35;
+const SYSCALL_SYMLINK: usize = 36;
+const SYSCALL_LINK: usize = 37;
+const SYSCALL_RENAME: usize = 38;
+const SYSCALL_UMOUNT: usize = 39;
+const SYSCALL_MOUNT: usize = 40;
+const SYSCALL_FTRUNCATE: usize = 46;
+const SYSCALL_CHDIR: usize = 49;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
+const SYSCALL_GETDENTS: usize = 61;
+const SYSCALL_LSEEK: usize = 62;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_PREAD: usize = 67;
+const SYSCALL_PWRITE: usize = 68;
+const SYSCALL_READLINK: usize = 78;
+const SYSCALL_STAT: usize = 79;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_SLEEP: usize = 101;
+const SYSCALL_SYSLOG: usize = 116;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_KILL: usize = 129;
+const SYSCALL_SIGACTION: usize = 134;
+const SYSCALL_SETPGID: usize = 154;
+const SYSCALL_GETPGID: usize = 155;
+const SYSCALL_GETSID: usize = 156;
+const SYSCALL_SETSID: usize = 157;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
 const SYSCALL_FORK: usize = 220;
@@ -39,32 +65,59 @@ mod macros {
 }
 
 mod fs;
+mod misc;
 mod process;
 mod sync;
 mod uaccess;
 
-use self::{fs::*, process::*, sync::*};
+use self::{fs::*, misc::*, process::*, sync::*};
 use crate::*;
 
 pub use uaccess::*;
 
-pub fn syscall(syscall_id: usize, args: [usize; 3]) -> isize {
+pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
   match syscall_id {
+    SYSCALL_GETCWD => sys_getcwd(args[0] as _, args[1]),
     SYSCALL_DUP => sys_dup(args[0]),
+    SYSCALL_IOCTL => sys_ioctl(args[0], args[1] as _, args[2]),
+    SYSCALL_MKDIR => sys_mkdir(args[0] as _),
+    SYSCALL_UNLINK => sys_unlink(args[0] as _, args[1] as _),
+    SYSCALL_SYMLINK => sys_symlink(args[0] as _, args[1] as _),
+    SYSCALL_LINK => sys_link(args[0] as _, args[1] as _),
+    SYSCALL_RENAME => sys_rename(args[0] as _, args[1] as _),
+    SYSCALL_UMOUNT => sys_umount(args[0] as _),
+    SYSCALL_MOUNT => sys_mount(args[0] as _, args[1] as _, args[2] as _),
+    SYSCALL_FTRUNCATE => sys_ftruncate(args[0], args[1]),
+    SYSCALL_CHDIR => sys_chdir(args[0] as _),
     SYSCALL_OPEN => sys_open(args[0] as _, args[1] as _),
     SYSCALL_CLOSE => sys_close(args[0]),
     SYSCALL_PIPE => sys_pipe(args[0] as _),
+    SYSCALL_GETDENTS => sys_getdents(args[0], args[1] as _, args[2]),
+    SYSCALL_LSEEK => sys_lseek(args[0], args[1] as _, args[2]),
     SYSCALL_READ => sys_read(args[0], args[1] as _, args[2]),
     SYSCALL_WRITE => sys_write(args[0], args[1] as _, args[2]),
+    SYSCALL_PREAD => sys_pread(args[0], args[1] as _, args[2], args[3]),
+    SYSCALL_PWRITE => sys_pwrite(args[0], args[1] as _, args[2], args[3]),
+    SYSCALL_READLINK => sys_readlink(args[0] as _, args[1] as _, args[2]),
+    SYSCALL_STAT => sys_stat(args[0] as _, args[1] as _),
+    SYSCALL_FSTAT => sys_fstat(args[0], args[1] as _),
+    SYSCALL_SYNC => sys_sync(),
+    SYSCALL_FSYNC => sys_fsync(args[0]),
     SYSCALL_EXIT => sys_exit(args[0] as i32),
     SYSCALL_SLEEP => sys_sleep(args[0]),
+    SYSCALL_SYSLOG => sys_syslog(args[0], args[1] as _, args[2]),
     SYSCALL_YIELD => sys_yield(),
-    SYSCALL_KILL => sys_kill(args[0], args[1] as _),
+    SYSCALL_KILL => sys_kill(args[0] as _, args[1] as _),
+    SYSCALL_SIGACTION => sys_sigaction(args[0] as _, args[1]),
+    SYSCALL_SETPGID => sys_setpgid(args[0], args[1]),
+    SYSCALL_GETPGID => sys_getpgid(args[0]),
+    SYSCALL_GETSID => sys_getsid(args[0]),
+    SYSCALL_SETSID => sys_setsid(),
     SYSCALL_GET_TIME => *pic::TICKS as _,
     SYSCALL_GETPID => sys_getpid(),
     SYSCALL_FORK => sys_fork(),
     SYSCALL_EXEC => sys_exec(args[0] as _, args[1] as _),
-    SYSCALL_WAITPID => sys_waitpid(args[0] as _, args[1] as _),
+    SYSCALL_WAITPID => sys_waitpid(args[0] as _, args[1] as _, args[2]),
     SYSCALL_THREAD_CREATE => sys_thread_create(args[0], args[1]),
     SYSCALL_GETTID => sys_gettid(),
     SYSCALL_WAITTID => sys_waittid(args[0]),
@@ -78,7 +131,7 @@ pub fn syscall(syscall_id: usize, args: [usize; 3]) -> isize {
     SYSCALL_CONDVAR_SIGNAL => sys_condvar_signal(args[0]),
     SYSCALL_CONDVAR_WAIT => sys_condvar_wait(args[0], args[1]),
     _ => {
-      println!("[kernel] unknown syscall: {}", syscall_id);
+      warn!("unknown syscall: {}", syscall_id);
       task::current().exit(-1);
     }
   }
@@ -10,14 +10,71 @@ pub fn sys_yield() -> isize {
   0
 }
 
-pub fn sys_kill(pid: usize, signal: u32) -> isize {
-  if let Some(p) = PID2PROC.get().get_mut(&pid) {
-    if let Some(signal) = SignalFlags::from_bits(signal as _) {
-      p.add_signal(signal);
-      return 0;
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+const WUNTRACED: usize = 2;
+
+/// A positive `pid` selects a process, 0 the caller's process group and -pgid another group.
+pub fn sys_kill(pid: isize, signal: u32) -> isize {
+  let signal = try_!(SignalFlags::from_bits(signal), -1);
+  if pid > 0 {
+    try_!(PID2PROC.get().get_mut(&(pid as usize)), -1).add_signal(signal);
+    0
+  } else {
+    let pgid = if pid == 0 { current().proc.pgid } else { -pid as usize };
+    if kill_group(pgid, signal) { 0 } else { -1 }
+  }
+}
+
+/// Only SIG_DFL and SIG_IGN are supported as handlers.
+pub fn sys_sigaction(signal: u32, handler: usize) -> isize {
+  let signal = try_!(SignalFlags::from_bits(signal), -1);
+  let p = &mut current().proc;
+  match handler {
+    SIG_DFL => p.sig_ignore.remove(signal),
+    SIG_IGN if !signal.intersects(SignalFlags::SIGKILL | SignalFlags::SIGSTOP) => {
+      p.sig_ignore.insert(signal);
+      p.signal.remove(signal);
     }
+    _ => return -1,
   }
-  -1
+  0
+}
+
+/// `pid` may be 0 for the caller, and `pgid` 0 for the process's own pid.
+pub fn sys_setpgid(pid: usize, pgid: usize) -> isize {
+  let cur = &mut current().proc;
+  let sid = cur.sid;
+  let target: &mut Proc = if pid == 0 || pid == cur.pid {
+    cur
+  } else {
+    try_!(cur.children.iter_mut().find(|p| p.pid == pid && !p.zombie), -1)
+  };
+  // A session leader cannot leave its group, and groups never span sessions.
+  if target.sid != sid || target.pid == target.sid { return -1; }
+  let pgid = if pgid == 0 { target.pid } else { pgid };
+  if pgid != target.pid && !PID2PROC.values().any(|p| p.pgid == pgid && p.sid == sid) { return -1; }
+  target.pgid = pgid;
+  0
+}
+
+pub fn sys_getpgid(pid: usize) -> isize {
+  if pid == 0 { return current().proc.pgid as _; }
+  try_!(PID2PROC.get().get(&pid), -1).pgid as _
+}
+
+pub fn sys_getsid(pid: usize) -> isize {
+  if pid == 0 { return current().proc.sid as _; }
+  try_!(PID2PROC.get().get(&pid), -1).sid as _
+}
+
+pub fn sys_setsid() -> isize {
+  let p = &mut current().proc;
+  if p.pgid == p.pid { return -1; }
+  p.pgid = p.pid;
+  p.sid = p.pid;
+  p.sid as _
 }
 
 pub fn sys_getpid() -> isize {
@@ -36,8 +93,9 @@ pub fn sys_exec(path: *const u8, args: *const *const u8) -> isize {
 
 /// If there is no child process has the same pid as the given, return -1.
 /// Else if there is a child process but it is still running, return -2.
-pub fn sys_waitpid(pid: isize, exit_code_p: *mut u32) -> isize {
-  let (pid, exit_code) = task::current().proc.waitpid(pid);
+/// With WUNTRACED, a stopped child is reported with the negated stop signal as exit code.
+pub fn sys_waitpid(pid: isize, exit_code_p: *mut u32, options: usize) -> isize {
+  let (pid, exit_code) = task::current().proc.waitpid(pid, options & WUNTRACED != 0);
   if pid >= 0 && !exit_code_p.is_null() {
     try_!(exit_code_p.write_user(exit_code as _), EFAULT);
   }
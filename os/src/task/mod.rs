@@ -10,13 +10,21 @@ use crate::{*, fs::*};
 // Dirty hack. rustc is unhappy about zero value in VecDeque.
 static TASK_MANAGER: Cell<TaskManager> = unsafe { transmute([1u8; size_of::<TaskManager>()]) };
 static ROOT_PROC: Cell<usize> = zero();
+static STARTED: Cell<bool> = zero();
 
 pub fn init() -> ! {
   assert_eq!(size_of::<Task>(), TASK_SIZE);
   unsafe { (TASK_MANAGER.get() as *mut TaskManager).write(TaskManager::default()); }
+  let console = |flags| -> Option<Rc<dyn File>> {
+    Some(open_file("/", "/dev/console", flags).expect("cannot open /dev/console"))
+  };
+  let pid = new_id();
   let root = Box::leak(Box::new(Proc {
-    pid: new_id(),
-    files: vec![Some(Rc::new(Stdin)), Some(Rc::new(Stdout)), Some(Rc::new(Stdout))],
+    pid,
+    pgid: pid,
+    sid: pid,
+    cwd: String::from("/"),
+    files: vec![console(OpenFlags::RDONLY), console(OpenFlags::WRONLY), console(OpenFlags::WRONLY)],
     ..Proc::default()
   }));
   *ROOT_PROC.get() = root as *mut _ as _;
@@ -25,16 +33,22 @@ pub fn init() -> ! {
     // Running idle and recycle orphans.
     loop {
       x86_64::disable_interrupts();
-      cur.proc.waitpid(-1);
+      cur.proc.waitpid(-1, false);
       x86_64::enable_interrupts_and_hlt();
     }
   }, 0);
   let shell = root.fork();
   shell.exec("user_shell", Vec::new());
+  *STARTED.get() = true;
   unsafe { context_switch(&mut Context::default(), &TASK_MANAGER.get().dequeue().ctx); }
   unreachable!();
 }
 
+/// Whether the first task is running, so that the current task can block.
+pub fn started() -> bool {
+  *STARTED
+}
+
 pub fn root_proc() -> ProcPtr {
   unsafe { transmute(*ROOT_PROC) }
 }
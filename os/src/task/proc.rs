@@ -4,7 +4,19 @@ use super::*;
 #[derive(Default)]
 pub struct Proc {
   pub pid: usize,
+  /// Process group, used for job control.
+  pub pgid: usize,
+  /// Session, the set of process groups sharing a controlling terminal.
+  pub sid: usize,
   pub signal: SignalFlags,
+  pub sig_ignore: SignalFlags,
+  pub stopped: bool,
+  /// Stop code not yet reported to `waitpid` by the parent.
+  pub stop_code: Option<i32>,
+  /// Absolute path of the current working directory, without "." or "..".
+  pub cwd: String,
+  /// Arguments of `exec`, or its path if there were none.
+  pub cmdline: Vec<String>,
   pub zombie: bool,
   pub exit_code: i32,
   pub vm: Option<MemorySet>,
@@ -34,6 +46,11 @@ impl Proc {
     assert_eq!(self.tasks.len(), 1);
     let child = Box::leak(Box::new(Proc {
       pid: new_id(),
+      pgid: self.pgid,
+      sid: self.sid,
+      sig_ignore: self.sig_ignore,
+      cwd: self.cwd.clone(),
+      cmdline: self.cmdline.clone(),
       vm: self.vm.clone(),
       files: self.files.clone(),
       ..Proc::default()
@@ -53,7 +70,7 @@ impl Proc {
   /// Only support processes with a single thread.
   pub fn exec(&mut self, path: &str, args: Vec<String>) -> isize {
     assert_eq!(self.tasks.len(), 1);
-    if let Some(file) = open_file(path, OpenFlags::RDONLY) {
+    if let Some(file) = open_file(&self.cwd, path, OpenFlags::RDONLY) {
       let elf_data = file.read_all();
       let (entry, vm) = mm::load_app(&elf_data);
       vm.activate(); // To access ustack.
@@ -76,15 +93,17 @@ impl Proc {
       f.callee.rsp = top as usize & !0xF; // Align down to 16.
       f.caller.rdi = args.len(); // _start parameter argc.
       f.caller.rsi = argv as _; // _start parameter argv.
+      self.cmdline = if args.is_empty() { vec![String::from(path)] } else { args };
       0
     } else {
       -1
     }
   }
 
-  pub fn waitpid(&mut self, pid: isize) -> (isize, i32) {
+  /// If `untraced` is set, also report children that have stopped since the last call.
+  pub fn waitpid(&mut self, pid: isize, untraced: bool) -> (isize, i32) {
     let mut found_pid = false;
-    for (idx, p) in self.children.iter().enumerate() {
+    for (idx, p) in self.children.iter_mut().enumerate() {
       if pid == -1 || p.pid == pid as usize {
         found_pid = true;
         if p.zombie {
@@ -93,6 +112,11 @@ impl Proc {
           unsafe { Box::from_raw(child); } // Drop it.
           return ret;
         }
+        if untraced && p.stopped {
+          if let Some(code) = p.stop_code.take() {
+            return (p.pid as _, code);
+          }
+        }
       }
     }
     (if found_pid { -2 } else { -1 }, 0)
@@ -122,6 +146,11 @@ impl Proc {
 
   pub fn add_signal(&mut self, signal: SignalFlags) {
     assert!(self.vm.is_some()); // Must not be a kernel task.
-    self.signal |= signal;
+    // SIGCONT resumes the process even if ignored, and is never left pending.
+    if signal.contains(SignalFlags::SIGCONT) {
+      self.signal.remove(SignalFlags::STOP);
+      self.stopped = false;
+    }
+    self.signal |= signal - self.sig_ignore - SignalFlags::SIGCONT;
   }
 }
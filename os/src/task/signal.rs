@@ -1,12 +1,18 @@
 use crate::*;
+use super::PID2PROC;
 
 bitflags::bitflags! {
-  pub struct SignalFlags: u16 {
+  pub struct SignalFlags: u32 {
     const SIGINT    = 1 << 2;
     const SIGILL    = 1 << 4;
     const SIGABRT   = 1 << 6;
     const SIGFPE    = 1 << 8;
+    const SIGKILL   = 1 << 9;
     const SIGSEGV   = 1 << 11;
+    const SIGCONT   = 1 << 18;
+    const SIGSTOP   = 1 << 19;
+    const SIGTSTP   = 1 << 20;
+    const SIGTTIN   = 1 << 21;
   }
 }
 
@@ -15,8 +21,13 @@ impl Default for SignalFlags {
 }
 
 impl SignalFlags {
+  /// Signals that suspend the process until SIGCONT.
+  pub const STOP: Self = Self { bits: Self::SIGSTOP.bits | Self::SIGTSTP.bits | Self::SIGTTIN.bits };
+
   pub fn check_error(self) -> Option<(i32, &'static str)> {
-    if self.contains(Self::SIGINT) {
+    if self.contains(Self::SIGKILL) {
+      Some((-9, "Killed, SIGKILL=9"))
+    } else if self.contains(Self::SIGINT) {
       Some((-2, "Killed, SIGINT=2"))
     } else if self.contains(Self::SIGILL) {
       Some((-4, "Illegal Instruction, SIGILL=4"))
@@ -30,12 +41,41 @@ impl SignalFlags {
       None
     }
   }
+
+  /// Return the code reported to `waitpid` for a pending stop signal, i.e. the
+  /// negated signal number as for fatal signals.
+  pub fn check_stop(self) -> Option<i32> {
+    let stop = self & Self::STOP;
+    if stop.is_empty() { None } else { Some(-(stop.bits.trailing_zeros() as i32)) }
+  }
+}
+
+/// Send `signal` to every process in group `pgid`. Return false if there is no such group.
+pub fn kill_group(pgid: usize, signal: SignalFlags) -> bool {
+  let mut found = false;
+  for p in PID2PROC.get().values_mut() {
+    if p.pgid == pgid {
+      p.add_signal(signal);
+      found = true;
+    }
+  }
+  found
 }
 
 pub fn current_check_signal() {
   let t = task::current();
-  if let Some((code, msg)) = t.proc.signal.check_error() {
-    println!("[kernel] {}", msg);
-    t.exit(code);
+  loop {
+    if let Some((code, msg)) = t.proc.signal.check_error() {
+      info!("Proc {}: {}", t.proc.pid, msg);
+      t.exit(code);
+    }
+    if let Some(code) = t.proc.signal.check_stop() {
+      t.proc.signal.remove(SignalFlags::STOP);
+      t.proc.stopped = true;
+      t.proc.stop_code = Some(code);
+    }
+    if !t.proc.stopped { break; }
+    // Keep yielding until SIGCONT or a fatal signal arrives.
+    task::sched_yield();
   }
 }
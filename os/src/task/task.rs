@@ -97,9 +97,11 @@ impl Task {
   }
 
   pub fn exit(&mut self, exit_code: i32) -> ! {
-    println!("[kernel] Proc {} task {} exited with code {}", self.proc.pid, self.tid, exit_code);
+    info!("Proc {} task {} exited with code {}", self.proc.pid, self.tid, exit_code);
     if self.tid == 0 {
       let p = &mut self.proc;
+      // another task of the process may be waiting for the disk
+      fs::wait_fs_leave(p.pid);
       PID2PROC.get().remove(&p.pid).unwrap();
       p.vm = None;
       p.zombie = true;
@@ -114,6 +116,8 @@ impl Task {
       }
       TASK_MANAGER.get().clear_zombie();
       clear_zombie_timer();
+      TTY.get().clear_zombie();
+      fs::clear_zombie();
       p.tasks.drain(1..);
       p.files.clear();
     }
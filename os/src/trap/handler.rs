@@ -4,7 +4,7 @@ use super::*;
 #[no_mangle]
 pub extern "C" fn syscall_handler(f: &'static mut SyscallFrame) -> isize {
   let r = &f.caller;
-  let ret = syscall::syscall(r.rax, [r.rdi, r.rsi, r.rdx]);
+  let ret = syscall::syscall(r.rax, [r.rdi, r.rsi, r.rdx, r.r10]);
   current_check_signal();
   ret
 }
@@ -16,16 +16,38 @@ const STACK_SEGMENT_FAULT: usize = 12;
 const GENERAL_PROTECTION_FAULT: usize = 13;
 const PAGE_FAULT: usize = 14;
 const TIMER: usize = 32;
+const KEYBOARD: usize = 33;
+const COM1: usize = 36;
+
+/// Number of traps taken by vector.
+pub static INTERRUPTS: Cell<[usize; 256]> = zero();
+
+/// Name of the trap `id`, empty if unknown.
+pub fn interrupt_name(id: usize) -> &'static str {
+  match id {
+    DIVIDE_BY_ZERO => "divide error",
+    INVALID_OPCODE => "invalid opcode",
+    SEGMENT_NOT_PRESENT => "segment not present",
+    STACK_SEGMENT_FAULT => "stack segment fault",
+    GENERAL_PROTECTION_FAULT => "general protection fault",
+    PAGE_FAULT => "page fault",
+    TIMER => "timer",
+    KEYBOARD => "keyboard",
+    COM1 => "com1",
+    id => drivers::interrupt_name(id).unwrap_or(""),
+  }
+}
 
 #[no_mangle]
 pub extern "C" fn trap_handler(f: &'static mut TrapFrame) {
+  if let Some(count) = INTERRUPTS.get().get_mut(f.id) { *count += 1; }
   match f.id {
     DIVIDE_BY_ZERO =>  current().proc.add_signal(SignalFlags::SIGFPE),
     INVALID_OPCODE => current().proc.add_signal(SignalFlags::SIGILL),
     SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT =>
       current().proc.add_signal(SignalFlags::SIGSEGV),
     PAGE_FAULT => if f.rip >= syscall::copy_user_start as usize && f.rip < syscall::copy_user_end as usize {
-      println!("[kernel] copy_user_fail");
+      warn!("copy_user_fail");
       f.rip = syscall::copy_user_fail as usize;
       return;
     } else {
@@ -37,8 +59,17 @@ pub extern "C" fn trap_handler(f: &'static mut TrapFrame) {
       check_timer();
       sched_yield();
     }
+    KEYBOARD => {
+      pic::ack();
+      drivers::KEYBOARD.get().interrupt();
+    }
+    COM1 => {
+      pic::ack();
+      fs::TTY.get().poll();
+    }
+    id if drivers::interrupt(id) => {}
     _ => {
-      println!("[kernel] unknown trap {:x?}", f);
+      error!("unknown trap {:x?}", f);
       current().exit(-1);
     }
   }
@@ -1,5 +1,7 @@
 mod handler;
 
+pub use handler::{interrupt_name, INTERRUPTS};
+
 use crate::{*, x86_64::*};
 
 core::arch::global_asm!(include_str!("trap.S"));
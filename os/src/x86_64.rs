@@ -54,6 +54,21 @@ pub fn enable_interrupts_and_hlt() {
   unsafe { asm!("sti; hlt", options(nomem, nostack)); }
 }
 
+/// Read the time-stamp counter.
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+  unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// A random number from the hardware generator, None if the CPU has none or it ran dry.
+pub fn rdrand() -> Option<u64> {
+  // CPUID.01H:ECX.RDRAND[bit 30]
+  if unsafe { core::arch::x86_64::__cpuid(1) }.ecx & (1 << 30) == 0 { return None; }
+  let (val, ok): (u64, u8);
+  unsafe { asm!("rdrand {}", "setc {}", out(reg) val, out(reg_byte) ok, options(nomem, nostack)); }
+  if ok != 0 { Some(val) } else { None }
+}
+
 pub const RING0: u16 = 0;
 pub const RING3: u16 = 3;
 
@@ -4,7 +4,7 @@
 extern crate alloc;
 
 use alloc::vec::Vec;
-pub use uefi::proto::console::gop::ModeInfo;
+pub use uefi::proto::console::gop::{ModeInfo, PixelFormat};
 pub use uefi::table::boot::{MemoryAttribute, MemoryDescriptor, MemoryType};
 
 /// This structure represents the information that the bootloader passes to the kernel.
@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{syslog_clear, syslog_console_level, syslog_read};
+
+static mut BUF: [u8; 1 << 16] = [0; 1 << 16];
+
+/// Usage: dmesg [-c | -C | -n level]
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    match (argc, argv.get(1).copied()) {
+        (1, _) | (2, Some("-c")) => {
+            let buf = unsafe { &mut BUF };
+            let len = syslog_read(buf, argc == 2);
+            if len < 0 {
+                println!("dmesg: read kernel buffer failed");
+                return -1;
+            }
+            print!("{}", core::str::from_utf8(&buf[..len as usize]).unwrap_or(""));
+        }
+        (2, Some("-C")) => {
+            syslog_clear();
+        }
+        (3, Some("-n")) => match argv[2].parse() {
+            Ok(level) if syslog_console_level(level) == 0 => {}
+            _ => {
+                println!("dmesg: invalid level {}", argv[2]);
+                return -1;
+            }
+        },
+        _ => {
+            println!("usage: dmesg [-c | -C | -n level]");
+            return -1;
+        }
+    }
+    0
+}
@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    close, fstat, ftruncate, lseek, open, pread, pwrite, read, write, OpenFlags, Stat, SEEK_CUR,
+    SEEK_END, SEEK_SET,
+};
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fileb = "/tmp/fileb\0";
+    let fd = open(fileb, OpenFlags::CREATE | OpenFlags::RDWR);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    write(fd, b"Hello, world!");
+    let mut buffer = [0u8; 100];
+
+    // seek back and read the second word
+    assert_eq!(lseek(fd, 7, SEEK_SET), 7);
+    assert_eq!(read(fd, &mut buffer[..5]), 5);
+    assert_eq!(&buffer[..5], b"world");
+    assert_eq!(lseek(fd, -6, SEEK_CUR), 6);
+    assert_eq!(lseek(fd, 0, SEEK_END), 13);
+    assert_eq!(lseek(fd, -14, SEEK_END), -1);
+
+    // positional I/O leaves the offset alone
+    assert_eq!(pwrite(fd, b"W", 7), 1);
+    assert_eq!(pread(fd, &mut buffer[..5], 7), 5);
+    assert_eq!(&buffer[..5], b"World");
+    assert_eq!(lseek(fd, 0, SEEK_CUR), 13);
+
+    // shrink, then grow with zeros
+    assert_eq!(ftruncate(fd, 5), 0);
+    assert_eq!(ftruncate(fd, 8), 0);
+    assert_eq!(pread(fd, &mut buffer, 0), 8);
+    assert_eq!(&buffer[..8], b"Hello\0\0\0");
+    close(fd);
+
+    // appends go to the end wherever the offset is
+    let fd = open(fileb, OpenFlags::WRONLY | OpenFlags::APPEND) as usize;
+    write(fd, b"!");
+    lseek(fd, 0, SEEK_SET);
+    write(fd, b"!");
+    let mut st = Stat::default();
+    assert_eq!(fstat(fd, &mut st), 0);
+    assert_eq!(st.size, 10);
+    close(fd);
+
+    println!("filetest_seek passed!");
+    0
+}
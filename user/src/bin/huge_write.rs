@@ -4,7 +4,7 @@
 #[macro_use]
 extern crate user_lib;
 
-use user_lib::{close, get_time, open, write, OpenFlags};
+use user_lib::{close, fsync, get_time, open, write, OpenFlags};
 
 #[no_mangle]
 pub fn main() -> i32 {
@@ -12,7 +12,7 @@ pub fn main() -> i32 {
     for (i, ch) in buffer.iter_mut().enumerate() {
         *ch = i as u8;
     }
-    let f = open("testf\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+    let f = open("/tmp/testf\0", OpenFlags::CREATE | OpenFlags::WRONLY);
     if f < 0 {
         panic!("Open test file failed!");
     }
@@ -22,6 +22,8 @@ pub fn main() -> i32 {
     for _ in 0..1024 * size_mb {
         write(f, &buffer);
     }
+    // count the time to reach the disk if /tmp is on one, not only the cache
+    fsync(f);
     close(f);
     let time_ms = (get_time() - start) as usize;
     let speed_kbs = size_mb * 1000000 / time_ms;
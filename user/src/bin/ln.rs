@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{link, symlink};
+
+/// Usage: ln [-s] target name
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let soft = argc == 4 && argv[1] == "-s";
+    if argc != 3 && !soft {
+        println!("usage: ln [-s] target name");
+        return -1;
+    }
+    let (target, name) = (argv[argc - 2], argv[argc - 1]);
+    let ret = if soft { symlink(target, name) } else { link(target, name) };
+    if ret == -1 {
+        println!("ln: cannot link {} to {}", name, target);
+        return -1;
+    }
+    0
+}
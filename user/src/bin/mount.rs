@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::mount;
+
+/// Usage: mount -t type source dir
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc != 5 || argv[1] != "-t" {
+        println!("usage: mount -t type source dir");
+        return -1;
+    }
+    if mount(argv[3], argv[4], argv[2]) == -1 {
+        println!("mount: cannot mount {} on {}", argv[3], argv[4]);
+        return -1;
+    }
+    0
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::{format, string::String, vec::Vec};
+use user_lib::{close, getdents, open, read, OpenFlags};
+
+/// Read the whole file `path`, None if it cannot be opened.
+fn read_file(path: &str) -> Option<String> {
+    let fd = open(path, OpenFlags::RDONLY);
+    if fd == -1 {
+        return None;
+    }
+    let mut data = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let size = read(fd as usize, &mut buf);
+        if size <= 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..size as usize]);
+    }
+    close(fd as usize);
+    String::from_utf8(data).ok()
+}
+
+/// Usage: ps
+#[no_mangle]
+pub fn main() -> i32 {
+    let fd = open("/proc\0", OpenFlags::RDONLY);
+    if fd == -1 {
+        println!("ps: /proc is not mounted");
+        return -1;
+    }
+    let mut pids: Vec<usize> = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let size = getdents(fd as usize, &mut buf);
+        if size <= 0 {
+            break;
+        }
+        for name in buf[..size as usize].split(|&c| c == 0).filter(|n| !n.is_empty()) {
+            if let Some(pid) = core::str::from_utf8(name).ok().and_then(|n| n.parse().ok()) {
+                pids.push(pid);
+            }
+        }
+    }
+    close(fd as usize);
+    pids.sort_unstable();
+    println!("{:>5} {:>5} {:>5} S {:>8} CMD", "PID", "PPID", "PGID", "RSS");
+    for pid in pids {
+        // The process may have been reaped since the directory was read.
+        let status = match read_file(&format!("/proc/{}/status\0", pid)) {
+            Some(status) => status,
+            None => continue,
+        };
+        let field = |key: &str| {
+            status
+                .lines()
+                .find_map(|l| l.strip_prefix(key))
+                .map_or("", |v| v.trim_start_matches(':').trim())
+        };
+        let cmd = read_file(&format!("/proc/{}/cmdline\0", pid)).unwrap_or_default();
+        let cmd = if cmd.trim().is_empty() { field("Name") } else { cmd.trim() };
+        println!(
+            "{:>5} {:>5} {:>5} {} {:>8} {}",
+            pid,
+            field("PPid"),
+            field("Pgid"),
+            field("State"),
+            field("VmRSS"),
+            cmd
+        );
+    }
+    0
+}
@@ -0,0 +1,20 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::unlink;
+
+/// Usage: rm file...
+#[no_mangle]
+pub fn main(_argc: usize, argv: &[&str]) -> i32 {
+    let mut ret = 0;
+    for file in &argv[1..] {
+        if unlink(file) == -1 {
+            println!("rm: cannot remove {}", file);
+            ret = -1;
+        }
+    }
+    ret
+}
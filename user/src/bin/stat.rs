@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{stat, Stat, S_IFBLK, S_IFCHR, S_IFDIR, S_IFMT};
+
+/// Print seconds since the epoch as "YYYY-MM-DD hh:mm:ss" (UTC).
+fn print_time(name: &str, secs: i64) {
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // civil_from_days by Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    println!(
+        "{}: {}-{:02}-{:02} {:02}:{:02}:{:02}",
+        name,
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    );
+}
+
+/// Usage: stat file...
+#[no_mangle]
+pub fn main(_argc: usize, argv: &[&str]) -> i32 {
+    let mut ret = 0;
+    for path in &argv[1..] {
+        let mut st = Stat::default();
+        if stat(path, &mut st) == -1 {
+            println!("stat: cannot stat {}", path);
+            ret = -1;
+            continue;
+        }
+        let kind = match st.mode & S_IFMT {
+            S_IFDIR => "directory",
+            S_IFCHR => "character special file",
+            S_IFBLK => "block special file",
+            _ => "regular file",
+        };
+        println!("  File: {}", path);
+        println!(
+            "  Size: {}\tBlocks: {}\tInode: {}\tLinks: {}\t{}",
+            st.size, st.blocks, st.ino, st.nlink, kind
+        );
+        println!("Access: {:o}\tUid: {}\tGid: {}", st.mode & !S_IFMT, st.uid, st.gid);
+        print_time("Access", st.atime);
+        print_time("Modify", st.mtime);
+        print_time("Change", st.ctime);
+    }
+    ret
+}
@@ -0,0 +1,11 @@
+#![no_std]
+#![no_main]
+
+extern crate user_lib;
+
+use user_lib::sync;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    sync() as i32
+}
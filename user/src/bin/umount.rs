@@ -0,0 +1,20 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::umount;
+
+/// Usage: umount dir...
+#[no_mangle]
+pub fn main(_argc: usize, argv: &[&str]) -> i32 {
+    let mut ret = 0;
+    for dir in &argv[1..] {
+        if umount(dir) == -1 {
+            println!("umount: cannot unmount {}", dir);
+            ret = -1;
+        }
+    }
+    ret
+}
@@ -11,12 +11,19 @@ const LF: u8 = 0x0au8;
 const CR: u8 = 0x0du8;
 const DL: u8 = 0x7fu8;
 const BS: u8 = 0x08u8;
+// getchar() returns 0 at end of file (^D), which the shell ignores.
+const EOF: u8 = 0x00u8;
 const LINE_START: &str = ">> ";
 
+use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 use user_lib::console::getchar;
-use user_lib::{close, dup, exec, fork, open, pipe, waitpid, OpenFlags};
+use user_lib::{
+    chdir, close, dup, exec, fork, getpid, is_stopped, killpg, open, pipe, setpgid, setsid, sigaction,
+    tcgetattr, tcsetattr, tcsetpgrp, waitpid_nb, waitpid_untraced, OpenFlags, SignalFlags,
+    Termios, ECHO, ICANON, SIG_DFL, SIG_IGN,
+};
 
 #[derive(Debug)]
 struct ProcessArguments {
@@ -74,9 +81,131 @@ impl ProcessArguments {
     }
 }
 
+struct Job {
+    id: usize,
+    pgid: usize,
+    pids: Vec<usize>,
+    command: String,
+    stopped: bool,
+}
+
+/// Signals that the shell ignores itself, but its children must not.
+fn job_control_signals() -> SignalFlags {
+    SignalFlags::SIGINT | SignalFlags::SIGTSTP | SignalFlags::SIGTTIN
+}
+
+/// Wait until every process of a foreground job exits, or the job is stopped.
+fn wait_job(job: &mut Job) {
+    let mut exit_code: i32 = 0;
+    let mut i = 0;
+    while i < job.pids.len() {
+        let exit_pid = waitpid_untraced(job.pids[i], &mut exit_code);
+        assert_eq!(job.pids[i] as isize, exit_pid);
+        if is_stopped(exit_code) {
+            job.stopped = true;
+            i += 1;
+        } else {
+            job.pids.remove(i);
+        }
+    }
+}
+
+/// Give the terminal to `job` and wait for it, keeping it in `jobs` if it is stopped.
+fn run_foreground(mut job: Job, jobs: &mut Vec<Job>) {
+    tcsetpgrp(0, job.pgid);
+    if job.stopped {
+        job.stopped = false;
+        killpg(job.pgid, SignalFlags::SIGCONT.bits());
+    }
+    wait_job(&mut job);
+    tcsetpgrp(0, getpid() as usize);
+    if job.stopped {
+        println!("[{}]+  Stopped\t{}", job.id, job.command);
+        jobs.push(job);
+    }
+}
+
+/// Report background jobs whose processes have all exited.
+fn reap_jobs(jobs: &mut Vec<Job>) {
+    let mut exit_code: i32 = 0;
+    let mut i = 0;
+    while i < jobs.len() {
+        jobs[i]
+            .pids
+            .retain(|&pid| waitpid_nb(pid, &mut exit_code) != pid as isize);
+        if jobs[i].pids.is_empty() {
+            let job = jobs.remove(i);
+            println!("[{}]+  Done\t{}", job.id, job.command);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Find the job given by `%n` or `n`, or the most recent one.
+fn find_job(jobs: &[Job], arg: Option<&str>) -> Option<usize> {
+    match arg {
+        Some(arg) => {
+            let id: usize = arg.trim_start_matches('%').parse().ok()?;
+            jobs.iter().position(|job| job.id == id)
+        }
+        None => jobs.len().checked_sub(1),
+    }
+}
+
+/// Run the `cd` and job control builtins. Return false if `line` is not one.
+fn builtin(line: &str, jobs: &mut Vec<Job>) -> bool {
+    let mut args = line.split(' ').filter(|arg| !arg.is_empty());
+    match args.next() {
+        Some("cd") => {
+            let dir = args.next().unwrap_or("/");
+            if chdir(format!("{}\0", dir).as_str()) == -1 {
+                println!("cd: {}: No such directory", dir);
+            }
+        }
+        Some("jobs") => {
+            for job in jobs.iter() {
+                let state = if job.stopped { "Stopped" } else { "Running" };
+                println!("[{}]  {}\t{}", job.id, state, job.command);
+            }
+        }
+        Some("fg") => match find_job(jobs, args.next()) {
+            Some(idx) => {
+                let job = jobs.remove(idx);
+                println!("{}", job.command);
+                run_foreground(job, jobs);
+            }
+            None => println!("fg: no such job"),
+        },
+        Some("bg") => match find_job(jobs, args.next()) {
+            Some(idx) => {
+                let job = &mut jobs[idx];
+                job.stopped = false;
+                killpg(job.pgid, SignalFlags::SIGCONT.bits());
+                println!("[{}]+ {} &", job.id, job.command);
+            }
+            None => println!("bg: no such job"),
+        },
+        _ => return false,
+    }
+    true
+}
+
 #[no_mangle]
 pub fn main() -> i32 {
     println!("Rust user shell");
+    // Take the terminal in a new session, so that ^C and ^Z only reach foreground jobs.
+    setsid();
+    tcsetpgrp(0, getpid() as usize);
+    sigaction(job_control_signals(), SIG_IGN);
+    // Commands run with the terminal's default (canonical) mode, while the shell edits the line
+    // itself in raw mode.
+    let mut job_mode = Termios::default();
+    tcgetattr(0, &mut job_mode);
+    let mut shell_mode = job_mode;
+    shell_mode.lflag &= !(ICANON | ECHO);
+    tcsetattr(0, &shell_mode);
+    let mut jobs: Vec<Job> = Vec::new();
     let mut line: String = String::new();
     print!("{}", LINE_START);
     loop {
@@ -84,8 +213,14 @@ pub fn main() -> i32 {
         match c {
             LF | CR => {
                 println!("");
-                if !line.is_empty() {
-                    let splited: Vec<_> = line.as_str().split('|').collect();
+                let command = String::from(line.trim());
+                let (command, background) = match command.strip_suffix('&') {
+                    Some(command) => (command.trim_end(), true),
+                    None => (command.as_str(), false),
+                };
+                tcsetattr(0, &job_mode);
+                if !command.is_empty() && !builtin(command, &mut jobs) {
+                    let splited: Vec<_> = command.split('|').collect();
                     let process_arguments_list: Vec<_> = splited
                         .iter()
                         .map(|&cmd| ProcessArguments::new(cmd))
@@ -120,10 +255,17 @@ pub fn main() -> i32 {
                                 pipes_fd.push(pipe_fd);
                             }
                         }
-                        let mut children: Vec<_> = Vec::new();
+                        let mut children: Vec<usize> = Vec::new();
                         for (i, process_argument) in process_arguments_list.iter().enumerate() {
                             let pid = fork();
                             if pid == 0 {
+                                // join the job's process group, led by the first process
+                                let pgid = children.first().copied().unwrap_or(getpid() as usize);
+                                setpgid(0, pgid);
+                                if !background {
+                                    tcsetpgrp(0, pgid);
+                                }
+                                sigaction(job_control_signals(), SIG_DFL);
                                 let input = &process_argument.input;
                                 let output = &process_argument.output;
                                 let args_copy = &process_argument.args_copy;
@@ -172,13 +314,19 @@ pub fn main() -> i32 {
                                     close(pipe_fd[0]);
                                     close(pipe_fd[1]);
                                 }
-                                // execute new application
-                                if exec(args_copy[0].as_str(), args_addr.as_slice()) == -1 {
+                                // execute new application, found in "/" unless a path is given
+                                let mut path = args_copy[0].clone();
+                                if !path.contains('/') {
+                                    path.insert(0, '/');
+                                }
+                                if exec(path.as_str(), args_addr.as_slice()) == -1 {
                                     println!("Error when executing!");
                                     return -4;
                                 }
                                 unreachable!();
                             } else {
+                                let pid = pid as usize;
+                                setpgid(pid, children.first().copied().unwrap_or(pid));
                                 children.push(pid);
                             }
                         }
@@ -186,15 +334,24 @@ pub fn main() -> i32 {
                             close(pipe_fd[0]);
                             close(pipe_fd[1]);
                         }
-                        let mut exit_code: i32 = 0;
-                        for pid in children.into_iter() {
-                            let exit_pid = waitpid(pid as usize, &mut exit_code);
-                            assert_eq!(pid, exit_pid);
-                            //println!("Shell: Process {} exited with code {}", pid, exit_code);
+                        let job = Job {
+                            id: jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1,
+                            pgid: children[0],
+                            pids: children,
+                            command: String::from(command),
+                            stopped: false,
+                        };
+                        if background {
+                            println!("[{}] {}", job.id, job.pgid);
+                            jobs.push(job);
+                        } else {
+                            run_foreground(job, &mut jobs);
                         }
                     }
-                    line.clear();
                 }
+                tcsetattr(0, &shell_mode);
+                line.clear();
+                reap_jobs(&mut jobs);
                 print!("{}", LINE_START);
             }
             BS | DL => {
@@ -205,6 +362,7 @@ pub fn main() -> i32 {
                     line.pop();
                 }
             }
+            EOF => {}
             _ => {
                 print!("{}", c as char);
                 line.push(c as char);
@@ -64,6 +64,7 @@ bitflags::bitflags! {
         const RDWR = 1 << 1;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        const APPEND = 1 << 11;
     }
 }
 
@@ -71,6 +72,98 @@ pub fn open(path: &str, flags: OpenFlags) -> isize {
     sys_open(path, flags.bits)
 }
 
+const AT_REMOVEDIR: u32 = 0x200;
+
+pub fn mkdir(path: &str) -> isize {
+    sys_mkdir(path)
+}
+
+/// Remove an empty directory.
+pub fn rmdir(path: &str) -> isize {
+    sys_unlink(path, AT_REMOVEDIR)
+}
+
+pub fn unlink(path: &str) -> isize {
+    sys_unlink(path, 0)
+}
+
+/// Make `new_path` another name of the file `old_path`.
+pub fn link(old_path: &str, new_path: &str) -> isize {
+    sys_link(old_path, new_path)
+}
+
+/// Make `path` a symbolic link to `target`.
+pub fn symlink(target: &str, path: &str) -> isize {
+    sys_symlink(target, path)
+}
+
+/// Store the target of the symbolic link `path` in `buf` without a '\0' terminator, return the
+/// bytes written or -1.
+pub fn readlink(path: &str, buf: &mut [u8]) -> isize {
+    sys_readlink(path, buf)
+}
+
+pub fn rename(old_path: &str, new_path: &str) -> isize {
+    sys_rename(old_path, new_path)
+}
+
+/// Mount the file system `fstype` on the device `source`, or "none", at the directory `target`.
+pub fn mount(source: &str, target: &str, fstype: &str) -> isize {
+    sys_mount(source, target, fstype)
+}
+
+pub fn umount(target: &str) -> isize {
+    sys_umount(target)
+}
+
+pub fn chdir(path: &str) -> isize {
+    sys_chdir(path)
+}
+
+/// Store the working directory in `buf` with a '\0' terminator, return the bytes written or -1.
+pub fn getcwd(buf: &mut [u8]) -> isize {
+    sys_getcwd(buf)
+}
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFBLK: u32 = 0o060000;
+
+/// File status, laid out as Linux's `struct stat`. Times are in seconds since the epoch.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pad0: u32,
+    pub rdev: u64,
+    pub size: i64,
+    pub blksize: i64,
+    pub blocks: i64,
+    pub atime: i64,
+    pub atime_nsec: i64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+    unused: [i64; 3],
+}
+
+pub fn stat(path: &str, st: &mut Stat) -> isize {
+    sys_stat(path, st as *mut _ as usize)
+}
+
+pub fn fstat(fd: usize, st: &mut Stat) -> isize {
+    sys_fstat(fd, st as *mut _ as usize)
+}
+
 pub fn dup(fd: usize) -> isize {
     sys_dup(fd)
 }
@@ -83,6 +176,12 @@ pub fn pipe(pipe_fd: &mut [usize]) -> isize {
     sys_pipe(pipe_fd)
 }
 
+/// Read the names of the next entries of the directory `fd`, each terminated by '\0'. Return
+/// the bytes read, 0 at the end.
+pub fn getdents(fd: usize, buf: &mut [u8]) -> isize {
+    sys_getdents(fd, buf)
+}
+
 pub fn read(fd: usize, buf: &mut [u8]) -> isize {
     sys_read(fd, buf)
 }
@@ -91,6 +190,39 @@ pub fn write(fd: usize, buf: &[u8]) -> isize {
     sys_write(fd, buf)
 }
 
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// Move the file offset to `offset` relative to `whence` (SEEK_*), return the new offset.
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    sys_lseek(fd, offset, whence)
+}
+
+/// Read at `offset` without moving the file offset.
+pub fn pread(fd: usize, buf: &mut [u8], offset: usize) -> isize {
+    sys_pread(fd, buf, offset)
+}
+
+/// Write at `offset` without moving the file offset.
+pub fn pwrite(fd: usize, buf: &[u8], offset: usize) -> isize {
+    sys_pwrite(fd, buf, offset)
+}
+
+pub fn ftruncate(fd: usize, len: usize) -> isize {
+    sys_ftruncate(fd, len)
+}
+
+/// Write all modified blocks of the file system back to the disk.
+pub fn sync() -> isize {
+    sys_sync()
+}
+
+/// Write the modified data of `fd` back to the disk.
+pub fn fsync(fd: usize) -> isize {
+    sys_fsync(fd)
+}
+
 pub fn exit(exit_code: i32) -> ! {
     sys_exit(exit_code)
 }
@@ -117,7 +249,7 @@ pub fn exec(path: &str, args: &[*const u8]) -> isize {
 
 pub fn wait(exit_code: &mut i32) -> isize {
     loop {
-        match sys_waitpid(-1, exit_code as *mut _) {
+        match sys_waitpid(-1, exit_code as *mut _, 0) {
             -2 => {
                 sched_yield();
             }
@@ -129,7 +261,7 @@ pub fn wait(exit_code: &mut i32) -> isize {
 
 pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     loop {
-        match sys_waitpid(pid as isize, exit_code as *mut _) {
+        match sys_waitpid(pid as isize, exit_code as *mut _, 0) {
             -2 => {
                 sched_yield();
             }
@@ -140,7 +272,26 @@ pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
 }
 
 pub fn waitpid_nb(pid: usize, exit_code: &mut i32) -> isize {
-    sys_waitpid(pid as isize, exit_code as *mut _)
+    sys_waitpid(pid as isize, exit_code as *mut _, 0)
+}
+
+const WUNTRACED: usize = 2;
+
+/// Like `waitpid`, but also returns when the child stops. Check with `is_stopped`.
+pub fn waitpid_untraced(pid: usize, exit_code: &mut i32) -> isize {
+    loop {
+        match sys_waitpid(pid as isize, exit_code as *mut _, WUNTRACED) {
+            -2 => {
+                sched_yield();
+            }
+            exit_pid => return exit_pid,
+        }
+    }
+}
+
+/// Stopped children are reported with the negated stop signal number as exit code.
+pub fn is_stopped(exit_code: i32) -> bool {
+    matches!(-exit_code, 19 | 20 | 21)
 }
 
 bitflags::bitflags! {
@@ -149,12 +300,126 @@ bitflags::bitflags! {
         const SIGILL    = 1 << 4;
         const SIGABRT   = 1 << 6;
         const SIGFPE    = 1 << 8;
+        const SIGKILL   = 1 << 9;
         const SIGSEGV   = 1 << 11;
+        const SIGCONT   = 1 << 18;
+        const SIGSTOP   = 1 << 19;
+        const SIGTSTP   = 1 << 20;
+        const SIGTTIN   = 1 << 21;
     }
 }
 
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
 pub fn kill(pid: usize, signal: i32) -> isize {
-    sys_kill(pid, signal)
+    sys_kill(pid as isize, signal)
+}
+
+/// Send a signal to every process in a process group.
+pub fn killpg(pgid: usize, signal: i32) -> isize {
+    sys_kill(-(pgid as isize), signal)
+}
+
+/// Set the disposition of signals to `SIG_DFL` or `SIG_IGN`.
+pub fn sigaction(signal: SignalFlags, handler: usize) -> isize {
+    sys_sigaction(signal.bits, handler)
+}
+
+pub fn setpgid(pid: usize, pgid: usize) -> isize {
+    sys_setpgid(pid, pgid)
+}
+
+pub fn getpgid(pid: usize) -> isize {
+    sys_getpgid(pid)
+}
+
+pub fn setsid() -> isize {
+    sys_setsid()
+}
+
+pub fn getsid(pid: usize) -> isize {
+    sys_getsid(pid)
+}
+
+const TCGETS: u32 = 0x5401;
+const TCSETS: u32 = 0x5402;
+const TIOCGPGRP: u32 = 0x540F;
+const TIOCSPGRP: u32 = 0x5410;
+
+/// Make `pgid` the foreground process group of the terminal `fd`.
+pub fn tcsetpgrp(fd: usize, pgid: usize) -> isize {
+    let pgid = pgid as u32;
+    sys_ioctl(fd, TIOCSPGRP, &pgid as *const _ as usize)
+}
+
+pub fn tcgetpgrp(fd: usize) -> isize {
+    let mut pgid = 0u32;
+    match sys_ioctl(fd, TIOCGPGRP, &mut pgid as *mut _ as usize) {
+        0 => pgid as isize,
+        err => err,
+    }
+}
+
+pub const ICRNL: u32 = 0o400;
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+
+pub const VINTR: usize = 0;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+
+/// Terminal attributes, laid out as Linux's `struct termios`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; 19],
+}
+
+pub fn tcgetattr(fd: usize, termios: &mut Termios) -> isize {
+    sys_ioctl(fd, TCGETS, termios as *mut _ as usize)
+}
+
+pub fn tcsetattr(fd: usize, termios: &Termios) -> isize {
+    sys_ioctl(fd, TCSETS, termios as *const _ as usize)
+}
+
+const SYSLOG_ACTION_READ_ALL: usize = 3;
+const SYSLOG_ACTION_READ_CLEAR: usize = 4;
+const SYSLOG_ACTION_CLEAR: usize = 5;
+const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+
+/// Read the most recent kernel log messages, optionally clearing the log.
+pub fn syslog_read(buf: &mut [u8], clear: bool) -> isize {
+    let ty = if clear {
+        SYSLOG_ACTION_READ_CLEAR
+    } else {
+        SYSLOG_ACTION_READ_ALL
+    };
+    sys_syslog(ty, buf.as_mut_ptr(), buf.len())
+}
+
+pub fn syslog_clear() -> isize {
+    sys_syslog(SYSLOG_ACTION_CLEAR, core::ptr::null_mut(), 0)
+}
+
+/// Set the level of kernel messages printed on the console: 0 (off), 1 (error) to 5 (trace).
+pub fn syslog_console_level(level: usize) -> isize {
+    sys_syslog(SYSLOG_ACTION_CONSOLE_LEVEL, core::ptr::null_mut(), level)
+}
+
+pub fn syslog_size() -> isize {
+    sys_syslog(SYSLOG_ACTION_SIZE_BUFFER, core::ptr::null_mut(), 0)
 }
 
 pub fn sleep(sleep_ms: usize) {
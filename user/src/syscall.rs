@@ -1,15 +1,41 @@
 use core::arch::asm;
 
+const SYSCALL_GETCWD: usize = 17;
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_IOCTL: usize = 29;
+const SYSCALL_MKDIR: usize = 34;
+const SYSCALL_UNLINK: usize = 35;
+const SYSCALL_SYMLINK: usize = 36;
+const SYSCALL_LINK: usize = 37;
+const SYSCALL_RENAME: usize = 38;
+const SYSCALL_UMOUNT: usize = 39;
+const SYSCALL_MOUNT: usize = 40;
+const SYSCALL_FTRUNCATE: usize = 46;
+const SYSCALL_CHDIR: usize = 49;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
+const SYSCALL_GETDENTS: usize = 61;
+const SYSCALL_LSEEK: usize = 62;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_PREAD: usize = 67;
+const SYSCALL_PWRITE: usize = 68;
+const SYSCALL_READLINK: usize = 78;
+const SYSCALL_STAT: usize = 79;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_SLEEP: usize = 101;
+const SYSCALL_SYSLOG: usize = 116;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_KILL: usize = 129;
+const SYSCALL_SIGACTION: usize = 134;
+const SYSCALL_SETPGID: usize = 154;
+const SYSCALL_GETPGID: usize = 155;
+const SYSCALL_GETSID: usize = 156;
+const SYSCALL_SETSID: usize = 157;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
 const SYSCALL_FORK: usize = 220;
@@ -42,10 +68,69 @@ fn syscall(id: usize, arg0: usize, arg1: usize, arg2: usize) -> isize {
   ret
 }
 
+pub fn sys_getcwd(buf: &mut [u8]) -> isize {
+  syscall(SYSCALL_GETCWD, buf.as_mut_ptr() as _, buf.len(), 0)
+}
+
+/// A system call with a fourth argument, passed in r10 since rcx is clobbered.
+#[inline(always)]
+fn syscall4(id: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> isize {
+  let ret;
+  unsafe {
+    asm!(
+    "syscall",
+    in("rax") id, in("rdi") arg0, in("rsi") arg1, in("rdx") arg2, in("r10") arg3,
+    out("rcx") _, out("r11") _, // clobbered by syscall
+    lateout("rax") ret
+    );
+  }
+  ret
+}
+
 pub fn sys_dup(fd: usize) -> isize {
   syscall(SYSCALL_DUP, fd, 0, 0)
 }
 
+pub fn sys_ioctl(fd: usize, cmd: u32, arg: usize) -> isize {
+  syscall(SYSCALL_IOCTL, fd, cmd as _, arg)
+}
+
+pub fn sys_mkdir(path: &str) -> isize {
+  syscall(SYSCALL_MKDIR, path.as_ptr() as _, 0, 0)
+}
+
+pub fn sys_unlink(path: &str, flags: u32) -> isize {
+  syscall(SYSCALL_UNLINK, path.as_ptr() as _, flags as _, 0)
+}
+
+pub fn sys_symlink(target: &str, path: &str) -> isize {
+  syscall(SYSCALL_SYMLINK, target.as_ptr() as _, path.as_ptr() as _, 0)
+}
+
+pub fn sys_readlink(path: &str, buf: &mut [u8]) -> isize {
+  syscall(SYSCALL_READLINK, path.as_ptr() as _, buf.as_mut_ptr() as _, buf.len())
+}
+
+pub fn sys_link(old_path: &str, new_path: &str) -> isize {
+  syscall(SYSCALL_LINK, old_path.as_ptr() as _, new_path.as_ptr() as _, 0)
+}
+
+pub fn sys_rename(old_path: &str, new_path: &str) -> isize {
+  syscall(SYSCALL_RENAME, old_path.as_ptr() as _, new_path.as_ptr() as _, 0)
+}
+
+pub fn sys_mount(source: &str, target: &str, fstype: &str) -> isize {
+  syscall(SYSCALL_MOUNT, source.as_ptr() as _, target.as_ptr() as _, fstype.as_ptr() as _)
+}
+
+pub fn sys_umount(target: &str) -> isize {
+  syscall(SYSCALL_UMOUNT, target.as_ptr() as _, 0, 0)
+}
+
+pub fn sys_chdir(path: &str) -> isize {
+  syscall(SYSCALL_CHDIR, path.as_ptr() as _, 0, 0)
+}
+
 pub fn sys_open(path: &str, flags: u32) -> isize {
   syscall(SYSCALL_OPEN, path.as_ptr() as _, flags as _, 0)
 }
@@ -58,6 +143,10 @@ pub fn sys_pipe(pipe: &mut [usize]) -> isize {
   syscall(SYSCALL_PIPE, pipe.as_ptr() as _, 0, 0)
 }
 
+pub fn sys_getdents(fd: usize, buf: &mut [u8]) -> isize {
+  syscall(SYSCALL_GETDENTS, fd, buf.as_mut_ptr() as _, buf.len())
+}
+
 pub fn sys_read(fd: usize, buf: &mut [u8]) -> isize {
   syscall(SYSCALL_READ, fd, buf.as_ptr() as _, buf.len())
 }
@@ -66,6 +155,38 @@ pub fn sys_write(fd: usize, buf: &[u8]) -> isize {
   syscall(SYSCALL_WRITE, fd, buf.as_ptr() as _, buf.len())
 }
 
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+  syscall(SYSCALL_LSEEK, fd, offset as _, whence)
+}
+
+pub fn sys_pread(fd: usize, buf: &mut [u8], offset: usize) -> isize {
+  syscall4(SYSCALL_PREAD, fd, buf.as_mut_ptr() as _, buf.len(), offset)
+}
+
+pub fn sys_pwrite(fd: usize, buf: &[u8], offset: usize) -> isize {
+  syscall4(SYSCALL_PWRITE, fd, buf.as_ptr() as _, buf.len(), offset)
+}
+
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+  syscall(SYSCALL_FTRUNCATE, fd, len, 0)
+}
+
+pub fn sys_stat(path: &str, st: usize) -> isize {
+  syscall(SYSCALL_STAT, path.as_ptr() as _, st, 0)
+}
+
+pub fn sys_fstat(fd: usize, st: usize) -> isize {
+  syscall(SYSCALL_FSTAT, fd, st, 0)
+}
+
+pub fn sys_sync() -> isize {
+  syscall(SYSCALL_SYNC, 0, 0, 0)
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+  syscall(SYSCALL_FSYNC, fd, 0, 0)
+}
+
 pub fn sys_exit(exit_code: i32) -> ! {
   syscall(SYSCALL_EXIT, exit_code as _, 0, 0);
   panic!("sys_exit never returns!");
@@ -75,12 +196,36 @@ pub fn sys_sleep(sleep_ms: usize) -> isize {
   syscall(SYSCALL_SLEEP, sleep_ms, 0, 0)
 }
 
+pub fn sys_syslog(ty: usize, buf: *mut u8, len: usize) -> isize {
+  syscall(SYSCALL_SYSLOG, ty, buf as _, len)
+}
+
 pub fn sys_yield() -> isize {
   syscall(SYSCALL_YIELD, 0, 0, 0)
 }
 
-pub fn sys_kill(pid: usize, signal: i32) -> isize {
-  syscall(SYSCALL_KILL, pid, signal as _, 0)
+pub fn sys_kill(pid: isize, signal: i32) -> isize {
+  syscall(SYSCALL_KILL, pid as _, signal as _, 0)
+}
+
+pub fn sys_sigaction(signal: i32, handler: usize) -> isize {
+  syscall(SYSCALL_SIGACTION, signal as _, handler, 0)
+}
+
+pub fn sys_setpgid(pid: usize, pgid: usize) -> isize {
+  syscall(SYSCALL_SETPGID, pid, pgid, 0)
+}
+
+pub fn sys_getpgid(pid: usize) -> isize {
+  syscall(SYSCALL_GETPGID, pid, 0, 0)
+}
+
+pub fn sys_getsid(pid: usize) -> isize {
+  syscall(SYSCALL_GETSID, pid, 0, 0)
+}
+
+pub fn sys_setsid() -> isize {
+  syscall(SYSCALL_SETSID, 0, 0, 0)
 }
 
 pub fn sys_get_time() -> isize {
@@ -99,8 +244,8 @@ pub fn sys_exec(path: &str, args: &[*const u8]) -> isize {
   syscall(SYSCALL_EXEC, path.as_ptr() as _, args.as_ptr() as _, 0)
 }
 
-pub fn sys_waitpid(pid: isize, exit_code: *mut i32) -> isize {
-  syscall(SYSCALL_WAITPID, pid as _, exit_code as _, 0)
+pub fn sys_waitpid(pid: isize, exit_code: *mut i32, options: usize) -> isize {
+  syscall(SYSCALL_WAITPID, pid as _, exit_code as _, options)
 }
 
 pub fn sys_thread_create(entry: usize, arg: usize) -> isize {